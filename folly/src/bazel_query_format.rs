@@ -0,0 +1,350 @@
+use std::collections::BTreeSet;
+use std::io::Error;
+
+use indexmap::IndexSet;
+use serde::Serialize;
+
+use crate::build_file::{
+  dep_target_name, external_label, package_label, rule_class, sorted_files,
+  target_name, test_rule_class, test_target_name, tests_use_library,
+  BuildOptions,
+};
+use crate::error::FollyError;
+use crate::file_system::FileSystemProvider;
+use crate::graph::sorted_nodes;
+use crate::types::{UnitInfo, UnitKey, UnitMap, UnitObj};
+
+// What `bazel query --output=jsonproto` prints: a QueryResult, whose fields
+// are named the way protobuf's JSON mapping names them. Only rules are
+// listed, and of their attributes only the ones a BUILD file would set.
+#[derive(Serialize)]
+struct QueryResult {
+  target: Vec<Target>,
+}
+
+#[derive(Serialize)]
+struct Target {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  rule: Rule,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+  name: String,
+  rule_class: &'static str,
+  attribute: Vec<Attribute>,
+  // Every label among the attributes, sorted.
+  rule_input: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Attribute {
+  name: &'static str,
+  #[serde(rename = "type")]
+  kind: &'static str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  string_value: Option<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  string_list_value: Vec<String>,
+  explicitly_specified: bool,
+}
+
+impl Attribute {
+  fn string(name: &'static str, value: String) -> Self {
+    Attribute {
+      name,
+      kind: "STRING",
+      string_value: Some(value),
+      string_list_value: Vec::new(),
+      explicitly_specified: true,
+    }
+  }
+
+  fn list(name: &'static str, kind: &'static str, values: Vec<String>) -> Self {
+    Attribute {
+      name,
+      kind,
+      string_value: None,
+      string_list_value: values,
+      explicitly_specified: true,
+    }
+  }
+}
+
+// Files are labels in the unit's own package, e.g. //folly/io:IOBuf.h.
+fn file_labels(
+  key: &UnitKey,
+  files: &[String],
+  opts: &BuildOptions,
+) -> Vec<String> {
+  files
+    .iter()
+    .map(|file| package_label(&opts.label_prefix, key, file))
+    .collect()
+}
+
+// What internal_label would give from another package.
+fn unit_label(unit: &UnitObj, opts: &BuildOptions, cc: bool) -> String {
+  package_label(&opts.label_prefix, &unit.key, &dep_target_name(unit, cc))
+}
+
+fn labels(
+  deps: &mut dyn Iterator<Item = &UnitObj>,
+  external: &IndexSet<(String, UnitKey)>,
+  opts: &BuildOptions,
+  cc: bool,
+) -> Vec<String> {
+  deps
+    .map(|dep| unit_label(dep, opts, cc))
+    .chain(
+      external
+        .iter()
+        .map(|(workspace, key)| external_label(workspace, key)),
+    )
+    .collect()
+}
+
+fn rule(
+  name: String,
+  rule_class: &'static str,
+  mut attribute: Vec<Attribute>,
+  info: &UnitInfo<UnitKey>,
+) -> Rule {
+  for (name, flags) in
+    [("copts", &info.build_flags), ("linkopts", &info.link_opts)]
+  {
+    if !flags.is_empty() {
+      attribute.push(Attribute::list(name, "STRING_LIST", flags.clone()));
+    }
+  }
+  attribute.push(Attribute::list(
+    "visibility",
+    "NODEP_LABEL_LIST",
+    vec![info.visibility.label().to_string()],
+  ));
+  let rule_input: BTreeSet<String> = attribute
+    .iter()
+    .filter(|attr| attr.kind == "LABEL_LIST")
+    .flat_map(|attr| attr.string_list_value.iter().cloned())
+    .collect();
+  Rule {
+    name,
+    rule_class,
+    attribute,
+    rule_input: rule_input.into_iter().collect(),
+  }
+}
+
+// The rules render_unit writes, less the cc_proto_library and sh_test
+// wrappers around the main ones. Unlike a BUILD file, every label is
+// absolute, as query prints them.
+fn render_rules(unit: &UnitObj, opts: &BuildOptions) -> Vec<Rule> {
+  let info = unit.val.borrow();
+  let mut rules = Vec::new();
+  let lists = |attribute: &mut Vec<Attribute>, name, values: Vec<String>| {
+    if !values.is_empty() {
+      attribute.push(Attribute::list(name, "LABEL_LIST", values));
+    }
+  };
+
+  if info.has_library() {
+    let mut attribute = vec![Attribute::string("name", target_name(&unit.key))];
+    if !info.protos.is_empty() {
+      lists(
+        &mut attribute,
+        "srcs",
+        file_labels(&unit.key, &info.protos, opts),
+      );
+    } else if info.has_main {
+      // As in render_unit, binaries export no headers.
+      let files: Vec<String> = info
+        .headers
+        .iter()
+        .chain(info.srcs.iter())
+        .cloned()
+        .collect();
+      lists(
+        &mut attribute,
+        "srcs",
+        file_labels(&unit.key, &sorted_files(&files, opts), opts),
+      );
+    } else {
+      lists(
+        &mut attribute,
+        "hdrs",
+        file_labels(&unit.key, &sorted_files(&info.headers, opts), opts),
+      );
+      lists(
+        &mut attribute,
+        "srcs",
+        file_labels(&unit.key, &sorted_files(&info.srcs, opts), opts),
+      );
+    }
+    let deps = labels(
+      &mut info.deps.keys(),
+      &info.external_deps,
+      opts,
+      info.protos.is_empty(),
+    );
+    lists(&mut attribute, "deps", deps);
+    rules.push(rule(
+      unit_label(unit, opts, false),
+      rule_class(&info, opts),
+      attribute,
+      &info,
+    ));
+  }
+
+  if !info.test_srcs.is_empty() {
+    let name = test_target_name(&unit.key);
+    let mut attribute = vec![Attribute::string("name", name.clone())];
+    lists(
+      &mut attribute,
+      "srcs",
+      file_labels(&unit.key, &sorted_files(&info.test_srcs, opts), opts),
+    );
+    let mut deps = labels(
+      &mut info.test_deps.iter(),
+      &info.test_external_deps,
+      opts,
+      true,
+    );
+    if tests_use_library(&info) {
+      deps.insert(0, unit_label(unit, opts, true));
+    }
+    lists(&mut attribute, "deps", deps);
+    rules.push(rule(
+      package_label(&opts.label_prefix, &unit.key, &name),
+      test_rule_class(opts),
+      attribute,
+      &info,
+    ));
+  }
+  rules
+}
+
+pub fn render_bazel_query(
+  map: &UnitMap,
+  opts: &BuildOptions,
+) -> Result<String, Error> {
+  let target: Vec<Target> = sorted_nodes(map)
+    .iter()
+    .filter(|unit| opts.emits(&unit.key) && !unit.val.borrow().pre_existing)
+    .flat_map(|unit| render_rules(unit, opts))
+    .map(|rule| Target { kind: "RULE", rule })
+    .collect();
+  Ok(serde_json::to_string_pretty(&QueryResult { target })? + "\n")
+}
+
+// Writes query.json to opts.output_dir, with the rules the BUILD files would
+// have.
+pub fn export_bazel_query<F: FileSystemProvider>(
+  fs: &F,
+  map: &UnitMap,
+  opts: &BuildOptions,
+) -> Result<(), FollyError> {
+  let contents = render_bazel_query(map, opts)?;
+  opts
+    .sink(fs)
+    .write(&opts.output_dir.join("query.json"), &contents)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{build, build_options, mock_fs};
+  use serde_json::{json, Value};
+  use std::io::Read;
+
+  const FILES: &[(&str, &str)] = &[
+    ("folly/Bar.h", "#pragma once\n"),
+    ("folly/io/Foo.cpp", "#include <folly/io/Foo.h>\n"),
+    ("folly/io/Foo.h", "#pragma once\n#include <folly/Bar.h>\n"),
+    ("folly/io/Footest.cpp", "#include <folly/io/Foo.h>\n"),
+  ];
+
+  #[test]
+  fn parses_as_a_query_result() {
+    let out = render_bazel_query(&build(FILES), &build_options()).unwrap();
+    let result: Value = serde_json::from_str(&out).unwrap();
+    let targets = result["target"].as_array().unwrap();
+    assert_eq!(targets.len(), 3);
+    for target in targets {
+      assert_eq!(target["type"], "RULE");
+    }
+
+    let bar = &targets[0]["rule"];
+    assert_eq!(bar["name"], "//folly:Bar");
+    assert_eq!(bar["ruleClass"], "cc_library");
+    assert_eq!(
+      bar["attribute"],
+      json!([
+        {
+          "name": "name",
+          "type": "STRING",
+          "stringValue": "Bar",
+          "explicitlySpecified": true,
+        },
+        {
+          "name": "hdrs",
+          "type": "LABEL_LIST",
+          "stringListValue": ["//folly:Bar.h"],
+          "explicitlySpecified": true,
+        },
+        {
+          "name": "visibility",
+          "type": "NODEP_LABEL_LIST",
+          "stringListValue": ["//visibility:public"],
+          "explicitlySpecified": true,
+        },
+      ])
+    );
+    assert_eq!(bar["ruleInput"], json!(["//folly:Bar.h"]));
+
+    // Rule inputs are sorted.
+    let foo = &targets[1]["rule"];
+    assert_eq!(foo["name"], "//folly/io:Foo");
+    assert_eq!(foo["ruleClass"], "cc_library");
+    assert_eq!(
+      foo["ruleInput"],
+      json!(["//folly/io:Foo.cpp", "//folly/io:Foo.h", "//folly:Bar"])
+    );
+
+    // The test is a rule of its own, on the library.
+    let test = &targets[2]["rule"];
+    assert_eq!(test["name"], "//folly/io:FooTest");
+    assert_eq!(test["ruleClass"], "cc_test");
+    let names: Vec<&str> = test["attribute"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|attr| attr["name"].as_str().unwrap())
+      .collect();
+    assert_eq!(names, ["name", "srcs", "deps", "visibility"]);
+    assert_eq!(
+      test["attribute"][2]["stringListValue"],
+      json!(["//folly/io:Foo"])
+    );
+    assert_eq!(
+      test["ruleInput"],
+      json!(["//folly/io:Foo", "//folly/io:Footest.cpp"])
+    );
+  }
+
+  #[test]
+  fn export_writes_query_json() {
+    let fs = mock_fs(&[]);
+    let opts = build_options();
+    let map = build(FILES);
+    export_bazel_query(&fs, &map, &opts).unwrap();
+    let mut written = String::new();
+    fs.open_file(&opts.output_dir.join("query.json"))
+      .unwrap()
+      .read_to_string(&mut written)
+      .unwrap();
+    assert_eq!(written, render_bazel_query(&map, &opts).unwrap());
+  }
+}
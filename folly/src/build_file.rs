@@ -0,0 +1,1324 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexSet;
+
+use crate::file_system::{FileSystemProvider, WriteSink};
+use crate::formatter::format_build_file;
+use crate::types::{UnitDepKind, UnitInfo, UnitKey, UnitObj};
+use crate::util::{snake_to_camel, Condition, Platform};
+
+// The oldest Bazel the BUILD files need to work with.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum,
+)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+pub enum BazelVersion {
+  #[default]
+  #[value(name = "5")]
+  #[cfg_attr(feature = "config", serde(rename = "5"))]
+  V5,
+  #[value(name = "6")]
+  #[cfg_attr(feature = "config", serde(rename = "6"))]
+  V6,
+  #[value(name = "7")]
+  #[cfg_attr(feature = "config", serde(rename = "7"))]
+  V7,
+}
+
+impl BazelVersion {
+  // cc_library's implementation_deps arrived in 6.0.
+  pub fn has_implementation_deps(self) -> bool {
+    self >= BazelVersion::V6
+  }
+}
+
+// What the files write_build_files writes are called. Bazel takes either,
+// and BUILD.bazel keeps clear of a build/ directory or another build
+// system's BUILD on case-insensitive file systems.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+pub enum BuildFileName {
+  #[default]
+  #[value(name = "BUILD")]
+  #[cfg_attr(feature = "config", serde(rename = "BUILD"))]
+  BUILD,
+  #[value(name = "BUILD.bazel")]
+  #[cfg_attr(feature = "config", serde(rename = "BUILD.bazel"))]
+  BUILD_BAZEL,
+}
+
+impl BuildFileName {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      BuildFileName::BUILD => "BUILD",
+      BuildFileName::BUILD_BAZEL => "BUILD.bazel",
+    }
+  }
+}
+
+pub struct BuildOptions {
+  pub output_dir: PathBuf,
+  pub build_file_name: BuildFileName,
+  pub dry_run: bool,
+  // With dry_run, print each BUILD file rather than just its path.
+  pub verbose: bool,
+  // Header-only units with more than glob_threshold headers get a glob()
+  // instead of an explicit hdrs list.
+  pub use_glob: bool,
+  pub glob_threshold: usize,
+  // Only units under this root_dir get rules. The rest of the graph is still
+  // scanned so that deps on it resolve to the right labels.
+  pub filter_root: Option<String>,
+  // When set, tests are wrapped in an sh_test that runs this script with
+  // the test binary's path as its argument.
+  pub test_wrapper: Option<String>,
+  // Goes in front of the package of every dep outside the rule's own
+  // package: "//" for the main repository, "@folly//" from another one, or
+  // e.g. "//third_party" when the tree is vendored below the root.
+  pub label_prefix: String,
+  pub bazel_version: BazelVersion,
+  // Header-only libraries become cc_header_only_library, which needs no
+  // archive, when the tree can load it from rules_cc. Otherwise they stay
+  // cc_library.
+  pub no_recursive_headers: bool,
+  pub rules_cc_available: bool,
+  // List hdrs and srcs in file name order, headers first, rather than in
+  // the order the scan came across them.
+  pub sort_srcs: bool,
+}
+
+impl BuildOptions {
+  // Where dry_run and verbose say files should go.
+  pub fn sink<'a, F: FileSystemProvider>(&self, fs: &'a F) -> WriteSink<'a, F> {
+    WriteSink::new(fs, self.dry_run, self.verbose)
+  }
+
+  // Matches whole path components, so "folly/io" takes in "folly/io/async"
+  // but not "folly/iobuf".
+  pub fn emits(&self, key: &UnitKey) -> bool {
+    match &self.filter_root {
+      None => true,
+      Some(prefix) => {
+        let prefix = prefix.trim_end_matches('/');
+        match key.root_dir.strip_prefix(prefix) {
+          Some(rest) => rest.is_empty() || rest.starts_with('/'),
+          None => false,
+        }
+      }
+    }
+  }
+}
+
+const HEADER_ONLY_LOAD: &str =
+  "load(\"@rules_cc//cc:defs.bzl\", \"cc_header_only_library\")\n";
+
+fn render_list(attr: &str, items: &[String]) -> String {
+  let mut out = format!("    {} = [\n", attr);
+  for item in items {
+    out += &format!("        \"{}\",\n", item);
+  }
+  out + "    ],\n"
+}
+
+// A label, how the unit came to depend on it, what to say about that, and
+// the condition it is only taken under, if any.
+type Dep = (String, UnitDepKind, Option<&'static str>, Option<Condition>);
+
+// Deps that came from something other than an #include say what, e.g.
+//   ":Foo",  # forward-decl
+// Conditional deps go in a select() after the rest, one for each macro,
+// keyed on the config_setting render_config_settings writes for it:
+//   deps = [":Bar"] + select({
+//       ":FOLLY_HAVE_LIBURING": [":Uring"],
+//       "//conditions:default": [":Poll"],
+//   }),
+fn render_deps(attr: &str, deps: &[Dep]) -> String {
+  let render_items = |deps: &[&Dep], indent: &str| {
+    let mut out = String::new();
+    for (label, _, comment, _) in deps {
+      out += &match comment {
+        Some(comment) => format!("{}\"{}\",  # {}\n", indent, label, comment),
+        None => format!("{}\"{}\",\n", indent, label),
+      };
+    }
+    out
+  };
+  let (conditional, deps): (Vec<&Dep>, Vec<&Dep>) = deps
+    .iter()
+    .partition(|(_, _, _, condition)| condition.is_some());
+  let mut out = format!("    {} = ", attr);
+  if !deps.is_empty() || conditional.is_empty() {
+    out += &format!("[\n{}    ]", render_items(&deps, "        "));
+  }
+  let mut defines: Vec<&str> = Vec::new();
+  for (_, _, _, condition) in &conditional {
+    let define = condition.as_ref().unwrap().define.as_str();
+    if !defines.contains(&define) {
+      defines.push(define);
+    }
+  }
+  for (i, define) in defines.into_iter().enumerate() {
+    if i > 0 || !deps.is_empty() {
+      out += " + ";
+    }
+    let branch = |defined: bool| {
+      let items: Vec<&Dep> = conditional
+        .iter()
+        .filter(|(_, _, _, condition)| {
+          condition
+            .as_ref()
+            .is_some_and(|c| c.define == define && c.defined == defined)
+        })
+        .copied()
+        .collect();
+      if items.is_empty() {
+        "[]".to_string()
+      } else {
+        format!("[\n{}        ]", render_items(&items, "            "))
+      }
+    };
+    out += &format!(
+      "select({{\n        \":{}\": {},\n        \"//conditions:default\": {},\n    }})",
+      define,
+      branch(true),
+      branch(false)
+    );
+  }
+  out + ",\n"
+}
+
+// One config_setting for each macro the units' selects are keyed on, which
+// the project turns on with e.g. --define=FOLLY_HAVE_LIBURING=1.
+fn render_config_settings(units: &[&UnitObj]) -> Vec<String> {
+  let defines: BTreeSet<String> = units
+    .iter()
+    .flat_map(|unit| {
+      let info = unit.val.borrow();
+      info
+        .deps
+        .keys()
+        .filter_map(|dep| info.conditions.get(&dep.key))
+        .map(|condition| condition.define.clone())
+        .collect::<Vec<_>>()
+    })
+    .collect();
+  defines
+    .into_iter()
+    .map(|define| {
+      format!(
+        "config_setting(\n    name = \"{0}\",\n    define_values = {{\n        \"{0}\": \"1\",\n    }},\n)\n",
+        define
+      )
+    })
+    .collect()
+}
+
+// Unlike file names and labels, flags can hold quotes, e.g.
+// -DFOLLY_VERSION="1.0".
+// Used for linkopts too.
+fn render_copts(attr: &str, flags: &[String]) -> String {
+  if flags.is_empty() {
+    return String::new();
+  }
+  let escaped: Vec<String> = flags
+    .iter()
+    .map(|flag| flag.replace('\\', "\\\\").replace('"', "\\\""))
+    .collect();
+  render_list(attr, &escaped)
+}
+
+// Every extension strip_file_name takes for a header. -inl.h is a .h.
+const HEADER_EXTENSIONS: [&str; 3] = ["h", "hh", "hpp"];
+
+fn header_glob() -> String {
+  let patterns: Vec<String> = HEADER_EXTENSIONS
+    .iter()
+    .map(|ext| format!("\"*.{}\"", ext))
+    .collect();
+  format!("glob([{}])", patterns.join(", "))
+}
+
+// A copy of files in the order sort_srcs asks for: headers, then everything
+// else, each by name.
+pub fn sorted_files(files: &[String], opts: &BuildOptions) -> Vec<String> {
+  let mut files = files.to_vec();
+  if opts.sort_srcs {
+    files.sort_by_cached_key(|file| {
+      let is_header = Path::new(file)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| HEADER_EXTENSIONS.contains(&ext));
+      (!is_header, file.clone())
+    });
+  }
+  files
+}
+
+// Platform-specific files only get built where their platform matches.
+fn render_srcs(items: &[String], platforms: &[Platform]) -> String {
+  if platforms.is_empty() {
+    return render_list("srcs", items);
+  }
+  let mut out = String::from("    srcs = select({\n");
+  for platform in platforms {
+    out += &format!("        \"{}\": [\n", platform.condition());
+    for item in items {
+      out += &format!("            \"{}\",\n", item);
+    }
+    out += "        ],\n";
+  }
+  out + "        \"//conditions:default\": [],\n    }),\n"
+}
+
+// folly's own BUILD files name targets in CamelCase, after the file.
+pub fn target_name(key: &UnitKey) -> String {
+  snake_to_camel(&key.name)
+}
+
+// Proto units are named foo_proto, so the C++ bindings become FooCcProto
+// next to FooProto.
+pub fn cc_proto_name(key: &UnitKey) -> String {
+  let base = key.name.strip_suffix("_proto").unwrap_or(&key.name);
+  snake_to_camel(&format!("{}_cc_proto", base))
+}
+
+// The target to depend on for dep. Rules from an existing BUILD file are
+// referred to by the name they were given there, and C++ rules, with `cc`
+// set, take a proto unit's cc_proto_library.
+pub fn dep_target_name(dep: &UnitObj, cc: bool) -> String {
+  let info = dep.val.borrow();
+  match &info.existing_name {
+    Some(name) => name.clone(),
+    None if cc && !info.protos.is_empty() => cc_proto_name(&dep.key),
+    None => target_name(&dep.key),
+  }
+}
+
+// Deps in the same package use the short `:name` form.
+pub fn internal_label(
+  from: &UnitKey,
+  dep: &UnitObj,
+  prefix: &str,
+  cc: bool,
+) -> String {
+  let key = &dep.key;
+  let name = dep_target_name(dep, cc);
+  if from.root_dir == key.root_dir {
+    format!(":{}", name)
+  } else {
+    package_label(prefix, key, &name)
+  }
+}
+
+pub fn package_label(prefix: &str, key: &UnitKey, name: &str) -> String {
+  if prefix.ends_with('/') {
+    format!("{}{}:{}", prefix, key.root_dir, name)
+  } else {
+    format!("{}/{}:{}", prefix, key.root_dir, name)
+  }
+}
+
+// Other workspaces have their own naming conventions, so these keep the
+// snake_case name.
+pub fn external_label(workspace: &str, key: &UnitKey) -> String {
+  format!("@{}//{}:{}", workspace, key.root_dir, key.name)
+}
+
+// Soft deps aren't something Bazel can act on, so they are only listed for
+// whoever maintains the BUILD file to check, e.g.
+//   # using-deps: :FooImpl //folly/detail:Bar
+// The comment goes just above deps, which format_build_file keeps it with.
+fn render_soft_deps(
+  from: &UnitKey,
+  soft_deps: &IndexSet<(UnitDepKind, UnitKey)>,
+  prefix: &str,
+) -> String {
+  let labels: Vec<String> = soft_deps
+    .iter()
+    .filter(|(kind, _)| *kind == UnitDepKind::USING)
+    .map(|(_, key)| {
+      let name = target_name(key);
+      if from.root_dir == key.root_dir {
+        format!(":{}", name)
+      } else {
+        package_label(prefix, key, &name)
+      }
+    })
+    .collect();
+  if labels.is_empty() {
+    String::new()
+  } else {
+    format!("    # using-deps: {}\n", labels.join(" "))
+  }
+}
+
+// The rule a unit that is neither a test nor a binary becomes.
+// Objective-C++ can only go in an objc_library, even without srcs.
+fn library_rule(info: &UnitInfo<UnitKey>, opts: &BuildOptions) -> &'static str {
+  if info.is_objc {
+    "objc_library"
+  } else if info.srcs.is_empty()
+    && opts.no_recursive_headers
+    && opts.rules_cc_available
+  {
+    "cc_header_only_library"
+  } else {
+    "cc_library"
+  }
+}
+
+// The rule render_unit writes under the unit's own name.
+#[cfg(feature = "json")]
+pub fn rule_class(
+  info: &UnitInfo<UnitKey>,
+  opts: &BuildOptions,
+) -> &'static str {
+  if !info.protos.is_empty() {
+    "proto_library"
+  } else if info.has_main {
+    "cc_binary"
+  } else {
+    library_rule(info, opts)
+  }
+}
+
+// And the one it writes for the unit's tests.
+#[cfg(feature = "json")]
+pub fn test_rule_class(opts: &BuildOptions) -> &'static str {
+  if opts.test_wrapper.is_some() {
+    "sh_test"
+  } else {
+    "cc_test"
+  }
+}
+
+// A unit's tests are a rule of their own, named after it: FooTest for Foo.
+pub fn test_target_name(key: &UnitKey) -> String {
+  format!("{}Test", target_name(key))
+}
+
+// Whether a unit's tests can depend on its main rule. A binary can't be
+// linked into anything, so tests of one only get what they include.
+pub fn tests_use_library(info: &UnitInfo<UnitKey>) -> bool {
+  info.has_library() && !info.has_main && info.protos.is_empty()
+}
+
+// Bazel resolves deps partly in the order given, so widely used ones go
+// first. The sort is stable, so ties keep the order they were included in.
+fn labeled_deps<'a>(
+  from: &UnitKey,
+  internal: impl Iterator<
+    Item = (&'a UnitObj, &'a UnitDepKind, Option<&'a Condition>),
+  >,
+  external: &IndexSet<(String, UnitKey)>,
+  opts: &BuildOptions,
+  cc: bool,
+) -> Vec<Dep> {
+  let mut internal: Vec<_> = internal.collect();
+  internal.sort_by_key(|(dep, _, _)| {
+    std::cmp::Reverse(dep.val.borrow().reverse_dep_count)
+  });
+  internal
+    .into_iter()
+    .map(|(dep, kind, condition)| {
+      let label = internal_label(from, dep, &opts.label_prefix, cc);
+      (label, *kind, kind.comment(), condition.cloned())
+    })
+    .chain(external.iter().map(|(workspace, key)| {
+      let label = external_label(workspace, key);
+      (label, UnitDepKind::INCLUDE, None, None)
+    }))
+    .collect()
+}
+
+// Returns None for units that never had a file of their own, e.g. ones that
+// only exist because something included them. A glob matches every header
+// in the package, so it is only safe for the one unit that owns headers
+// there; render_build_file works that out.
+pub fn render_unit(
+  unit: &UnitObj,
+  opts: &BuildOptions,
+  sole_header_unit: bool,
+) -> Option<String> {
+  let info = unit.val.borrow();
+  let mut rules = Vec::new();
+  if info.has_library() {
+    rules.push(render_library(unit, &info, opts, sole_header_unit));
+  }
+  if !info.test_srcs.is_empty() {
+    rules.push(render_test(unit, &info, opts));
+  }
+  if rules.is_empty() {
+    None
+  } else {
+    Some(rules.join("\n"))
+  }
+}
+
+// Every rule a unit expands to is seen by the same dependents.
+fn render_visibility(info: &UnitInfo<UnitKey>) -> String {
+  render_list("visibility", &[info.visibility.label().to_string()])
+}
+
+fn render_flags(info: &UnitInfo<UnitKey>) -> String {
+  render_copts("copts", &info.build_flags)
+    + &render_copts("linkopts", &info.link_opts)
+}
+
+// The rule dependents of the unit get, from everything but its tests.
+fn render_library(
+  unit: &UnitObj,
+  info: &UnitInfo<UnitKey>,
+  opts: &BuildOptions,
+  sole_header_unit: bool,
+) -> String {
+  let deps = labeled_deps(
+    &unit.key,
+    info
+      .deps
+      .iter()
+      .map(|(dep, kind)| (dep, kind, info.conditions.get(&dep.key))),
+    &info.external_deps,
+    opts,
+    info.protos.is_empty(),
+  );
+  let visibility = render_visibility(info);
+  let soft_deps =
+    render_soft_deps(&unit.key, &info.soft_deps, &opts.label_prefix);
+
+  let mut rule = String::new();
+  if !info.protos.is_empty() {
+    rule += "proto_library(\n";
+    rule += &format!("    name = \"{}\",\n", target_name(&unit.key));
+    rule += &render_list("srcs", &info.protos);
+    if !deps.is_empty() {
+      rule += &render_deps("deps", &deps);
+    }
+    rule += &visibility;
+    rule += ")\n\n";
+    rule += "cc_proto_library(\n";
+    rule += &format!("    name = \"{}\",\n", cc_proto_name(&unit.key));
+    rule += &render_list("deps", &[format!(":{}", target_name(&unit.key))]);
+  } else if info.has_main {
+    // cc_binary doesn't export headers, so everything goes in srcs.
+    let srcs: Vec<String> = info
+      .headers
+      .iter()
+      .chain(info.srcs.iter())
+      .cloned()
+      .collect();
+    rule += "cc_binary(\n";
+    rule += &format!("    name = \"{}\",\n", target_name(&unit.key));
+    rule += &render_srcs(&sorted_files(&srcs, opts), &info.platforms);
+    rule += &render_flags(info);
+    rule += &soft_deps;
+    if !deps.is_empty() {
+      rule += &render_deps("deps", &deps);
+    }
+  } else {
+    // Sources are assumed to be ARC-clean. Files built with manual reference
+    // counting would have to move to non_arc_srcs instead.
+    let library = library_rule(info, opts);
+    rule += &format!("{}(\n", library);
+    rule += &format!("    name = \"{}\",\n", target_name(&unit.key));
+    if opts.use_glob
+      && sole_header_unit
+      && info.srcs.is_empty()
+      && info.headers.len() > opts.glob_threshold
+      // Headers merged in from subdirectories wouldn't match the glob.
+      && !info.headers.iter().any(|header| header.contains('/'))
+    {
+      rule += &format!("    hdrs = {},\n", header_glob());
+    } else if !info.headers.is_empty() {
+      rule += &render_list("hdrs", &sorted_files(&info.headers, opts));
+    }
+    if !info.srcs.is_empty() {
+      rule += &render_srcs(&sorted_files(&info.srcs, opts), &info.platforms);
+    }
+    rule += &render_flags(info);
+    rule += &soft_deps;
+    // Deps that only srcs include can stay out of the library's interface,
+    // where Bazel has a place for them.
+    let (deps, implementation_deps): (Vec<Dep>, Vec<Dep>) =
+      deps.into_iter().partition(|(_, kind, _, _)| {
+        *kind != UnitDepKind::SOURCE_INCLUDE
+          || library != "cc_library"
+          || !opts.bazel_version.has_implementation_deps()
+      });
+    if !deps.is_empty() {
+      rule += &render_deps("deps", &deps);
+    }
+    if !implementation_deps.is_empty() {
+      rule += &render_deps("implementation_deps", &implementation_deps);
+    }
+  }
+  rule += &visibility;
+  rule += ")\n";
+  rule
+}
+
+// The unit's test files, which get the library they test and whatever they
+// include themselves. Soft deps go with the library when there is one.
+fn render_test(
+  unit: &UnitObj,
+  info: &UnitInfo<UnitKey>,
+  opts: &BuildOptions,
+) -> String {
+  let mut deps = Vec::new();
+  if tests_use_library(info) {
+    let label = format!(":{}", target_name(&unit.key));
+    deps.push((label, UnitDepKind::INCLUDE, None, None));
+  }
+  deps.extend(labeled_deps(
+    &unit.key,
+    info
+      .test_deps
+      .iter()
+      .map(|dep| (dep, &UnitDepKind::INCLUDE, None)),
+    &info.test_external_deps,
+    opts,
+    true,
+  ));
+
+  // A wrapped test is built as a binary for the sh_test to run, and the
+  // sh_test takes over the test's name.
+  let name = test_target_name(&unit.key);
+  let binary = match &opts.test_wrapper {
+    Some(_) => format!("{}Bin", name),
+    None => name.clone(),
+  };
+  let mut rule = match &opts.test_wrapper {
+    Some(_) => String::from("cc_binary(\n"),
+    None => String::from("cc_test(\n"),
+  };
+  rule += &format!("    name = \"{}\",\n", binary);
+  rule += &render_srcs(&sorted_files(&info.test_srcs, opts), &info.platforms);
+  rule += &render_flags(info);
+  if !info.has_library() {
+    rule += &render_soft_deps(&unit.key, &info.soft_deps, &opts.label_prefix);
+  }
+  if !deps.is_empty() {
+    rule += &render_deps("deps", &deps);
+  }
+  if let Some(script) = &opts.test_wrapper {
+    rule += "    testonly = True,\n";
+    rule += &render_visibility(info);
+    rule += ")\n\n";
+    rule += "sh_test(\n";
+    rule += &format!("    name = \"{}\",\n", name);
+    rule += &render_list("srcs", std::slice::from_ref(script));
+    rule += &render_list("data", &[format!(":{}", binary)]);
+    rule += &render_list("args", &[format!("$(location :{})", binary)]);
+  }
+  rule += &render_visibility(info);
+  rule += ")\n";
+  rule
+}
+
+pub fn render_build_file(
+  units: &[UnitObj],
+  opts: &BuildOptions,
+) -> Option<String> {
+  // A package that already had a BUILD file is taken as canonical.
+  if units.iter().any(|unit| unit.val.borrow().pre_existing) {
+    return None;
+  }
+  let units: Vec<&UnitObj> =
+    units.iter().filter(|unit| opts.emits(&unit.key)).collect();
+  let header_units = units
+    .iter()
+    .filter(|unit| !unit.val.borrow().headers.is_empty())
+    .count();
+  let mut rules: Vec<String> = units
+    .iter()
+    .filter_map(|unit| render_unit(unit, opts, header_units == 1))
+    .collect();
+  if rules.is_empty() {
+    return None;
+  }
+  rules.splice(0..0, render_config_settings(&units));
+  // Unlike the native rules, this one has to be loaded.
+  if rules
+    .iter()
+    .any(|rule| rule.starts_with("cc_header_only_library("))
+  {
+    rules.insert(0, HEADER_ONLY_LOAD.to_string());
+  }
+  Some(format_build_file(&rules.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::builder::GraphBuilder;
+  use crate::test_util::{build_options, keys, scan, scan_with, unit};
+  use crate::types::{ScanOptions, UnitKey};
+
+  #[test]
+  fn header_glob_covers_every_header_extension() {
+    let map = scan(&[
+      ("folly/a.h", "#pragma once\n"),
+      ("folly/a-inl.h", "#pragma once\n"),
+      ("folly/a.hh", "#pragma once\n"),
+      ("folly/a.hpp", "#pragma once\n"),
+    ]);
+    let opts = BuildOptions {
+      use_glob: true,
+      glob_threshold: 3,
+      ..build_options()
+    };
+    let rule = render_unit(&unit(&map, "folly/a"), &opts, true).unwrap();
+    assert!(
+      rule.contains("hdrs = glob([\"*.h\", \"*.hh\", \"*.hpp\"]),"),
+      "{}",
+      rule
+    );
+  }
+
+  #[test]
+  fn units_with_main_are_binaries() {
+    let map = scan(&[
+      ("folly/Tool.h", "#pragma once\n"),
+      (
+        "folly/Tool.cpp",
+        "#include <folly/Tool.h>\nint main () {}\n",
+      ),
+    ]);
+    let rule = render_unit(&unit(&map, "folly/tool"), &build_options(), true);
+    assert_eq!(
+      rule.unwrap(),
+      "cc_binary(
+    name = \"Tool\",
+    srcs = [
+        \"Tool.h\",
+        \"Tool.cpp\",
+    ],
+    visibility = [
+        \"//visibility:public\",
+    ],
+)
+"
+    );
+  }
+
+  #[test]
+  fn objective_c_units_are_objc_libraries() {
+    let map = scan(&[
+      ("folly/Apple.h", "#pragma once\n"),
+      ("folly/Apple.mm", "#include <folly/Apple.h>\n"),
+      ("folly/Plain.h", "#pragma once\n"),
+    ]);
+    assert!(unit(&map, "folly/apple").val.borrow().is_objc);
+    assert!(!unit(&map, "folly/plain").val.borrow().is_objc);
+    let rule = render_unit(&unit(&map, "folly/apple"), &build_options(), true);
+    assert_eq!(
+      rule.unwrap(),
+      "objc_library(
+    name = \"Apple\",
+    hdrs = [
+        \"Apple.h\",
+    ],
+    srcs = [
+        \"Apple.mm\",
+    ],
+    visibility = [
+        \"//visibility:public\",
+    ],
+)
+"
+    );
+  }
+
+  #[test]
+  fn deps_keep_the_order_they_were_included_in() {
+    let mut map = scan(&[
+      ("folly/A.h", "#pragma once\n"),
+      ("folly/B.h", "#pragma once\n"),
+      ("folly/C.h", "#pragma once\n"),
+      (
+        "folly/Foo.h",
+        "#include <folly/C.h>\n#include <folly/A.h>\n#include <folly/B.h>\n",
+      ),
+    ]);
+    GraphBuilder::new().finish(&mut map).unwrap();
+    let build_file =
+      render_build_file(&[unit(&map, "folly/foo")], &build_options());
+    assert_eq!(
+      build_file.unwrap(),
+      "cc_library(
+    name = \"Foo\",
+    deps = [
+        \":C\",
+        \":A\",
+        \":B\",
+    ],
+    hdrs = [
+        \"Foo.h\",
+    ],
+    visibility = [
+        \"//visibility:private\",
+    ],
+)
+"
+    );
+  }
+
+  fn foo_test() -> UnitObj {
+    let map = scan(&[
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/Footest.cpp", "#include <folly/Foo.h>\n"),
+    ]);
+    unit(&map, "folly/foo")
+  }
+
+  #[test]
+  fn wrapped_tests_become_a_binary_and_an_sh_test() {
+    let opts = BuildOptions {
+      test_wrapper: Some("//tools:run_test.sh".to_string()),
+      ..build_options()
+    };
+    let rule = render_build_file(&[foo_test()], &opts).unwrap();
+    assert_eq!(
+      rule,
+      "cc_library(
+    name = \"Foo\",
+    hdrs = [
+        \"Foo.h\",
+    ],
+    visibility = [
+        \"//visibility:public\",
+    ],
+)
+
+cc_binary(
+    name = \"FooTestBin\",
+    deps = [
+        \":Foo\",
+    ],
+    srcs = [
+        \"Footest.cpp\",
+    ],
+    testonly = True,
+    visibility = [
+        \"//visibility:public\",
+    ],
+)
+
+sh_test(
+    name = \"FooTest\",
+    args = [
+        \"$(location :FooTestBin)\",
+    ],
+    data = [
+        \":FooTestBin\",
+    ],
+    srcs = [
+        \"//tools:run_test.sh\",
+    ],
+    visibility = [
+        \"//visibility:public\",
+    ],
+)
+"
+    );
+  }
+
+  #[test]
+  fn tests_are_cc_tests_without_a_wrapper() {
+    let rule = render_unit(&foo_test(), &build_options(), true).unwrap();
+    assert!(
+      rule.starts_with("cc_library(\n    name = \"Foo\",\n"),
+      "{}",
+      rule
+    );
+    assert!(
+      rule.contains("cc_test(\n    name = \"FooTest\",\n"),
+      "{}",
+      rule
+    );
+    assert!(!rule.contains("sh_test"), "{}", rule);
+  }
+
+  #[test]
+  fn abseil_deps_are_abseil_cpp_labels() {
+    let files = [(
+      "folly/Foo.h",
+      "#include <absl/container/flat_hash_map.h>\n\
+       #include <absl/strings/str_cat.h>\n",
+    )];
+    let rule =
+      render_unit(&unit(&scan(&files), "folly/foo"), &build_options(), true);
+    let rule = rule.unwrap();
+    assert!(
+      rule.contains("\"@abseil-cpp//absl/container:flat_hash_map\""),
+      "{}",
+      rule
+    );
+    assert!(
+      rule.contains("\"@abseil-cpp//absl/strings:str_cat\""),
+      "{}",
+      rule
+    );
+
+    let opts = ScanOptions {
+      abseil_targets: [(
+        "absl/strings/str_cat".to_string(),
+        "strings".to_string(),
+      )]
+      .into_iter()
+      .collect(),
+      ..ScanOptions::default()
+    };
+    let map = scan_with(&files, &opts);
+    let rule = render_unit(&unit(&map, "folly/foo"), &build_options(), true);
+    let rule = rule.unwrap();
+    assert!(
+      rule.contains("\"@abseil-cpp//absl/strings:strings\""),
+      "{}",
+      rule
+    );
+  }
+
+  #[test]
+  fn package_label_joins_the_prefix() {
+    let key = UnitKey::new("io_buf", "folly/io");
+    for (prefix, label) in [
+      ("//", "//folly/io:IoBuf"),
+      ("@folly//", "@folly//folly/io:IoBuf"),
+      ("//third_party", "//third_party/folly/io:IoBuf"),
+      ("//third_party/", "//third_party/folly/io:IoBuf"),
+    ] {
+      assert_eq!(package_label(prefix, &key, "IoBuf"), label, "{}", prefix);
+    }
+  }
+
+  #[test]
+  fn label_prefix_applies_to_other_packages_only() {
+    let map = scan(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      (
+        "folly/Foo.h",
+        "#include <folly/Bar.h>\n#include <folly/io/IOBuf.h>\n",
+      ),
+      ("folly/io/IOBuf.h", "#pragma once\n"),
+    ]);
+    let opts = BuildOptions {
+      label_prefix: "@folly//".to_string(),
+      ..build_options()
+    };
+    let rule = render_unit(&unit(&map, "folly/foo"), &opts, true).unwrap();
+    assert!(rule.contains("\":Bar\""), "{}", rule);
+    assert!(rule.contains("\"@folly//folly/io:IoBuf\""), "{}", rule);
+  }
+
+  #[test]
+  fn implementation_deps_depend_on_the_bazel_version() {
+    let map = scan(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Baz.h", "#pragma once\n"),
+      ("folly/Foo.cpp", "#include <folly/Baz.h>\n"),
+      ("folly/Foo.h", "#include <folly/Bar.h>\n"),
+    ]);
+    let foo = unit(&map, "folly/foo");
+    for (version, implementation_deps) in [
+      (BazelVersion::V5, false),
+      (BazelVersion::V6, true),
+      (BazelVersion::V7, true),
+    ] {
+      let opts = BuildOptions {
+        bazel_version: version,
+        ..build_options()
+      };
+      let rule = render_unit(&foo, &opts, true).unwrap();
+      let expected = if implementation_deps {
+        "    deps = [\n        \":Bar\",\n    ],\n    \
+         implementation_deps = [\n        \":Baz\",\n    ],\n"
+      } else {
+        "    deps = [\n        \":Baz\",\n        \":Bar\",\n    ],\n"
+      };
+      assert!(rule.contains(expected), "{:?}: {}", version, rule);
+    }
+  }
+
+  #[test]
+  fn bazel_versions_order_by_release() {
+    assert!(BazelVersion::V5 < BazelVersion::V6);
+    assert!(BazelVersion::V6 < BazelVersion::V7);
+    assert!(!BazelVersion::V5.has_implementation_deps());
+    assert!(BazelVersion::V7.has_implementation_deps());
+  }
+
+  #[test]
+  fn header_only_rule_needs_both_flags() {
+    let map = scan(&[
+      ("folly/Bar.cpp", "#include <folly/Bar.h>\n"),
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Foo.h", "#pragma once\n"),
+    ]);
+    let (foo, bar) = (unit(&map, "folly/foo"), unit(&map, "folly/bar"));
+    for (no_recursive_headers, rules_cc_available, header_only) in [
+      (false, false, false),
+      (true, false, false),
+      (false, true, false),
+      (true, true, true),
+    ] {
+      let opts = BuildOptions {
+        no_recursive_headers,
+        rules_cc_available,
+        ..build_options()
+      };
+      let expected = if header_only {
+        "cc_header_only_library"
+      } else {
+        "cc_library"
+      };
+      assert_eq!(library_rule(&foo.val.borrow(), &opts), expected);
+      assert_eq!(library_rule(&bar.val.borrow(), &opts), "cc_library");
+    }
+  }
+
+  #[test]
+  fn header_only_rules_are_loaded_from_rules_cc() {
+    let map = scan(&[("folly/Foo.h", "#pragma once\n")]);
+    let opts = BuildOptions {
+      no_recursive_headers: true,
+      rules_cc_available: true,
+      ..build_options()
+    };
+    let build_file = render_build_file(&[unit(&map, "folly/foo")], &opts);
+    let build_file = build_file.unwrap();
+    assert!(build_file.starts_with(HEADER_ONLY_LOAD), "{}", build_file);
+    assert!(
+      build_file.contains("cc_header_only_library(\n    name = \"Foo\",\n"),
+      "{}",
+      build_file
+    );
+    let without =
+      render_build_file(&[unit(&map, "folly/foo")], &build_options());
+    assert!(!without.unwrap().contains("load("));
+  }
+
+  #[test]
+  fn copts_comments_become_copts() {
+    let map = scan(&[
+      ("folly/Simd.cpp", "// BAZEL_COPTS: -mavx2 -DFOLLY_X=\"y\"\n"),
+      (
+        "folly/Simd.h",
+        "#pragma once\n// BAZEL_COPTS: -mavx2 -msse4.2\n",
+      ),
+      ("folly/Simdtest.cpp", "// BAZEL_LINKOPTS: -lpthread\n"),
+    ]);
+    let simd = unit(&map, "folly/simd");
+    assert_eq!(
+      simd.val.borrow().build_flags,
+      ["-mavx2", "-DFOLLY_X=\"y\"", "-msse4.2"]
+    );
+    let rule = render_unit(&simd, &build_options(), true).unwrap();
+    assert!(rule.starts_with("cc_library("), "{}", rule);
+    assert!(
+      rule.contains(
+        "    copts = [
+        \"-mavx2\",
+        \"-DFOLLY_X=\\\"y\\\"\",
+        \"-msse4.2\",
+    ],
+    linkopts = [
+        \"-lpthread\",
+    ],
+"
+      ),
+      "{}",
+      rule
+    );
+
+    let plain = scan(&[("folly/Plain.h", "#pragma once\n")]);
+    let rule =
+      render_unit(&unit(&plain, "folly/plain"), &build_options(), true);
+    assert!(!rule.unwrap().contains("copts"));
+  }
+
+  #[test]
+  fn sorted_files_puts_headers_first() {
+    let files: Vec<String> = ["b.cpp", "Z.hpp", "a.cxx", "m.h", "c.cc", "A.hh"]
+      .iter()
+      .map(|file| file.to_string())
+      .collect();
+    assert_eq!(
+      sorted_files(&files, &build_options()),
+      ["A.hh", "Z.hpp", "m.h", "a.cxx", "b.cpp", "c.cc"]
+    );
+    let unsorted = BuildOptions {
+      sort_srcs: false,
+      ..build_options()
+    };
+    assert_eq!(sorted_files(&files, &unsorted), files);
+  }
+
+  #[test]
+  fn srcs_come_out_sorted_whatever_the_scan_order() {
+    let map = scan(&[
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/Foo.cpp", "#include <folly/Foo.h>\n"),
+      ("folly/Footest.cpp", "#include <folly/Foo.h>\n"),
+    ]);
+    // The order they were scanned in.
+    assert_eq!(unit(&map, "folly/foo").val.borrow().srcs, ["Foo.cpp"]);
+    assert_eq!(
+      unit(&map, "folly/foo").val.borrow().test_srcs,
+      ["Footest.cpp"]
+    );
+    let rule = render_unit(&unit(&map, "folly/foo"), &build_options(), true);
+    assert!(rule.unwrap().contains(
+      "    srcs = [
+        \"Foo.cpp\",
+    ],"
+    ));
+  }
+
+  #[test]
+  fn test_only_includes_are_test_deps() {
+    let map = scan(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Foo.cpp", "#include <folly/Bar.h>\n"),
+      ("folly/Foo.h", "#pragma once\n"),
+      (
+        "folly/Footest.cpp",
+        "#include <folly/Bar.h>\n#include <folly/Mock.h>\n",
+      ),
+      ("folly/Mock.h", "#pragma once\n"),
+    ]);
+    let foo = unit(&map, "folly/foo");
+    let info = foo.val.borrow();
+    let deps: Vec<&str> =
+      info.deps.keys().map(|d| d.key.name.as_str()).collect();
+    let test_deps: Vec<&str> =
+      info.test_deps.iter().map(|d| d.key.name.as_str()).collect();
+    assert_eq!(deps, ["bar"]);
+    assert_eq!(test_deps, ["bar", "mock"]);
+    drop(info);
+
+    let rule = render_unit(&foo, &build_options(), true).unwrap();
+    let (library, test) = rule.split_once("\n\n").unwrap();
+    assert!(library.starts_with("cc_library("), "{}", rule);
+    assert!(library.contains("\":Bar\""), "{}", rule);
+    assert!(!library.contains("\":Mock\""), "{}", rule);
+    assert!(test.starts_with("cc_test("), "{}", rule);
+    for label in ["\":Foo\"", "\":Bar\"", "\":Mock\""] {
+      assert!(test.contains(label), "{}", rule);
+    }
+  }
+
+  #[test]
+  fn linkopts_comments_become_linkopts() {
+    let map = scan(&[
+      (
+        "folly/Thread.cpp",
+        "#include <folly/Thread.h>\n\
+         // BAZEL_LINKOPTS: -lpthread -latomic\n",
+      ),
+      (
+        "folly/Thread.h",
+        "#pragma once\n// BAZEL_LINKOPTS: -ldl -lpthread\n",
+      ),
+    ]);
+    let thread = unit(&map, "folly/thread");
+    assert_eq!(
+      thread.val.borrow().link_opts,
+      ["-lpthread", "-latomic", "-ldl"]
+    );
+    assert!(thread.val.borrow().build_flags.is_empty());
+    assert_eq!(
+      render_unit(&thread, &build_options(), true).unwrap(),
+      "cc_library(
+    name = \"Thread\",
+    hdrs = [
+        \"Thread.h\",
+    ],
+    srcs = [
+        \"Thread.cpp\",
+    ],
+    linkopts = [
+        \"-lpthread\",
+        \"-latomic\",
+        \"-ldl\",
+    ],
+    visibility = [
+        \"//visibility:public\",
+    ],
+)
+"
+    );
+  }
+
+  // A unit with a test keeps its library, so what includes it still deps
+  // on a cc_library rather than on the test.
+  #[test]
+  fn tests_build_beside_the_library_they_test() {
+    let mut map = scan(&[
+      ("folly/Lib.h", "#include <folly/io/IOBuf.h>\n"),
+      ("folly/io/IOBuf.cpp", "#include <folly/io/IOBuf.h>\n"),
+      ("folly/io/IOBuf.h", "#pragma once\n"),
+      ("folly/io/IOBuftest.cpp", "#include <folly/io/IOBuf.h>\n"),
+    ]);
+    GraphBuilder::new().finish(&mut map).unwrap();
+    let io_buf = unit(&map, "folly/io/io_buf");
+    let rule = render_unit(&io_buf, &build_options(), true).unwrap();
+    let (library, test) = rule.split_once("\n\n").unwrap();
+    assert!(
+      library.starts_with("cc_library(\n    name = \"IoBuf\",\n"),
+      "{}",
+      rule
+    );
+    assert!(!library.contains("IOBuftest.cpp"), "{}", rule);
+    assert!(
+      test.starts_with("cc_test(\n    name = \"IoBufTest\",\n"),
+      "{}",
+      rule
+    );
+    assert!(test.contains("\"IOBuftest.cpp\""), "{}", rule);
+    assert!(test.contains("\":IoBuf\""), "{}", rule);
+
+    let lib = render_unit(&unit(&map, "folly/lib"), &build_options(), true);
+    let lib = lib.unwrap();
+    assert!(lib.contains("\"//folly/io:IoBuf\""), "{}", lib);
+    assert!(!lib.contains("IoBufTest"), "{}", lib);
+  }
+
+  // protoc writes types.pb.h for types.proto, which C++ gets from the
+  // cc_proto_library.
+  #[test]
+  fn pb_h_includes_dep_on_the_cc_proto_library() {
+    let mut map = scan(&[
+      ("folly/Lib.h", "#include <folly/rpc/Types.pb.h>\n"),
+      ("folly/rpc/Client.h", "#include \"folly/rpc/types.pb.h\"\n"),
+      (
+        "folly/rpc/types.proto",
+        "syntax = \"proto3\";\nmessage Id {}\n",
+      ),
+    ]);
+    GraphBuilder::new().finish(&mut map).unwrap();
+    assert_eq!(
+      keys(&map),
+      ["folly/lib", "folly/rpc/client", "folly/rpc/types_proto"]
+    );
+
+    let client =
+      render_unit(&unit(&map, "folly/rpc/client"), &build_options(), true);
+    let client = client.unwrap();
+    assert!(client.contains("\":TypesCcProto\""), "{}", client);
+    let lib = render_unit(&unit(&map, "folly/lib"), &build_options(), true);
+    let lib = lib.unwrap();
+    assert!(lib.contains("\"//folly/rpc:TypesCcProto\""), "{}", lib);
+  }
+
+  #[test]
+  fn conditional_deps_are_selected_on_their_macro() {
+    let mut map = scan(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Epoll.h", "#pragma once\n"),
+      (
+        "folly/Event.cpp",
+        "#include <folly/Event.h>\n\
+         #if defined(FOLLY_HAVE_LIBURING)\n\
+         #include <folly/Uring.h>\n\
+         #include <folly/UringImpl.h>\n\
+         #endif\n",
+      ),
+      (
+        "folly/Event.h",
+        "#pragma once\n\
+         #include <folly/Bar.h>\n\
+         #ifdef FOLLY_HAVE_LIBURING\n\
+         #include <folly/Uring.h>\n\
+         #elif defined(FOLLY_HAVE_EPOLL)\n\
+         #include <folly/Epoll.h>\n\
+         #else\n\
+         #include <folly/Poll.h>\n\
+         #endif\n\
+         #ifndef FOLLY_NO_LOG\n\
+         #include <folly/Log.h>\n\
+         #else\n\
+         #include <folly/Bar.h>\n\
+         #endif\n",
+      ),
+      ("folly/Log.h", "#pragma once\n"),
+      ("folly/Poll.h", "#pragma once\n"),
+      ("folly/Uring.h", "#pragma once\n"),
+      ("folly/UringImpl.h", "#pragma once\n"),
+    ]);
+    GraphBuilder::new().finish(&mut map).unwrap();
+    // Under Bazel 6, so that UringImpl, which only the source includes,
+    // stays an implementation dep for all it is conditional.
+    let opts = BuildOptions {
+      bazel_version: BazelVersion::V6,
+      ..build_options()
+    };
+    let build_file = render_build_file(&[unit(&map, "folly/event")], &opts);
+    // Poll is the #else of an #elif, which no one macro decides, and Bar is
+    // also included outside any #if.
+    assert_eq!(
+      build_file.unwrap(),
+      "config_setting(
+    name = \"FOLLY_HAVE_EPOLL\",
+    define_values = {
+        \"FOLLY_HAVE_EPOLL\": \"1\",
+    },
+)
+
+config_setting(
+    name = \"FOLLY_HAVE_LIBURING\",
+    define_values = {
+        \"FOLLY_HAVE_LIBURING\": \"1\",
+    },
+)
+
+config_setting(
+    name = \"FOLLY_NO_LOG\",
+    define_values = {
+        \"FOLLY_NO_LOG\": \"1\",
+    },
+)
+
+cc_library(
+    name = \"Event\",
+    deps = [
+        \":Bar\",
+        \":Poll\",
+    ] + select({
+        \":FOLLY_HAVE_LIBURING\": [
+            \":Uring\",
+        ],
+        \"//conditions:default\": [],
+    }) + select({
+        \":FOLLY_HAVE_EPOLL\": [
+            \":Epoll\",
+        ],
+        \"//conditions:default\": [],
+    }) + select({
+        \":FOLLY_NO_LOG\": [],
+        \"//conditions:default\": [
+            \":Log\",
+        ],
+    }),
+    hdrs = [
+        \"Event.h\",
+    ],
+    implementation_deps = select({
+        \":FOLLY_HAVE_LIBURING\": [
+            \":UringImpl\",
+        ],
+        \"//conditions:default\": [],
+    }),
+    srcs = [
+        \"Event.cpp\",
+    ],
+    visibility = [
+        \"//visibility:private\",
+    ],
+)
+"
+    );
+  }
+}
@@ -0,0 +1,556 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "serde")]
+use crate::cache;
+use crate::error::FollyError;
+use crate::file_system::RealFileSystem;
+use crate::graph;
+use crate::profiler::Profiler;
+use crate::types::{ScanOptions, UnitKey, UnitMap};
+use crate::warnings::WARNINGS;
+use crate::{_UnitMap, context, CompileGraph, ProgressCallback};
+
+type Inspector<'a> =
+  Box<dyn FnMut(&UnitMap) -> Result<(), Box<dyn Error>> + 'a>;
+
+// Scans a tree into a graph with its cycles collapsed, ready for
+// generate_compilation_trie. Settings chain:
+//
+//   GraphBuilder::new().scan(root).exclude(".git").build()?
+#[derive(Default)]
+pub struct GraphBuilder<'a> {
+  root: PathBuf,
+  files: Option<Vec<PathBuf>>,
+  opts: ScanOptions,
+  #[cfg(feature = "serde")]
+  cache: Option<PathBuf>,
+  #[cfg(feature = "parallel")]
+  parallel: bool,
+  prune_unreachable: bool,
+  check_header_cycles: bool,
+  strict: bool,
+  merges: Vec<(UnitKey, UnitKey)>,
+  progress: Option<ProgressCallback>,
+  profiler: Option<&'a Profiler>,
+  inspectors: Vec<Inspector<'a>>,
+}
+
+impl<'a> GraphBuilder<'a> {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn scan(&mut self, root: &Path) -> &mut Self {
+    self.root = root.to_path_buf();
+    self
+  }
+
+  // Scan only these files, each of which must be under the root, rather
+  // than walking it. exclude and max_depth don't apply to them, nor do
+  // incremental, parallel and progress.
+  pub fn files(&mut self, files: Vec<PathBuf>) -> &mut Self {
+    self.files = Some(files);
+    self
+  }
+
+  pub fn exclude(&mut self, dir: &str) -> &mut Self {
+    self.opts.exclude_dirs.insert(dir.to_string());
+    self
+  }
+
+  pub fn with_known_root(
+    &mut self,
+    prefix: &str,
+    workspace: &str,
+  ) -> &mut Self {
+    self
+      .opts
+      .known_roots
+      .insert(prefix.to_string(), workspace.to_string());
+    self
+  }
+
+  // Take #includes of virtual_path, or of anything under it, as naming real
+  // instead.
+  pub fn with_path_alias(
+    &mut self,
+    virtual_path: &str,
+    real: &Path,
+  ) -> &mut Self {
+    self
+      .opts
+      .path_aliases
+      .insert(virtual_path.to_string(), real.to_path_buf());
+    self
+  }
+
+  // header is the absl/ path without its extension.
+  #[cfg(feature = "config")]
+  pub fn with_abseil_target(
+    &mut self,
+    header: &str,
+    target: &str,
+  ) -> &mut Self {
+    self
+      .opts
+      .abseil_targets
+      .insert(header.to_string(), target.to_string());
+    self
+  }
+
+  // Take copts from these, by canonical file path, rather than from
+  // comments.
+  #[cfg(feature = "json")]
+  pub fn compile_flags(
+    &mut self,
+    flags: std::collections::HashMap<PathBuf, Vec<String>>,
+  ) -> &mut Self {
+    self.opts.compile_flags = Some(flags);
+    self
+  }
+
+  pub fn max_depth(&mut self, max_depth: Option<usize>) -> &mut Self {
+    self.opts.max_depth = max_depth;
+    self
+  }
+
+  pub fn error_on_missing_guard(&mut self, error: bool) -> &mut Self {
+    self.opts.error_on_missing_guard = error;
+    self
+  }
+
+  pub fn skip_generated(&mut self, skip: bool) -> &mut Self {
+    self.opts.skip_generated = skip;
+    self
+  }
+
+  pub fn seed_existing(&mut self, seed_existing: bool) -> &mut Self {
+    self.opts.seed_existing = seed_existing;
+    self
+  }
+
+  // Drop units with no files of their own before collapsing cycles.
+  pub fn prune_unreachable(&mut self, prune: bool) -> &mut Self {
+    self.prune_unreachable = prune;
+    self
+  }
+
+  // Fail the build on any #include cycle between headers, which
+  // collapse_cycles would otherwise hide in a merged unit.
+  pub fn check_header_cycles(&mut self, check: bool) -> &mut Self {
+    self.check_header_cycles = check;
+    self
+  }
+
+  // Time the scan and the passes that follow it.
+  pub fn profile(&mut self, profiler: &'a Profiler) -> &mut Self {
+    self.profiler = Some(profiler);
+    self
+  }
+
+  fn start_phase(&self, name: &str) {
+    if let Some(profiler) = self.profiler {
+      profiler.start_phase(name);
+    }
+  }
+
+  fn end_phase(&self, name: &str) {
+    if let Some(profiler) = self.profiler {
+      profiler.end_phase(name);
+    }
+  }
+
+  // Fail the build on deps on phantom units, which otherwise only warn.
+  // Checked after prune_unreachable, which leaves none.
+  pub fn strict(&mut self, strict: bool) -> &mut Self {
+    self.strict = strict;
+    self
+  }
+
+  // Fold unit b into unit a once scanned, before anything is pruned or
+  // collapsed. Merges happen in the order added.
+  pub fn merge_units(&mut self, a: UnitKey, b: UnitKey) -> &mut Self {
+    self.merges.push((a, b));
+    self
+  }
+
+  // Start from the graph cached here, if it was built from the same root,
+  // and write the updated graph back once scanned.
+  #[cfg(feature = "serde")]
+  pub fn incremental(&mut self, cache: &Path) -> &mut Self {
+    self.cache = Some(cache.to_path_buf());
+    self
+  }
+
+  // Ignored along with incremental, which only rescans what changed.
+  #[cfg(feature = "parallel")]
+  pub fn parallel(&mut self, parallel: bool) -> &mut Self {
+    self.parallel = parallel;
+    self
+  }
+
+  // Reports each file a full scan processes to cb. Ignored along with
+  // parallel, and when incremental finds a cache to rescan.
+  pub fn progress(&mut self, cb: ProgressCallback) -> &mut Self {
+    self.progress = Some(cb);
+    self
+  }
+
+  // Runs f on the graph as scanned, before cycles are collapsed. Inspectors
+  // run in the order they were added, and under --watch again on every
+  // rescan.
+  pub fn inspect(
+    &mut self,
+    f: impl FnMut(&UnitMap) -> Result<(), Box<dyn Error>> + 'a,
+  ) -> &mut Self {
+    self.inspectors.push(Box::new(f));
+    self
+  }
+
+  #[cfg(feature = "watch")]
+  pub fn options(&self) -> &ScanOptions {
+    &self.opts
+  }
+
+  // The graph as scanned, before any inspectors run or cycles are
+  // collapsed. For callers that go on to change it, such as --watch.
+  pub fn scan_graph(&self) -> Result<UnitMap, Box<dyn Error>> {
+    let scan_failed = || format!("Failed to scan {}", self.root.display());
+
+    if let Some(files) = &self.files {
+      let mut dict = UnitMap::new();
+      for file in files {
+        context(
+          dict.add_file(&RealFileSystem, &self.root, file, &self.opts),
+          || format!("Failed to scan {}", file.display()),
+        )?;
+      }
+      return Ok(dict);
+    }
+
+    #[cfg(feature = "serde")]
+    if let Some(cache_path) = &self.cache {
+      let root = context(self.root.canonicalize(), scan_failed)?;
+      let cached = context(cache::load(cache_path, &root), || {
+        format!("Failed to load cache {}", cache_path.display())
+      })?;
+      let dict = match cached {
+        Some(mut dict) => {
+          context(
+            dict.rescan_changed(&RealFileSystem, &root, &self.opts),
+            scan_failed,
+          )?;
+          dict
+        }
+        None => {
+          let mut dict = UnitMap::new();
+          context(
+            dict.add_initial_subtree_with_progress(
+              &RealFileSystem,
+              &root,
+              &self.opts,
+              self.progress,
+            ),
+            scan_failed,
+          )?;
+          dict
+        }
+      };
+      context(cache::save(cache_path, &root, &dict), || {
+        format!("Failed to write cache {}", cache_path.display())
+      })?;
+      return Ok(dict);
+    }
+
+    let mut dict = UnitMap::new();
+    #[cfg(feature = "parallel")]
+    if self.parallel {
+      context(
+        dict.add_initial_subtree_parallel(
+          &RealFileSystem,
+          &self.root,
+          &self.opts,
+        ),
+        scan_failed,
+      )?;
+      return Ok(dict);
+    }
+    let scanned = match self.progress {
+      Some(_) => dict.add_initial_subtree_with_progress(
+        &RealFileSystem,
+        &self.root,
+        &self.opts,
+        self.progress,
+      ),
+      None => dict.add_initial_subtree(&RealFileSystem, &self.root, &self.opts),
+    };
+    context(scanned, scan_failed)?;
+    Ok(dict)
+  }
+
+  // Everything build() does to a graph once it has been scanned and
+  // inspected.
+  pub fn finish(&self, dict: &mut UnitMap) -> Result<(), Box<dyn Error>> {
+    self.start_phase("check");
+    for (a, b) in &self.merges {
+      context(graph::merge_units(dict, a, b), || {
+        format!("Failed to merge {} into {}", b, a)
+      })?;
+    }
+    if self.prune_unreachable {
+      dict.prune_unreachable();
+    }
+    if self.strict {
+      graph::assert_no_phantom_deps(dict)?;
+    } else {
+      for (unit, dep) in graph::phantom_deps(dict) {
+        WARNINGS.warn(format_args!(
+          "{} depends on {}, which has no files",
+          unit, dep
+        ));
+      }
+    }
+    if self.check_header_cycles {
+      let cycles = graph::check_header_cycles(dict);
+      for cycle in &cycles {
+        let members: Vec<String> =
+          cycle.iter().map(|key| key.to_string()).collect();
+        WARNINGS
+          .error(format_args!("Header include cycle: {}", members.join(", ")));
+      }
+      if !cycles.is_empty() {
+        return Err(Box::new(FollyError::HeaderCycles(cycles.len())));
+      }
+    }
+    self.end_phase("check");
+    self.start_phase("collapse cycles");
+    context(dict.collapse_cycles(), || {
+      "Failed to collapse cycles in dependency graph".to_string()
+    })?;
+    // Pruning and merging can leave most of the set empty on a large tree.
+    dict.compact();
+    self.end_phase("collapse cycles");
+    self.start_phase("infer deps");
+    dict.add_inferred_deps();
+    // Merging moves reverse deps around, so these have to come after.
+    dict.compute_reverse_dep_counts();
+    dict.infer_visibility();
+    dict.resolve_using_hints();
+    self.end_phase("infer deps");
+    Ok(())
+  }
+
+  // Runs every inspector on dict, in the order they were added.
+  pub fn run_inspectors(
+    &mut self,
+    dict: &UnitMap,
+  ) -> Result<(), Box<dyn Error>> {
+    for inspect in &mut self.inspectors {
+      inspect(dict)?;
+    }
+    Ok(())
+  }
+
+  pub fn build(&mut self) -> Result<UnitMap, Box<dyn Error>> {
+    // Includes are parsed file by file as the walk reaches them, so the
+    // two are one phase.
+    self.start_phase("scan");
+    let mut dict = self.scan_graph()?;
+    self.end_phase("scan");
+    self.run_inspectors(&dict)?;
+    self.finish(&mut dict)?;
+    Ok(dict)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::keys;
+
+  // Writes files, relative to a fresh directory, to disk for the builder's
+  // RealFileSystem, and returns the folly directory under it.
+  fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let base = std::env::temp_dir()
+      .join(format!("folly-builder-test-{}", std::process::id()))
+      .join(name);
+    for (path, contents) in files {
+      let path = base.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, contents).unwrap();
+    }
+    base.join("folly")
+  }
+
+  #[test]
+  fn scan_then_build_collapses_cycles() {
+    let root = tree(
+      "cycle",
+      &[
+        ("folly/A.h", "#include <folly/B.h>\n"),
+        ("folly/B.h", "#include <folly/A.h>\n"),
+        ("folly/C.h", "#include <folly/A.h>\n"),
+      ],
+    );
+    let map = GraphBuilder::new().scan(&root).build().unwrap();
+    assert_eq!(keys(&map), ["folly/a", "folly/c"]);
+    let a = map.get(&UnitKey::new("a", "folly")).unwrap().clone();
+    let mut headers = a.val.borrow().headers.clone();
+    headers.sort();
+    assert_eq!(headers, ["A.h", "B.h"]);
+  }
+
+  #[test]
+  fn exclude_skips_directories_by_name() {
+    let root = tree(
+      "exclude",
+      &[
+        ("folly/A.h", "#pragma once\n"),
+        ("folly/build/Gen.h", "#pragma once\n"),
+        ("folly/io/B.h", "#pragma once\n"),
+      ],
+    );
+    let map = GraphBuilder::new()
+      .scan(&root)
+      .exclude("build")
+      .build()
+      .unwrap();
+    assert_eq!(keys(&map), ["folly/a", "folly/io/b"]);
+  }
+
+  #[test]
+  fn with_known_root_makes_external_deps() {
+    let root = tree(
+      "known_root",
+      &[(
+        "folly/A.h",
+        "#include <boost/any.hpp>\n#include <glog/logging.h>\n",
+      )],
+    );
+    let map = GraphBuilder::new()
+      .scan(&root)
+      .with_known_root("glog", "com_github_google_glog")
+      .build()
+      .unwrap();
+    let a = map.get(&UnitKey::new("a", "folly")).unwrap().clone();
+    let external: Vec<String> = a
+      .val
+      .borrow()
+      .external_deps
+      .iter()
+      .map(|(workspace, key)| format!("@{}//{}", workspace, key))
+      .collect();
+    assert_eq!(external, ["@com_github_google_glog//glog/logging"]);
+  }
+
+  #[test]
+  fn build_fails_on_a_missing_root() {
+    let root = tree("missing", &[]);
+    assert!(GraphBuilder::new().scan(&root).build().is_err());
+  }
+
+  #[test]
+  fn check_header_cycles_fails_the_build() {
+    let root = tree(
+      "header_cycle",
+      &[
+        ("folly/A.h", "#include <folly/B.h>\n"),
+        ("folly/B.h", "#include <folly/A.h>\n"),
+      ],
+    );
+    let Err(err) = GraphBuilder::new()
+      .scan(&root)
+      .check_header_cycles(true)
+      .build()
+    else {
+      panic!("expected the header cycle to fail the build");
+    };
+    assert!(
+      matches!(err.downcast_ref(), Some(FollyError::HeaderCycles(1))),
+      "{}",
+      err
+    );
+    assert!(GraphBuilder::new().scan(&root).build().is_ok());
+  }
+
+  #[test]
+  fn strict_fails_on_phantom_deps() {
+    let root = tree(
+      "phantom",
+      &[("folly/Foo.h", "#include <folly/Missing.h>\n")],
+    );
+    let Err(err) = GraphBuilder::new().scan(&root).strict(true).build() else {
+      panic!("expected the phantom dep to fail the build");
+    };
+    match err.downcast_ref() {
+      Some(FollyError::PhantomDeps(targets)) => {
+        assert_eq!(targets, &[UnitKey::new("missing", "folly")])
+      }
+      _ => panic!("{}", err),
+    }
+
+    let map = GraphBuilder::new().scan(&root).build().unwrap();
+    assert_eq!(keys(&map), ["folly/foo", "folly/missing"]);
+    let pruned = GraphBuilder::new()
+      .scan(&root)
+      .strict(true)
+      .prune_unreachable(true)
+      .build()
+      .unwrap();
+    assert_eq!(keys(&pruned), ["folly/foo"]);
+  }
+
+  #[test]
+  fn profile_times_every_build_phase() {
+    let root = tree("profile", &[("folly/Foo.h", "#pragma once\n")]);
+    let profiler = Profiler::new();
+    GraphBuilder::new()
+      .scan(&root)
+      .profile(&profiler)
+      .build()
+      .unwrap();
+    let phases: Vec<String> = profiler
+      .to_string()
+      .lines()
+      .map(|line| line.rsplit_once(' ').unwrap().0.trim_end().to_string())
+      .collect();
+    assert_eq!(
+      phases,
+      [
+        "Phase",
+        "scan",
+        "check",
+        "collapse cycles",
+        "infer deps",
+        "Total"
+      ]
+    );
+  }
+
+  #[test]
+  fn files_scans_just_the_listed_files() {
+    let root = tree(
+      "files",
+      &[
+        ("folly/Bar.h", "#pragma once\n"),
+        ("folly/Foo.h", "#include <folly/Bar.h>\n"),
+        ("folly/io/Skipped.h", "#pragma once\n"),
+      ],
+    );
+    let map = GraphBuilder::new()
+      .scan(&root)
+      .files(vec![root.join("Foo.h"), root.join("Bar.h")])
+      .build()
+      .unwrap();
+    assert_eq!(keys(&map), ["folly/bar", "folly/foo"]);
+
+    let outside = root.parent().unwrap().join("Outside.h");
+    std::fs::write(&outside, "#pragma once\n").unwrap();
+    assert!(GraphBuilder::new()
+      .scan(&root)
+      .files(vec![outside])
+      .build()
+      .is_err());
+  }
+}
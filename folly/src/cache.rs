@@ -0,0 +1,122 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::FollyError;
+use crate::serialization;
+use crate::types::UnitMap;
+
+// On-disk form of a scan, used by --incremental. The root is kept so that a
+// cache from a different tree is never mistaken for this one.
+#[derive(Deserialize)]
+struct Cache {
+  root: PathBuf,
+  #[serde(with = "serialization")]
+  units: UnitMap,
+}
+
+#[derive(Serialize)]
+struct CacheRef<'a> {
+  root: &'a Path,
+  #[serde(serialize_with = "serialize_units")]
+  units: &'a UnitMap,
+}
+
+fn serialize_units<S: Serializer>(
+  units: &&UnitMap,
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  serialization::serialize(units, serializer)
+}
+
+fn read_cache(path: &Path) -> Result<Cache, FollyError> {
+  let file = File::open(path).map_err(|e| FollyError::at(path, e))?;
+  serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+    FollyError::ParseError {
+      file: path.to_path_buf(),
+      line: e.line(),
+      message: e.to_string(),
+    }
+  })
+}
+
+// Returns None when there is no usable cache, in which case the caller
+// should fall back to a full scan.
+pub fn load(path: &Path, root: &Path) -> Result<Option<UnitMap>, FollyError> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  let cache = read_cache(path)?;
+  if cache.root != root {
+    return Ok(None);
+  }
+  Ok(Some(cache.units))
+}
+
+// For reading a cache outside of a scan, where there is no root to check
+// it against and a missing file is an error.
+pub fn load_units(path: &Path) -> Result<UnitMap, FollyError> {
+  let cache = read_cache(path)?;
+  Ok(cache.units)
+}
+
+// Writes to a sibling .tmp file first so that an interrupted run never
+// leaves a truncated cache behind.
+pub fn save(
+  path: &Path,
+  root: &Path,
+  units: &UnitMap,
+) -> Result<(), FollyError> {
+  let mut tmp_path = path.as_os_str().to_owned();
+  tmp_path.push(".tmp");
+  let tmp_path = PathBuf::from(tmp_path);
+
+  let file =
+    File::create(&tmp_path).map_err(|e| FollyError::at(&tmp_path, e))?;
+  let mut writer = BufWriter::new(file);
+  serde_json::to_writer(&mut writer, &CacheRef { root, units })
+    .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    .and_then(|_| writer.flush())
+    .map_err(|e| FollyError::at(&tmp_path, e))?;
+  fs::rename(&tmp_path, path).map_err(|e| FollyError::at(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{keys, scan};
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir()
+      .join(format!("folly-cache-test-{}", std::process::id()))
+      .join(name)
+  }
+
+  #[test]
+  fn save_names_the_file_it_failed_to_create() {
+    let path = temp_path("missing/cache.json");
+    let err = save(&path, Path::new("/src"), &UnitMap::new()).unwrap_err();
+    assert!(
+      err.to_string().contains("missing/cache.json.tmp"),
+      "{}",
+      err
+    );
+  }
+
+  #[test]
+  fn save_then_load_round_trips() {
+    let map = scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#pragma once\n"),
+    ]);
+    let path = temp_path("cache.json");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    save(&path, Path::new("/src"), &map).unwrap();
+    assert!(load(&path, Path::new("/elsewhere")).unwrap().is_none());
+    let loaded = load(&path, Path::new("/src")).unwrap().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(keys(&loaded), keys(&map));
+  }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+
+use crate::build_file::{BazelVersion, BuildFileName};
+use crate::cmake::OutputFormat;
+use crate::types::UnitKey;
+
+#[derive(Parser)]
+#[command(about = "Generates Bazel BUILD files for a C++ source tree.")]
+pub struct Args {
+  /// Directory to scan. Include paths are resolved relative to its parent,
+  /// so for folly this is the `folly` directory inside the checkout.
+  /// Required unless it comes from --config, or with --diff.
+  #[arg(long)]
+  pub root: Option<PathBuf>,
+
+  /// Read settings from this TOML file. Flags given on the command line
+  /// override the file; see config.rs for the keys.
+  #[cfg(feature = "config")]
+  #[arg(long)]
+  pub config: Option<PathBuf>,
+
+  /// What to write: BUILD files, or one CMakeLists.txt or meson.build for
+  /// the whole tree in --output-dir with the same targets. With the json
+  /// feature, json-bazel-query writes the rules to query.json there instead,
+  /// as `bazel query --output=jsonproto` would print them. --verify only
+  /// checks BUILD files.
+  #[arg(
+    long,
+    value_enum,
+    default_value_t = OutputFormat::BAZEL,
+    conflicts_with = "verify"
+  )]
+  pub output_format: OutputFormat,
+
+  /// Where to write BUILD files. Defaults to the parent of --root, which
+  /// places each BUILD file next to the sources it describes.
+  #[arg(long)]
+  pub output_dir: Option<PathBuf>,
+
+  /// Print the BUILD files that would be written without touching disk.
+  #[arg(long)]
+  pub dry_run: bool,
+
+  /// With --dry-run, print every BUILD file in full, each under a
+  /// `# === <path> ===` line.
+  #[arg(long)]
+  pub verbose: bool,
+
+  /// Check the BUILD files already in --output-dir against the graph instead
+  /// of writing new ones, failing if any rule's srcs or deps have drifted.
+  #[arg(long)]
+  pub verify: bool,
+
+  /// Use glob() for the hdrs of header-only units with many headers.
+  #[arg(long)]
+  pub use_glob: bool,
+
+  /// List each rule's hdrs and srcs by file name, headers first, rather
+  /// than in the order the scan found them, which depends on the file
+  /// system. Pass `--sort-srcs-alphabetically=false` to turn it off.
+  #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+  pub sort_srcs_alphabetically: bool,
+
+  /// How many headers a unit needs, exclusive, before --use-glob kicks in.
+  #[arg(long, default_value_t = 5)]
+  pub glob_threshold: usize,
+
+  /// Only write BUILD files for units whose directory is under this include
+  /// path, e.g. `folly/futures`. Everything is still scanned so that deps
+  /// outside it get the right labels.
+  #[arg(long)]
+  pub filter_root: Option<String>,
+
+  /// Put in front of the package in labels for deps in other packages, e.g.
+  /// `@folly//` when the BUILD files are used from another repository.
+  /// Deps outside --filter-root get it too, since they are assumed to have
+  /// BUILD files by the same labels already.
+  #[arg(long, default_value = "//")]
+  pub label_prefix: String,
+
+  /// What to call the BUILD files written. BUILD.bazel keeps out of the way
+  /// of other build systems' files in the same directories.
+  #[arg(long, value_enum, default_value_t = BuildFileName::BUILD)]
+  pub build_filename: BuildFileName,
+
+  /// Oldest Bazel major version the BUILD files have to work with. From 6
+  /// on, libraries put deps that only their sources include in
+  /// implementation_deps, so that dependents don't see those headers.
+  #[arg(long, value_enum, default_value_t = BazelVersion::V5)]
+  pub bazel_version: BazelVersion,
+
+  /// Make libraries without sources cc_header_only_library rules, which
+  /// skip the static archive. Only takes effect with --rules-cc-available.
+  #[arg(long)]
+  pub no_recursive_headers: bool,
+
+  /// The tree can load rules from @rules_cc.
+  #[arg(long)]
+  pub rules_cc_available: bool,
+
+  /// Build each test as a cc_binary and run it from an sh_test, using the
+  /// script given by --test-wrapper-script.
+  #[arg(
+    long,
+    alias = "wrap-tests-with-sh_test",
+    requires = "test_wrapper_script"
+  )]
+  pub wrap_tests_with_sh_test: bool,
+
+  /// Label or package-relative path of the script the sh_test runs. It gets
+  /// the path to the test binary as its first argument.
+  #[arg(long)]
+  pub test_wrapper_script: Option<String>,
+
+  /// Comma-separated include roots that come from external workspaces, as
+  /// `prefix=workspace` or just `prefix` when the two names match, e.g.
+  /// `boost,glog=com_github_google_glog`.
+  #[arg(long, value_delimiter = ',')]
+  pub known_roots: Vec<String>,
+
+  /// Take #includes of VIRTUAL, or of a file under it, as naming REAL
+  /// instead, both spelled as an #include would, e.g.
+  /// `folly/compat:folly/impl` for a tree that symlinks one to the other.
+  /// The longest matching VIRTUAL wins. Repeat for more than one alias.
+  #[arg(long, value_name = "VIRTUAL:REAL")]
+  pub path_alias: Vec<String>,
+
+  /// WORKSPACE or MODULE.bazel file to take --known-roots repository names
+  /// from. Each known root points at the http_archive, git_repository or
+  /// bazel_dep named after its workspace, or else at the only one with the
+  /// root as a word of its name, e.g. glog at com_github_google_glog.
+  #[arg(long)]
+  pub workspace: Option<PathBuf>,
+
+  /// TOML table mapping absl/ headers, minus extension, to the Abseil
+  /// target that provides them, e.g. `"absl/strings/str_cat" = "strings"`.
+  /// Headers not listed map to a target of their own name.
+  #[cfg(feature = "config")]
+  #[arg(long)]
+  pub abseil_targets: Option<PathBuf>,
+
+  /// compile_commands.json, as CMake or Bear write it, to take each file's
+  /// copts from instead of `// BAZEL_COPTS:` comments. Flags naming an
+  /// absolute path, such as -I/usr/include, are left out, as are the
+  /// compiler, -c, -o and the like.
+  #[cfg(feature = "json")]
+  #[arg(long)]
+  pub copts_from_compile_commands: Option<PathBuf>,
+
+  /// Scan only the files listed in this manifest, one path per line, rather
+  /// than everything under --root. Each must still be under --root, which
+  /// names units as usual.
+  #[arg(long)]
+  pub files_from: Option<PathBuf>,
+
+  /// Comma-separated directory names to skip while scanning.
+  #[arg(long, value_delimiter = ',', default_value = ".git,CMakeFiles")]
+  pub exclude_dirs: Vec<String>,
+
+  /// Compare the graphs in two --incremental caches instead of scanning,
+  /// printing added and removed units, deps and files. Exits with 1 when
+  /// they differ.
+  #[cfg(feature = "serde")]
+  #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+  pub diff: Option<Vec<PathBuf>>,
+
+  /// Keep running after the BUILD files are written, rescanning files under
+  /// the root as they change and writing the BUILD files again.
+  #[cfg(feature = "watch")]
+  #[arg(long, conflicts_with_all = ["verify", "diff"])]
+  pub watch: bool,
+
+  /// Walk and parse the tree on all cores. Ignored with --incremental.
+  #[cfg(feature = "parallel")]
+  #[arg(long)]
+  pub parallel: bool,
+
+  /// Treat BUILD, BUILD.bazel and BUCK files already in the tree as
+  /// canonical: their cc_* rules become nodes in the graph, and their
+  /// packages get no generated BUILD file. Off by default, since otherwise
+  /// rerunning would find its own output and skip everything.
+  #[arg(long)]
+  pub seed_existing: bool,
+
+  /// Skip directories more than this many levels below the root, with a
+  /// warning for each.
+  #[arg(long)]
+  pub max_depth: Option<usize>,
+
+  /// Fail when a header has neither an include guard nor `#pragma once` in
+  /// its first 20 lines, instead of warning about it.
+  #[arg(long)]
+  pub error_on_missing_guard: bool,
+
+  /// Don't follow the includes of generated files, those with
+  /// `// @generated`, `// Generated by` or `/* DO NOT EDIT */` in their
+  /// first 5 lines. They are still units that others can depend on.
+  #[arg(long)]
+  pub skip_generated: bool,
+
+  /// Drop units that no file under the root provides, such as system
+  /// headers mistaken for ours, along with every dep on them.
+  #[arg(long)]
+  pub prune_unreachable: bool,
+
+  /// Fold one unit into another as A=B, keeping A's name, e.g.
+  /// `folly/io/io_buf=folly/io/io_buf_impl`. Units are named as
+  /// --dump-graph prints them. Both must be in the same package. Repeat for
+  /// more than one merge.
+  #[arg(long, value_name = "A=B")]
+  pub merge_units: Vec<String>,
+
+  /// Print every unit in the parsed graph before any cycles are collapsed.
+  #[arg(long)]
+  pub dump_graph: bool,
+
+  /// Run every correctness check on the parsed graph and warn about each
+  /// problem found: deps on units with no files, non-test units that depend
+  /// on tests, #include cycles between headers, tests without sources and
+  /// units without a name.
+  #[arg(long)]
+  pub validate: bool,
+
+  /// Print every cycle in the parsed graph, before they are collapsed.
+  #[arg(long)]
+  pub list_cycles: bool,
+
+  /// Fail on deps on units that no file was found for, such as a header
+  /// taken for one of ours that isn't in the tree, rather than warn about
+  /// each one. --prune-unreachable drops them instead.
+  #[arg(long)]
+  pub strict: bool,
+
+  /// Fail on any #include cycle between headers, printing each one. Unlike
+  /// other cycles these can't be built however they are merged, so the
+  /// check happens before cycles are collapsed.
+  #[arg(long)]
+  pub check_header_cycles: bool,
+
+  /// Print every unit in the order it can be built, deps first, each with
+  /// how deep it is, e.g. `[depth=3] folly/futures/future`. Units of the
+  /// same depth are in alphabetical order.
+  #[arg(long)]
+  pub print_build_order: bool,
+
+  /// Print every unit the given one depends on, directly or not, instead
+  /// of writing build files. Units are named as --dump-graph prints them.
+  #[arg(long, value_name = "UNIT")]
+  pub query_deps: Option<String>,
+
+  /// Print every unit that depends on the given one, directly or not,
+  /// instead of writing build files.
+  #[arg(long, value_name = "UNIT")]
+  pub query_rdeps: Option<String>,
+
+  /// Only follow this many edges out for --query-deps and --query-rdeps; 1
+  /// is just direct deps.
+  #[arg(long)]
+  pub query_depth: Option<usize>,
+
+  /// Print how long each phase of the run took, in milliseconds, once it
+  /// is done: the scan, the checks before cycles are collapsed, collapsing
+  /// them, inferring deps, building the trie and writing files.
+  #[arg(long)]
+  pub profile: bool,
+
+  /// Show how many files the scan has got through, every 100 files. Not
+  /// shown with --parallel, or when --incremental only rescans what
+  /// changed.
+  #[arg(long)]
+  pub progress: bool,
+
+  /// Print node, edge and degree counts for the graph once cycles have been
+  /// collapsed, along with how deps are spread across its units.
+  #[arg(long)]
+  pub stats: bool,
+
+  /// Warn on stderr about units that seem too big for one rule: more than
+  /// --lint-max-srcs source files, or more than 50 deps.
+  #[arg(long)]
+  pub lint: bool,
+
+  /// How many source files a unit can have before --lint warns about it.
+  #[arg(long, default_value_t = 20)]
+  pub lint_max_srcs: usize,
+
+  /// Exit with status 2 if the run printed more than this many warnings,
+  /// counting --lint's. The BUILD files are still written; 0 makes any
+  /// warning fail the run.
+  #[arg(long)]
+  pub max_warnings: Option<usize>,
+
+  /// Warn on stderr about source files that name a class or struct defined
+  /// in another unit's headers without depending on that unit. Goes by
+  /// tokens alone, so expect some noise.
+  #[arg(long)]
+  pub check_missing_deps: bool,
+
+  /// Write the parsed dependency graph to this file in Graphviz DOT format.
+  #[arg(long)]
+  pub dot: Option<PathBuf>,
+
+  /// Write the dependency graph to this file as JSON, once cycles are
+  /// collapsed, i.e. with one entry per generated rule.
+  #[cfg(feature = "json")]
+  #[arg(long)]
+  pub json_output: Option<PathBuf>,
+
+  /// Reuse the graph cached in this file from a previous run, re-parsing
+  /// only files that changed since, then update the cache.
+  #[cfg(feature = "serde")]
+  #[arg(long)]
+  pub incremental: Option<PathBuf>,
+}
+
+// A unit as --dump-graph prints it, e.g. folly/io/io_buf.
+pub fn unit_key(s: &str) -> UnitKey {
+  match s.rsplit_once('/') {
+    Some((root_dir, name)) => UnitKey::new(name, root_dir),
+    None => UnitKey::new(s, ""),
+  }
+}
+
+impl Args {
+  // Always set by the time run() needs it, since parse_args fails otherwise.
+  pub fn root(&self) -> &Path {
+    self.root.as_deref().unwrap()
+  }
+
+  // --merge-units as pairs of keys, failing on the first entry without an
+  // `=`.
+  pub fn merge_units(&self) -> Result<Vec<(UnitKey, UnitKey)>, String> {
+    self
+      .merge_units
+      .iter()
+      .map(|entry| match entry.split_once('=') {
+        Some((a, b)) => Ok((unit_key(a), unit_key(b))),
+        None => Err(format!("--merge-units {}: expected A=B", entry)),
+      })
+      .collect()
+  }
+
+  // --path-alias as (virtual, real) pairs, failing on the first entry
+  // without a `:`.
+  pub fn path_aliases(&self) -> Result<Vec<(String, PathBuf)>, String> {
+    self
+      .path_alias
+      .iter()
+      .map(|entry| match entry.split_once(':') {
+        Some((virtual_path, real)) => {
+          Ok((virtual_path.to_string(), PathBuf::from(real)))
+        }
+        None => Err(format!("--path-alias {}: expected VIRTUAL:REAL", entry)),
+      })
+      .collect()
+  }
+
+  pub fn known_roots(&self) -> HashMap<String, String> {
+    self
+      .known_roots
+      .iter()
+      .map(|entry| match entry.split_once('=') {
+        Some((prefix, workspace)) => {
+          (prefix.to_string(), workspace.to_string())
+        }
+        None => (entry.clone(), entry.clone()),
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn path_aliases_split_on_the_first_colon() {
+    let args = Args::parse_from([
+      "folly",
+      "--path-alias",
+      "folly/compat:folly/impl",
+      "--path-alias",
+      "fmt:third_party/fmt:v2",
+    ]);
+    assert_eq!(
+      args.path_aliases().unwrap(),
+      [
+        ("folly/compat".to_string(), PathBuf::from("folly/impl")),
+        ("fmt".to_string(), PathBuf::from("third_party/fmt:v2")),
+      ]
+    );
+
+    let args = Args::parse_from(["folly", "--path-alias", "folly/compat"]);
+    assert_eq!(
+      args.path_aliases().unwrap_err(),
+      "--path-alias folly/compat: expected VIRTUAL:REAL"
+    );
+  }
+}
@@ -0,0 +1,447 @@
+use std::collections::HashSet;
+use std::io::{BufRead, Error, ErrorKind};
+
+use crate::build_file::{
+  sorted_files, target_name, tests_use_library, BuildOptions,
+};
+use crate::error::FollyError;
+use crate::file_system::FileSystemProvider;
+use crate::graph::sorted_nodes;
+use crate::types::{UnitInfo, UnitKey, UnitMap, UnitObj};
+
+// Which kind of build files a run writes.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+pub enum OutputFormat {
+  #[default]
+  #[value(name = "bazel")]
+  #[cfg_attr(feature = "config", serde(rename = "bazel"))]
+  BAZEL,
+  #[value(name = "cmake")]
+  #[cfg_attr(feature = "config", serde(rename = "cmake"))]
+  CMAKE,
+  #[value(name = "meson")]
+  #[cfg_attr(feature = "config", serde(rename = "meson"))]
+  MESON,
+  #[cfg(feature = "json")]
+  #[value(name = "json-bazel-query")]
+  #[cfg_attr(feature = "config", serde(rename = "json-bazel-query"))]
+  JSON_BAZEL_QUERY,
+}
+
+// CMake target names share one namespace across the whole project, so
+// unlike Bazel's they carry the package: folly/io's IOBuf is folly_io_IOBuf.
+pub fn cmake_target(key: &UnitKey) -> String {
+  let name = target_name(key);
+  if key.root_dir.is_empty() {
+    name
+  } else {
+    format!("{}_{}", key.root_dir.replace(['/', '-', '.'], "_"), name)
+  }
+}
+
+// Files are named from the output directory, where the one CMakeLists.txt
+// lives. A platform-specific file is wrapped in a generator expression, so
+// that elsewhere the target is still there but builds without it.
+fn cmake_files(unit: &UnitObj, files: &[String]) -> Vec<String> {
+  let info = unit.val.borrow();
+  let dir = &unit.key.root_dir;
+  files
+    .iter()
+    .map(|file| {
+      let path = if dir.is_empty() {
+        file.clone()
+      } else {
+        format!("{}/{}", dir, file)
+      };
+      if info.platforms.is_empty() {
+        path
+      } else {
+        let ids: Vec<&str> =
+          info.platforms.iter().map(|p| p.cmake_id()).collect();
+        format!("$<$<PLATFORM_ID:{}>:{}>", ids.join(","), path)
+      }
+    })
+    .collect()
+}
+
+fn command(name: &str, args: &[String]) -> String {
+  if args.len() <= 2 {
+    return format!("{}({})\n", name, args.join(" "));
+  }
+  let mut out = format!("{}({}\n", name, args[0]);
+  for arg in &args[1..] {
+    out += &format!("  {}\n", arg);
+  }
+  out + ")\n"
+}
+
+// The CMake counterpart of render_unit. Header-only libraries become
+// INTERFACE libraries, which have nothing to compile. Protos and deps
+// outside the tree are left out: they need protobuf_generate and
+// find_package, which are the project's to set up. Only deps in defined
+// are linked, since CMake takes any other name for a system library. A
+// unit's tests are an executable of their own, {target}Test, linked
+// against its library.
+fn render_target(
+  unit: &UnitObj,
+  opts: &BuildOptions,
+  defined: &HashSet<UnitKey>,
+) -> Option<String> {
+  let info = unit.val.borrow();
+  if !info.protos.is_empty() {
+    return None;
+  }
+  let target = cmake_target(&unit.key);
+  let link_targets = |deps: &mut dyn Iterator<Item = &UnitObj>| {
+    let mut deps: Vec<String> = deps
+      .filter(|dep| defined.contains(&dep.key))
+      .map(|dep| cmake_target(&dep.key))
+      .collect();
+    deps.sort();
+    deps.dedup();
+    deps
+  };
+
+  let mut out = String::new();
+  if info.has_library() {
+    let headers = cmake_files(unit, &sorted_files(&info.headers, opts));
+    let srcs = cmake_files(unit, &sorted_files(&info.srcs, opts));
+    let scope = if info.has_main {
+      out += &command(
+        "add_executable",
+        &[std::slice::from_ref(&target), &headers[..], &srcs[..]].concat(),
+      );
+      "PRIVATE"
+    } else if srcs.is_empty() {
+      out +=
+        &command("add_library", &[target.clone(), "INTERFACE".to_string()]);
+      "INTERFACE"
+    } else {
+      out += &command(
+        "add_library",
+        &[std::slice::from_ref(&target), &headers[..], &srcs[..]].concat(),
+      );
+      "PUBLIC"
+    };
+    let deps = link_targets(&mut info.deps.keys());
+    out += &target_settings(&target, scope, &deps, &info);
+  }
+  if !info.test_srcs.is_empty() {
+    if !out.is_empty() {
+      out += "\n";
+    }
+    let test = format!("{}Test", target);
+    let srcs = cmake_files(unit, &sorted_files(&info.test_srcs, opts));
+    out += &command(
+      "add_executable",
+      &[std::slice::from_ref(&test), &srcs[..]].concat(),
+    );
+    out += &command(
+      "add_test",
+      &[format!("NAME {}", test), format!("COMMAND {}", test)],
+    );
+    let mut deps = link_targets(&mut info.test_deps.iter());
+    if tests_use_library(&info) {
+      deps.insert(0, target);
+    }
+    out += &target_settings(&test, "PRIVATE", &deps, &info);
+  }
+  Some(out).filter(|out| !out.is_empty())
+}
+
+// What every target of a unit gets after it is added.
+fn target_settings(
+  target: &str,
+  scope: &str,
+  deps: &[String],
+  info: &UnitInfo<UnitKey>,
+) -> String {
+  let target = target.to_string();
+  // #includes are written from where the file lives.
+  let mut out = command(
+    "target_include_directories",
+    &[
+      target.clone(),
+      scope.to_string(),
+      "${CMAKE_CURRENT_SOURCE_DIR}".to_string(),
+    ],
+  );
+  if !deps.is_empty() {
+    out += &command(
+      "target_link_libraries",
+      &[&[target.clone(), scope.to_string()], deps].concat(),
+    );
+  }
+  // Linker flags carry over to whatever links this in, as Bazel's linkopts
+  // do.
+  if !info.link_opts.is_empty() {
+    out += &command(
+      "target_link_options",
+      &[&[target.clone(), scope.to_string()], &info.link_opts[..]].concat(),
+    );
+  }
+  // An INTERFACE library has nothing of its own to compile.
+  if !info.build_flags.is_empty() && scope != "INTERFACE" {
+    out += &command(
+      "target_compile_options",
+      &[&[target, "PRIVATE".to_string()], &info.build_flags[..]].concat(),
+    );
+  }
+  out
+}
+
+// The first line of every file we write outside of BUILD files, by which we
+// know the ones that are ours to overwrite. Both CMake and Meson take it as
+// a comment.
+const GENERATED_HEADER: &str = "# Generated by folly from #includes.";
+
+pub fn render_cmake(map: &UnitMap, opts: &BuildOptions) -> String {
+  let units: Vec<UnitObj> = sorted_nodes(map)
+    .into_iter()
+    .filter(|unit| opts.emits(&unit.key) && !unit.val.borrow().pre_existing)
+    .collect();
+  // The units whose library render_target adds: whatever else a unit
+  // depends on, such as a pre-existing package or one outside filter_root,
+  // is the project's to link in.
+  let defined: HashSet<UnitKey> = units
+    .iter()
+    .filter(|unit| {
+      let info = unit.val.borrow();
+      info.protos.is_empty() && info.has_library()
+    })
+    .map(|unit| unit.key.clone())
+    .collect();
+  let targets: Vec<String> = units
+    .iter()
+    .filter_map(|unit| render_target(unit, opts, &defined))
+    .collect();
+  targets.join("\n")
+}
+
+// Writes one CMakeLists.txt for the whole tree to opts.output_dir, where
+// the BUILD files' packages would start.
+pub fn export_cmake<F: FileSystemProvider>(
+  fs: &F,
+  map: &UnitMap,
+  opts: &BuildOptions,
+) -> Result<(), FollyError> {
+  write_generated(fs, "CMakeLists.txt", &render_cmake(map, opts), opts)
+}
+
+// Writes contents under GENERATED_HEADER to file_name in opts.output_dir,
+// going where opts.sink says as BUILD files do. A file
+// already there that we didn't write is the project's own, and is left
+// alone with an error.
+pub fn write_generated<F: FileSystemProvider>(
+  fs: &F,
+  file_name: &str,
+  contents: &str,
+  opts: &BuildOptions,
+) -> Result<(), FollyError> {
+  let contents = format!("{}\n\n{}", GENERATED_HEADER, contents);
+  let path = opts.output_dir.join(file_name);
+  if let Ok(existing) = fs.open_file(&path) {
+    let first = existing.lines().next().and_then(Result::ok);
+    if first.as_deref() != Some(GENERATED_HEADER) {
+      return Err(FollyError::IoError(Error::new(
+        ErrorKind::AlreadyExists,
+        format!(
+          "{} was not generated by us; pass --output-dir to write elsewhere",
+          path.display()
+        ),
+      )));
+    }
+  }
+  opts.sink(fs).write(&path, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::builder::GraphBuilder;
+  use crate::test_util::{build, build_options, mock_fs, scan_with};
+  use crate::types::ScanOptions;
+  use std::io::Read;
+  use std::path::Path;
+
+  fn graph() -> UnitMap {
+    build(&[
+      (
+        "folly/Foo.cpp",
+        "// BAZEL_COPTS: -mavx2\n#include <folly/Foo.h>\n",
+      ),
+      ("folly/Foo.h", "#include <folly/io/IOBuf.h>\n"),
+      ("folly/Bartest.cpp", "#include <folly/Foo.h>\n"),
+      ("folly/Tool.cpp", "#include <folly/Foo.h>\nint main() {}\n"),
+      ("folly/io/IOBuf.h", "#pragma once\n"),
+    ])
+  }
+
+  #[test]
+  fn renders_each_kind_of_target() {
+    assert_eq!(
+      render_cmake(&graph(), &build_options()),
+      "add_executable(folly_BarTest folly/Bartest.cpp)
+add_test(NAME folly_BarTest COMMAND folly_BarTest)
+target_include_directories(folly_BarTest
+  PRIVATE
+  ${CMAKE_CURRENT_SOURCE_DIR}
+)
+target_link_libraries(folly_BarTest
+  PRIVATE
+  folly_Foo
+)
+
+add_library(folly_Foo
+  folly/Foo.h
+  folly/Foo.cpp
+)
+target_include_directories(folly_Foo
+  PUBLIC
+  ${CMAKE_CURRENT_SOURCE_DIR}
+)
+target_link_libraries(folly_Foo
+  PUBLIC
+  folly_io_IoBuf
+)
+target_compile_options(folly_Foo
+  PRIVATE
+  -mavx2
+)
+
+add_executable(folly_Tool folly/Tool.cpp)
+target_include_directories(folly_Tool
+  PRIVATE
+  ${CMAKE_CURRENT_SOURCE_DIR}
+)
+target_link_libraries(folly_Tool
+  PRIVATE
+  folly_Foo
+)
+
+add_library(folly_io_IoBuf INTERFACE)
+target_include_directories(folly_io_IoBuf
+  INTERFACE
+  ${CMAKE_CURRENT_SOURCE_DIR}
+)
+"
+    );
+  }
+
+  #[test]
+  fn cmake_targets_carry_their_package() {
+    for (name, root_dir, target) in [
+      ("io_buf", "folly/io", "folly_io_IoBuf"),
+      (
+        "foo",
+        "folly/experimental-x/v1.2",
+        "folly_experimental_x_v1_2_Foo",
+      ),
+      ("top", "", "Top"),
+    ] {
+      assert_eq!(cmake_target(&UnitKey::new(name, root_dir)), target);
+    }
+  }
+
+  #[test]
+  fn filter_root_limits_the_targets() {
+    let opts = BuildOptions {
+      filter_root: Some("folly/io".to_string()),
+      ..build_options()
+    };
+    let cmake = render_cmake(&graph(), &opts);
+    assert!(cmake.starts_with("add_library(folly_io_IoBuf INTERFACE)\n"));
+    assert!(!cmake.contains("folly_Foo"), "{}", cmake);
+  }
+
+  #[test]
+  fn links_only_targets_it_defines() {
+    let mut map = scan_with(
+      &[
+        (
+          "folly/net/Socket.h",
+          "#pragma once
+#include <folly/io/IOBuf.h>
+           #include <folly/net/old/Old.h>
+#include \"folly/net/Msg.pb.h\"
+           #include <folly/net/Missing.h>
+#include <folly/net/Addr.h>
+",
+        ),
+        (
+          "folly/net/Addr.h",
+          "#pragma once
+",
+        ),
+        (
+          "folly/net/Msg.proto",
+          "syntax = \"proto3\";
+",
+        ),
+        (
+          "folly/net/old/Old.h",
+          "#pragma once
+",
+        ),
+        (
+          "folly/net/old/BUILD",
+          "cc_library(name = \"Old\")
+",
+        ),
+        (
+          "folly/io/IOBuf.h",
+          "#pragma once
+",
+        ),
+      ],
+      &ScanOptions {
+        seed_existing: true,
+        ..ScanOptions::default()
+      },
+    );
+    GraphBuilder::new().finish(&mut map).unwrap();
+    let opts = BuildOptions {
+      filter_root: Some("folly/net".to_string()),
+      ..build_options()
+    };
+    let cmake = render_cmake(&map, &opts);
+    assert!(
+      cmake.contains(
+        "target_link_libraries(folly_net_Socket\n  INTERFACE\n  folly_net_Addr\n)\n"
+      ),
+      "{}",
+      cmake
+    );
+    assert_eq!(
+      cmake.matches("target_link_libraries").count(),
+      1,
+      "{}",
+      cmake
+    );
+  }
+
+  #[test]
+  fn export_cmake_writes_but_never_overwrites_a_foreign_file() {
+    let fs = mock_fs(&[]);
+    export_cmake(&fs, &graph(), &build_options()).unwrap();
+    let mut written = String::new();
+    fs.open_file(Path::new("/src/CMakeLists.txt"))
+      .unwrap()
+      .read_to_string(&mut written)
+      .unwrap();
+    assert!(written.starts_with(&format!("{}\n\n", GENERATED_HEADER)));
+    assert!(written.contains("add_library(folly_Foo\n"));
+    // Ours, so it can be written again.
+    export_cmake(&fs, &graph(), &build_options()).unwrap();
+
+    let fs = mock_fs(&[("CMakeLists.txt", "project(mine)\n")]);
+    let err = export_cmake(&fs, &graph(), &build_options()).unwrap_err();
+    assert!(
+      err.to_string().contains("was not generated by us"),
+      "{}",
+      err
+    );
+  }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::FollyError;
+
+// One entry of a compilation database, as CMake's
+// CMAKE_EXPORT_COMPILE_COMMANDS and Bear write them. Entries have either
+// the whole command line, shell-quoted, or its arguments already split.
+#[derive(Deserialize)]
+struct CompileCommand {
+  directory: PathBuf,
+  file: PathBuf,
+  #[serde(default)]
+  command: Option<String>,
+  #[serde(default)]
+  arguments: Option<Vec<String>>,
+}
+
+// Flags that take the next argument as their value, and say nothing about
+// how the file should be compiled under Bazel.
+const DROPPED_WITH_VALUE: [&str; 5] = ["-o", "-MF", "-MT", "-MQ", "-Xclang"];
+
+// Flags whose value is a path, which only means something on the machine
+// the database came from when it is absolute.
+const PATH_FLAGS: [&str; 6] = [
+  "-I",
+  "-isystem",
+  "-iquote",
+  "-idirafter",
+  "-include",
+  "--sysroot",
+];
+
+// Splits a command line the way a POSIX shell would for one simple command:
+// on unquoted whitespace, with quotes removed and backslash escapes undone.
+fn split_command(command: &str) -> Vec<String> {
+  let mut args = Vec::new();
+  let mut arg: Option<String> = None;
+  let mut chars = command.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\'' => {
+        let arg = arg.get_or_insert_with(String::new);
+        arg.extend(chars.by_ref().take_while(|c| *c != '\''));
+      }
+      '"' => {
+        let arg = arg.get_or_insert_with(String::new);
+        while let Some(c) = chars.next() {
+          match c {
+            '"' => break,
+            '\\' => arg.extend(chars.next()),
+            c => arg.push(c),
+          }
+        }
+      }
+      '\\' => arg.get_or_insert_with(String::new).extend(chars.next()),
+      c if c.is_whitespace() => args.extend(arg.take()),
+      c => arg.get_or_insert_with(String::new).push(c),
+    }
+  }
+  args.extend(arg);
+  args
+}
+
+fn is_absolute(path: &str) -> bool {
+  path.starts_with('/') || Path::new(path).is_absolute()
+}
+
+// The flags in args worth keeping as copts: not the compiler, the file
+// under any of its names, its output or dependency-file settings, nor
+// anything naming an absolute path, which would tie the BUILD file to one
+// machine.
+fn copts(args: &[String], file: &[&Path]) -> Vec<String> {
+  let mut flags = Vec::new();
+  let mut args = args.iter().skip(1);
+  while let Some(arg) = args.next() {
+    if DROPPED_WITH_VALUE.contains(&arg.as_str()) {
+      args.next();
+      continue;
+    }
+    if arg == "-c"
+      || arg == "-MD"
+      || arg == "-MMD"
+      || file.contains(&Path::new(arg))
+    {
+      continue;
+    }
+    if PATH_FLAGS.contains(&arg.as_str()) {
+      match args.next() {
+        Some(path) if !is_absolute(path) => {
+          flags.push(arg.clone());
+          flags.push(path.clone());
+        }
+        _ => {}
+      }
+      continue;
+    }
+    // Joined forms such as -I/usr/include and --sysroot=/opt/sdk.
+    let joined = PATH_FLAGS.iter().find_map(|flag| {
+      arg
+        .strip_prefix(flag)
+        .map(|rest| rest.strip_prefix('=').unwrap_or(rest))
+    });
+    if joined.is_some_and(is_absolute) {
+      continue;
+    }
+    flags.push(arg.clone());
+  }
+  flags
+}
+
+// File path to its copts for every entry in a compilation database. Paths
+// are canonicalized where the file exists, so that they compare equal to
+// the ones a scan walks.
+pub fn parse(
+  contents: &str,
+) -> Result<HashMap<PathBuf, Vec<String>>, serde_json::Error> {
+  let commands: Vec<CompileCommand> = serde_json::from_str(contents)?;
+  Ok(
+    commands
+      .into_iter()
+      .map(|entry| {
+        let file = entry.directory.join(&entry.file);
+        let args = match (entry.arguments, entry.command) {
+          (Some(arguments), _) => arguments,
+          (None, Some(command)) => split_command(&command),
+          (None, None) => Vec::new(),
+        };
+        let flags = copts(&args, &[&entry.file, &file]);
+        (fs::canonicalize(&file).unwrap_or(file), flags)
+      })
+      .collect(),
+  )
+}
+
+pub fn load(path: &Path) -> Result<HashMap<PathBuf, Vec<String>>, FollyError> {
+  let contents =
+    fs::read_to_string(path).map_err(|e| FollyError::at(path, e))?;
+  parse(&contents).map_err(|e| FollyError::ParseError {
+    file: path.to_path_buf(),
+    line: e.line(),
+    message: e.to_string(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn split_command_undoes_shell_quoting() {
+    for (command, args) in [
+      ("c++ -c a.cpp", vec!["c++", "-c", "a.cpp"]),
+      ("  c++\t-O2  ", vec!["c++", "-O2"]),
+      ("c++ -DX='a b'", vec!["c++", "-DX=a b"]),
+      ("c++ \"-DY=\\\"q\\\"\"", vec!["c++", "-DY=\"q\""]),
+      ("c++ -DZ=a\\ b", vec!["c++", "-DZ=a b"]),
+      ("c++ ''", vec!["c++", ""]),
+    ] {
+      assert_eq!(split_command(command), args, "{}", command);
+    }
+  }
+
+  #[test]
+  fn copts_keep_only_portable_flags() {
+    let file = Path::new("folly/A.cpp");
+    for (command, flags) in [
+      ("c++ -O2 -c folly/A.cpp -o A.o", vec!["-O2"]),
+      ("c++ -I/usr/include -Ifolly folly/A.cpp", vec!["-Ifolly"]),
+      ("c++ -I /usr/include -I folly", vec!["-I", "folly"]),
+      ("c++ -isystem /opt/include -isystem/opt/x", vec![]),
+      ("c++ -isystem third_party", vec!["-isystem", "third_party"]),
+      ("c++ --sysroot=/opt/sdk -std=c++17", vec!["-std=c++17"]),
+      ("c++ -Xclang -fno-pch-timestamp -Wall", vec!["-Wall"]),
+      ("c++ -MD -MF A.d -MT A.o -DX=1", vec!["-DX=1"]),
+    ] {
+      assert_eq!(
+        copts(&split_command(command), &[file]),
+        flags,
+        "{}",
+        command
+      );
+    }
+  }
+
+  #[test]
+  fn parse_takes_commands_or_arguments() {
+    let flags = parse(
+      r#"[
+        {"directory": "/nonexistent", "file": "a.cpp",
+         "command": "c++ -DA -c a.cpp"},
+        {"directory": "/nonexistent", "file": "b.cpp",
+         "arguments": ["c++", "-DB", "-c", "/nonexistent/b.cpp"]}
+      ]"#,
+    )
+    .unwrap();
+    assert_eq!(flags[Path::new("/nonexistent/a.cpp")], ["-DA"]);
+    assert_eq!(flags[Path::new("/nonexistent/b.cpp")], ["-DB"]);
+    assert!(parse("{}").is_err());
+  }
+}
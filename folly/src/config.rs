@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::build_file::{BazelVersion, BuildFileName};
+use crate::cli::Args;
+use crate::cmake::OutputFormat;
+use crate::error::FollyError;
+
+// Settings read from --config. Every key is optional and named after the
+// flag it stands in for:
+//
+//   root = "folly"                      # path
+//   output-format = "bazel"             # or "cmake", "meson", or
+//                                       # "json-bazel-query" with json
+//   output-dir = "out"                  # path
+//   dry-run = false
+//   verbose = false
+//   verify = false
+//   use-glob = true
+//   sort-srcs-alphabetically = true
+//   glob-threshold = 5
+//   filter-root = "folly/futures"
+//   label-prefix = "@folly//"
+//   build-filename = "BUILD.bazel"      # or "BUILD"
+//   bazel-version = "6"
+//   no-recursive-headers = false
+//   rules-cc-available = false
+//   wrap-tests-with-sh-test = false
+//   test-wrapper-script = "//tools:run_test.sh"
+//   known-roots = ["boost", "glog=com_github_google_glog"]
+//   files-from = "files.txt"            # path
+//   exclude-dirs = [".git", "CMakeFiles"]
+//   workspace = "WORKSPACE.bazel"       # path
+//   abseil-targets = "absl_targets.toml"  # path
+//   copts-from-compile-commands = "build/compile_commands.json"
+//                                       # path, needs the json feature
+//   seed-existing = false
+//   prune-unreachable = false
+//   merge-units = ["folly/io/io_buf=folly/io/io_buf_impl"]
+//   path-alias = ["folly/compat:folly/impl"]
+//   max-depth = 32
+//   error-on-missing-guard = false
+//   skip-generated = false
+//   stats = false
+//   lint = false
+//   lint-max-srcs = 20
+//   max-warnings = 0
+//   check-missing-deps = false
+//   dump-graph = false
+//   validate = false
+//   list-cycles = false
+//   strict = false
+//   check-header-cycles = false
+//   print-build-order = false
+//   progress = false
+//   profile = false
+//   dot = "graph.dot"                   # path
+//   json-output = "graph.json"          # path, needs the json feature
+//   incremental = "cache.json"          # path, needs the serde feature
+//   parallel = false                    # needs the parallel feature
+//   watch = false                       # needs the watch feature
+//
+// Relative paths are taken as-is, i.e. relative to the working directory
+// rather than to the config file.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+  pub root: Option<PathBuf>,
+  pub output_format: Option<OutputFormat>,
+  pub output_dir: Option<PathBuf>,
+  pub dry_run: Option<bool>,
+  pub verbose: Option<bool>,
+  pub verify: Option<bool>,
+  pub use_glob: Option<bool>,
+  pub sort_srcs_alphabetically: Option<bool>,
+  pub glob_threshold: Option<usize>,
+  pub filter_root: Option<String>,
+  pub label_prefix: Option<String>,
+  pub build_filename: Option<BuildFileName>,
+  pub bazel_version: Option<BazelVersion>,
+  pub no_recursive_headers: Option<bool>,
+  pub rules_cc_available: Option<bool>,
+  pub wrap_tests_with_sh_test: Option<bool>,
+  pub test_wrapper_script: Option<String>,
+  pub known_roots: Option<Vec<String>>,
+  pub files_from: Option<PathBuf>,
+  pub exclude_dirs: Option<Vec<String>>,
+  pub workspace: Option<PathBuf>,
+  pub abseil_targets: Option<PathBuf>,
+  #[cfg(feature = "json")]
+  pub copts_from_compile_commands: Option<PathBuf>,
+  pub seed_existing: Option<bool>,
+  pub prune_unreachable: Option<bool>,
+  pub merge_units: Option<Vec<String>>,
+  pub path_alias: Option<Vec<String>>,
+  pub max_depth: Option<usize>,
+  pub error_on_missing_guard: Option<bool>,
+  pub skip_generated: Option<bool>,
+  pub stats: Option<bool>,
+  pub lint: Option<bool>,
+  pub lint_max_srcs: Option<usize>,
+  pub max_warnings: Option<usize>,
+  pub check_missing_deps: Option<bool>,
+  pub dump_graph: Option<bool>,
+  pub validate: Option<bool>,
+  pub list_cycles: Option<bool>,
+  pub strict: Option<bool>,
+  pub check_header_cycles: Option<bool>,
+  pub print_build_order: Option<bool>,
+  pub progress: Option<bool>,
+  pub profile: Option<bool>,
+  pub dot: Option<PathBuf>,
+  #[cfg(feature = "json")]
+  pub json_output: Option<PathBuf>,
+  #[cfg(feature = "serde")]
+  pub incremental: Option<PathBuf>,
+  #[cfg(feature = "parallel")]
+  pub parallel: Option<bool>,
+  #[cfg(feature = "watch")]
+  pub watch: Option<bool>,
+}
+
+fn read_toml<T: DeserializeOwned>(path: &Path) -> Result<T, FollyError> {
+  let contents =
+    fs::read_to_string(path).map_err(|e| FollyError::at(path, e))?;
+  toml::from_str(&contents).map_err(|e| FollyError::ParseError {
+    file: path.to_path_buf(),
+    line: e
+      .span()
+      .map_or(0, |span| contents[..span.start].matches('\n').count() + 1),
+    message: e.message().to_string(),
+  })
+}
+
+pub fn load(path: &Path) -> Result<Config, FollyError> {
+  read_toml(path)
+}
+
+// Abseil's targets don't follow its file names, so --abseil-targets reads a
+// table from header path, minus extension, to target name:
+//
+//   "absl/strings/str_cat" = "strings"
+//   "absl/strings/str_split" = "strings"
+//   "absl/synchronization/mutex" = "synchronization"
+pub fn load_abseil_targets(
+  path: &Path,
+) -> Result<HashMap<String, String>, FollyError> {
+  read_toml(path)
+}
+
+impl Config {
+  // Fills in every setting that wasn't given on the command line, which
+  // always wins over the file.
+  pub fn apply(self, args: &mut Args, matches: &ArgMatches) {
+    let from_cli =
+      |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    // Optional flags are wrapped in Some; the rest are assigned directly.
+    macro_rules! fill {
+      ($field:ident $(, $wrap:path)?) => {
+        if !from_cli(stringify!($field)) {
+          if let Some(value) = self.$field {
+            args.$field = $($wrap)?(value);
+          }
+        }
+      };
+    }
+
+    fill!(root, Some);
+    fill!(output_format);
+    fill!(output_dir, Some);
+    fill!(dry_run);
+    fill!(verbose);
+    fill!(verify);
+    fill!(use_glob);
+    fill!(sort_srcs_alphabetically);
+    fill!(glob_threshold);
+    fill!(filter_root, Some);
+    fill!(label_prefix);
+    fill!(build_filename);
+    fill!(bazel_version);
+    fill!(no_recursive_headers);
+    fill!(rules_cc_available);
+    fill!(wrap_tests_with_sh_test);
+    fill!(test_wrapper_script, Some);
+    fill!(known_roots);
+    fill!(files_from, Some);
+    fill!(exclude_dirs);
+    fill!(workspace, Some);
+    fill!(abseil_targets, Some);
+    #[cfg(feature = "json")]
+    fill!(copts_from_compile_commands, Some);
+    fill!(seed_existing);
+    fill!(prune_unreachable);
+    fill!(merge_units);
+    fill!(path_alias);
+    fill!(max_depth, Some);
+    fill!(error_on_missing_guard);
+    fill!(skip_generated);
+    fill!(stats);
+    fill!(lint);
+    fill!(lint_max_srcs);
+    fill!(max_warnings, Some);
+    fill!(check_missing_deps);
+    fill!(dump_graph);
+    fill!(validate);
+    fill!(list_cycles);
+    fill!(strict);
+    fill!(check_header_cycles);
+    fill!(print_build_order);
+    fill!(progress);
+    fill!(profile);
+    fill!(dot, Some);
+    #[cfg(feature = "json")]
+    fill!(json_output, Some);
+    #[cfg(feature = "serde")]
+    fill!(incremental, Some);
+    #[cfg(feature = "parallel")]
+    fill!(parallel);
+    #[cfg(feature = "watch")]
+    fill!(watch);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Every key that doesn't need another feature, none at its default.
+  const EVERY_KEY: &str = r#"
+root = "folly"
+output-format = "cmake"
+output-dir = "out"
+dry-run = true
+verbose = true
+verify = true
+use-glob = true
+sort-srcs-alphabetically = false
+glob-threshold = 7
+filter-root = "folly/futures"
+label-prefix = "@folly//"
+build-filename = "BUILD.bazel"
+bazel-version = "6"
+no-recursive-headers = true
+rules-cc-available = true
+wrap-tests-with-sh-test = true
+test-wrapper-script = "//tools:run_test.sh"
+known-roots = ["boost", "glog=com_github_google_glog"]
+files-from = "files.txt"
+exclude-dirs = [".git", "CMakeFiles"]
+workspace = "WORKSPACE.bazel"
+abseil-targets = "absl_targets.toml"
+seed-existing = true
+prune-unreachable = true
+merge-units = ["folly/io/io_buf=folly/io/io_buf_impl"]
+path-alias = ["folly/compat:folly/impl"]
+max-depth = 32
+error-on-missing-guard = true
+skip-generated = true
+stats = true
+lint = true
+lint-max-srcs = 20
+max-warnings = 3
+check-missing-deps = true
+dump-graph = true
+validate = true
+list-cycles = true
+strict = true
+check-header-cycles = true
+print-build-order = true
+progress = true
+profile = true
+dot = "graph.dot"
+"#;
+
+  fn write_config(name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+      .join(format!("folly-config-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path
+  }
+
+  #[test]
+  fn load_reads_every_key() {
+    let config = load(&write_config("every_key.toml", EVERY_KEY)).unwrap();
+    let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+    assert_eq!(config.root, Some(PathBuf::from("folly")));
+    assert_eq!(config.output_format, Some(OutputFormat::CMAKE));
+    assert_eq!(config.output_dir, Some(PathBuf::from("out")));
+    assert_eq!(config.dry_run, Some(true));
+    assert_eq!(config.verbose, Some(true));
+    assert_eq!(config.verify, Some(true));
+    assert_eq!(config.use_glob, Some(true));
+    assert_eq!(config.sort_srcs_alphabetically, Some(false));
+    assert_eq!(config.glob_threshold, Some(7));
+    assert_eq!(config.filter_root.as_deref(), Some("folly/futures"));
+    assert_eq!(config.label_prefix.as_deref(), Some("@folly//"));
+    assert_eq!(config.build_filename, Some(BuildFileName::BUILD_BAZEL));
+    assert_eq!(config.bazel_version, Some(BazelVersion::V6));
+    assert_eq!(config.no_recursive_headers, Some(true));
+    assert_eq!(config.rules_cc_available, Some(true));
+    assert_eq!(config.wrap_tests_with_sh_test, Some(true));
+    assert_eq!(
+      config.test_wrapper_script.as_deref(),
+      Some("//tools:run_test.sh")
+    );
+    assert_eq!(
+      config.known_roots,
+      Some(strings(&["boost", "glog=com_github_google_glog"]))
+    );
+    assert_eq!(config.files_from, Some(PathBuf::from("files.txt")));
+    assert_eq!(config.exclude_dirs, Some(strings(&[".git", "CMakeFiles"])));
+    assert_eq!(config.workspace, Some(PathBuf::from("WORKSPACE.bazel")));
+    assert_eq!(
+      config.abseil_targets,
+      Some(PathBuf::from("absl_targets.toml"))
+    );
+    assert_eq!(config.seed_existing, Some(true));
+    assert_eq!(config.prune_unreachable, Some(true));
+    assert_eq!(
+      config.merge_units,
+      Some(strings(&["folly/io/io_buf=folly/io/io_buf_impl"]))
+    );
+    assert_eq!(
+      config.path_alias,
+      Some(strings(&["folly/compat:folly/impl"]))
+    );
+    assert_eq!(config.max_depth, Some(32));
+    assert_eq!(config.error_on_missing_guard, Some(true));
+    assert_eq!(config.skip_generated, Some(true));
+    assert_eq!(config.stats, Some(true));
+    assert_eq!(config.lint, Some(true));
+    assert_eq!(config.lint_max_srcs, Some(20));
+    assert_eq!(config.max_warnings, Some(3));
+    assert_eq!(config.check_missing_deps, Some(true));
+    assert_eq!(config.dump_graph, Some(true));
+    assert_eq!(config.validate, Some(true));
+    assert_eq!(config.list_cycles, Some(true));
+    assert_eq!(config.strict, Some(true));
+    assert_eq!(config.check_header_cycles, Some(true));
+    assert_eq!(config.print_build_order, Some(true));
+    assert_eq!(config.progress, Some(true));
+    assert_eq!(config.profile, Some(true));
+    assert_eq!(config.dot, Some(PathBuf::from("graph.dot")));
+  }
+
+  #[test]
+  fn command_line_flags_override_the_file() {
+    use clap::{CommandFactory, FromArgMatches};
+
+    let path = write_config("override.toml", EVERY_KEY);
+    let matches = Args::command().get_matches_from([
+      "folly".as_ref(),
+      "--config".as_ref(),
+      path.as_os_str(),
+      "--glob-threshold".as_ref(),
+      "9".as_ref(),
+    ]);
+    let mut args = Args::from_arg_matches(&matches).unwrap();
+    load(&path).unwrap().apply(&mut args, &matches);
+    assert_eq!(args.glob_threshold, 9);
+    assert_eq!(args.root, Some(PathBuf::from("folly")));
+    assert_eq!(args.label_prefix, "@folly//");
+    assert_eq!(args.exclude_dirs, [".git", "CMakeFiles"]);
+  }
+
+  #[test]
+  fn load_rejects_unknown_keys_with_their_line() {
+    let path = write_config("unknown.toml", "verbose = true\nbogus = 1\n");
+    match load(&path) {
+      Err(FollyError::ParseError { line, message, .. }) => {
+        assert_eq!(line, 2);
+        assert!(message.contains("bogus"), "{}", message);
+      }
+      _ => panic!("expected a parse error"),
+    }
+  }
+
+  #[test]
+  fn load_abseil_targets_reads_the_header_table() {
+    let path = write_config(
+      "absl_targets.toml",
+      "\"absl/strings/str_cat\" = \"strings\"\n\
+       \"absl/synchronization/mutex\" = \"synchronization\"\n",
+    );
+    let targets = load_abseil_targets(&path).unwrap();
+    assert_eq!(targets.len(), 2);
+    assert_eq!(targets["absl/strings/str_cat"], "strings");
+    assert_eq!(targets["absl/synchronization/mutex"], "synchronization");
+  }
+}
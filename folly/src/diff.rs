@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::types::UnitMap;
+
+// What the diff looks at for one unit, keyed and sorted by display form so
+// that the output comes out in a stable order.
+struct Summary {
+  files: BTreeSet<String>,
+  deps: BTreeSet<String>,
+}
+
+fn summarize(map: &UnitMap) -> BTreeMap<String, Summary> {
+  map
+    .iter()
+    .map(|node| {
+      let info = node.val.borrow();
+      let files = info
+        .headers
+        .iter()
+        .chain(info.srcs.iter())
+        .chain(info.protos.iter())
+        .chain(info.test_srcs.iter())
+        .cloned()
+        .collect();
+      let deps = info
+        .deps
+        .keys()
+        .chain(&info.test_deps)
+        .map(|dep| dep.key.to_string())
+        .chain(
+          info
+            .external_deps
+            .iter()
+            .chain(&info.test_external_deps)
+            .map(|(workspace, key)| format!("@{}//{}", workspace, key)),
+        )
+        .collect();
+      (node.key.to_string(), Summary { files, deps })
+    })
+    .collect()
+}
+
+fn diff_set(
+  lines: &mut Vec<String>,
+  what: &str,
+  old: &BTreeSet<String>,
+  new: &BTreeSet<String>,
+) {
+  for removed in old.difference(new) {
+    lines.push(format!("-   {} {}", what, removed));
+  }
+  for added in new.difference(old) {
+    lines.push(format!("+   {} {}", what, added));
+  }
+}
+
+// Units only in one map get a `-` or `+` line. Units in both that changed
+// get a ` ` line followed by their added and removed files and deps. Returns
+// nothing when the maps match.
+pub fn diff_maps(old: &UnitMap, new: &UnitMap) -> Vec<String> {
+  let old = summarize(old);
+  let new = summarize(new);
+  let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+  let mut lines = Vec::new();
+  for key in keys {
+    match (old.get(key), new.get(key)) {
+      (Some(_), None) => lines.push(format!("- {}", key)),
+      (None, Some(_)) => lines.push(format!("+ {}", key)),
+      (Some(old), Some(new)) => {
+        let mut changes = Vec::new();
+        diff_set(&mut changes, "file", &old.files, &new.files);
+        diff_set(&mut changes, "dep", &old.deps, &new.deps);
+        if !changes.is_empty() {
+          lines.push(format!("  {}", key));
+          lines.extend(changes);
+        }
+      }
+      (None, None) => unreachable!(),
+    }
+  }
+  lines
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::scan;
+
+  #[test]
+  fn identical_maps_have_no_diff() {
+    let files = [
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Foo.h", "#include <folly/Bar.h>\n"),
+    ];
+    assert!(diff_maps(&scan(&files), &scan(&files)).is_empty());
+  }
+
+  #[test]
+  fn lists_added_and_removed_units_files_and_deps() {
+    let old = scan(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Foo.h", "#include <folly/Bar.h>\n"),
+      ("folly/Old.h", "#pragma once\n"),
+    ]);
+    let new = scan(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Baz.h", "#pragma once\n"),
+      ("folly/Foo.cpp", "#include <folly/Foo.h>\n"),
+      ("folly/Foo.h", "#include <folly/Baz.h>\n"),
+    ]);
+    assert_eq!(
+      diff_maps(&old, &new),
+      [
+        "+ folly/baz",
+        "  folly/foo",
+        "+   file Foo.cpp",
+        "-   dep folly/bar",
+        "+   dep folly/baz",
+        "- folly/old",
+      ]
+    );
+  }
+}
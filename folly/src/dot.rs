@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::io::{Error, Write};
+
+use crate::types::UnitMap;
+
+fn quote(s: &str) -> String {
+  format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Emits the deps graph as a Graphviz digraph, with one cluster per root_dir.
+// Output is sorted so that reruns over the same tree diff cleanly.
+pub fn write_dot(map: &UnitMap, output: &mut dyn Write) -> Result<(), Error> {
+  let mut clusters: BTreeMap<String, Vec<String>> = BTreeMap::new();
+  let mut edges: Vec<(String, String)> = Vec::new();
+  for node in map.iter() {
+    clusters
+      .entry(node.key.root_dir.clone())
+      .or_default()
+      .push(node.key.to_string());
+    let info = node.val.borrow();
+    for dep in info.deps.keys().chain(&info.test_deps) {
+      edges.push((node.key.to_string(), dep.key.to_string()));
+    }
+  }
+  edges.sort();
+
+  writeln!(output, "digraph deps {{")?;
+  for (i, (root_dir, nodes)) in clusters.iter_mut().enumerate() {
+    nodes.sort();
+    writeln!(output, "  subgraph cluster_{} {{", i)?;
+    writeln!(output, "    label = {};", quote(root_dir))?;
+    for node in nodes {
+      writeln!(output, "    {};", quote(node))?;
+    }
+    writeln!(output, "  }}")?;
+  }
+  for (from, to) in edges {
+    writeln!(output, "  {} -> {};", quote(&from), quote(&to))?;
+  }
+  writeln!(output, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::scan;
+
+  #[test]
+  fn writes_nodes_in_clusters_and_edges() {
+    let map = scan(&[
+      ("folly/Foo.h", "#include <folly/io/Bar.h>\n"),
+      ("folly/io/Bar.h", "#pragma once\n"),
+    ]);
+    let mut out = Vec::new();
+    write_dot(&map, &mut out).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "digraph deps {
+  subgraph cluster_0 {
+    label = \"folly\";
+    \"folly/foo\";
+  }
+  subgraph cluster_1 {
+    label = \"folly/io\";
+    \"folly/io/bar\";
+  }
+  \"folly/foo\" -> \"folly/io/bar\";
+}
+"
+    );
+  }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::types::UnitKey;
+
+#[derive(Debug)]
+pub enum FollyError {
+  FileNotFound(PathBuf),
+  // line is 1-based, or 0 when the parser couldn't say.
+  ParseError {
+    file: PathBuf,
+    line: usize,
+    message: String,
+  },
+  // topo_order found a cycle through this unit; collapse_cycles should have
+  // merged it away first.
+  CycleNotCollapsed(UnitKey),
+  // How many #include cycles between headers --check-header-cycles found,
+  // each of which has already been printed.
+  HeaderCycles(usize),
+  // A header with neither an include guard nor #pragma once, under
+  // --error-on-missing-guard.
+  MissingIncludeGuard(PathBuf),
+  // Something else still holds the unit's value, so it couldn't be updated.
+  UnitBorrowed(UnitKey),
+  // Under --strict, the phantom units that something depends on.
+  PhantomDeps(Vec<UnitKey>),
+  // Asked for by key, e.g. to merge, but not in the graph.
+  UnknownUnit(UnitKey),
+  // Units to be merged, through a cycle or --merge-units, that live in more
+  // than one package. One rule can't take files from another package.
+  CrossPackageMerge(Vec<UnitKey>),
+  IoError(io::Error),
+}
+
+impl FollyError {
+  // For failures opening or reading path. A missing file gets its own
+  // variant; anything else keeps its io::Error, prefixed with the path.
+  pub fn at(path: &Path, e: io::Error) -> Self {
+    match e.kind() {
+      io::ErrorKind::NotFound => FollyError::FileNotFound(path.to_path_buf()),
+      kind => FollyError::IoError(io::Error::new(
+        kind,
+        format!("{}: {}", path.display(), e),
+      )),
+    }
+  }
+}
+
+impl fmt::Display for FollyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      FollyError::FileNotFound(path) => {
+        write!(f, "{}: No such file or directory", path.display())
+      }
+      FollyError::ParseError {
+        file,
+        line,
+        message,
+      } => write!(f, "{}:{}: {}", file.display(), line, message),
+      FollyError::CycleNotCollapsed(key) => {
+        write!(f, "Dependency cycle through {}", key)
+      }
+      FollyError::HeaderCycles(count) => {
+        write!(f, "Found {} #include cycles between headers", count)
+      }
+      FollyError::MissingIncludeGuard(path) => {
+        write!(f, "{}: No include guard or #pragma once", path.display())
+      }
+      FollyError::UnitBorrowed(key) => {
+        write!(f, "Could not update unit {}: already borrowed", key)
+      }
+      FollyError::PhantomDeps(keys) => {
+        let keys: Vec<String> =
+          keys.iter().map(|key| key.to_string()).collect();
+        write!(f, "Deps on units with no files: {}", keys.join(", "))
+      }
+      FollyError::UnknownUnit(key) => write!(f, "No unit {} in the graph", key),
+      FollyError::CrossPackageMerge(keys) => {
+        let keys: Vec<String> =
+          keys.iter().map(|key| key.to_string()).collect();
+        write!(
+          f,
+          "Units in different packages can't share a rule: {}",
+          keys.join(", ")
+        )
+      }
+      FollyError::IoError(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for FollyError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      FollyError::IoError(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for FollyError {
+  fn from(e: io::Error) -> Self {
+    FollyError::IoError(e)
+  }
+}
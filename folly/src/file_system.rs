@@ -0,0 +1,264 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::error::FollyError;
+#[cfg(test)]
+use std::{
+  collections::{HashMap, HashSet},
+  io::Cursor,
+  sync::Mutex,
+  time::{Duration, UNIX_EPOCH},
+};
+
+// Everything scanning and writing BUILD files needs from the disk, so that
+// a tree can be stood in for. Sync so that the parallel walk can share one.
+pub trait FileSystemProvider: Sync {
+  // Paths of the entries directly under path.
+  fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+  fn open_file(&self, path: &Path) -> io::Result<Box<dyn BufRead + '_>>;
+  fn is_dir(&self, path: &Path) -> bool;
+  // Creates any missing parent directories first.
+  fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+  fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+  fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+pub struct RealFileSystem;
+
+impl FileSystemProvider for RealFileSystem {
+  fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::read_dir(path)?
+      .map(|entry| entry.map(|entry| entry.path()))
+      .collect()
+  }
+
+  fn open_file(&self, path: &Path) -> io::Result<Box<dyn BufRead + '_>> {
+    Ok(Box::new(BufReader::new(File::open(path)?)))
+  }
+
+  fn is_dir(&self, path: &Path) -> bool {
+    path.is_dir()
+  }
+
+  fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+    fs::write(path, contents)
+  }
+
+  fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+  }
+
+  fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+    path.canonicalize()
+  }
+}
+
+// Where generated files end up: written through a FileSystemProvider, or
+// for --dry-run listed by path, and under --verbose printed in full.
+pub enum WriteSink<'a, F: FileSystemProvider> {
+  FILES(&'a F),
+  PATHS(Box<dyn Write + 'a>),
+  CONTENTS(Box<dyn Write + 'a>),
+}
+
+impl<'a, F: FileSystemProvider> WriteSink<'a, F> {
+  // A dry run goes to stdout.
+  pub fn new(fs: &'a F, dry_run: bool, verbose: bool) -> Self {
+    match (dry_run, verbose) {
+      (false, _) => WriteSink::FILES(fs),
+      (true, false) => WriteSink::PATHS(Box::new(io::stdout())),
+      (true, true) => WriteSink::CONTENTS(Box::new(io::stdout())),
+    }
+  }
+
+  // Printed files each start with a `# === path ===` line and end with a
+  // blank one.
+  pub fn write(
+    &mut self,
+    path: &Path,
+    contents: &str,
+  ) -> Result<(), FollyError> {
+    let written = match self {
+      WriteSink::FILES(fs) => fs.write_file(path, contents.as_bytes()),
+      WriteSink::PATHS(out) => writeln!(out, "Would write {}", path.display()),
+      WriteSink::CONTENTS(out) => {
+        writeln!(out, "# === {} ===\n{}", path.display(), contents)
+      }
+    };
+    written.map_err(|e| {
+      FollyError::IoError(io::Error::new(
+        e.kind(),
+        format!("Could not write {}: {}", path.display(), e),
+      ))
+    })
+  }
+}
+
+// A tree held in memory, for tests that would otherwise have to write one
+// out to a temporary directory. Paths are taken as given, so use absolute
+// ones. Every file is first written at UNIX_EPOCH, and each rewrite of it
+// lands a second later.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockFileSystem {
+  files: Mutex<HashMap<PathBuf, (Vec<u8>, SystemTime)>>,
+  dirs: Mutex<HashSet<PathBuf>>,
+}
+
+#[cfg(test)]
+impl MockFileSystem {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_file(self, path: &str, contents: &str) -> Self {
+    self
+      .write_file(Path::new(path), contents.as_bytes())
+      .unwrap();
+    self
+  }
+
+  // Directories stay behind, as they would after an rm.
+  pub fn remove_file(&self, path: &Path) {
+    self.files.lock().unwrap().remove(path);
+  }
+
+  fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+      io::ErrorKind::NotFound,
+      format!("{}: not in mock file system", path.display()),
+    )
+  }
+}
+
+#[cfg(test)]
+impl FileSystemProvider for MockFileSystem {
+  // Sorted, unlike the real thing, so that tests see a fixed order.
+  fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !self.is_dir(path) {
+      return Err(Self::not_found(path));
+    }
+    // Locked in the same order as write_file does.
+    let dirs = self.dirs.lock().unwrap();
+    let files = self.files.lock().unwrap();
+    let mut entries: Vec<PathBuf> = files
+      .keys()
+      .chain(dirs.iter())
+      .filter(|entry| entry.parent() == Some(path))
+      .cloned()
+      .collect();
+    entries.sort();
+    Ok(entries)
+  }
+
+  fn open_file(&self, path: &Path) -> io::Result<Box<dyn BufRead + '_>> {
+    match self.files.lock().unwrap().get(path) {
+      Some((contents, _)) => Ok(Box::new(Cursor::new(contents.clone()))),
+      None => Err(Self::not_found(path)),
+    }
+  }
+
+  fn is_dir(&self, path: &Path) -> bool {
+    self.dirs.lock().unwrap().contains(path)
+  }
+
+  fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut dirs = self.dirs.lock().unwrap();
+    for dir in path.ancestors().skip(1) {
+      dirs.insert(dir.to_path_buf());
+    }
+    let mut files = self.files.lock().unwrap();
+    let mtime = files
+      .get(path)
+      .map_or(UNIX_EPOCH, |(_, mtime)| *mtime + Duration::from_secs(1));
+    files.insert(path.to_path_buf(), (contents.to_vec(), mtime));
+    Ok(())
+  }
+
+  fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+    match self.files.lock().unwrap().get(path) {
+      Some((_, mtime)) => Ok(*mtime),
+      None => Err(Self::not_found(path)),
+    }
+  }
+
+  fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+    if self.is_dir(path) || self.files.lock().unwrap().contains_key(path) {
+      Ok(path.to_path_buf())
+    } else {
+      Err(Self::not_found(path))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{dep_keys, keys, scan};
+
+  fn fs() -> MockFileSystem {
+    MockFileSystem::new()
+      .with_file("/src/folly/io/IOBuf.h", "#pragma once\n")
+      .with_file("/src/folly/Range.h", "#pragma once\n")
+  }
+
+  #[test]
+  fn read_dir_lists_files_and_dirs_sorted() {
+    let fs = fs();
+    assert_eq!(
+      fs.read_dir(Path::new("/src/folly")).unwrap(),
+      [
+        PathBuf::from("/src/folly/Range.h"),
+        PathBuf::from("/src/folly/io")
+      ]
+    );
+    assert!(fs.is_dir(Path::new("/src")));
+    assert!(!fs.is_dir(Path::new("/src/folly/Range.h")));
+    let err = fs.read_dir(Path::new("/src/folly/Range.h")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+  }
+
+  #[test]
+  fn files_read_back_what_was_written() {
+    let fs = fs();
+    let mut lines = fs
+      .open_file(Path::new("/src/folly/Range.h"))
+      .unwrap()
+      .lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "#pragma once");
+    assert!(lines.next().is_none());
+    assert_eq!(
+      fs.modified(Path::new("/src/folly/Range.h")).unwrap(),
+      UNIX_EPOCH
+    );
+    fs.write_file(Path::new("/src/folly/Range.h"), b"").unwrap();
+    assert_eq!(
+      fs.modified(Path::new("/src/folly/Range.h")).unwrap(),
+      UNIX_EPOCH + Duration::from_secs(1)
+    );
+    fs.remove_file(Path::new("/src/folly/Range.h"));
+    assert!(fs.open_file(Path::new("/src/folly/Range.h")).is_err());
+    assert!(fs.is_dir(Path::new("/src/folly")));
+    assert!(fs.open_file(Path::new("/src/folly/Missing.h")).is_err());
+    assert!(fs.canonicalize(Path::new("/src/folly/Missing.h")).is_err());
+  }
+
+  #[test]
+  fn scans_a_tree_without_the_disk() {
+    let map = scan(&[
+      (
+        "folly/Range.h",
+        "#pragma once\n#include <folly/io/IOBuf.h>\n",
+      ),
+      ("folly/io/IOBuf.h", "#pragma once\n"),
+      ("folly/io/IOBuf.cpp", "#include <folly/io/IOBuf.h>\n"),
+    ]);
+    assert_eq!(keys(&map), ["folly/range", "folly/io/io_buf"]);
+    assert_eq!(dep_keys(&map, "folly/range"), ["folly/io/io_buf"]);
+  }
+}
@@ -0,0 +1,487 @@
+// Lays out a BUILD file the one way we write them:
+//
+//   cc_library(
+//       name = "Foo",
+//       deps = [
+//           ":Bar",
+//       ],
+//       hdrs = [
+//           "Foo.h",
+//       ],
+//   )
+//
+// i.e. a rule's attributes one per line, name first and the rest sorted,
+// every list one item per line with a trailing comma, and a blank line
+// between top-level statements. Comments may sit on lines of their own at
+// the top level or between attributes, where they stay with whatever
+// follows them, or at the end of a list item's line. Anything else this
+// doesn't understand, such as a comment on a line of its own inside a
+// list, leaves the file as it was.
+
+const INDENT: usize = 4;
+
+#[derive(Clone)]
+enum Token {
+  // An identifier or a number.
+  Word(String),
+  // A string literal as written, quotes and escapes included.
+  Str(String),
+  Punct(char),
+  // On a line of its own.
+  Comment(String),
+  // After something else on the same line.
+  TrailingComment(String),
+}
+
+fn tokenize(content: &str) -> Option<Vec<Token>> {
+  let mut tokens = Vec::new();
+  let mut chars = content.chars().peekable();
+  let mut line_start = true;
+  while let Some(c) = chars.next() {
+    if c.is_whitespace() {
+      line_start |= c == '\n';
+      continue;
+    }
+    let own_line = std::mem::replace(&mut line_start, false);
+    if c == '#' {
+      let mut comment = c.to_string();
+      while let Some(c) = chars.next_if(|c| *c != '\n') {
+        comment.push(c);
+      }
+      let comment = comment.trim_end().to_string();
+      tokens.push(if own_line {
+        Token::Comment(comment)
+      } else {
+        Token::TrailingComment(comment)
+      });
+    } else if c == '"' || c == '\'' {
+      let mut s = c.to_string();
+      loop {
+        let next = chars.next()?;
+        s.push(next);
+        if next == '\\' {
+          s.push(chars.next()?);
+        } else if next == c {
+          break;
+        }
+      }
+      tokens.push(Token::Str(s));
+    } else if c == '_' || c.is_ascii_alphanumeric() {
+      let mut word = c.to_string();
+      while let Some(c) =
+        chars.next_if(|c| *c == '_' || *c == '.' || c.is_ascii_alphanumeric())
+      {
+        word.push(c);
+      }
+      tokens.push(Token::Word(word));
+    } else {
+      tokens.push(Token::Punct(c));
+    }
+  }
+  Some(tokens)
+}
+
+enum Expr {
+  // A string, identifier or number, as written.
+  Atom(String),
+  // Each item with the comment at the end of its line, if any.
+  List(Vec<(Expr, Option<String>)>),
+  Dict(Vec<(Expr, Expr)>),
+  Call(String, Vec<Arg>),
+  // Operands of `+`, e.g. a glob() plus a list.
+  Sum(Vec<Expr>),
+}
+
+struct Arg {
+  // Own-line comments just above the argument.
+  comments: Vec<String>,
+  name: Option<String>,
+  value: Expr,
+}
+
+enum Stmt {
+  Comment(String),
+  Expr(Expr),
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn at_punct(&self, c: char) -> bool {
+    matches!(self.peek(), Some(Token::Punct(p)) if *p == c)
+  }
+
+  fn expect(&mut self, c: char) -> Option<()> {
+    if !self.at_punct(c) {
+      return None;
+    }
+    self.pos += 1;
+    Some(())
+  }
+
+  fn trailing_comment(&mut self) -> Option<String> {
+    match self.peek() {
+      Some(Token::TrailingComment(comment)) => {
+        let comment = comment.clone();
+        self.pos += 1;
+        Some(comment)
+      }
+      _ => None,
+    }
+  }
+
+  // Consumes a `,` unless close comes next; anything else is an error.
+  fn separator(&mut self, close: char) -> Option<()> {
+    if self.at_punct(close) {
+      return Some(());
+    }
+    self.expect(',')
+  }
+
+  fn statements(&mut self) -> Option<Vec<Stmt>> {
+    let mut stmts = Vec::new();
+    while let Some(token) = self.peek() {
+      if let Token::Comment(comment) = token {
+        stmts.push(Stmt::Comment(comment.clone()));
+        self.pos += 1;
+      } else {
+        stmts.push(Stmt::Expr(self.expr(true)?));
+      }
+    }
+    Some(stmts)
+  }
+
+  // Only a top-level call can hold comments, between its arguments.
+  fn expr(&mut self, top_level: bool) -> Option<Expr> {
+    let mut terms = vec![self.primary(top_level)?];
+    while self.at_punct('+') {
+      self.pos += 1;
+      terms.push(self.primary(false)?);
+    }
+    Some(if terms.len() == 1 {
+      terms.pop().unwrap()
+    } else {
+      Expr::Sum(terms)
+    })
+  }
+
+  fn primary(&mut self, top_level: bool) -> Option<Expr> {
+    let token = self.peek()?.clone();
+    self.pos += 1;
+    match token {
+      Token::Str(s) => Some(Expr::Atom(s)),
+      Token::Word(word) => {
+        if self.at_punct('(') {
+          self.pos += 1;
+          Some(Expr::Call(word, self.args(top_level)?))
+        } else {
+          Some(Expr::Atom(word))
+        }
+      }
+      Token::Punct('[') => {
+        let mut items = Vec::new();
+        while !self.at_punct(']') {
+          let item = self.expr(false)?;
+          // The comma may come before the comment or not at all.
+          let mut comment = self.trailing_comment();
+          self.separator(']')?;
+          if comment.is_none() {
+            comment = self.trailing_comment();
+          }
+          items.push((item, comment));
+        }
+        self.pos += 1;
+        Some(Expr::List(items))
+      }
+      Token::Punct('{') => {
+        let mut entries = Vec::new();
+        while !self.at_punct('}') {
+          let key = self.expr(false)?;
+          self.expect(':')?;
+          entries.push((key, self.expr(false)?));
+          self.separator('}')?;
+        }
+        self.pos += 1;
+        Some(Expr::Dict(entries))
+      }
+      Token::Punct('-') => match self.peek()? {
+        Token::Word(word) => {
+          let atom = format!("-{}", word);
+          self.pos += 1;
+          Some(Expr::Atom(atom))
+        }
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+
+  fn args(&mut self, top_level: bool) -> Option<Vec<Arg>> {
+    let mut args = Vec::new();
+    loop {
+      let mut comments = Vec::new();
+      while let Some(Token::Comment(comment)) = self.peek() {
+        if !top_level {
+          return None;
+        }
+        comments.push(comment.clone());
+        self.pos += 1;
+      }
+      if self.at_punct(')') {
+        // A comment after the last argument has nothing left to stay with.
+        if !comments.is_empty() {
+          return None;
+        }
+        self.pos += 1;
+        return Some(args);
+      }
+      let name = match (self.peek(), self.tokens.get(self.pos + 1)) {
+        (Some(Token::Word(name)), Some(Token::Punct('='))) => {
+          let name = name.clone();
+          self.pos += 2;
+          Some(name)
+        }
+        _ => None,
+      };
+      let value = self.expr(false)?;
+      args.push(Arg {
+        comments,
+        name,
+        value,
+      });
+      self.separator(')')?;
+    }
+  }
+}
+
+fn pad(indent: usize) -> String {
+  " ".repeat(indent)
+}
+
+fn format_arg(arg: &Arg, indent: usize) -> String {
+  let value = format_expr(&arg.value, indent);
+  match &arg.name {
+    Some(name) => format!("{} = {}", name, value),
+    None => value,
+  }
+}
+
+// Formats e as if it started on a line indented by indent.
+fn format_expr(e: &Expr, indent: usize) -> String {
+  let block =
+    |open: &str, items: Vec<(String, Option<&String>)>, close: &str| {
+      if items.is_empty() {
+        return format!("{}{}", open, close);
+      }
+      let mut out = format!("{}\n", open);
+      for (item, comment) in items {
+        out += &format!("{}{},", pad(indent + INDENT), item);
+        if let Some(comment) = comment {
+          out += &format!("  {}", comment);
+        }
+        out += "\n";
+      }
+      out + &pad(indent) + close
+    };
+  match e {
+    Expr::Atom(atom) => atom.clone(),
+    Expr::List(items) => block(
+      "[",
+      items
+        .iter()
+        .map(|(item, comment)| {
+          (format_expr(item, indent + INDENT), comment.as_ref())
+        })
+        .collect(),
+      "]",
+    ),
+    Expr::Dict(entries) => block(
+      "{",
+      entries
+        .iter()
+        .map(|(key, value)| {
+          let entry = format!(
+            "{}: {}",
+            format_expr(key, indent + INDENT),
+            format_expr(value, indent + INDENT)
+          );
+          (entry, None)
+        })
+        .collect(),
+      "}",
+    ),
+    Expr::Call(name, args) => {
+      let args: Vec<String> =
+        args.iter().map(|arg| format_arg(arg, indent)).collect();
+      format!("{}({})", name, args.join(", "))
+    }
+    Expr::Sum(terms) => terms
+      .iter()
+      .map(|term| format_expr(term, indent))
+      .collect::<Vec<_>>()
+      .join(" + "),
+  }
+}
+
+// A call made only of named arguments is a rule, and gets one line per
+// attribute. Anything else, such as load(), stays on one line, so None if
+// it has comments that would need lines of their own.
+fn format_stmt(e: &Expr) -> Option<String> {
+  let Expr::Call(kind, args) = e else {
+    return Some(format_expr(e, 0));
+  };
+  if args.is_empty() || args.iter().any(|arg| arg.name.is_none()) {
+    if args.iter().any(|arg| !arg.comments.is_empty()) {
+      return None;
+    }
+    return Some(format_expr(e, 0));
+  }
+  let mut attrs: Vec<&Arg> = args.iter().collect();
+  attrs.sort_by_key(|arg| {
+    let name = arg.name.as_deref().unwrap();
+    (name != "name", name)
+  });
+  let mut out = format!("{}(\n", kind);
+  for attr in attrs {
+    for comment in &attr.comments {
+      out += &format!("{}{}\n", pad(INDENT), comment);
+    }
+    out += &format!("{}{},\n", pad(INDENT), format_arg(attr, INDENT));
+  }
+  Some(out + ")")
+}
+
+pub fn format_build_file(content: &str) -> String {
+  let Some(stmts) =
+    tokenize(content).and_then(|tokens| Parser { tokens, pos: 0 }.statements())
+  else {
+    return content.to_string();
+  };
+  // Comments go in the same block as the statement after them.
+  let mut blocks: Vec<String> = Vec::new();
+  let mut pending = String::new();
+  for stmt in &stmts {
+    match stmt {
+      Stmt::Comment(comment) => pending += &format!("{}\n", comment),
+      Stmt::Expr(e) => {
+        let Some(stmt) = format_stmt(e) else {
+          return content.to_string();
+        };
+        blocks.push(format!("{}{}\n", std::mem::take(&mut pending), stmt));
+      }
+    }
+  }
+  if !pending.is_empty() {
+    blocks.push(pending);
+  }
+  blocks.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn formats_a_messy_build_file() {
+    let messy = r#"load("@rules_cc//cc:defs.bzl",   "cc_library")
+cc_library(visibility=["//visibility:public"],
+  srcs = ["b.cpp","a.cpp"], name="Foo",
+      deps = [":Bar", # forward-decl
+    "//folly/io:IoBuf"],
+)
+# The test.
+cc_test(name = "FooTest", srcs = glob(["*test.cpp"]) + ["extra.cpp"],
+  copts = ["-DX", ], deps = [":Foo"])
+"#;
+    assert_eq!(
+      format_build_file(messy),
+      r#"load("@rules_cc//cc:defs.bzl", "cc_library")
+
+cc_library(
+    name = "Foo",
+    deps = [
+        ":Bar",  # forward-decl
+        "//folly/io:IoBuf",
+    ],
+    srcs = [
+        "b.cpp",
+        "a.cpp",
+    ],
+    visibility = [
+        "//visibility:public",
+    ],
+)
+
+# The test.
+cc_test(
+    name = "FooTest",
+    copts = [
+        "-DX",
+    ],
+    deps = [
+        ":Foo",
+    ],
+    srcs = glob([
+        "*test.cpp",
+    ]) + [
+        "extra.cpp",
+    ],
+)
+"#
+    );
+  }
+
+  #[test]
+  fn formats_selects_and_comments_between_attributes() {
+    let messy = "cc_library(name='Foo',
+    srcs = select({'@platforms//os:linux': ['Linux.cpp'],
+      '//conditions:default': []}),
+    # using-deps: :Impl
+    deps = [':Bar'])";
+    assert_eq!(
+      format_build_file(messy),
+      "cc_library(
+    name = 'Foo',
+    # using-deps: :Impl
+    deps = [
+        ':Bar',
+    ],
+    srcs = select({
+        '@platforms//os:linux': [
+            'Linux.cpp',
+        ],
+        '//conditions:default': [],
+    }),
+)
+"
+    );
+  }
+
+  #[test]
+  fn formatting_is_idempotent() {
+    let once = format_build_file(
+      "cc_library(name = \"A\", hdrs = [\"A.h\"], deps = [\":B\"])\n\
+       cc_library(name = \"B\", hdrs = [\"B.h\"])",
+    );
+    assert_eq!(format_build_file(&once), once);
+  }
+
+  #[test]
+  fn leaves_what_it_cannot_parse_alone() {
+    for content in [
+      "cc_library(name = \"Foo\"",
+      "cc_library(\n    srcs = [\n        # own line\n        \"a.cpp\",\n    ],\n)\n",
+      "cc_library(name = \"Foo\",\n    # after the last argument\n)\n",
+      "x = 1\n",
+    ] {
+      assert_eq!(format_build_file(content), content, "{}", content);
+    }
+  }
+}
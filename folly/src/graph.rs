@@ -0,0 +1,942 @@
+use std::cmp::min;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use crate::_UnitMap;
+use crate::error::FollyError;
+use crate::intrusive_hashmap::{self, HashObj, IntrusiveRefCell, Shared};
+use crate::types::*;
+
+// Nodes are compared by address during traversal, which is cheaper than
+// hashing the key and sidesteps borrowing the RefCell.
+type NodeId = *const IntrusiveRefCell<UnitKey, UnitInfo<UnitKey>>;
+
+#[derive(Default)]
+struct Tarjan {
+  next_index: usize,
+  index: HashMap<NodeId, usize>,
+  low_link: HashMap<NodeId, usize>,
+  stack: Vec<UnitObj>,
+  on_stack: HashSet<NodeId>,
+  components: Vec<Vec<UnitObj>>,
+}
+
+impl Tarjan {
+  fn visit(&mut self, node: &UnitObj) {
+    let id = Shared::as_ptr(node);
+    self.index.insert(id, self.next_index);
+    self.low_link.insert(id, self.next_index);
+    self.next_index += 1;
+    self.stack.push(node.clone());
+    self.on_stack.insert(id);
+
+    // Collect first so the borrow isn't held across the recursion.
+    let deps: Vec<UnitObj> = node.val.borrow().deps.keys().cloned().collect();
+    for dep in deps {
+      let dep_id = Shared::as_ptr(&dep);
+      if !self.index.contains_key(&dep_id) {
+        self.visit(&dep);
+        let low = min(self.low_link[&id], self.low_link[&dep_id]);
+        self.low_link.insert(id, low);
+      } else if self.on_stack.contains(&dep_id) {
+        let low = min(self.low_link[&id], self.index[&dep_id]);
+        self.low_link.insert(id, low);
+      }
+    }
+
+    if self.low_link[&id] == self.index[&id] {
+      let mut component = Vec::new();
+      loop {
+        let member = self.stack.pop().unwrap();
+        let member_id = Shared::as_ptr(&member);
+        self.on_stack.remove(&member_id);
+        component.push(member);
+        if member_id == id {
+          break;
+        }
+      }
+      self.components.push(component);
+    }
+  }
+}
+
+// The map iterates in hash order, which changes from run to run. Traversals
+// start from the nodes in key order instead so that their output doesn't.
+pub fn sorted_nodes(map: &UnitMap) -> Vec<UnitObj> {
+  let mut nodes: Vec<UnitObj> = map.iter().collect();
+  nodes.sort_by(|a, b| a.key.cmp(&b.key));
+  nodes
+}
+
+// Tarjan's algorithm over the deps edges. test_deps belong to a unit's
+// cc_test, which nothing depends on, so they can't close a cycle.
+// Components are returned in reverse topological order: every component
+// comes after the ones it depends on.
+pub fn strongly_connected_components(map: &UnitMap) -> Vec<Vec<UnitObj>> {
+  let mut tarjan = Tarjan::default();
+  for node in sorted_nodes(map) {
+    if !tarjan.index.contains_key(&Shared::as_ptr(&node)) {
+      tarjan.visit(&node);
+    }
+  }
+  tarjan.components
+}
+
+// Johnson's algorithm over nodes numbered in key order. Each pass finds
+// the cycles whose lowest-numbered node is start, within start's component
+// of the nodes numbered start or higher.
+struct Johnson<'a> {
+  deps: &'a [Vec<usize>],
+  start: usize,
+  component: HashSet<usize>,
+  blocked: Vec<bool>,
+  // Nodes to unblock once the node at the same index is.
+  blocked_by: Vec<HashSet<usize>>,
+  path: Vec<usize>,
+  cycles: Vec<Vec<usize>>,
+}
+
+impl Johnson<'_> {
+  fn unblock(&mut self, node: usize) {
+    self.blocked[node] = false;
+    for other in std::mem::take(&mut self.blocked_by[node]) {
+      if self.blocked[other] {
+        self.unblock(other);
+      }
+    }
+  }
+
+  // Whether some cycle through start goes through node, by way of path.
+  fn circuit(&mut self, node: usize) -> bool {
+    let mut found = false;
+    self.path.push(node);
+    self.blocked[node] = true;
+    for &dep in &self.deps[node] {
+      if !self.component.contains(&dep) {
+        continue;
+      }
+      if dep == self.start {
+        self.cycles.push(self.path.clone());
+        found = true;
+      } else if !self.blocked[dep] && self.circuit(dep) {
+        found = true;
+      }
+    }
+    if found {
+      self.unblock(node);
+    } else {
+      for &dep in &self.deps[node] {
+        if self.component.contains(&dep) {
+          self.blocked_by[dep].insert(node);
+        }
+      }
+    }
+    self.path.pop();
+    found
+  }
+}
+
+// Nodes at or above start that start reaches and that reach it back.
+fn component_from(
+  deps: &[Vec<usize>],
+  rdeps: &[Vec<usize>],
+  start: usize,
+) -> HashSet<usize> {
+  let reach = |edges: &[Vec<usize>]| {
+    let mut seen = HashSet::from([start]);
+    let mut stack = vec![start];
+    while let Some(node) = stack.pop() {
+      for &next in &edges[node] {
+        if next >= start && seen.insert(next) {
+          stack.push(next);
+        }
+      }
+    }
+    seen
+  };
+  let forward = reach(deps);
+  reach(rdeps)
+    .into_iter()
+    .filter(|node| forward.contains(node))
+    .collect()
+}
+
+// Every simple cycle in the deps edges, each as its members in key order,
+// without changing the graph. Cycles are sorted, so two that go through
+// the same units in different orders come out next to each other. There
+// can be exponentially many, so this is for looking at a graph rather than
+// for building one; collapse_cycles only needs strongly_connected_components.
+pub fn find_cycles(map: &UnitMap) -> Vec<Vec<UnitKey>> {
+  cycles_over(map, |_| true)
+}
+
+// The cycles find_cycles would report with only INCLUDE edges, which come
+// from headers: those are #include cycles between headers, which no
+// compiler can build, however they are collapsed. A unit's headers count
+// together, so Foo.h and Foo-inl.h are one node.
+pub fn check_header_cycles(map: &UnitMap) -> Vec<Vec<UnitKey>> {
+  cycles_over(map, |kind| kind == UnitDepKind::INCLUDE)
+}
+
+fn cycles_over(
+  map: &UnitMap,
+  follow: impl Fn(UnitDepKind) -> bool,
+) -> Vec<Vec<UnitKey>> {
+  let nodes = sorted_nodes(map);
+  let ids: HashMap<NodeId, usize> = nodes
+    .iter()
+    .enumerate()
+    .map(|(i, node)| (Shared::as_ptr(node), i))
+    .collect();
+  let mut deps = vec![Vec::new(); nodes.len()];
+  let mut rdeps = vec![Vec::new(); nodes.len()];
+  for (i, node) in nodes.iter().enumerate() {
+    for (dep, kind) in &node.val.borrow().deps {
+      if !follow(*kind) {
+        continue;
+      }
+      // Deps are always in the map, but don't count on it.
+      if let Some(&j) = ids.get(&Shared::as_ptr(dep)) {
+        deps[i].push(j);
+        rdeps[j].push(i);
+      }
+    }
+  }
+
+  let mut johnson = Johnson {
+    deps: &deps,
+    start: 0,
+    component: HashSet::new(),
+    blocked: vec![false; nodes.len()],
+    blocked_by: vec![HashSet::new(); nodes.len()],
+    path: Vec::new(),
+    cycles: Vec::new(),
+  };
+  for start in 0..nodes.len() {
+    johnson.start = start;
+    johnson.component = component_from(&deps, &rdeps, start);
+    for &node in &johnson.component {
+      johnson.blocked[node] = false;
+      johnson.blocked_by[node].clear();
+    }
+    johnson.circuit(start);
+  }
+
+  let mut cycles: Vec<Vec<UnitKey>> = johnson
+    .cycles
+    .into_iter()
+    .map(|cycle| {
+      let mut keys: Vec<UnitKey> =
+        cycle.into_iter().map(|i| nodes[i].key.clone()).collect();
+      keys.sort();
+      keys
+    })
+    .collect();
+  cycles.sort();
+  cycles
+}
+
+// Folds b into a, as collapse_cycles does with a cycle, e.g. for a -inl.h
+// that should share its header's target. b's files and edges move to a, an
+// edge between the two is dropped, and b leaves the map. Returns a. Both
+// must be in the same package.
+pub fn merge_units(
+  map: &mut UnitMap,
+  a: &UnitKey,
+  b: &UnitKey,
+) -> Result<UnitObj, FollyError> {
+  let lookup = |key: &UnitKey| {
+    map
+      .get(key)
+      .cloned()
+      .ok_or_else(|| FollyError::UnknownUnit(key.clone()))
+  };
+  let a_node = lookup(a)?;
+  let b_node = lookup(b)?;
+  if a.root_dir != b.root_dir {
+    return Err(FollyError::CrossPackageMerge(vec![a.clone(), b.clone()]));
+  }
+  if a != b {
+    map.merge_into(&a_node, vec![a_node.clone(), b_node]);
+  }
+  Ok(a_node)
+}
+
+// The nodes a node leads to, for BfsIter to follow.
+pub type Edges<K, V> = fn(&HashObj<K, V>) -> Vec<HashObj<K, V>>;
+
+// Nodes in breadth-first order from a root, root first, each once however
+// many paths lead to it. Borrows the map it started from, so that the graph
+// can't change underneath it.
+pub struct BfsIter<'a, K: Hash, V> {
+  queue: VecDeque<(HashObj<K, V>, usize)>,
+  seen: HashSet<HashObj<K, V>>,
+  edges: Edges<K, V>,
+  max_depth: Option<usize>,
+  map: PhantomData<&'a intrusive_hashmap::HashMap<K, V>>,
+}
+
+impl<'a, K: Eq + Hash, V> BfsIter<'a, K, V> {
+  // Empty if root isn't in map.
+  pub fn new(
+    map: &'a intrusive_hashmap::HashMap<K, V>,
+    root: &K,
+    edges: Edges<K, V>,
+  ) -> Self {
+    let start: Vec<HashObj<K, V>> =
+      map.get(root).cloned().into_iter().collect();
+    BfsIter {
+      queue: start.iter().map(|node| (node.clone(), 0)).collect(),
+      seen: start.into_iter().collect(),
+      edges,
+      max_depth: None,
+      map: PhantomData,
+    }
+  }
+
+  // Stops at nodes max_depth edges out from root.
+  pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+    self.max_depth = max_depth;
+    self
+  }
+}
+
+impl<K: Eq + Hash, V> Iterator for BfsIter<'_, K, V> {
+  type Item = HashObj<K, V>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let (node, depth) = self.queue.pop_front()?;
+    if self.max_depth.is_none_or(|max| depth < max) {
+      for next in (self.edges)(&node) {
+        if self.seen.insert(next.clone()) {
+          self.queue.push_back((next, depth + 1));
+        }
+      }
+    }
+    Some(node)
+  }
+}
+
+// Everything iter reaches from root, not counting root itself.
+fn reachable(
+  map: &UnitMap,
+  root: &UnitKey,
+  iter: BfsIter<'_, UnitKey, UnitInfo<UnitKey>>,
+) -> Result<HashSet<UnitKey>, FollyError> {
+  if !map.contains(root) {
+    return Err(FollyError::UnknownUnit(root.clone()));
+  }
+  Ok(iter.skip(1).map(|node| node.key.clone()).collect())
+}
+
+// What root needs to build, as --query-deps prints it. max_depth 1 is just
+// its own deps, test_deps included.
+pub fn transitive_deps(
+  map: &UnitMap,
+  root: &UnitKey,
+  max_depth: Option<usize>,
+) -> Result<HashSet<UnitKey>, FollyError> {
+  reachable(map, root, map.bfs(root).max_depth(max_depth))
+}
+
+// What has to be rebuilt when root changes, as --query-rdeps prints it.
+pub fn transitive_reverse_deps(
+  map: &UnitMap,
+  root: &UnitKey,
+  max_depth: Option<usize>,
+) -> Result<HashSet<UnitKey>, FollyError> {
+  reachable(map, root, map.bfs_reverse(root).max_depth(max_depth))
+}
+
+// Every (unit, dep) edge to a phantom unit, sorted.
+pub fn phantom_deps(map: &UnitMap) -> Vec<(UnitKey, UnitKey)> {
+  let mut edges = Vec::new();
+  for node in sorted_nodes(map) {
+    let info = node.val.borrow();
+    for dep in info.deps.keys().chain(&info.test_deps) {
+      if dep.val.borrow().is_phantom() {
+        edges.push((node.key.clone(), dep.key.clone()));
+      }
+    }
+  }
+  edges.sort();
+  edges.dedup();
+  edges
+}
+
+// Every (unit, dep) pair, sorted, where something outside a test needs a
+// test of dep's: a dep on a unit with nothing but tests, or an #include of
+// one of dep's test files. Such an include most likely pulls test helpers
+// into production code, which only a cc_test builds.
+pub fn check_abi_stability(map: &UnitMap) -> Vec<(UnitKey, UnitKey)> {
+  let mut edges = Vec::new();
+  for node in sorted_nodes(map) {
+    let info = node.val.borrow();
+    for dep in info.deps.keys() {
+      if dep.val.borrow().is_test_only() {
+        edges.push((node.key.clone(), dep.key.clone()));
+      }
+    }
+    for dep in &info.test_includes {
+      edges.push((node.key.clone(), dep.clone()));
+    }
+  }
+  edges.sort();
+  edges.dedup();
+  edges
+}
+
+// For --strict: fails naming every phantom unit something depends on.
+pub fn assert_no_phantom_deps(map: &UnitMap) -> Result<(), FollyError> {
+  let targets: BTreeSet<UnitKey> =
+    phantom_deps(map).into_iter().map(|(_, dep)| dep).collect();
+  if targets.is_empty() {
+    return Ok(());
+  }
+  Err(FollyError::PhantomDeps(targets.into_iter().collect()))
+}
+
+#[derive(PartialEq)]
+enum Mark {
+  InProgress,
+  Done,
+}
+
+fn visit_postorder(
+  node: &UnitObj,
+  marks: &mut HashMap<NodeId, Mark>,
+  order: &mut Vec<UnitObj>,
+) -> Result<(), FollyError> {
+  let id = Shared::as_ptr(node);
+  match marks.get(&id) {
+    Some(Mark::Done) => return Ok(()),
+    Some(Mark::InProgress) => {
+      return Err(FollyError::CycleNotCollapsed(node.key.clone()))
+    }
+    None => {}
+  }
+  marks.insert(id, Mark::InProgress);
+  let deps: Vec<UnitObj> = node.val.borrow().deps.keys().cloned().collect();
+  for dep in deps {
+    visit_postorder(&dep, marks, order)?;
+  }
+  marks.insert(id, Mark::Done);
+  order.push(node.clone());
+  Ok(())
+}
+
+// Every node comes after all of its deps. Fails if the graph still has a
+// cycle, which shouldn't happen once collapse_cycles has run.
+pub fn topo_order(map: &UnitMap) -> Result<Vec<UnitObj>, FollyError> {
+  let mut marks = HashMap::new();
+  let mut order = Vec::with_capacity(map.len());
+  for node in sorted_nodes(map) {
+    visit_postorder(&node, &mut marks, &mut order)?;
+  }
+  Ok(order)
+}
+
+// The layer of the graph every unit sits in: 0 for a unit with no deps,
+// and otherwise one more than its deepest dep, so that a unit can build
+// once everything in lower layers has. Fails as topo_order does on a cycle.
+// That is the longest path down to a leaf, where bfs would find the shortest,
+// so this goes by topo_order instead.
+pub fn topological_levels(
+  map: &UnitMap,
+) -> Result<HashMap<UnitKey, usize>, FollyError> {
+  let mut levels: HashMap<UnitKey, usize> = HashMap::with_capacity(map.len());
+  for node in topo_order(map)? {
+    let level = node
+      .val
+      .borrow()
+      .deps
+      .keys()
+      .filter_map(|dep| levels.get(&dep.key))
+      .map(|level| level + 1)
+      .max()
+      .unwrap_or(0);
+    levels.insert(node.key.clone(), level);
+  }
+  Ok(levels)
+}
+
+// topological_levels sorted by level and then key, which is the order
+// --print-build-order prints.
+pub fn build_levels(
+  map: &UnitMap,
+) -> Result<Vec<(usize, UnitKey)>, FollyError> {
+  let mut levels: Vec<(usize, UnitKey)> = topological_levels(map)?
+    .into_iter()
+    .map(|(key, level)| (level, key))
+    .collect();
+  levels.sort();
+  Ok(levels)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::intrusive_hashmap::WeakHashObj;
+  use crate::test_util::{key, keys, scan, unit};
+  use crate::CompileGraph;
+
+  // a.h includes b.h, and b's test includes a.h back.
+  fn test_cycle() -> UnitMap {
+    scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#pragma once\n"),
+      ("folly/btest.cpp", "#include <folly/a.h>\n"),
+    ])
+  }
+
+  // a.h and b.h include each other.
+  fn header_cycle() -> UnitMap {
+    scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#include <folly/a.h>\n"),
+    ])
+  }
+
+  #[test]
+  fn test_deps_back_into_a_dependent_are_not_a_cycle() {
+    let mut map = test_cycle();
+    let sizes: Vec<usize> = strongly_connected_components(&map)
+      .iter()
+      .map(Vec::len)
+      .collect();
+    assert_eq!(sizes, vec![1, 1]);
+    assert!(find_cycles(&map).is_empty());
+    map.collapse_cycles().unwrap();
+    assert_eq!(keys(&map), vec!["folly/a", "folly/b"]);
+    let b = map.get(&key("folly/b")).unwrap().val.borrow();
+    assert!(b.is_test && b.has_library());
+    assert_eq!(b.test_deps.len(), 1);
+    drop(b);
+    assert!(topo_order(&map).is_ok());
+  }
+
+  #[test]
+  fn header_cycle_is_one_component_and_collapses() {
+    let mut map = header_cycle();
+    assert_eq!(
+      find_cycles(&map),
+      vec![vec![key("folly/a"), key("folly/b")]]
+    );
+    assert_eq!(check_header_cycles(&map).len(), 1);
+    map.collapse_cycles().unwrap();
+    assert_eq!(keys(&map), vec!["folly/a"]);
+    let a = map.get(&key("folly/a")).unwrap().val.borrow();
+    assert!(a.deps.is_empty() && a.test_deps.is_empty());
+    drop(a);
+    assert!(map.generate_compilation_trie().is_ok());
+  }
+
+  // a includes b, c and d; b and c include d; d includes e.
+  fn branching() -> UnitMap {
+    scan(&[
+      (
+        "folly/a.h",
+        "#include <folly/b.h>\n#include <folly/c.h>\n#include <folly/d.h>\n",
+      ),
+      ("folly/b.h", "#include <folly/d.h>\n"),
+      ("folly/c.h", "#include <folly/d.h>\n"),
+      ("folly/d.h", "#include <folly/e.h>\n"),
+      ("folly/e.h", "#pragma once\n"),
+    ])
+  }
+
+  fn names(iter: BfsIter<'_, UnitKey, UnitInfo<UnitKey>>) -> Vec<String> {
+    iter.map(|node| node.key.name.clone()).collect()
+  }
+
+  #[test]
+  fn bfs_visits_each_node_once_by_distance() {
+    let map = branching();
+    assert_eq!(names(map.bfs(&key("folly/a"))), ["a", "b", "c", "d", "e"]);
+    assert_eq!(
+      names(map.bfs(&key("folly/a")).max_depth(Some(1))),
+      ["a", "b", "c", "d"]
+    );
+    assert_eq!(names(map.bfs(&key("folly/e"))), ["e"]);
+    assert!(names(map.bfs(&key("folly/missing"))).is_empty());
+  }
+
+  #[test]
+  fn bfs_reverse_follows_reverse_deps() {
+    let map = branching();
+    assert_eq!(
+      names(map.bfs_reverse(&key("folly/e"))),
+      ["e", "d", "a", "b", "c"]
+    );
+    let rdeps = transitive_reverse_deps(&map, &key("folly/d"), None).unwrap();
+    assert_eq!(
+      rdeps,
+      HashSet::from([key("folly/a"), key("folly/b"), key("folly/c")])
+    );
+  }
+
+  // a reaches d in one step, but builds only after b and c, which need it.
+  #[test]
+  fn levels_go_by_longest_path() {
+    let levels = topological_levels(&branching()).unwrap();
+    let level = |name: &str| levels[&key(&format!("folly/{}", name))];
+    assert_eq!(
+      [level("a"), level("b"), level("c"), level("d"), level("e")],
+      [3, 2, 2, 1, 0]
+    );
+  }
+
+  // Two leaves, two units on top of them and a unit at the top whose test
+  // reaches the middle, which doesn't lift the unit's own level.
+  fn three_tiers() -> UnitMap {
+    scan(&[
+      ("folly/Base.h", "#pragma once\n"),
+      ("folly/Bits.h", "#pragma once\n"),
+      ("folly/Fmt.h", "#include <folly/Base.h>\n"),
+      (
+        "folly/Range.h",
+        "#include <folly/Base.h>\n#include <folly/Bits.h>\n",
+      ),
+      ("folly/Top.h", "#include <folly/Bits.h>\n"),
+      ("folly/Toptest.cpp", "#include <folly/Range.h>\n"),
+    ])
+  }
+
+  #[test]
+  fn levels_on_three_tiers() {
+    let levels = topological_levels(&three_tiers()).unwrap();
+    assert_eq!(levels.len(), 5);
+    for (path, level) in [
+      ("folly/base", 0),
+      ("folly/bits", 0),
+      ("folly/fmt", 1),
+      ("folly/range", 1),
+      ("folly/top", 1),
+    ] {
+      assert_eq!(levels[&key(path)], level, "{}", path);
+    }
+  }
+
+  #[test]
+  fn build_levels_sort_by_level_then_key() {
+    let order: Vec<(usize, String)> = build_levels(&three_tiers())
+      .unwrap()
+      .into_iter()
+      .map(|(level, key)| (level, key.name))
+      .collect();
+    assert_eq!(
+      order,
+      [
+        (0, "base".to_string()),
+        (0, "bits".to_string()),
+        (1, "fmt".to_string()),
+        (1, "range".to_string()),
+        (1, "top".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn levels_fail_on_a_cycle() {
+    let err = topological_levels(&header_cycle()).err().unwrap();
+    assert!(matches!(err, FollyError::CycleNotCollapsed(_)), "{}", err);
+  }
+
+  #[test]
+  fn topo_order_puts_deps_first() {
+    let map = branching();
+    let order: Vec<UnitKey> = topo_order(&map)
+      .unwrap()
+      .iter()
+      .map(|node| node.key.clone())
+      .collect();
+    assert_eq!(order.len(), 5);
+    let position = |key: &UnitKey| order.iter().position(|k| k == key).unwrap();
+    for node in map.iter() {
+      for dep in node.val.borrow().deps.keys() {
+        assert!(position(&dep.key) < position(&node.key), "{:?}", order);
+      }
+    }
+  }
+
+  #[test]
+  fn topo_order_fails_on_a_cycle() {
+    let err = topo_order(&header_cycle()).err().unwrap();
+    assert!(matches!(err, FollyError::CycleNotCollapsed(_)), "{}", err);
+  }
+
+  fn cycle_names(cycles: Vec<Vec<UnitKey>>) -> Vec<String> {
+    cycles
+      .iter()
+      .map(|cycle| {
+        let names: Vec<&str> =
+          cycle.iter().map(|key| key.name.as_str()).collect();
+        names.join(" ")
+      })
+      .collect()
+  }
+
+  #[test]
+  fn find_cycles_on_an_acyclic_graph_is_empty() {
+    assert!(find_cycles(&branching()).is_empty());
+    assert!(find_cycles(&UnitMap::new()).is_empty());
+  }
+
+  #[test]
+  fn find_cycles_finds_one_cycle_without_changing_the_graph() {
+    let map = scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#include <folly/c.h>\n"),
+      ("folly/c.h", "#include <folly/a.h>\n#include <folly/d.h>\n"),
+      ("folly/d.h", "#pragma once\n"),
+    ]);
+    assert_eq!(cycle_names(find_cycles(&map)), ["a b c"]);
+    assert_eq!(keys(&map), ["folly/a", "folly/b", "folly/c", "folly/d"]);
+    assert_eq!(map.get(&key("folly/c")).unwrap().val.borrow().deps.len(), 2);
+  }
+
+  #[test]
+  fn find_cycles_finds_every_simple_cycle() {
+    // Two separate cycles, and a third unit on two cycles through b.
+    let map = scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#include <folly/a.h>\n#include <folly/c.h>\n"),
+      ("folly/c.h", "#include <folly/a.h>\n#include <folly/b.h>\n"),
+      ("folly/x.h", "#include <folly/y.h>\n"),
+      ("folly/y.h", "#include <folly/x.h>\n"),
+    ]);
+    assert_eq!(
+      cycle_names(find_cycles(&map)),
+      ["a b", "a b c", "b c", "x y"]
+    );
+  }
+
+  #[test]
+  fn merge_units_repoints_b_dependents_to_a() {
+    let mut map = scan(&[
+      ("folly/Baz.h", "#pragma once\n"),
+      ("folly/Foo.h", "#include <folly/FooImpl.h>\n"),
+      ("folly/FooImpl.cpp", "#include <folly/FooImpl.h>\n"),
+      ("folly/FooImpl.h", "#include <folly/Baz.h>\n"),
+      ("folly/Qux.h", "#include <folly/FooImpl.h>\n"),
+    ]);
+    let foo =
+      merge_units(&mut map, &key("folly/foo"), &key("folly/foo_impl")).unwrap();
+    assert_eq!(foo.key, key("folly/foo"));
+    assert_eq!(keys(&map), ["folly/baz", "folly/foo", "folly/qux"]);
+
+    let info = foo.val.borrow();
+    let mut headers = info.headers.clone();
+    headers.sort();
+    assert_eq!(headers, ["Foo.h", "FooImpl.h"]);
+    assert_eq!(info.srcs, ["FooImpl.cpp"]);
+    let deps: Vec<UnitKey> =
+      info.deps.keys().map(|dep| dep.key.clone()).collect();
+    assert_eq!(deps, [key("folly/baz")]);
+
+    let qux = map.get(&key("folly/qux")).unwrap().val.borrow();
+    let (dep, _) = qux.deps.iter().next().unwrap();
+    assert!(Shared::ptr_eq(dep, &foo));
+    drop(qux);
+    let rdeps: Vec<UnitKey> = info
+      .reverse_deps
+      .iter()
+      .filter_map(WeakHashObj::upgrade)
+      .map(|rdep| rdep.key.clone())
+      .collect();
+    assert_eq!(rdeps, [key("folly/qux")]);
+    let baz = map.get(&key("folly/baz")).unwrap().val.borrow();
+    let baz_rdeps: Vec<UnitObj> = baz
+      .reverse_deps
+      .iter()
+      .filter_map(WeakHashObj::upgrade)
+      .collect();
+    assert_eq!(baz_rdeps.len(), 1);
+    assert!(Shared::ptr_eq(&baz_rdeps[0], &foo));
+  }
+
+  #[test]
+  fn merge_units_fails_on_an_unknown_unit() {
+    let mut map = scan(&[("folly/Foo.h", "#pragma once\n")]);
+    let err =
+      merge_units(&mut map, &key("folly/foo"), &key("folly/nope")).unwrap_err();
+    assert!(matches!(err, FollyError::UnknownUnit(key) if key.name == "nope"));
+    assert_eq!(keys(&map), ["folly/foo"]);
+  }
+
+  #[test]
+  fn merge_units_fails_across_packages() {
+    let mut map = scan(&[
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/io/Foo.h", "#pragma once\n"),
+    ]);
+    let err = merge_units(&mut map, &key("folly/foo"), &key("folly/io/foo"))
+      .unwrap_err();
+    assert!(matches!(err, FollyError::CrossPackageMerge(_)), "{}", err);
+    assert_eq!(keys(&map), ["folly/foo", "folly/io/foo"]);
+  }
+
+  #[test]
+  fn check_header_cycles_ignores_cycles_through_sources() {
+    let map = scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#include <folly/a.h>\n"),
+      // c's header includes d, and only d's source includes c back.
+      ("folly/c.h", "#include <folly/d.h>\n"),
+      ("folly/d.cpp", "#include <folly/c.h>\n"),
+      ("folly/d.h", "#pragma once\n"),
+    ]);
+    assert_eq!(cycle_names(check_header_cycles(&map)), ["a b"]);
+    assert_eq!(cycle_names(find_cycles(&map)), ["a b", "c d"]);
+  }
+
+  fn key_set(paths: &[&str]) -> HashSet<UnitKey> {
+    paths.iter().map(|path| key(path)).collect()
+  }
+
+  #[test]
+  fn transitive_deps_is_the_closure_over_deps() {
+    let map = branching();
+    assert_eq!(
+      transitive_deps(&map, &key("folly/a"), None).unwrap(),
+      key_set(&["folly/b", "folly/c", "folly/d", "folly/e"])
+    );
+    assert_eq!(
+      transitive_deps(&map, &key("folly/b"), None).unwrap(),
+      key_set(&["folly/d", "folly/e"])
+    );
+    assert!(transitive_deps(&map, &key("folly/e"), None)
+      .unwrap()
+      .is_empty());
+  }
+
+  #[test]
+  fn transitive_queries_stop_at_max_depth() {
+    let map = branching();
+    assert_eq!(
+      transitive_deps(&map, &key("folly/b"), Some(1)).unwrap(),
+      key_set(&["folly/d"])
+    );
+    assert!(transitive_deps(&map, &key("folly/a"), Some(0))
+      .unwrap()
+      .is_empty());
+    assert_eq!(
+      transitive_reverse_deps(&map, &key("folly/e"), Some(2)).unwrap(),
+      key_set(&["folly/a", "folly/b", "folly/c", "folly/d"])
+    );
+    assert_eq!(
+      transitive_reverse_deps(&map, &key("folly/e"), Some(1)).unwrap(),
+      key_set(&["folly/d"])
+    );
+  }
+
+  #[test]
+  fn transitive_queries_follow_test_deps_and_reject_unknown_units() {
+    let map = test_cycle();
+    assert_eq!(
+      transitive_deps(&map, &key("folly/b"), None).unwrap(),
+      key_set(&["folly/a"])
+    );
+    assert!(matches!(
+      transitive_deps(&map, &key("folly/nope"), None),
+      Err(FollyError::UnknownUnit(_))
+    ));
+    assert!(transitive_reverse_deps(&map, &key("folly/nope"), None).is_err());
+  }
+
+  // Nothing provides folly/Gone.h or folly/Missing.h, so the scan makes
+  // units with no files for them.
+  fn with_phantoms() -> UnitMap {
+    scan(&[
+      (
+        "folly/Bar.h",
+        "#include <folly/Foo.h>\n#include <folly/Missing.h>\n",
+      ),
+      ("folly/Foo.h", "#include <folly/Missing.h>\n"),
+      ("folly/Footest.cpp", "#include <folly/Gone.h>\n"),
+    ])
+  }
+
+  #[test]
+  fn phantom_deps_finds_every_edge_to_a_unit_with_no_files() {
+    let map = with_phantoms();
+    let missing = map.get(&key("folly/missing")).unwrap();
+    assert!(missing.val.borrow().is_phantom());
+    assert!(!map
+      .get(&key("folly/foo"))
+      .unwrap()
+      .val
+      .borrow()
+      .is_phantom());
+
+    let edges: Vec<(String, String)> = phantom_deps(&map)
+      .into_iter()
+      .map(|(unit, dep)| (unit.name, dep.name))
+      .collect();
+    assert_eq!(
+      edges,
+      [
+        ("bar".to_string(), "missing".to_string()),
+        ("foo".to_string(), "gone".to_string()),
+        ("foo".to_string(), "missing".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn assert_no_phantom_deps_names_each_target_once() {
+    let Err(err) = assert_no_phantom_deps(&with_phantoms()) else {
+      panic!("expected the phantom deps to be caught");
+    };
+    match &err {
+      FollyError::PhantomDeps(targets) => {
+        assert_eq!(targets, &[key("folly/gone"), key("folly/missing")])
+      }
+      _ => panic!("{}", err),
+    }
+    assert_eq!(
+      err.to_string(),
+      "Deps on units with no files: folly/gone, folly/missing"
+    );
+
+    let map = scan(&[
+      ("folly/Bar.h", "#include <folly/Foo.h>\n"),
+      ("folly/Foo.h", "#pragma once\n"),
+    ]);
+    assert!(phantom_deps(&map).is_empty());
+    assert!(assert_no_phantom_deps(&map).is_ok());
+  }
+
+  #[test]
+  fn check_abi_stability_flags_libraries_that_need_tests() {
+    let map = scan(&[
+      ("folly/Bad.cpp", "#include <folly/Helpertest.cpp>\n"),
+      ("folly/Helper.h", "#pragma once\n"),
+      ("folly/Helpertest.cpp", "#include <folly/Helper.h>\n"),
+      (
+        "folly/Lib.h",
+        "#include <folly/Helper.h>\n#include <folly/Mock.h>\n",
+      ),
+      ("folly/Mocktest.cpp", "#pragma once\n"),
+      ("folly/Other.h", "#pragma once\n"),
+      (
+        "folly/Othertest.cpp",
+        "#include <folly/Helpertest.cpp>\n#include <folly/Mock.h>\n",
+      ),
+    ]);
+    // Helper has tests, but its library is no test. Mock has nothing else.
+    assert!(unit(&map, "folly/helper").val.borrow().has_library());
+    assert!(unit(&map, "folly/mock").val.borrow().is_test_only());
+    // The test file is no unit of its own.
+    assert!(map.get(&key("folly/helpertest.cpp")).is_none());
+    // Other's own test may use both.
+    assert_eq!(
+      check_abi_stability(&map),
+      [
+        (key("folly/bad"), key("folly/helper")),
+        (key("folly/lib"), key("folly/mock")),
+      ]
+    );
+  }
+}
@@ -1,8 +1,114 @@
 use std::borrow::Borrow;
+#[cfg(not(feature = "threaded"))]
 use std::cell::RefCell;
 use std::collections::HashSet;
+use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::rc::Rc;
+#[cfg(not(feature = "threaded"))]
+use std::rc::{Rc, Weak};
+#[cfg(feature = "threaded")]
+use std::sync::{
+  Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError, Weak,
+};
+#[cfg(feature = "threaded")]
+use std::thread::{self, ThreadId};
+
+// The threaded feature swaps the pointer and cell types for their
+// thread-safe counterparts, making HashObj Send + Sync. Everything else
+// goes through these aliases so that call sites don't need to care.
+#[cfg(not(feature = "threaded"))]
+pub type Shared<T> = Rc<T>;
+#[cfg(feature = "threaded")]
+pub type Shared<T> = Arc<T>;
+
+pub type WeakShared<T> = Weak<T>;
+
+#[cfg(not(feature = "threaded"))]
+pub type ValCell<V> = RefCell<V>;
+#[cfg(not(feature = "threaded"))]
+pub use std::cell::BorrowMutError;
+// What ValCell::borrow_mut hands out.
+#[cfg(not(feature = "threaded"))]
+pub type ValRefMut<'a, V> = std::cell::RefMut<'a, V>;
+#[cfg(feature = "threaded")]
+pub type ValRefMut<'a, V> = RwLockWriteGuard<'a, V>;
+
+#[cfg(feature = "threaded")]
+#[derive(Debug)]
+pub struct BorrowMutError;
+
+#[cfg(feature = "threaded")]
+impl std::fmt::Display for BorrowMutError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "already borrowed")
+  }
+}
+
+#[cfg(feature = "threaded")]
+impl std::error::Error for BorrowMutError {}
+
+// Mirrors the RefCell borrow API on top of an RwLock. A poisoned lock means
+// another thread panicked mid-update, which we treat as fatal. The second
+// field is the thread inside with_mut, if any.
+#[cfg(feature = "threaded")]
+#[derive(Default)]
+pub struct ValCell<V>(RwLock<V>, Mutex<Option<ThreadId>>);
+
+#[cfg(feature = "threaded")]
+impl<V> ValCell<V> {
+  pub fn new(val: V) -> Self {
+    ValCell(RwLock::new(val), Mutex::new(None))
+  }
+
+  pub fn borrow(&self) -> RwLockReadGuard<'_, V> {
+    self.0.read().unwrap()
+  }
+
+  pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, V> {
+    self.0.write().unwrap()
+  }
+
+  // Fails while anyone holds the lock for writing, this thread or another:
+  // an RwLock can't tell the two apart.
+  pub fn try_borrow(&self) -> Result<RwLockReadGuard<'_, V>, BorrowMutError> {
+    match self.0.try_read() {
+      Ok(val) => Ok(val),
+      Err(TryLockError::WouldBlock) => Err(BorrowMutError),
+      Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+    }
+  }
+
+  // Likewise while anyone holds it at all.
+  pub fn try_borrow_mut(
+    &self,
+  ) -> Result<RwLockWriteGuard<'_, V>, BorrowMutError> {
+    match self.0.try_write() {
+      Ok(val) => Ok(val),
+      Err(TryLockError::WouldBlock) => Err(BorrowMutError),
+      Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+    }
+  }
+
+  // Runs f on the value under the write lock. Another thread's borrow is
+  // soon over, so that is waited out, but one this thread holds through
+  // with_mut never would be, and fails instead. A borrow_mut guard of this
+  // thread's still deadlocks, since the lock can't say whose it is.
+  pub fn with_mut<R>(
+    &self,
+    f: impl FnOnce(&mut V) -> R,
+  ) -> Result<R, BorrowMutError> {
+    let this = thread::current().id();
+    let mut val = match self.try_borrow_mut() {
+      Ok(val) => val,
+      Err(e) if *self.1.lock().unwrap() == Some(this) => return Err(e),
+      Err(_) => self.borrow_mut(),
+    };
+    *self.1.lock().unwrap() = Some(this);
+    let result = f(&mut val);
+    *self.1.lock().unwrap() = None;
+    Ok(result)
+  }
+}
 
 pub trait MutateExtract<K, V> {
   fn extract_with_create(&mut self, key: K) -> V;
@@ -12,22 +118,135 @@ impl<K: Eq + Hash + PartialEq, V: Default> MutateExtract<K, HashObj<K, V>>
   for HashMap<K, V>
 {
   fn extract_with_create(&mut self, key: K) -> HashObj<K, V> {
-    if self.contains(&key) {
-      self.get(&key).unwrap().0.clone()
-    } else {
-      let val = Rc::new(IntrusiveRefCell::from(key));
-      self.insert(HashWrap(val.clone()));
-      val
+    self.get_or_insert_with(key, V::default)
+  }
+}
+
+// Lets several threads populate one map: lookups only need the read guard,
+// and the write guard is taken just long enough to insert a missing key.
+#[cfg(feature = "threaded")]
+impl<K: Eq + Hash + PartialEq, V: Default> MutateExtract<K, HashObj<K, V>>
+  for &RwLock<HashMap<K, V>>
+{
+  fn extract_with_create(&mut self, key: K) -> HashObj<K, V> {
+    if let Some(val) = self.read().unwrap().get(&key) {
+      return val.clone();
     }
+    // Someone else may have inserted the key between dropping the read guard
+    // and acquiring the write guard, so check again.
+    self.write().unwrap().get_or_insert_with(key, V::default)
   }
 }
 
 // Potentially not the best way to work around needing
 // mutable references to two values at once.
-pub type HashObj<K, V> = Rc<IntrusiveRefCell<K, V>>;
-pub type HashMap<K, V> = HashSet<HashWrap<K, V>>;
+pub type HashObj<K, V> = Shared<IntrusiveRefCell<K, V>>;
+
+// A set of objects looked up by their own keys. Only the map-like half of
+// HashSet is exposed, so that every entry goes in through insert.
+pub struct HashMap<K, V>(HashSet<HashWrap<K, V>>);
 
-// IntrusiveRefCell encapsulates a key and a RefCell<value>.
+impl<K, V> Default for HashMap<K, V> {
+  fn default() -> Self {
+    HashMap(HashSet::new())
+  }
+}
+
+impl<K: Eq + Hash, V> HashMap<K, V> {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  // deps and reverse_deps go by pointer, so a second object under a key
+  // that is already taken would silently split that key in two. Returns
+  // false, leaving the map as it was, if that's what this would do.
+  pub fn insert(&mut self, obj: HashObj<K, V>) -> bool {
+    if self.contains(&obj.key) {
+      debug_assert!(
+        self
+          .get(&obj.key)
+          .is_some_and(|old| Shared::ptr_eq(old, &obj)),
+        "two objects under one key"
+      );
+      return false;
+    }
+    self.0.insert(HashWrap(obj))
+  }
+
+  pub fn get(&self, key: &K) -> Option<&HashObj<K, V>> {
+    self.0.get(key).map(HashWrap::as_inner)
+  }
+
+  // The object under key, made from f() first if there isn't one yet. f
+  // isn't called for a key that is already taken.
+  pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> HashObj<K, V>
+  where
+    F: FnOnce() -> V,
+  {
+    if let Some(val) = self.get(&key) {
+      return val.clone();
+    }
+    let val = Shared::new(IntrusiveRefCell::new(key, f()));
+    self.insert(val.clone());
+    val
+  }
+
+  // Returns the stored pointer itself rather than a fresh one, so it still
+  // compares equal to whatever deps/reverse_deps sets are holding.
+  pub fn remove(&mut self, key: &K) -> Option<HashObj<K, V>> {
+    self.0.take(key).map(HashWrap::into_inner)
+  }
+
+  pub fn contains(&self, key: &K) -> bool {
+    self.0.contains(key)
+  }
+
+  // Rebuilds the set with room for exactly the entries left, giving back
+  // what removals freed. The objects themselves are moved, not copied, so
+  // edges to them stay valid.
+  pub fn compact(&mut self) {
+    let old = std::mem::take(&mut self.0);
+    let mut set = HashSet::with_capacity(old.len());
+    set.extend(old);
+    self.0 = set;
+  }
+}
+
+impl<K, V> HashMap<K, V> {
+  // Hands out owned pointers, which callers need anyway to follow edges.
+  pub fn iter(&self) -> impl Iterator<Item = HashObj<K, V>> + '_ {
+    self.0.iter().map(|wrap| wrap.as_inner().clone())
+  }
+
+  // Each value borrowed mutably in turn, for passes that update every unit
+  // in place. Mutability comes from the cells, so the map itself is only
+  // borrowed; each borrow has to end before the next value's begins.
+  pub fn values_mut(&self) -> impl Iterator<Item = ValRefMut<'_, V>> + '_ {
+    self.0.iter().map(|wrap| wrap.as_inner().val.borrow_mut())
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  // Drops every entry f returns false for. Edges to a dropped entry are left
+  // for the caller to unlink.
+  pub fn retain<F>(&mut self, mut f: F)
+  where
+    F: FnMut(&K, &ValCell<V>) -> bool,
+  {
+    self.0.retain(|wrap| {
+      let obj = wrap.as_inner();
+      f(&obj.key, &obj.val)
+    });
+  }
+}
+
+// IntrusiveRefCell encapsulates a key and a ValCell<value>.
 // It is meant to be the (key, value) pair in an intrusive hashmap, so it implements PartialEq, Eq,
 // and Hash by leveragint the trait implementations of the key.
 //
@@ -35,7 +254,51 @@ pub type HashMap<K, V> = HashSet<HashWrap<K, V>>;
 #[derive(Default)]
 pub struct IntrusiveRefCell<K, V> {
   pub key: K,
-  pub val: RefCell<V>,
+  pub val: ValCell<V>,
+}
+
+impl<K, V> IntrusiveRefCell<K, V> {
+  pub fn new(key: K, val: V) -> Self {
+    IntrusiveRefCell {
+      key,
+      val: ValCell::new(val),
+    }
+  }
+
+  // Like val.borrow_mut(), but an outstanding borrow is reported instead of
+  // panicking. Under threaded the borrow may be another thread's, soon to
+  // end, which ValCell::with_mut waits for; only this thread's own fails.
+  pub fn with_val_mut<F, R>(&self, f: F) -> Result<R, BorrowMutError>
+  where
+    F: FnOnce(&mut V) -> R,
+  {
+    #[cfg(not(feature = "threaded"))]
+    let result = self.val.try_borrow_mut().map(|mut val| f(&mut val));
+    #[cfg(feature = "threaded")]
+    let result = self.val.with_mut(f);
+    result
+  }
+}
+
+// A one-line description of a value that doesn't follow its links to other
+// nodes, for use where the full value could recurse through the graph.
+pub trait DebugSummary {
+  fn debug_summary(&self) -> String;
+}
+
+// Deriving Debug would walk val, and through it every node reachable from
+// this one, which never ends on a cyclic graph. A value that is mutably
+// borrowed at the time is reported as such rather than panicking.
+impl<K: fmt::Debug, V: DebugSummary> fmt::Debug for IntrusiveRefCell<K, V> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut node = f.debug_struct("IntrusiveRefCell");
+    node.field("key", &self.key);
+    match self.val.try_borrow() {
+      Ok(val) => node.field("val", &format_args!("{}", val.debug_summary())),
+      Err(_) => node.field("val", &format_args!("<borrowed>")),
+    };
+    node.finish()
+  }
 }
 
 impl<K: PartialEq, V> PartialEq for IntrusiveRefCell<K, V> {
@@ -61,14 +324,67 @@ impl<K, V: Default> From<K> for IntrusiveRefCell<K, V> {
   }
 }
 
+// Non-owning counterpart to HashObj, for back edges that would otherwise
+// keep both ends of every edge alive forever.
+//
+// Hash and Eq go by address rather than by upgrading and comparing keys: a
+// key-based hash would change once the target is dropped, which would
+// corrupt any set holding it. Since the map holds one object per key, the
+// two notions of identity agree for live entries.
+pub struct WeakHashObj<K, V>(WeakShared<IntrusiveRefCell<K, V>>);
+
+impl<K, V> WeakHashObj<K, V> {
+  pub fn upgrade(&self) -> Option<HashObj<K, V>> {
+    self.0.upgrade()
+  }
+}
+
+// Derive would want K: Clone and V: Clone, which a pointer doesn't need.
+impl<K, V> Clone for WeakHashObj<K, V> {
+  fn clone(&self) -> Self {
+    WeakHashObj(self.0.clone())
+  }
+}
+
+impl<K, V> From<&HashObj<K, V>> for WeakHashObj<K, V> {
+  fn from(item: &HashObj<K, V>) -> Self {
+    WeakHashObj(Shared::downgrade(item))
+  }
+}
+
+impl<K, V> Hash for WeakHashObj<K, V> {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.0.as_ptr().hash(state);
+  }
+}
+
+impl<K, V> PartialEq for WeakHashObj<K, V> {
+  fn eq(&self, other: &Self) -> bool {
+    self.0.ptr_eq(&other.0)
+  }
+}
+
+impl<K, V> Eq for WeakHashObj<K, V> {}
+
 // Sad! Borrow trait not transitive. We wouldn't need this if:
-// Rc<T>: Borrow<T> && T: Borrow<T'> => Rc<T>: Borrow<T'>
+// Shared<T>: Borrow<T> && T: Borrow<T'> => Shared<T>: Borrow<T'>
 // This is also the reason HashObj uses the newtype pattern
 // and not the alias. Very unfortunate.
-pub struct HashWrap<K, V>(HashObj<K, V>);
+struct HashWrap<K, V>(HashObj<K, V>);
+
+impl<K, V> HashWrap<K, V> {
+  pub fn into_inner(self) -> HashObj<K, V> {
+    self.0
+  }
+
+  pub fn as_inner(&self) -> &HashObj<K, V> {
+    &self.0
+  }
+}
+
 impl<K, V> Borrow<K> for HashWrap<K, V> {
   fn borrow(&self) -> &K {
-    &self.0.key
+    &self.as_inner().key
   }
 }
 
@@ -86,8 +402,186 @@ impl<K: PartialEq, V> PartialEq for HashWrap<K, V> {
 
 impl<K: Eq, V> Eq for HashWrap<K, V> {}
 
-impl<K, V> From<HashObj<K, V>> for HashWrap<K, V> {
-  fn from(item: HashObj<K, V>) -> Self {
-    HashWrap(item)
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn try_borrow_mut_fails_while_borrowed() {
+    let cell = IntrusiveRefCell::new("a", 1);
+    let val = cell.val.borrow();
+    assert!(cell.val.try_borrow_mut().is_err());
+    drop(val);
+    let mut val = cell.val.try_borrow_mut().unwrap();
+    *val += 1;
+    assert!(cell.val.try_borrow().is_err());
+    assert!(cell.val.try_borrow_mut().is_err());
+    drop(val);
+    assert_eq!(*cell.val.try_borrow().unwrap(), 2);
+  }
+
+  #[test]
+  fn with_val_mut_updates_in_place() {
+    let cell = IntrusiveRefCell::new("a", vec![1]);
+    assert_eq!(cell.with_val_mut(|val| val.push(2)).ok(), Some(()));
+    assert_eq!(*cell.val.borrow(), vec![1, 2]);
+  }
+
+  #[test]
+  fn with_val_mut_reports_reentrant_borrow() {
+    let cell = IntrusiveRefCell::new("a", 1);
+    let nested = cell.with_val_mut(|_| cell.with_val_mut(|val| *val += 1));
+    assert!(matches!(nested, Ok(Err(_))));
+    assert_eq!(*cell.val.borrow(), 1);
+    // The cell is free again afterwards.
+    assert!(cell.with_val_mut(|val| *val += 1).is_ok());
+    assert_eq!(*cell.val.borrow(), 2);
+  }
+
+  #[cfg(feature = "threaded")]
+  #[test]
+  fn with_val_mut_waits_for_another_threads_borrow() {
+    let cell = IntrusiveRefCell::new("a", 1);
+    thread::scope(|scope| {
+      let held = cell.val.borrow_mut();
+      let other = scope.spawn(|| cell.with_val_mut(|val| *val += 1));
+      thread::sleep(std::time::Duration::from_millis(10));
+      drop(held);
+      assert!(other.join().unwrap().is_ok());
+    });
+    assert_eq!(*cell.val.borrow(), 2);
+  }
+
+  #[test]
+  fn iter_yields_every_entry() {
+    let mut map: HashMap<&str, i32> = HashMap::new();
+    assert!(map.is_empty());
+    for (key, val) in [("a", 1), ("b", 2), ("c", 3)] {
+      assert!(map.insert(Shared::new(IntrusiveRefCell::new(key, val))));
+    }
+    assert_eq!(map.len(), 3);
+    assert!(!map.is_empty());
+    let mut entries: Vec<(&str, i32)> =
+      map.iter().map(|obj| (obj.key, *obj.val.borrow())).collect();
+    entries.sort();
+    assert_eq!(entries, [("a", 1), ("b", 2), ("c", 3)]);
+    // Pointers into the map, not copies.
+    let a = map.iter().find(|obj| obj.key == "a").unwrap();
+    assert!(Shared::ptr_eq(&a, map.get(&"a").unwrap()));
+  }
+
+  #[test]
+  fn retain_keeps_only_what_f_accepts() {
+    let mut map: HashMap<&str, i32> = HashMap::new();
+    for (key, val) in [("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 5)] {
+      map.insert(Shared::new(IntrusiveRefCell::new(key, val)));
+    }
+    let kept = map.get(&"b").unwrap().clone();
+    map.retain(|key, val| *key != "c" && *val.borrow() % 2 == 0);
+    assert_eq!(map.len(), 2);
+    let mut keys: Vec<&str> = map.iter().map(|obj| obj.key).collect();
+    keys.sort();
+    assert_eq!(keys, ["b", "d"]);
+    assert!(Shared::ptr_eq(&kept, map.get(&"b").unwrap()));
+    assert!(map.get(&"a").is_none());
+
+    map.retain(|_, _| false);
+    assert!(map.is_empty());
+  }
+
+  #[test]
+  fn hash_wrap_gives_back_the_same_node() {
+    let obj = Shared::new(IntrusiveRefCell::new("a", 1));
+    let wrap = HashWrap(obj.clone());
+    assert!(Shared::ptr_eq(wrap.as_inner(), &obj));
+    let inner = wrap.into_inner();
+    assert!(Shared::ptr_eq(&inner, &obj));
+    assert_eq!(Shared::strong_count(&obj), 2);
+
+    let mut map: HashMap<&str, i32> = HashMap::new();
+    map.insert(obj.clone());
+    let removed = map.remove(&"a").unwrap();
+    assert!(Shared::ptr_eq(&removed, &obj));
+    assert!(map.remove(&"a").is_none());
+  }
+
+  #[test]
+  fn get_or_insert_with_runs_the_initializer_once() {
+    let mut map: HashMap<&str, Vec<&str>> = HashMap::new();
+    let foo = map.get_or_insert_with("foo", || vec!["Foo.h", "Foo-inl.h"]);
+    assert_eq!(foo.key, "foo");
+    assert_eq!(*foo.val.borrow(), ["Foo.h", "Foo-inl.h"]);
+    assert!(Shared::ptr_eq(&foo, map.get(&"foo").unwrap()));
+
+    let again = map.get_or_insert_with("foo", || panic!("foo is taken"));
+    assert!(Shared::ptr_eq(&again, &foo));
+    assert_eq!(map.len(), 1);
+  }
+
+  #[test]
+  fn extract_with_create_starts_from_default() {
+    let mut map: HashMap<&str, Vec<&str>> = HashMap::new();
+    let bar = map.get_or_insert_with("bar", || vec!["Bar.h"]);
+    let baz = map.extract_with_create("baz");
+    assert!(baz.val.borrow().is_empty());
+    assert!(Shared::ptr_eq(&map.extract_with_create("bar"), &bar));
+    assert_eq!(*bar.val.borrow(), ["Bar.h"]);
+    assert_eq!(map.len(), 2);
+  }
+
+  #[cfg(feature = "threaded")]
+  #[test]
+  fn extract_with_create_through_a_lock_shares_one_node() {
+    let lock = RwLock::new(HashMap::<&str, i32>::new());
+    let first = (&lock).extract_with_create("a");
+    first.with_val_mut(|val| *val = 7).unwrap();
+    let second = (&lock).extract_with_create("a");
+    assert!(Shared::ptr_eq(&first, &second));
+    assert_eq!(*second.val.borrow(), 7);
+    assert_eq!(lock.read().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn values_mut_updates_persist() {
+    let mut map: HashMap<&str, Vec<i32>> = HashMap::new();
+    let a = map.get_or_insert_with("a", || vec![1]);
+    map.get_or_insert_with("b", || vec![2]);
+    for mut val in map.values_mut() {
+      let doubled = val[0] * 2;
+      val.push(doubled);
+    }
+    assert_eq!(*a.val.borrow(), [1, 2]);
+    assert_eq!(*map.get(&"b").unwrap().val.borrow(), [2, 4]);
+    assert_eq!(map.values_mut().count(), 2);
+  }
+
+  #[test]
+  fn compact_keeps_the_survivors_and_frees_the_rest() {
+    let mut map: HashMap<i32, i32> = HashMap::new();
+    let nodes: Vec<HashObj<i32, i32>> = (0..100)
+      .map(|key| map.get_or_insert_with(key, || key * 10))
+      .collect();
+    for key in (0..100).step_by(2) {
+      assert!(map.remove(&key).is_some());
+    }
+    let before = map.0.capacity();
+    map.compact();
+    assert_eq!(map.len(), 50);
+    assert!(map.0.capacity() < before);
+    assert!(map.0.capacity() >= 50);
+    for node in &nodes {
+      match map.get(&node.key) {
+        Some(kept) => {
+          assert!(node.key % 2 == 1);
+          assert!(Shared::ptr_eq(kept, node));
+          assert_eq!(*kept.val.borrow(), node.key * 10);
+        }
+        None => assert!(node.key % 2 == 0),
+      }
+    }
+
+    map.retain(|_, _| false);
+    map.compact();
+    assert!(map.is_empty());
   }
 }
@@ -0,0 +1,89 @@
+use std::io::{Error, Write};
+
+use serde::Serialize;
+
+use crate::graph::sorted_nodes;
+use crate::types::{UnitKey, UnitMap};
+
+// One unit of --json-output. Unlike the --incremental cache, this is meant
+// for other tools to read, so it leaves out anything only a rescan needs.
+#[derive(Serialize)]
+struct JsonUnit {
+  name: String,
+  root_dir: String,
+  headers: Vec<String>,
+  srcs: Vec<String>,
+  test_srcs: Vec<String>,
+  deps: Vec<UnitKey>,
+  test_deps: Vec<UnitKey>,
+}
+
+// Writes the graph as an array of units sorted by key, each with its deps
+// as {name, root_dir} objects in the order the BUILD files list them.
+pub fn write_json(map: &UnitMap, output: &mut dyn Write) -> Result<(), Error> {
+  let units: Vec<JsonUnit> = sorted_nodes(map)
+    .into_iter()
+    .map(|node| {
+      let info = node.val.borrow();
+      JsonUnit {
+        name: node.key.name.clone(),
+        root_dir: node.key.root_dir.clone(),
+        headers: info.headers.clone(),
+        srcs: info.srcs.clone(),
+        test_srcs: info.test_srcs.clone(),
+        deps: info.deps.keys().map(|dep| dep.key.clone()).collect(),
+        test_deps: info.test_deps.iter().map(|dep| dep.key.clone()).collect(),
+      }
+    })
+    .collect();
+  serde_json::to_writer_pretty(&mut *output, &units)?;
+  writeln!(output)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::scan;
+
+  #[test]
+  fn parses_back_with_every_edge() {
+    let map = scan(&[
+      ("folly/Bar.h", "#include <folly/io/Baz.h>\n"),
+      ("folly/Foo.cpp", "#include <folly/Foo.h>\n"),
+      (
+        "folly/Foo.h",
+        "#include <folly/Bar.h>\n#include <folly/io/Baz.h>\n",
+      ),
+      ("folly/Footest.cpp", "#include <folly/Qux.h>\n"),
+      ("folly/Qux.h", "#pragma once\n"),
+      ("folly/io/Baz.h", "#pragma once\n"),
+    ]);
+    let mut out = Vec::new();
+    write_json(&map, &mut out).unwrap();
+    let units: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    let units = units.as_array().unwrap();
+    assert_eq!(units.len(), map.len());
+
+    let edges = |attr: &str| -> usize {
+      units
+        .iter()
+        .map(|unit| unit[attr].as_array().unwrap().len())
+        .sum()
+    };
+    assert_eq!(edges("deps"), 3);
+    assert_eq!(edges("test_deps"), 1);
+
+    let foo = units.iter().find(|unit| unit["name"] == "foo").unwrap();
+    assert_eq!(foo["root_dir"], "folly");
+    assert_eq!(foo["headers"], serde_json::json!(["Foo.h"]));
+    assert_eq!(foo["srcs"], serde_json::json!(["Foo.cpp"]));
+    assert_eq!(foo["test_srcs"], serde_json::json!(["Footest.cpp"]));
+    assert_eq!(
+      foo["deps"],
+      serde_json::json!([
+        {"name": "bar", "root_dir": "folly"},
+        {"name": "baz", "root_dir": "folly/io"},
+      ])
+    );
+  }
+}
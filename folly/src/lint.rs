@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::error::FollyError;
+use crate::file_system::FileSystemProvider;
+use crate::types::{UnitKey, UnitMap};
+
+// More deps than this usually means a layer is missing in between.
+pub const MAX_DEPS: usize = 50;
+
+pub struct LintWarning {
+  pub key: UnitKey,
+  pub message: String,
+}
+
+impl fmt::Display for LintWarning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.key, self.message)
+  }
+}
+
+// What CompileGraph::validate can find, one variant per check.
+pub enum ValidationWarning {
+  // unit depends on dep, which has no files.
+  PhantomDep { unit: UnitKey, dep: UnitKey },
+  // unit isn't a test, but needs one of dep's tests, as
+  // check_abi_stability finds them.
+  TestDep { unit: UnitKey, dep: UnitKey },
+  // An #include cycle between headers, as check_header_cycles finds them.
+  HeaderCycle(Vec<UnitKey>),
+  // A test with nothing to compile.
+  TestWithoutSources(UnitKey),
+  EmptyName(UnitKey),
+}
+
+impl fmt::Display for ValidationWarning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ValidationWarning::PhantomDep { unit, dep } => {
+        write!(f, "{} depends on {}, which has no files", unit, dep)
+      }
+      ValidationWarning::TestDep { unit, dep } => {
+        write!(f, "{} is not a test, but depends on {}'s tests", unit, dep)
+      }
+      ValidationWarning::HeaderCycle(cycle) => {
+        let members: Vec<String> =
+          cycle.iter().map(|key| key.to_string()).collect();
+        write!(f, "Header include cycle: {}", members.join(", "))
+      }
+      ValidationWarning::TestWithoutSources(key) => {
+        write!(f, "{}: test has no source files", key)
+      }
+      ValidationWarning::EmptyName(key) => {
+        write!(f, "{}: unit has an empty name", key)
+      }
+    }
+  }
+}
+
+// Flags units that are likely too coarse a target for Bazel to rebuild
+// incrementally: more than max_srcs sources, or more than MAX_DEPS deps,
+// counting external ones. Meant for the graph once cycles are collapsed,
+// since that is what becomes the rules. Sorted by key.
+pub fn lint_targets(map: &UnitMap, max_srcs: usize) -> Vec<LintWarning> {
+  let mut warnings = Vec::new();
+  for node in map.iter() {
+    let info = node.val.borrow();
+    if info.srcs.len() > max_srcs {
+      warnings.push(LintWarning {
+        key: node.key.clone(),
+        message: format!(
+          "{} source files, more than {}; consider splitting it",
+          info.srcs.len(),
+          max_srcs
+        ),
+      });
+    }
+    let deps = info.deps.len() + info.external_deps.len();
+    if deps > MAX_DEPS {
+      warnings.push(LintWarning {
+        key: node.key.clone(),
+        message: format!(
+          "{} deps, more than {}; it may be missing a layer in between",
+          deps, MAX_DEPS
+        ),
+      });
+    }
+  }
+  // Stable, so a unit's source warning stays ahead of its deps one.
+  warnings.sort_by(|a, b| a.key.cmp(&b.key));
+  warnings
+}
+
+fn read_lines<F: FileSystemProvider>(
+  fs: &F,
+  path: &Path,
+) -> Result<Vec<String>, FollyError> {
+  let file = fs.open_file(path).map_err(|e| FollyError::at(path, e))?;
+  file
+    .lines()
+    .map(|line| {
+      let line = line.map_err(|e| FollyError::at(path, e))?;
+      Ok(match line.find("//") {
+        Some(i) => line[..i].to_string(),
+        None => line,
+      })
+    })
+    .collect()
+}
+
+fn identifiers(line: &str) -> impl Iterator<Item = &str> {
+  line
+    .split(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+    .filter(|token| {
+      token.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic())
+    })
+}
+
+// Names that a header line defines a class or struct by. Forward
+// declarations and template parameters don't count, and neither does
+// `enum class`, whose enumerators would usually be qualified anyway.
+fn defined_types(line: &str) -> Vec<String> {
+  let is_ident = |c: char| c == '_' || c.is_ascii_alphanumeric();
+  let mut types = Vec::new();
+  let mut prev = "";
+  let mut rest = line;
+  while let Some(start) = rest.find(|c: char| is_ident(c)) {
+    let tail = &rest[start..];
+    let end = tail.find(|c: char| !is_ident(c)).unwrap_or(tail.len());
+    let word = &tail[..end];
+    rest = &tail[end..];
+    if matches!(word, "class" | "struct") && prev != "enum" {
+      let after = rest.trim_start();
+      let len = after.find(|c: char| !is_ident(c)).unwrap_or(after.len());
+      let (name, follow) = after.split_at(len);
+      let follow = follow.trim_start();
+      if !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && (follow.is_empty()
+          || follow.starts_with('{')
+          || follow.starts_with(':') && !follow.starts_with("::")
+          || follow.starts_with("final"))
+      {
+        types.push(name.to_string());
+      }
+    }
+    prev = word;
+  }
+  types
+}
+
+// Warns about source files that use a type defined in some other unit's
+// headers without depending on that unit. Only goes by tokens: a type
+// counts as used wherever its name appears, and a name that more than one
+// unit defines is skipped as ambiguous. Keys are relative to base, as when
+// the map was built.
+pub fn check_missing_deps<F: FileSystemProvider>(
+  fs: &F,
+  map: &UnitMap,
+  base: &Path,
+) -> Result<Vec<LintWarning>, FollyError> {
+  let mut types: BTreeMap<String, UnitKey> = BTreeMap::new();
+  let mut ambiguous: BTreeSet<String> = BTreeSet::new();
+  for node in map.iter() {
+    let dir = base.join(&node.key.root_dir);
+    for header in &node.val.borrow().headers {
+      for line in read_lines(fs, &dir.join(header))? {
+        for name in defined_types(&line) {
+          match types.get(&name) {
+            Some(key) if *key != node.key => {
+              ambiguous.insert(name);
+            }
+            Some(_) => {}
+            None => {
+              types.insert(name, node.key.clone());
+            }
+          }
+        }
+      }
+    }
+  }
+  for name in &ambiguous {
+    types.remove(name);
+  }
+
+  let mut warnings = Vec::new();
+  for node in map.iter() {
+    let info = node.val.borrow();
+    let dir = base.join(&node.key.root_dir);
+    let mut missing: BTreeMap<&UnitKey, BTreeSet<&str>> = BTreeMap::new();
+    for src in info.srcs.iter().chain(&info.test_srcs) {
+      for line in read_lines(fs, &dir.join(src))? {
+        for token in identifiers(&line) {
+          let Some((name, key)) = types.get_key_value(token) else {
+            continue;
+          };
+          let deps = info.deps.keys().chain(&info.test_deps);
+          if *key != node.key && !deps.into_iter().any(|dep| dep.key == *key) {
+            missing.entry(key).or_default().insert(name);
+          }
+        }
+      }
+    }
+    for (key, names) in missing {
+      warnings.push(LintWarning {
+        key: node.key.clone(),
+        message: format!(
+          "uses {} from {} without depending on it",
+          names.into_iter().collect::<Vec<_>>().join(", "),
+          key
+        ),
+      });
+    }
+  }
+  warnings.sort_by(|a, b| a.key.cmp(&b.key));
+  Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{mock_fs, scan, unit, BASE};
+
+  #[test]
+  fn defined_types_skips_declarations_and_enums() {
+    assert_eq!(defined_types("class Foo {"), ["Foo"]);
+    assert_eq!(defined_types("struct Foo"), ["Foo"]);
+    assert_eq!(defined_types("struct Foo : public Base {"), ["Foo"]);
+    assert_eq!(defined_types("class Foo final : Base {"), ["Foo"]);
+    assert_eq!(defined_types("class A {}; struct B {};"), ["A", "B"]);
+    assert!(defined_types("class Foo;").is_empty());
+    assert!(defined_types("enum class Color {").is_empty());
+    assert!(defined_types("enum struct Color : int {").is_empty());
+    // Defines a member of Foo, rather than Foo itself.
+    assert!(defined_types("class Foo::Impl {").is_empty());
+    assert!(defined_types("template <class T, class 2U>").is_empty());
+  }
+
+  #[test]
+  fn lint_targets_flags_units_over_either_threshold() {
+    let mut files = vec![
+      ("folly/Small.h".to_string(), "#pragma once\n".to_string()),
+      ("folly/Hub.h".to_string(), "#pragma once\n".to_string()),
+      ("folly/Big.h".to_string(), "#pragma once\n".to_string()),
+    ];
+    for i in 0..=MAX_DEPS {
+      let dep = format!("Dep{}.h", i);
+      let include = format!("#include <folly/{}>\n", dep);
+      // Hub.h is at the limit, and Big.h one over it.
+      if i < MAX_DEPS {
+        files[1].1 += &include;
+      }
+      files[2].1 += &include;
+      files.push((format!("folly/{}", dep), "#pragma once\n".to_string()));
+    }
+    let files: Vec<(&str, &str)> = files
+      .iter()
+      .map(|(p, c)| (p.as_str(), c.as_str()))
+      .collect();
+    let map = scan(&files);
+    unit(&map, "folly/small").val.borrow_mut().srcs =
+      vec!["A.cpp".to_string(), "B.cpp".to_string()];
+    unit(&map, "folly/hub").val.borrow_mut().srcs = vec!["A.cpp".to_string()];
+
+    let warnings: Vec<String> = lint_targets(&map, 1)
+      .iter()
+      .map(|warning| warning.to_string())
+      .collect();
+    assert_eq!(
+      warnings,
+      [
+        "folly/big: 51 deps, more than 50; it may be missing a layer in between",
+        "folly/small: 2 source files, more than 1; consider splitting it",
+      ]
+    );
+    assert_eq!(lint_targets(&map, 2).len(), 1);
+  }
+
+  #[test]
+  fn check_missing_deps_skips_names_defined_twice() {
+    let files = [
+      ("folly/A.h", "#pragma once\nclass Widget {};\n"),
+      ("folly/B.h", "#pragma once\nclass Widget {};\n"),
+      ("folly/C.h", "#pragma once\nstruct Gadget {\n};\n"),
+      ("folly/D.h", "#pragma once\nclass Gizmo;\n"),
+      (
+        "folly/Use.cpp",
+        "#include <folly/C.h>\nWidget w; Gizmo *g; // Gadget\n",
+      ),
+      ("folly/Other.cpp", "Gadget g; Widget w;\n"),
+    ];
+    let map = scan(&files);
+    let warnings: Vec<String> =
+      check_missing_deps(&mock_fs(&files), &map, Path::new(BASE))
+        .unwrap()
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect();
+    // Use depends on C, Widget is ambiguous and Gizmo only declared.
+    assert_eq!(
+      warnings,
+      ["folly/other: uses Gadget from folly/c without depending on it"]
+    );
+  }
+}
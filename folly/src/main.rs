@@ -1,69 +1,710 @@
+#![allow(clippy::upper_case_acronyms)]
+// Every key in the intrusive hashmap carries a RefCell; hashing only ever
+// looks at the immutable key half, so this lint is a false positive here.
+#![allow(clippy::mutable_key_type)]
+
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind};
-use std::path::Path;
+use std::io::{BufRead, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[cfg(not(feature = "config"))]
+use clap::Parser;
 
-use crate::intrusive_hashmap::MutateExtract;
+use crate::build_file::{render_build_file, BuildOptions};
+use crate::builder::GraphBuilder;
+use crate::cli::Args;
+use crate::cmake::OutputFormat;
+use crate::error::FollyError;
+use crate::file_system::{FileSystemProvider, RealFileSystem, WriteSink};
+use crate::graph::{strongly_connected_components, BfsIter};
+use crate::intrusive_hashmap::{MutateExtract, Shared, WeakHashObj};
+use crate::lint::ValidationWarning;
+use crate::profiler::Profiler;
+use crate::starlark_parser::parse_build_file;
 use crate::types::*;
 use crate::util::*;
+use crate::warnings::WARNINGS;
 
 pub use crate::util::FileType;
 
+#[cfg(feature = "json")]
+mod bazel_query_format;
+mod build_file;
+mod builder;
+#[cfg(feature = "serde")]
+mod cache;
+mod cli;
+mod cmake;
+#[cfg(feature = "json")]
+mod compile_commands;
+#[cfg(feature = "config")]
+mod config;
+#[cfg(feature = "serde")]
+mod diff;
+mod dot;
+mod error;
+mod file_system;
+mod formatter;
+mod graph;
 mod intrusive_hashmap;
+#[cfg(feature = "json")]
+mod json;
+mod lint;
+mod meson;
+mod preproc;
+mod profiler;
+#[cfg(feature = "serde")]
+mod serialization;
+mod starlark_parser;
+mod stats;
+#[cfg(test)]
+mod test_util;
 mod types;
 mod util;
+mod verify;
+mod warnings;
+#[cfg(feature = "watch")]
+mod watch;
+mod workspace_parser;
 
+// Everything that touches the disk goes through an F, so that a tree held
+// in memory can stand in for the real one.
 trait CompileTrie {
-  fn write_build_files(&self) -> Result<(), Error>;
+  fn write_build_files<F: FileSystemProvider>(
+    &self,
+    sink: &mut WriteSink<F>,
+    opts: &BuildOptions,
+  ) -> Result<(), FollyError>;
+  // Compares the BUILD files already in the output directory against the
+  // ones we would write, returning every discrepancy found.
+  fn verify_build_files<F: FileSystemProvider>(
+    &self,
+    fs: &F,
+    opts: &BuildOptions,
+  ) -> Result<Vec<String>, FollyError>;
 }
 
+// Called after each file a scan has processed, with how many it has done so
+// far, this one included.
+pub type ProgressCallback = fn(files_processed: usize, current_file: &Path);
+
 trait CompileGraph<T: CompileTrie> {
-  fn add_initial_subtree(&mut self, file_path: &Path) -> Result<(), Error>;
-  fn collapse_cycles(&mut self) -> Result<(), Error>;
-  fn generate_compilation_trie(&mut self) -> Result<T, Error>;
+  fn add_initial_subtree<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError>;
+  // add_initial_subtree, reporting progress to cb if there is one.
+  fn add_initial_subtree_with_progress<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+    cb: Option<ProgressCallback>,
+  ) -> Result<(), FollyError>;
+  // Re-parses only the units with a file that was added, removed or
+  // modified since the map was built. Used with a map loaded from a cache.
+  #[cfg(feature = "serde")]
+  fn rescan_changed<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError>;
+  // Same as add_initial_subtree, but walks and parses files concurrently.
+  #[cfg(feature = "parallel")]
+  fn add_initial_subtree_parallel<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError>;
+  // Adds the one file at file_path, which must be under root, without
+  // walking anything else. root is what add_initial_subtree would have been
+  // given, and names units the same way.
+  fn add_file<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    root: &Path,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError>;
+  fn collapse_cycles(&mut self) -> Result<(), FollyError>;
+  fn generate_compilation_trie(&mut self) -> Result<T, FollyError>;
+  fn topo_order(&self) -> Result<Vec<UnitObj>, FollyError>;
+  // Every correctness check in turn, collecting what they find instead of
+  // stopping at the first. Meant for the graph as scanned, before cycles
+  // are collapsed.
+  fn validate(&self) -> Result<Vec<ValidationWarning>, FollyError>;
 }
 
-trait _UnitMap {
-  fn add_dependency_edges(
+// Only needs to look up nodes, so it works both on a UnitMap and on one
+// shared between threads behind an RwLock.
+trait _ParseFile {
+  // kind is what the file's includes count as: INCLUDE or SOURCE_INCLUDE.
+  fn add_dependency_edges<F: FileSystemProvider>(
     &mut self,
+    fs: &F,
     file_path: &Path,
     curr_node: UnitObj,
-  ) -> Result<(), Error>;
-  fn add_node(&mut self, file_path: &Path) -> Result<(), Error>;
+    kind: UnitDepKind,
+    test_only: bool,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError>;
+  fn add_node<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    base: &Path,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError>;
+  fn add_seed_nodes<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    root_dir: &str,
+    file_path: &Path,
+  ) -> Result<(), FollyError>;
+}
+
+trait _UnitMap {
+  fn add_subtree<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    base: &Path,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError>;
+  fn merge_component(&mut self, component: Vec<UnitObj>);
+  // merge_component, but into rep, which must be one of component. The
+  // members must all be in rep's package.
+  fn merge_into(&mut self, rep: &UnitObj, component: Vec<UnitObj>);
+  fn prune_unreachable(&mut self);
+  fn compute_reverse_dep_counts(&mut self);
+  fn infer_visibility(&mut self);
+  fn resolve_using_hints(&mut self);
+  fn add_inferred_deps(&mut self);
+  // Everything root reaches through deps and test_deps, breadth first and
+  // root first.
+  fn bfs(&self, root: &UnitKey) -> BfsIter<'_, UnitKey, UnitInfo<UnitKey>>;
+  // Likewise through reverse_deps, for everything that reaches root.
+  fn bfs_reverse(
+    &self,
+    root: &UnitKey,
+  ) -> BfsIter<'_, UnitKey, UnitInfo<UnitKey>>;
+}
+
+fn units_by_name(
+  map: &UnitMap,
+) -> std::collections::HashMap<String, Vec<UnitKey>> {
+  let mut by_name: std::collections::HashMap<String, Vec<UnitKey>> =
+    std::collections::HashMap::new();
+  for node in map.iter() {
+    by_name
+      .entry(node.key.name.clone())
+      .or_default()
+      .push(node.key.clone());
+  }
+  by_name
+}
+
+// A unit named in a hint is the one of that name in from's package if
+// there is one, or else the only one of that name anywhere.
+fn resolve_hint(
+  map: &UnitMap,
+  by_name: &std::collections::HashMap<String, Vec<UnitKey>>,
+  from: &UnitKey,
+  name: &str,
+) -> Option<UnitKey> {
+  let same_package = UnitKey::new(name, from.root_dir.clone());
+  if map.contains(&same_package) {
+    return Some(same_package);
+  }
+  match by_name.get(name).map(Vec::as_slice) {
+    Some([only]) => Some(only.clone()),
+    _ => None,
+  }
+}
+
+// Whether to can be reached from from by following deps.
+fn reaches(from: &UnitObj, to: &UnitObj) -> bool {
+  let mut seen: HashSet<UnitKey> = HashSet::new();
+  let mut stack = vec![from.clone()];
+  while let Some(node) = stack.pop() {
+    if Shared::ptr_eq(&node, to) {
+      return true;
+    }
+    if seen.insert(node.key.clone()) {
+      stack.extend(node.val.borrow().deps.keys().cloned());
+    }
+  }
+  false
+}
+
+fn with_unit_mut<F, R>(node: &UnitObj, f: F) -> Result<R, FollyError>
+where
+  F: FnOnce(&mut UnitInfo<UnitKey>) -> R,
+{
+  node
+    .with_val_mut(f)
+    .map_err(|_| FollyError::UnitBorrowed(node.key.clone()))
+}
+
+// Keys are relative to base so that they line up with the paths used in
+// #include directives.
+fn file_unit_key(
+  base: &Path,
+  file_path: &Path,
+) -> Result<(UnitKey, String, FileType), FollyError> {
+  let key =
+    UnitKey::from_path(file_path.strip_prefix(base).unwrap_or(file_path))?;
+  // from_path has already turned away paths without a usable file name.
+  let file_name = file_path.file_name().unwrap().to_str().unwrap();
+  let (_, file_type) = strip_file_name(file_name)?;
+  Ok((key, file_name.to_string(), file_type))
+}
+
+fn is_excluded<F: FileSystemProvider>(
+  fs: &F,
+  child: &Path,
+  opts: &ScanOptions,
+) -> bool {
+  fs.is_dir(child)
+    && child
+      .file_name()
+      .and_then(|name| name.to_str())
+      .is_some_and(|name| opts.exclude_dirs.contains(name))
+}
+
+// depth counts directories below where the walk started.
+fn too_deep(dir: &Path, depth: usize, opts: &ScanOptions) -> bool {
+  match opts.max_depth {
+    Some(max_depth) if depth > max_depth => {
+      WARNINGS.warn(format_args!(
+        "not descending into {}, deeper than {}",
+        dir.display(),
+        max_depth
+      ));
+      true
+    }
+    _ => false,
+  }
+}
+
+// Calls visit on every file under file_path, skipping excluded directories.
+// Keeps its own stack rather than recursing, so that no tree is too deep to
+// walk; max_depth is only there for trees that are too deep to want to.
+fn walk_files<F: FileSystemProvider>(
+  fs: &F,
+  file_path: &Path,
+  opts: &ScanOptions,
+  visit: &mut dyn FnMut(&Path) -> Result<(), FollyError>,
+) -> Result<(), FollyError> {
+  let mut stack = vec![(file_path.to_path_buf(), 0)];
+  while let Some((path, depth)) = stack.pop() {
+    if !fs.is_dir(&path) {
+      visit(&path)?;
+      continue;
+    }
+    if too_deep(&path, depth, opts) {
+      continue;
+    }
+    let mut children = Vec::new();
+    for child in fs.read_dir(&path).map_err(|e| FollyError::at(&path, e))? {
+      if !is_excluded(fs, &child, opts) {
+        children.push((child, depth + 1));
+      }
+    }
+    // Reversed so that entries come off the stack in read_dir order, the
+    // same order a recursive walk would visit them in.
+    stack.extend(children.into_iter().rev());
+  }
+  Ok(())
+}
+
+// walk_files, but sibling entries are visited concurrently on rayon's pool.
+// This one still recurses, since each directory is its own rayon task.
+#[cfg(feature = "parallel")]
+fn par_walk_files<F: FileSystemProvider>(
+  fs: &F,
+  file_path: &Path,
+  depth: usize,
+  opts: &ScanOptions,
+  visit: &(dyn Fn(&Path) -> Result<(), FollyError> + Sync),
+) -> Result<(), FollyError> {
+  use rayon::prelude::*;
+
+  if fs.is_dir(file_path) {
+    if too_deep(file_path, depth, opts) {
+      return Ok(());
+    }
+    let children = fs
+      .read_dir(file_path)
+      .map_err(|e| FollyError::at(file_path, e))?;
+    children.par_iter().try_for_each(|child| {
+      if is_excluded(fs, child, opts) {
+        Ok(())
+      } else {
+        par_walk_files(fs, child, depth + 1, opts, visit)
+      }
+    })
+  } else {
+    visit(file_path)
+  }
+}
+
+fn file_defines_main<F: FileSystemProvider>(
+  fs: &F,
+  file_path: &Path,
+) -> Result<bool, FollyError> {
+  let file = fs
+    .open_file(file_path)
+    .map_err(|e| FollyError::at(file_path, e))?;
+  for line in file.lines() {
+    if defines_main(&line.map_err(|e| FollyError::at(file_path, e))?) {
+      return Ok(true);
+    }
+  }
+  Ok(false)
+}
+
+fn file_has_include_guard<F: FileSystemProvider>(
+  fs: &F,
+  file_path: &Path,
+) -> Result<bool, FollyError> {
+  let file = fs
+    .open_file(file_path)
+    .map_err(|e| FollyError::at(file_path, e))?;
+  let lines = file
+    .lines()
+    .take(GUARD_SCAN_LINES)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| FollyError::at(file_path, e))?;
+  Ok(has_include_guard(&lines))
+}
+
+fn file_is_generated<F: FileSystemProvider>(
+  fs: &F,
+  file_path: &Path,
+) -> Result<bool, FollyError> {
+  let file = fs
+    .open_file(file_path)
+    .map_err(|e| FollyError::at(file_path, e))?;
+  let lines = file
+    .lines()
+    .take(GENERATED_SCAN_LINES)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| FollyError::at(file_path, e))?;
+  Ok(is_generated(&lines))
+}
+
+// Forgets everything a unit learned from its own files. Incoming edges are
+// left alone since they come from other units' files.
+#[cfg(feature = "serde")]
+fn reset_unit(node: &UnitObj) -> Result<(), FollyError> {
+  let deps = with_unit_mut(node, |info| {
+    info.headers.clear();
+    info.srcs.clear();
+    info.protos.clear();
+    info.test_srcs.clear();
+    info.is_test = false;
+    info.has_main = false;
+    info.is_objc = false;
+    info.missing_include_guard = false;
+    info.generated = false;
+    info.platforms.clear();
+    info.using_hints.clear();
+    info.forward_decl_hints.clear();
+    info.build_flags.clear();
+    info.link_opts.clear();
+    info.external_deps.clear();
+    info.test_external_deps.clear();
+    info.test_includes.clear();
+    info.mtimes.clear();
+    info.conditions.clear();
+    let test_deps = std::mem::take(&mut info.test_deps);
+    let deps = std::mem::take(&mut info.deps);
+    deps.into_keys().chain(test_deps).collect::<Vec<_>>()
+  })?;
+  for dep in &deps {
+    with_unit_mut(dep, |info| {
+      info.reverse_deps.shift_remove(&WeakHashObj::from(node))
+    })?;
+  }
+  Ok(())
+}
+
+fn write_trie_node<F: FileSystemProvider>(
+  sink: &mut WriteSink<F>,
+  trie: &UnitTrie,
+  dir: &Path,
+  opts: &BuildOptions,
+) -> Result<(), FollyError> {
+  if let Some(contents) = render_build_file(&trie.units, opts) {
+    sink.write(&dir.join(opts.build_file_name.as_str()), &contents)?;
+  }
+  // Sorted so that --dry-run --verbose prints packages in a stable order.
+  let mut children: Vec<_> = trie.children.iter().collect();
+  children.sort_by(|a, b| a.0.cmp(b.0));
+  for (component, child) in children {
+    write_trie_node(sink, child, &dir.join(component), opts)?;
+  }
+  Ok(())
+}
+
+fn verify_trie_node<F: FileSystemProvider>(
+  fs: &F,
+  trie: &UnitTrie,
+  dir: &Path,
+  opts: &BuildOptions,
+  problems: &mut Vec<String>,
+) -> Result<(), FollyError> {
+  if let Some(expected) = render_build_file(&trie.units, opts) {
+    // Bazel prefers BUILD.bazel when a package has both.
+    let mut build_path = dir.join("BUILD");
+    let mut actual = String::new();
+    for name in ["BUILD.bazel", "BUILD"] {
+      let path = dir.join(name);
+      match fs.open_file(&path) {
+        Ok(mut file) => {
+          file
+            .read_to_string(&mut actual)
+            .map_err(|e| FollyError::at(&path, e))?;
+          build_path = path;
+          break;
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+        Err(e) => return Err(FollyError::at(&path, e)),
+      }
+    }
+    let diff = verify::diff_build_file(&expected, &actual)
+      .map_err(|e| e.at(&build_path))?;
+    problems.extend(
+      diff
+        .into_iter()
+        .map(|problem| format!("{}: {}", build_path.display(), problem)),
+    );
+  }
+  for (component, child) in &trie.children {
+    verify_trie_node(fs, child, &dir.join(component), opts, problems)?;
+  }
+  Ok(())
 }
 
 impl CompileTrie for UnitTrie {
-  fn write_build_files(&self) -> Result<(), Error> {
-    Ok(())
+  fn write_build_files<F: FileSystemProvider>(
+    &self,
+    sink: &mut WriteSink<F>,
+    opts: &BuildOptions,
+  ) -> Result<(), FollyError> {
+    write_trie_node(sink, self, &opts.output_dir, opts)
+  }
+
+  fn verify_build_files<F: FileSystemProvider>(
+    &self,
+    fs: &F,
+    opts: &BuildOptions,
+  ) -> Result<Vec<String>, FollyError> {
+    let mut problems = Vec::new();
+    verify_trie_node(fs, self, &opts.output_dir, opts, &mut problems)?;
+    problems.sort();
+    Ok(problems)
   }
 }
 
-impl _UnitMap for UnitMap {
-  fn add_dependency_edges(
+impl<M: MutateExtract<UnitKey, UnitObj>> _ParseFile for M {
+  fn add_dependency_edges<F: FileSystemProvider>(
     &mut self,
+    fs: &F,
     file_path: &Path,
     curr_node: UnitObj,
-  ) -> Result<(), Error> {
-    let file = BufReader::new(File::open(file_path)?);
-    for line in file.lines() {
-      let line = line.unwrap();
-      match strip_include(&line) {
+    kind: UnitDepKind,
+    test_only: bool,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError> {
+    let file = fs
+      .open_file(file_path)
+      .map_err(|e| FollyError::at(file_path, e))?;
+    // A trailing backslash splices the next physical line onto this one, so
+    // accumulate until we have a whole logical line.
+    let mut logical_line = String::new();
+    let is_proto = file_path.extension().is_some_and(|ext| ext == "proto");
+    let strip_dep = if is_proto {
+      strip_import
+    } else {
+      strip_include
+    };
+    // The #if blocks the current line is inside, innermost last and not
+    // counting an include guard: the condition of the branch we are in, if
+    // one macro decides it, and whether an #elif has been seen, after which
+    // an #else is the opposite of more than one. guard is the macro of an
+    // #ifndef that may turn out to be an include guard once the next
+    // directive is seen.
+    let mut blocks: Vec<(Option<Condition>, bool)> = Vec::new();
+    let mut guard: Option<String> = None;
+    for (n, line) in file.lines().enumerate() {
+      let line = line.map_err(|e| FollyError::ParseError {
+        file: file_path.to_path_buf(),
+        line: n + 1,
+        message: e.to_string(),
+      })?;
+      if let Some(head) = line.strip_suffix('\\') {
+        logical_line += head;
+        continue;
+      }
+      logical_line += &line;
+      let line = std::mem::take(&mut logical_line);
+      if !is_proto {
+        if let Some(words) = directive_words(&line) {
+          match words.as_slice() {
+            [ifndef, name, ..] if ifndef == "ifndef" && blocks.is_empty() => {
+              guard = Some(name.clone());
+              blocks.push((Condition::parse(&words), false));
+            }
+            [define, name, ..]
+              if define == "define" && guard.as_ref() == Some(name) =>
+            {
+              // Every line of the header is inside its include guard.
+              guard = None;
+              blocks.pop();
+            }
+            [open, ..]
+              if open == "if" || open == "ifdef" || open == "ifndef" =>
+            {
+              guard = None;
+              blocks.push((Condition::parse(&words), false));
+            }
+            [elif, ..]
+              if elif == "elif" || elif == "elifdef" || elif == "elifndef" =>
+            {
+              guard = None;
+              if let Some(block) = blocks.last_mut() {
+                *block = (Condition::parse(&words), true);
+              }
+            }
+            [other, ..] if other == "else" => {
+              guard = None;
+              if let Some((condition, after_elif)) = blocks.last_mut() {
+                *condition = if *after_elif {
+                  None
+                } else {
+                  condition.take().map(Condition::negate)
+                };
+              }
+            }
+            [endif, ..] if endif == "endif" => {
+              guard = None;
+              blocks.pop();
+            }
+            _ => guard = None,
+          }
+        }
+        if let Some(flags) = copts_comment(&line) {
+          if opts.compile_flags.is_none() {
+            with_unit_mut(&curr_node, |info| {
+              for flag in flags {
+                if !info.build_flags.contains(&flag) {
+                  info.build_flags.push(flag);
+                }
+              }
+            })?;
+          }
+          continue;
+        }
+        if let Some(flags) = linkopts_comment(&line) {
+          with_unit_mut(&curr_node, |info| {
+            for flag in flags {
+              if !info.link_opts.contains(&flag) {
+                info.link_opts.push(flag);
+              }
+            }
+          })?;
+          continue;
+        }
+        if let Some(name) = using_alias_target(&line) {
+          with_unit_mut(&curr_node, |info| {
+            if name != curr_node.key.name && !info.using_hints.contains(&name) {
+              info.using_hints.push(name);
+            }
+          })?;
+          continue;
+        }
+        if let Some(name) = forward_decl_target(&line) {
+          with_unit_mut(&curr_node, |info| {
+            if name != curr_node.key.name
+              && !info.forward_decl_hints.contains(&name)
+            {
+              info.forward_decl_hints.push(name);
+            }
+          })?;
+          continue;
+        }
+      }
+      // Only the unit's cc_test builds a test file, so there is nothing for
+      // an #include of one to depend on.
+      if let Some(dep_key) = included_test(&line, opts) {
+        if !test_only && dep_key != curr_node.key {
+          with_unit_mut(&curr_node, |info| info.test_includes.insert(dep_key))?;
+        }
+        continue;
+      }
+      match strip_dep(&line, opts) {
         None => continue,
         Some((dep_key, hlib)) => {
           if dep_key == curr_node.key {
             continue;
           }
-          println!("{}, {}", dep_key.name, dep_key.root_dir);
           match hlib {
             HeaderLib::FOLLY => {
               let dep_node: UnitObj = self.extract_with_create(dep_key);
+              // The nearest enclosing #if that one macro decides. Taking the
+              // dep whenever that holds may take it more often than needed,
+              // but never less.
+              let condition = blocks
+                .iter()
+                .rev()
+                .find_map(|(condition, _)| condition.clone());
 
-              dep_node
-                .val
-                .borrow_mut()
-                .reverse_deps
-                .insert(curr_node.clone());
-              curr_node.val.borrow_mut().deps.insert(dep_node.clone());
+              with_unit_mut(&dep_node, |info| {
+                info.reverse_deps.insert(WeakHashObj::from(&curr_node))
+              })?;
+              with_unit_mut(&curr_node, |info| {
+                if test_only {
+                  info.test_deps.insert(dep_node.clone());
+                } else {
+                  info.add_dep(dep_node.clone(), kind, condition)
+                }
+              })?;
+            }
+            HeaderLib::THIRDPARTY(workspace) => {
+              // Nothing in the scanned tree provides these, so there is no
+              // node to link against; just remember the external label.
+              with_unit_mut(&curr_node, |info| {
+                let external = if test_only {
+                  &mut info.test_external_deps
+                } else {
+                  &mut info.external_deps
+                };
+                external.insert((workspace, dep_key))
+              })?;
+            }
+            HeaderLib::ABSEIL => {
+              let name = opts
+                .abseil_targets
+                .get(&dep_key.to_string())
+                .cloned()
+                .unwrap_or(dep_key.name);
+              let key = UnitKey::new(name, dep_key.root_dir);
+              with_unit_mut(&curr_node, |info| {
+                let external = if test_only {
+                  &mut info.test_external_deps
+                } else {
+                  &mut info.external_deps
+                };
+                external.insert((ABSEIL_WORKSPACE.to_string(), key))
+              })?;
             }
             HeaderLib::UNKNOWN => {
               // TODO other header types
@@ -76,100 +717,1941 @@ impl _UnitMap for UnitMap {
     Ok(())
   }
 
-  fn add_node(&mut self, file_path: &Path) -> Result<(), Error> {
-    let file_name: &str = match file_path.file_name() {
-      Some(osstr) => Ok(osstr.to_str().unwrap()),
-      None => Err(Error::new(
-        ErrorKind::NotFound,
-        format!("Could not determine file name {}", file_path.display()),
-      )),
-    }?;
-    let (curr_node_name, file_type): (String, FileType) =
-      strip_file_name(file_name)?;
+  fn add_node<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    base: &Path,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError> {
+    let (curr_key, file_name, file_type) = file_unit_key(base, file_path)?;
 
     if file_type == FileType::UNKNOWN {
-      println!("Ignoring file: {}", curr_node_name);
       return Ok(());
     }
+    if file_type == FileType::BAZEL_BUILD {
+      if opts.seed_existing {
+        return self.add_seed_nodes(fs, &curr_key.root_dir, file_path);
+      }
+      return Ok(());
+    }
+    let mtime = fs
+      .modified(file_path)
+      .map_err(|e| FollyError::at(file_path, e))?;
+    let has_main =
+      file_type == FileType::SOURCE && file_defines_main(fs, file_path)?;
+    let missing_include_guard =
+      file_type == FileType::HEADER && !file_has_include_guard(fs, file_path)?;
+    if missing_include_guard {
+      if opts.error_on_missing_guard {
+        return Err(FollyError::MissingIncludeGuard(file_path.to_path_buf()));
+      }
+      WARNINGS.warn(format_args!(
+        "{} has no include guard or #pragma once",
+        file_path.display()
+      ));
+    }
 
-    let parent_string = match Path::parent(file_path) {
-      Some(path) => match path.to_str() {
-        Some(path_str) => Ok(path_str.to_string()),
-        None => Err(std::io::Error::new(
-          ErrorKind::NotFound,
-          format!("Failure converting {} to string", path.display()),
-        )),
-      },
-      None => Err(std::io::Error::new(
-        ErrorKind::NotFound,
-        "Parent dir not found.",
-      )),
-    }?;
+    let generated = file_is_generated(fs, file_path)?;
 
     // Populate initial information.
-    let curr_key = UnitKey {
-      name: curr_node_name,
-      root_dir: parent_string,
-    };
+    let platform = detect_platform(&curr_key.name);
     let curr_node: UnitObj = self.extract_with_create(curr_key);
-    match file_type {
-      FileType::TEMPLATE | FileType::HEADER => curr_node
-        .val
-        .borrow_mut()
-        .headers
-        .push(file_name.to_string()),
-      FileType::SOURCE | FileType::TEST => {
-        curr_node.val.borrow_mut().srcs.push(file_name.to_string())
+    let compile_flags = opts
+      .compile_flags
+      .as_ref()
+      .and_then(|flags| flags.get(file_path));
+    with_unit_mut(&curr_node, |info| {
+      info.mtimes.insert(file_name.clone(), mtime);
+      for flag in compile_flags.into_iter().flatten() {
+        if !info.build_flags.contains(flag) {
+          info.build_flags.push(flag.clone());
+        }
+      }
+      info.generated |= generated;
+      if let Some(platform) = platform {
+        if !info.platforms.contains(&platform) {
+          info.platforms.push(platform);
+        }
+      }
+    })?;
+    with_unit_mut(&curr_node, |info| match file_type {
+      FileType::TEMPLATE | FileType::HEADER => {
+        info.headers.push(file_name.to_string());
+        info.missing_include_guard |= missing_include_guard;
+      }
+      FileType::SOURCE => {
+        info.srcs.push(file_name.to_string());
+        info.has_main |= has_main;
+      }
+      FileType::PROTO => info.protos.push(file_name.to_string()),
+      FileType::OBJC => {
+        info.srcs.push(file_name.to_string());
+        info.is_objc = true;
+      }
+      FileType::TEST => {
+        info.test_srcs.push(file_name.to_string());
+        info.is_test = true;
+      }
+      FileType::UNKNOWN | FileType::BAZEL_BUILD => unreachable!(),
+    })?;
+
+    let kind = match file_type {
+      FileType::SOURCE | FileType::OBJC | FileType::TEST => {
+        UnitDepKind::SOURCE_INCLUDE
+      }
+      _ => UnitDepKind::INCLUDE,
+    };
+    // The file is still a unit that others can include, but what it
+    // includes is the generator's business.
+    if generated && opts.skip_generated {
+      return Ok(());
+    }
+    let test_only = file_type == FileType::TEST;
+    self.add_dependency_edges(fs, file_path, curr_node, kind, test_only, opts)
+  }
+
+  // Every cc_* rule becomes a node under the key an #include of the file it
+  // is named after would produce, so that includes resolve to it.
+  fn add_seed_nodes<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    root_dir: &str,
+    file_path: &Path,
+  ) -> Result<(), FollyError> {
+    let mut contents = String::new();
+    fs.open_file(file_path)
+      .and_then(|mut file| file.read_to_string(&mut contents))
+      .map_err(|e| FollyError::at(file_path, e))?;
+    // The file is someone else's to fix, and the scan can do without it.
+    let rules = match parse_build_file(&contents) {
+      Ok(rules) => rules,
+      Err(e) => {
+        WARNINGS.warn(format_args!(
+          "{}:{}: {}; not seeding from it",
+          file_path.display(),
+          e.line,
+          e.message
+        ));
+        return Ok(());
+      }
+    };
+    for rule in rules {
+      if !rule.rule_type.starts_with("cc_") {
+        continue;
+      }
+      let node = self.extract_with_create(UnitKey::new(
+        camel_to_snake(&rule.name),
+        root_dir,
+      ));
+      with_unit_mut(&node, |info| {
+        info.pre_existing = true;
+        info.existing_name = Some(rule.name.clone());
+      })?;
+    }
+    Ok(())
+  }
+}
+
+impl _UnitMap for UnitMap {
+  fn add_subtree<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    base: &Path,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError> {
+    walk_files(fs, file_path, opts, &mut |path| {
+      self.add_node(fs, base, path, opts)
+    })
+  }
+
+  // Folds every member of the component into the one with the smallest key,
+  // rewiring external edges so that they point at the survivor.
+  fn merge_component(&mut self, component: Vec<UnitObj>) {
+    let rep: UnitObj = component
+      .iter()
+      .min_by(|a, b| a.key.cmp(&b.key))
+      .unwrap()
+      .clone();
+    self.merge_into(&rep, component);
+  }
+
+  fn merge_into(&mut self, rep: &UnitObj, component: Vec<UnitObj>) {
+    let members: HashSet<UnitKey> =
+      component.iter().map(|node| node.key.clone()).collect();
+
+    for node in component.iter().filter(|node| node.key != rep.key) {
+      // File names are relative to root_dir, which callers make sure the
+      // members share.
+      debug_assert_eq!(node.key.root_dir, rep.key.root_dir);
+      let info = std::mem::take(&mut *node.val.borrow_mut());
+      let mut rep_info = rep.val.borrow_mut();
+      rep_info.is_test |= info.is_test;
+      rep_info.has_main |= info.has_main;
+      rep_info.is_objc |= info.is_objc;
+      rep_info.missing_include_guard |= info.missing_include_guard;
+      rep_info.generated |= info.generated;
+      // Empty means every platform, which a merge can only widen to.
+      if rep_info.platforms.is_empty() || info.platforms.is_empty() {
+        rep_info.platforms.clear();
+      } else {
+        for platform in info.platforms {
+          if !rep_info.platforms.contains(&platform) {
+            rep_info.platforms.push(platform);
+          }
+        }
+      }
+      rep_info.pre_existing |= info.pre_existing;
+      rep_info.external_deps.extend(info.external_deps);
+      rep_info.test_external_deps.extend(info.test_external_deps);
+      rep_info.test_includes.extend(info.test_includes);
+      for hint in info.using_hints {
+        if !rep_info.using_hints.contains(&hint) {
+          rep_info.using_hints.push(hint);
+        }
+      }
+      for hint in info.forward_decl_hints {
+        if !rep_info.forward_decl_hints.contains(&hint) {
+          rep_info.forward_decl_hints.push(hint);
+        }
+      }
+      for flag in info.build_flags {
+        if !rep_info.build_flags.contains(&flag) {
+          rep_info.build_flags.push(flag);
+        }
+      }
+      for flag in info.link_opts {
+        if !rep_info.link_opts.contains(&flag) {
+          rep_info.link_opts.push(flag);
+        }
+      }
+      rep_info.headers.extend(info.headers);
+      rep_info.srcs.extend(info.srcs);
+      rep_info.protos.extend(info.protos);
+      rep_info.test_srcs.extend(info.test_srcs);
+      for (dep, kind) in info.deps {
+        if members.contains(&dep.key) {
+          continue;
+        }
+        let mut dep_info = dep.val.borrow_mut();
+        dep_info.reverse_deps.shift_remove(&WeakHashObj::from(node));
+        dep_info.reverse_deps.insert(WeakHashObj::from(rep));
+        drop(dep_info);
+        let condition = info.conditions.get(&dep.key).cloned();
+        rep_info.add_dep(dep, kind, condition);
+      }
+      for dep in info.test_deps {
+        if members.contains(&dep.key) {
+          continue;
+        }
+        let mut dep_info = dep.val.borrow_mut();
+        dep_info.reverse_deps.shift_remove(&WeakHashObj::from(node));
+        dep_info.reverse_deps.insert(WeakHashObj::from(rep));
+        drop(dep_info);
+        rep_info.test_deps.insert(dep);
+      }
+      for rdep in info.reverse_deps.iter().filter_map(WeakHashObj::upgrade) {
+        if members.contains(&rdep.key) {
+          continue;
+        }
+        let mut rdep_info = rdep.val.borrow_mut();
+        // Put the survivor where the member was so that deps keep their order.
+        if let Some((i, _, kind)) = rdep_info.deps.shift_remove_full(node) {
+          let condition = rdep_info.conditions.shift_remove(&node.key);
+          match rdep_info.deps.get_mut(rep) {
+            Some(existing) => {
+              existing.widen(kind);
+              if rdep_info.conditions.get(&rep.key) != condition.as_ref() {
+                rdep_info.conditions.shift_remove(&rep.key);
+              }
+            }
+            None => {
+              rdep_info.deps.shift_insert(i, rep.clone(), kind);
+              if let Some(condition) = condition {
+                rdep_info.conditions.insert(rep.key.clone(), condition);
+              }
+            }
+          }
+        }
+        if let Some((i, _)) = rdep_info.test_deps.shift_remove_full(node) {
+          if !rdep_info.test_deps.contains(rep) {
+            rdep_info.test_deps.shift_insert(i, rep.clone());
+          }
+        }
+        drop(rdep_info);
+        rep_info.reverse_deps.insert(WeakHashObj::from(&rdep));
+      }
+      self.remove(&node.key);
+    }
+
+    // Edges between members are now self-loops on the survivor.
+    let mut rep_info = rep.val.borrow_mut();
+    rep_info.deps.retain(|dep, _| !members.contains(&dep.key));
+    rep_info.conditions.retain(|dep, _| !members.contains(dep));
+    rep_info.test_deps.retain(|dep| !members.contains(&dep.key));
+    rep_info.reverse_deps.retain(|rdep| {
+      rdep
+        .upgrade()
+        .is_some_and(|rdep| !members.contains(&rdep.key))
+    });
+  }
+
+  fn bfs(&self, root: &UnitKey) -> BfsIter<'_, UnitKey, UnitInfo<UnitKey>> {
+    BfsIter::new(self, root, |node| {
+      let info = node.val.borrow();
+      info.deps.keys().chain(&info.test_deps).cloned().collect()
+    })
+  }
+
+  fn bfs_reverse(
+    &self,
+    root: &UnitKey,
+  ) -> BfsIter<'_, UnitKey, UnitInfo<UnitKey>> {
+    BfsIter::new(self, root, |node| {
+      let info = node.val.borrow();
+      info
+        .reverse_deps
+        .iter()
+        .filter_map(WeakHashObj::upgrade)
+        .collect()
+    })
+  }
+
+  // Drops phantom units, along with every edge to them.
+  fn prune_unreachable(&mut self) {
+    let phantoms: Vec<UnitObj> = self
+      .iter()
+      .filter(|node| node.val.borrow().is_phantom())
+      .collect();
+    for node in &phantoms {
+      let info = std::mem::take(&mut *node.val.borrow_mut());
+      for rdep in info.reverse_deps.iter().filter_map(WeakHashObj::upgrade) {
+        let mut rdep_info = rdep.val.borrow_mut();
+        rdep_info.deps.shift_remove(node);
+        rdep_info.test_deps.shift_remove(node);
+      }
+      for dep in info.deps.keys().chain(&info.test_deps) {
+        dep
+          .val
+          .borrow_mut()
+          .reverse_deps
+          .shift_remove(&WeakHashObj::from(node));
+      }
+    }
+    let phantoms: HashSet<UnitKey> =
+      phantoms.into_iter().map(|node| node.key.clone()).collect();
+    self.retain(|key, _| !phantoms.contains(key));
+  }
+
+  fn compute_reverse_dep_counts(&mut self) {
+    for mut info in self.values_mut() {
+      info.reverse_dep_count = info
+        .reverse_deps
+        .iter()
+        .filter(|rdep| rdep.upgrade().is_some())
+        .count();
+    }
+  }
+
+  // Anything already in deps, or that can't be pinned down, is dropped.
+  fn resolve_using_hints(&mut self) {
+    let by_name = units_by_name(self);
+    for node in self.iter() {
+      let mut info = node.val.borrow_mut();
+      info.soft_deps.clear();
+      for name in info.using_hints.clone() {
+        let Some(target) = resolve_hint(self, &by_name, &node.key, &name)
+        else {
+          continue;
+        };
+        if target == node.key
+          || info
+            .deps
+            .keys()
+            .chain(&info.test_deps)
+            .any(|dep| dep.key == target)
+        {
+          continue;
+        }
+        info.soft_deps.insert((UnitDepKind::USING, target));
       }
-      FileType::UNKNOWN => unreachable!(),
     }
+  }
 
-    println!("Path {}", file_path.display());
-    self.add_dependency_edges(file_path, curr_node)
+  // Adds FORWARD_DECL deps from forward_decl_hints, and IMPLICIT ones from
+  // tests and benchmarks to what they are named after: FooTest to Foo in
+  // the same package, or in the parent of a test/ directory. An edge that
+  // would close a cycle is dropped, so this has to come after
+  // collapse_cycles and leaves the graph acyclic.
+  fn add_inferred_deps(&mut self) {
+    let by_name = units_by_name(self);
+    let mut edges: Vec<(UnitObj, UnitKey, UnitDepKind)> = Vec::new();
+    for node in self.iter() {
+      let info = node.val.borrow();
+      for name in &info.forward_decl_hints {
+        if let Some(target) = resolve_hint(self, &by_name, &node.key, name) {
+          edges.push((node.clone(), target, UnitDepKind::FORWARD_DECL));
+        }
+      }
+      let base = ["_test", "_benchmark"]
+        .into_iter()
+        .find_map(|suffix| node.key.name.strip_suffix(suffix));
+      if let Some(base) = base {
+        let mut dirs = vec![node.key.root_dir.clone()];
+        for dir in ["/test", "/benchmark"] {
+          if let Some(parent) = node.key.root_dir.strip_suffix(dir) {
+            dirs.push(parent.to_string());
+          }
+        }
+        let target = dirs
+          .into_iter()
+          .map(|root_dir| UnitKey::new(base, root_dir));
+        if let Some(target) = target.into_iter().find(|key| self.contains(key))
+        {
+          edges.push((node.clone(), target, UnitDepKind::IMPLICIT));
+        }
+      }
+    }
+    for (from, target, kind) in edges {
+      let to = self.get(&target).unwrap().clone();
+      if Shared::ptr_eq(&from, &to)
+        || from.val.borrow().deps.contains_key(&to)
+        || from.val.borrow().test_deps.contains(&to)
+        || reaches(&to, &from)
+      {
+        continue;
+      }
+      to.val
+        .borrow_mut()
+        .reverse_deps
+        .insert(WeakHashObj::from(&from));
+      from.val.borrow_mut().deps.insert(to, kind);
+    }
+  }
+
+  fn infer_visibility(&mut self) {
+    for node in self.iter() {
+      let rdeps: Vec<UnitObj> = node
+        .val
+        .borrow()
+        .reverse_deps
+        .iter()
+        .filter_map(WeakHashObj::upgrade)
+        .collect();
+      let visibility = if rdeps.is_empty() {
+        Visibility::PRIVATE
+      } else if rdeps
+        .iter()
+        .all(|rdep| rdep.key.root_dir == node.key.root_dir)
+      {
+        Visibility::PACKAGE
+      } else {
+        Visibility::PUBLIC
+      };
+      node.val.borrow_mut().visibility = visibility;
+    }
   }
 }
 
 impl CompileGraph<UnitTrie> for UnitMap {
-  fn add_initial_subtree(&mut self, file_path: &Path) -> Result<(), Error> {
-    if file_path.is_dir() {
-      for child in std::fs::read_dir(file_path)? {
-        self.add_initial_subtree(&child?.path())?;
+  fn add_initial_subtree<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError> {
+    self.add_initial_subtree_with_progress(fs, file_path, opts, None)
+  }
+
+  fn add_initial_subtree_with_progress<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+    cb: Option<ProgressCallback>,
+  ) -> Result<(), FollyError> {
+    let file_path = fs
+      .canonicalize(file_path)
+      .map_err(|e| FollyError::at(file_path, e))?;
+    let base = file_path.parent().unwrap_or(&file_path).to_path_buf();
+    let Some(cb) = cb else {
+      return self.add_subtree(fs, &base, &file_path, opts);
+    };
+    let mut files_processed = 0;
+    walk_files(fs, &file_path, opts, &mut |path| {
+      self.add_node(fs, &base, path, opts)?;
+      files_processed += 1;
+      cb(files_processed, path);
+      Ok(())
+    })
+  }
+
+  fn add_file<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    root: &Path,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError> {
+    let root = fs.canonicalize(root).map_err(|e| FollyError::at(root, e))?;
+    let file_path = fs
+      .canonicalize(file_path)
+      .map_err(|e| FollyError::at(file_path, e))?;
+    if !file_path.starts_with(&root) || fs.is_dir(&file_path) {
+      return Err(FollyError::FileNotFound(file_path));
+    }
+    let base = root.parent().unwrap_or(&root);
+    self.add_node(fs, base, &file_path, opts)
+  }
+
+  #[cfg(feature = "serde")]
+  fn rescan_changed<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError> {
+    let file_path = fs
+      .canonicalize(file_path)
+      .map_err(|e| FollyError::at(file_path, e))?;
+    let base = file_path.parent().unwrap_or(&file_path).to_path_buf();
+
+    // Group what is on disk now by unit.
+    let mut on_disk: std::collections::HashMap<
+      UnitKey,
+      Vec<(std::path::PathBuf, String, std::time::SystemTime)>,
+    > = std::collections::HashMap::new();
+    walk_files(fs, &file_path, opts, &mut |path| {
+      let (key, file_name, file_type) = file_unit_key(&base, path)?;
+      // Seeds from BUILD files are kept as they were in the cached run.
+      if file_type != FileType::UNKNOWN && file_type != FileType::BAZEL_BUILD {
+        let mtime = fs.modified(path).map_err(|e| FollyError::at(path, e))?;
+        on_disk.entry(key).or_default().push((
+          path.to_path_buf(),
+          file_name,
+          mtime,
+        ));
+      }
+      Ok(())
+    })?;
+    // Units whose files have all been deleted still need resetting.
+    for node in self.iter() {
+      if !node.val.borrow().mtimes.is_empty() {
+        on_disk.entry(node.key.clone()).or_default();
+      }
+    }
+
+    for (key, files) in on_disk {
+      let node = self.extract_with_create(key);
+      let stale = {
+        let info = node.val.borrow();
+        info.mtimes.len() != files.len()
+          || files
+            .iter()
+            .any(|(_, name, mtime)| info.mtimes.get(name) != Some(mtime))
+      };
+      if stale {
+        reset_unit(&node)?;
+        for (path, _, _) in files {
+          self.add_node(fs, &base, &path, opts)?;
+        }
       }
-    } else {
-      self.add_node(file_path)?;
     }
+    // Whatever was left with no files and nothing including it is gone for
+    // good, as it would be from a fresh scan.
+    self.retain(|_, val| {
+      let info = val.borrow();
+      !info.mtimes.is_empty()
+        || info.pre_existing
+        || info
+          .reverse_deps
+          .iter()
+          .any(|rdep| rdep.upgrade().is_some())
+    });
     Ok(())
   }
 
-  fn collapse_cycles(&mut self) -> Result<(), Error> {
-    Ok(())
+  #[cfg(feature = "parallel")]
+  fn add_initial_subtree_parallel<F: FileSystemProvider>(
+    &mut self,
+    fs: &F,
+    file_path: &Path,
+    opts: &ScanOptions,
+  ) -> Result<(), FollyError> {
+    let file_path = fs
+      .canonicalize(file_path)
+      .map_err(|e| FollyError::at(file_path, e))?;
+    let base = file_path.parent().unwrap_or(&file_path).to_path_buf();
+    // Threads share the map behind a lock, which is only held for writing
+    // while a key is inserted; each node has its own lock for the edges.
+    let map = std::sync::RwLock::new(std::mem::take(self));
+    let result = par_walk_files(fs, &file_path, 0, opts, &|path| {
+      let mut shared = &map;
+      shared.add_node(fs, &base, path, opts)
+    });
+    *self = map.into_inner().unwrap();
+    result
   }
 
-  // TODO trie-building
-  fn generate_compilation_trie(&mut self) -> Result<UnitTrie, Error> {
+  fn collapse_cycles(&mut self) -> Result<(), FollyError> {
+    let cycles: Vec<Vec<UnitObj>> = strongly_connected_components(self)
+      .into_iter()
+      .filter(|component| component.len() > 1)
+      .collect();
+    // Bazel has no way to build a cycle between packages, so it is left to
+    // the user to break, before anything is merged.
+    for cycle in &cycles {
+      if cycle
+        .iter()
+        .any(|node| node.key.root_dir != cycle[0].key.root_dir)
+      {
+        let mut keys: Vec<UnitKey> =
+          cycle.iter().map(|node| node.key.clone()).collect();
+        keys.sort();
+        return Err(FollyError::CrossPackageMerge(keys));
+      }
+    }
+    for cycle in cycles {
+      self.merge_component(cycle);
+    }
     Ok(())
   }
+
+  fn generate_compilation_trie(&mut self) -> Result<UnitTrie, FollyError> {
+    // Inserting in dependency order means each BUILD file declares a rule's
+    // same-package deps before the rule itself.
+    let mut trie = UnitTrie::default();
+    for unit in self.topo_order()? {
+      trie.insert(unit);
+    }
+    Ok(trie)
+  }
+
+  fn topo_order(&self) -> Result<Vec<UnitObj>, FollyError> {
+    graph::topo_order(self)
+  }
+
+  fn validate(&self) -> Result<Vec<ValidationWarning>, FollyError> {
+    let mut warnings: Vec<ValidationWarning> = graph::phantom_deps(self)
+      .into_iter()
+      .map(|(unit, dep)| ValidationWarning::PhantomDep { unit, dep })
+      .collect();
+    warnings.extend(
+      graph::check_abi_stability(self)
+        .into_iter()
+        .map(|(unit, dep)| ValidationWarning::TestDep { unit, dep }),
+    );
+    warnings.extend(
+      graph::check_header_cycles(self)
+        .into_iter()
+        .map(ValidationWarning::HeaderCycle),
+    );
+    for node in graph::sorted_nodes(self) {
+      let info = node.val.borrow();
+      if info.is_test && info.test_srcs.is_empty() && !info.pre_existing {
+        warnings.push(ValidationWarning::TestWithoutSources(node.key.clone()));
+      }
+      if node.key.name.is_empty() {
+        warnings.push(ValidationWarning::EmptyName(node.key.clone()));
+      }
+    }
+    Ok(warnings)
+  }
 }
 
-fn main() {
-  let root = Path::new("/Users/victoria/folly");
-  let input_root = root.join("folly");
-  let mut dict: UnitMap = HashSet::new();
-  match dict.add_initial_subtree(&input_root) {
-    Ok(_) => match dict.collapse_cycles() {
-      Ok(_) => match dict.generate_compilation_trie() {
-        Ok(trie) => match trie.write_build_files() {
-          Ok(_) => println!("Successfully generated Starlark build files."),
-          Err(_) => {
-            println!("Failed to generate build files for compilation units.")
-          }
-        },
-        Err(_) => {
-          println!("Failed to generate trie of compilation units.")
-        }
-      },
-      Err(_) => println!("Failed to collapse cycles in dependency graph."),
-    },
-    Err(_) => println!("Failed to populate initial minimal compilation units."),
+// Overwrites one line of stderr every PROGRESS_INTERVAL files.
+const PROGRESS_INTERVAL: usize = 100;
+
+fn report_progress(files_processed: usize, current_file: &Path) {
+  if files_processed.is_multiple_of(PROGRESS_INTERVAL) {
+    eprint!(
+      "\r\x1b[KScanned {} files: {}",
+      files_processed,
+      current_file.display()
+    );
+  }
+}
+
+fn dump_graph(dict: &UnitMap) {
+  for node in dict.iter() {
+    println!("{}: {}", node.key, node.val.borrow());
+  }
+}
+
+// Prefixes an error with the step that produced it, so that failures read
+// as "Failed to scan ...: <cause>" rather than just the bare error.
+fn context<T, E: std::fmt::Display>(
+  result: Result<T, E>,
+  step: impl FnOnce() -> String,
+) -> Result<T, Box<dyn std::error::Error>> {
+  result.map_err(|e| format!("{}: {}", step(), e).into())
+}
+
+#[cfg(feature = "serde")]
+fn diff_caches(
+  old_path: &Path,
+  new_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let load = |path: &Path| {
+    context(cache::load_units(path), || {
+      format!("Failed to load cache {}", path.display())
+    })
+  };
+  let lines = diff::diff_maps(&load(old_path)?, &load(new_path)?);
+  for line in &lines {
+    println!("{}", line);
+  }
+  if !lines.is_empty() {
+    return Err(
+      format!("{} and {} differ", old_path.display(), new_path.display())
+        .into(),
+    );
+  }
+  Ok(())
+}
+
+// One path per line, as find or git ls-files print them. Blank lines are
+// skipped.
+fn read_manifest(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+  let contents = std::fs::read_to_string(path)?;
+  Ok(
+    contents
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty())
+      .map(PathBuf::from)
+      .collect(),
+  )
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+  #[cfg(feature = "serde")]
+  if let Some(paths) = &args.diff {
+    return diff_caches(&paths[0], &paths[1]);
+  }
+  if !args.root().exists() {
+    return Err(
+      format!("Root {} does not exist.", args.root().display()).into(),
+    );
+  }
+  if !args.root().is_dir() {
+    return Err(
+      format!("Root {} is not a directory.", args.root().display()).into(),
+    );
+  }
+  let output_dir = match &args.output_dir {
+    Some(dir) => dir.clone(),
+    None => {
+      let root = context(args.root().canonicalize(), || {
+        format!("Could not resolve {}", args.root().display())
+      })?;
+      root.parent().unwrap_or(&root).to_path_buf()
+    }
+  };
+  let opts = BuildOptions {
+    output_dir,
+    build_file_name: args.build_filename,
+    dry_run: args.dry_run,
+    verbose: args.verbose,
+    use_glob: args.use_glob,
+    glob_threshold: args.glob_threshold,
+    filter_root: args.filter_root.clone(),
+    test_wrapper: args
+      .wrap_tests_with_sh_test
+      .then(|| args.test_wrapper_script.clone())
+      .flatten(),
+    label_prefix: args.label_prefix.clone(),
+    bazel_version: args.bazel_version,
+    no_recursive_headers: args.no_recursive_headers,
+    rules_cc_available: args.rules_cc_available,
+    sort_srcs: args.sort_srcs_alphabetically,
+  };
+
+  let profiler = Profiler::new();
+  let mut builder = GraphBuilder::new();
+  if let Some(manifest) = &args.files_from {
+    let files = context(read_manifest(manifest), || {
+      format!("Failed to read manifest {}", manifest.display())
+    })?;
+    builder.files(files);
+  }
+  builder
+    .scan(args.root())
+    .seed_existing(args.seed_existing)
+    .prune_unreachable(args.prune_unreachable)
+    .check_header_cycles(args.check_header_cycles)
+    .strict(args.strict)
+    .max_depth(args.max_depth)
+    .error_on_missing_guard(args.error_on_missing_guard)
+    .skip_generated(args.skip_generated);
+  for dir in &args.exclude_dirs {
+    builder.exclude(dir);
+  }
+  for (a, b) in args.merge_units()? {
+    builder.merge_units(a, b);
+  }
+  for (virtual_path, real) in args.path_aliases()? {
+    builder.with_path_alias(&virtual_path, &real);
+  }
+  let repositories = match &args.workspace {
+    Some(path) => Some(context(workspace_parser::load(path), || {
+      format!("Failed to read workspace {}", path.display())
+    })?),
+    None => None,
+  };
+  for (prefix, workspace) in args.known_roots() {
+    let workspace = match &repositories {
+      Some(repositories) => {
+        workspace_parser::resolve_repository(&prefix, &workspace, repositories)
+          .unwrap_or_else(|| {
+            WARNINGS.warn(format_args!(
+              "{} declares no repository for known root {}",
+              args.workspace.as_ref().unwrap().display(),
+              prefix
+            ));
+            workspace
+          })
+      }
+      None => workspace,
+    };
+    builder.with_known_root(&prefix, &workspace);
+  }
+  #[cfg(feature = "config")]
+  if let Some(path) = &args.abseil_targets {
+    let targets = context(config::load_abseil_targets(path), || {
+      format!("Failed to load Abseil targets {}", path.display())
+    })?;
+    for (header, target) in targets {
+      builder.with_abseil_target(&header, &target);
+    }
+  }
+  #[cfg(feature = "json")]
+  if let Some(path) = &args.copts_from_compile_commands {
+    let flags = context(compile_commands::load(path), || {
+      format!("Failed to load compile commands {}", path.display())
+    })?;
+    builder.compile_flags(flags);
+  }
+  #[cfg(feature = "serde")]
+  if let Some(cache_path) = &args.incremental {
+    builder.incremental(cache_path);
+  }
+  #[cfg(feature = "parallel")]
+  builder.parallel(args.parallel);
+  if args.progress {
+    builder.progress(report_progress);
+  }
+  if args.profile {
+    builder.profile(&profiler);
+  }
+  if args.dump_graph {
+    builder.inspect(|dict| {
+      dump_graph(dict);
+      Ok(())
+    });
+  }
+  if args.validate {
+    builder.inspect(|dict| {
+      for warning in dict.validate()? {
+        WARNINGS.warn(warning);
+      }
+      Ok(())
+    });
+  }
+  if args.list_cycles {
+    builder.inspect(|dict| {
+      for cycle in graph::find_cycles(dict) {
+        let members: Vec<String> =
+          cycle.iter().map(|key| key.to_string()).collect();
+        println!("Cycle: {}", members.join(", "));
+      }
+      Ok(())
+    });
+  }
+  if let Some(dot_path) = &args.dot {
+    builder.inspect(move |dict| {
+      context(
+        File::create(dot_path).and_then(|mut f| dot::write_dot(dict, &mut f)),
+        || format!("Failed to write {}", dot_path.display()),
+      )
+    });
+  }
+  #[cfg(feature = "watch")]
+  if args.watch {
+    return watch::watch(&mut builder, args.root(), &opts);
+  }
+  let mut dict = builder.build()?;
+  if args.progress {
+    // Ends the progress line, so that what comes next starts on its own.
+    eprintln!();
+  }
+  if dict.is_empty() {
+    WARNINGS.warn(format_args!(
+      "found no source files under {}",
+      args.root().display()
+    ));
+  }
+  profiler.start_phase("generate trie");
+  let trie = context(dict.generate_compilation_trie(), || {
+    "Failed to generate trie of compilation units".to_string()
+  })?;
+  profiler.end_phase("generate trie");
+  #[cfg(feature = "json")]
+  if let Some(json_path) = &args.json_output {
+    context(
+      File::create(json_path).and_then(|mut f| json::write_json(&dict, &mut f)),
+      || format!("Failed to write {}", json_path.display()),
+    )?;
+  }
+  if args.print_build_order {
+    for (depth, key) in graph::build_levels(&dict)? {
+      println!("[depth={}] {}", depth, key);
+    }
+  }
+  if args.stats {
+    print!("{}", stats::report_statistics(&dict));
+    print!("{}", stats::GraphMetrics::compute(&dict));
+  }
+  if args.lint {
+    for warning in lint::lint_targets(&dict, args.lint_max_srcs) {
+      WARNINGS.lint(warning);
+    }
+  }
+  if args.check_missing_deps {
+    let root = context(args.root().canonicalize(), || {
+      format!("Could not resolve {}", args.root().display())
+    })?;
+    let warnings = context(
+      lint::check_missing_deps(
+        &RealFileSystem,
+        &dict,
+        root.parent().unwrap_or(&root),
+      ),
+      || "Failed to check for missing deps".to_string(),
+    )?;
+    for warning in warnings {
+      WARNINGS.warn(warning);
+    }
+  }
+  if args.query_deps.is_some() || args.query_rdeps.is_some() {
+    let print_keys = |keys: HashSet<UnitKey>| {
+      let mut keys: Vec<UnitKey> = keys.into_iter().collect();
+      keys.sort();
+      for key in keys {
+        println!("{}", key);
+      }
+    };
+    if let Some(unit) = &args.query_deps {
+      let key = cli::unit_key(unit);
+      print_keys(graph::transitive_deps(&dict, &key, args.query_depth)?);
+    }
+    if let Some(unit) = &args.query_rdeps {
+      let key = cli::unit_key(unit);
+      print_keys(graph::transitive_reverse_deps(
+        &dict,
+        &key,
+        args.query_depth,
+      )?);
+    }
+    return Ok(());
+  }
+  profiler.start_phase("write");
+  if args.verify {
+    let problems =
+      context(trie.verify_build_files(&RealFileSystem, &opts), || {
+        "Failed to verify BUILD files".to_string()
+      })?;
+    for problem in &problems {
+      println!("{}", problem);
+    }
+    if !problems.is_empty() {
+      return Err(
+        format!("BUILD files are out of date: {} problems", problems.len())
+          .into(),
+      );
+    }
+    println!("BUILD files are up to date.");
+    profiler.end_phase("write");
+    if args.profile {
+      eprint!("{}", profiler);
+    }
+    return Ok(());
+  }
+  match args.output_format {
+    OutputFormat::BAZEL => {
+      let mut sink = opts.sink(&RealFileSystem);
+      context(trie.write_build_files(&mut sink, &opts), || {
+        "Failed to write BUILD files".to_string()
+      })?;
+      println!("Successfully generated Starlark build files.");
+    }
+    OutputFormat::CMAKE => {
+      context(cmake::export_cmake(&RealFileSystem, &dict, &opts), || {
+        "Failed to write CMakeLists.txt".to_string()
+      })?;
+      println!("Successfully generated CMake build files.");
+    }
+    OutputFormat::MESON => {
+      context(meson::export_meson(&RealFileSystem, &dict, &opts), || {
+        "Failed to write meson.build".to_string()
+      })?;
+      println!("Successfully generated Meson build files.");
+    }
+    #[cfg(feature = "json")]
+    OutputFormat::JSON_BAZEL_QUERY => {
+      context(
+        bazel_query_format::export_bazel_query(&RealFileSystem, &dict, &opts),
+        || "Failed to write query.json".to_string(),
+      )?;
+      println!("Successfully generated Bazel query output.");
+    }
+  }
+  profiler.end_phase("write");
+  if args.profile {
+    eprint!("{}", profiler);
+  }
+  Ok(())
+}
+
+fn parse_args() -> Result<Args, Box<dyn std::error::Error>> {
+  #[cfg(feature = "config")]
+  let args = {
+    use clap::{CommandFactory, FromArgMatches};
+
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+    if let Some(config_path) = args.config.clone() {
+      let config = context(config::load(&config_path), || {
+        format!("Failed to load config {}", config_path.display())
+      })?;
+      config.apply(&mut args, &matches);
+    }
+    args
+  };
+  #[cfg(not(feature = "config"))]
+  let args = Args::parse();
+
+  // clap can't require --root itself, since it may come from a config file
+  // and --diff doesn't scan at all.
+  #[cfg(feature = "serde")]
+  let needs_root = args.diff.is_none();
+  #[cfg(not(feature = "serde"))]
+  let needs_root = true;
+  if needs_root && args.root.is_none() {
+    return Err("No root given; pass --root".into());
+  }
+  Ok(args)
+}
+
+fn main() -> ExitCode {
+  let args = match parse_args() {
+    Ok(args) => args,
+    Err(e) => {
+      eprintln!("{}", e);
+      return ExitCode::FAILURE;
+    }
+  };
+  if let Err(e) = run(&args) {
+    eprintln!("{}", e);
+    return ExitCode::FAILURE;
+  }
+  match args.max_warnings {
+    Some(max) if WARNINGS.count() > max => {
+      eprintln!(
+        "{} warnings, more than --max-warnings {}",
+        WARNINGS.count(),
+        max
+      );
+      ExitCode::from(2)
+    }
+    _ => ExitCode::SUCCESS,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::build_file::BuildFileName;
+  use crate::test_util::{
+    build, build_options, dep_keys, key, keys, mock_fs, scan, scan_with, unit,
+  };
+  #[cfg(feature = "serde")]
+  use crate::{file_system::MockFileSystem, test_util::BASE};
+
+  fn trie() -> UnitTrie {
+    build(&[
+      ("folly/Foo.h", "#pragma once\n#include <folly/io/Bar.h>\n"),
+      ("folly/io/Bar.h", "#pragma once\n"),
+    ])
+    .generate_compilation_trie()
+    .unwrap()
+  }
+
+  fn read(fs: &impl FileSystemProvider, path: &str) -> String {
+    let mut contents = String::new();
+    fs.open_file(Path::new(path))
+      .unwrap()
+      .read_to_string(&mut contents)
+      .unwrap();
+    contents
+  }
+
+  #[test]
+  fn write_build_files_writes_one_per_package() {
+    let fs = mock_fs(&[]);
+    let opts = build_options();
+    trie()
+      .write_build_files(&mut opts.sink(&fs), &opts)
+      .unwrap();
+    assert!(read(&fs, "/src/folly/BUILD").contains("name = \"Foo\""));
+    assert!(read(&fs, "/src/folly/io/BUILD").contains("name = \"Bar\""));
+  }
+
+  #[test]
+  fn verify_build_files_checks_what_was_written() {
+    let fs = mock_fs(&[]);
+    let opts = build_options();
+    let trie = trie();
+    trie.write_build_files(&mut opts.sink(&fs), &opts).unwrap();
+    assert!(trie.verify_build_files(&fs, &opts).unwrap().is_empty());
+
+    // Hand-written rules beside ours are fine.
+    let mut build = read(&fs, "/src/folly/io/BUILD");
+    build.push_str("\nfilegroup(\n    name = \"Docs\",\n)\n");
+    fs.write_file(Path::new("/src/folly/io/BUILD"), build.as_bytes())
+      .unwrap();
+    assert!(trie.verify_build_files(&fs, &opts).unwrap().is_empty());
+
+    let fs = mock_fs(&[]);
+    assert_eq!(
+      trie.verify_build_files(&fs, &opts).unwrap(),
+      [
+        "/src/folly/BUILD: Foo: missing cc_library rule",
+        "/src/folly/io/BUILD: Bar: missing cc_library rule",
+      ]
+    );
+  }
+
+  #[test]
+  fn verify_build_files_prefers_build_bazel() {
+    let fs = mock_fs(&[]);
+    let opts = build_options();
+    let trie = trie();
+    trie.write_build_files(&mut opts.sink(&fs), &opts).unwrap();
+    fs.write_file(
+      Path::new("/src/folly/BUILD.bazel"),
+      b"cc_library(\n    name = \"Foo\",\n    hdrs = [\"Foo.h\"],\n)\n",
+    )
+    .unwrap();
+    assert_eq!(
+      trie.verify_build_files(&fs, &opts).unwrap(),
+      ["/src/folly/BUILD.bazel: Foo: missing dep //folly/io:Bar"]
+    );
+  }
+
+  #[test]
+  fn build_filename_names_every_written_file() {
+    let fs = mock_fs(&[]);
+    let opts = BuildOptions {
+      build_file_name: BuildFileName::BUILD_BAZEL,
+      ..build_options()
+    };
+    trie()
+      .write_build_files(&mut opts.sink(&fs), &opts)
+      .unwrap();
+    assert!(read(&fs, "/src/folly/BUILD.bazel").contains("name = \"Foo\""));
+    assert!(read(&fs, "/src/folly/io/BUILD.bazel").contains("name = \"Bar\""));
+    assert!(fs.open_file(Path::new("/src/folly/BUILD")).is_err());
+    assert!(fs.open_file(Path::new("/src/folly/io/BUILD")).is_err());
+
+    let mut out = Vec::new();
+    trie()
+      .write_build_files(
+        &mut WriteSink::<RealFileSystem>::PATHS(Box::new(&mut out)),
+        &opts,
+      )
+      .unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "Would write /src/folly/BUILD.bazel\n\
+       Would write /src/folly/io/BUILD.bazel\n"
+    );
+  }
+
+  #[test]
+  fn dry_run_verbose_prints_every_file() {
+    let opts = build_options();
+    let mut out = Vec::new();
+    trie()
+      .write_build_files(
+        &mut WriteSink::<RealFileSystem>::CONTENTS(Box::new(&mut out)),
+        &opts,
+      )
+      .unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let folly = out.find("# === /src/folly/BUILD ===\n").unwrap();
+    let io = out.find("# === /src/folly/io/BUILD ===\n").unwrap();
+    assert!(folly < io, "{}", out);
+    assert!(out[folly..io].contains("name = \"Foo\""), "{}", out);
+    assert!(out[io..].contains("name = \"Bar\""), "{}", out);
+  }
+
+  #[test]
+  fn dry_run_lists_paths() {
+    let opts = build_options();
+    let mut out = Vec::new();
+    trie()
+      .write_build_files(
+        &mut WriteSink::<RealFileSystem>::PATHS(Box::new(&mut out)),
+        &opts,
+      )
+      .unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "Would write /src/folly/BUILD\nWould write /src/folly/io/BUILD\n"
+    );
+  }
+
+  // Threads finish in any order, so only the edges can be compared, not the
+  // order they were added in.
+  #[cfg(feature = "parallel")]
+  #[test]
+  fn parallel_scan_finds_the_same_graph() {
+    let mut files: Vec<(String, String)> = Vec::new();
+    for dir in 0..4 {
+      for file in 0..25 {
+        let includes: String = (1..4)
+          .map(|i| {
+            let other = (file + i * 7) % 25;
+            format!("#include <folly/d{}/F{}.h>\n", (dir + i) % 4, other)
+          })
+          .collect();
+        files.push((format!("folly/d{}/F{}.h", dir, file), includes));
+      }
+    }
+    let files: Vec<(&str, &str)> = files
+      .iter()
+      .map(|(path, contents)| (path.as_str(), contents.as_str()))
+      .collect();
+    let fs = mock_fs(&files);
+    let root = Path::new("/src/folly");
+    let opts = ScanOptions::default();
+    let edges = |map: &UnitMap| {
+      let mut edges: Vec<(UnitKey, UnitKey)> = map
+        .iter()
+        .flat_map(|node| {
+          let deps: Vec<UnitKey> = node
+            .val
+            .borrow()
+            .deps
+            .keys()
+            .map(|dep| dep.key.clone())
+            .collect();
+          deps.into_iter().map(move |dep| (node.key.clone(), dep))
+        })
+        .collect();
+      edges.sort();
+      edges
+    };
+
+    let mut sequential = UnitMap::new();
+    sequential.add_initial_subtree(&fs, root, &opts).unwrap();
+    let mut parallel = UnitMap::new();
+    parallel
+      .add_initial_subtree_parallel(&fs, root, &opts)
+      .unwrap();
+    assert_eq!(sequential.len(), 100);
+    assert_eq!(edges(&parallel), edges(&sequential));
+  }
+
+  // a, b and c include each other in a ring; d includes b from outside it.
+  #[test]
+  fn collapse_cycles_merges_a_ring_into_its_smallest_key() {
+    let mut map = scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#include <folly/c.h>\n"),
+      ("folly/b.cpp", "#include <folly/b.h>\n"),
+      ("folly/c.h", "#include <folly/a.h>\n"),
+      ("folly/d.h", "#include <folly/b.h>\n"),
+    ]);
+    map.collapse_cycles().unwrap();
+    assert_eq!(keys(&map), ["folly/a", "folly/d"]);
+    let a = unit(&map, "folly/a");
+    let info = a.val.borrow();
+    let mut headers = info.headers.clone();
+    headers.sort();
+    assert_eq!(headers, ["a.h", "b.h", "c.h"]);
+    assert_eq!(info.srcs, ["b.cpp"]);
+    assert!(info.deps.is_empty());
+    let rdeps: Vec<UnitKey> = info
+      .reverse_deps
+      .iter()
+      .filter_map(WeakHashObj::upgrade)
+      .map(|rdep| rdep.key.clone())
+      .collect();
+    assert_eq!(rdeps, [key("folly/d")]);
+    assert_eq!(dep_keys(&map, "folly/d"), ["folly/a"]);
+  }
+
+  // futures and executors include each other, and io's header includes its
+  // parent's, which includes it back.
+  #[test]
+  fn collapse_cycles_fails_on_a_cycle_between_packages() {
+    for files in [
+      [
+        (
+          "folly/executors/Executor.h",
+          "#include <folly/futures/Future.h>\n",
+        ),
+        (
+          "folly/futures/Future.h",
+          "#include <folly/executors/Executor.h>\n",
+        ),
+      ],
+      [
+        ("folly/Buf.h", "#include <folly/io/IOBuf.h>\n"),
+        ("folly/io/IOBuf.h", "#include <folly/Buf.h>\n"),
+      ],
+    ] {
+      let mut map = scan(&files);
+      let err = map.collapse_cycles().unwrap_err();
+      assert!(matches!(err, FollyError::CrossPackageMerge(_)), "{}", err);
+      assert!(err.to_string().contains(" can't share a rule: "), "{}", err);
+      // Nothing was merged, so no file moved packages.
+      assert_eq!(map.len(), 2);
+      for node in map.iter() {
+        let info = node.val.borrow();
+        assert_eq!(info.headers.len(), 1);
+        assert!(!info.headers[0].contains('/'), "{:?}", info.headers);
+      }
+    }
+  }
+
+  #[test]
+  fn excluded_dirs_are_never_scanned() {
+    let opts = ScanOptions {
+      exclude_dirs: HashSet::from([
+        ".git".to_string(),
+        "CMakeFiles".to_string(),
+      ]),
+      ..ScanOptions::default()
+    };
+    let map = scan_with(
+      &[
+        ("folly/Foo.h", "#pragma once\n"),
+        ("folly/.git/Hook.h", "#pragma once\n"),
+        ("folly/io/CMakeFiles/Probe.cpp", "int main() {}\n"),
+        ("folly/io/Bar.h", "#pragma once\n"),
+      ],
+      &opts,
+    );
+    assert_eq!(keys(&map), ["folly/foo", "folly/io/bar"]);
+  }
+
+  // reverse_deps are weak, so only deps and the map own a node.
+  #[test]
+  fn dropping_the_map_frees_every_node() {
+    let map = scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#pragma once\n"),
+    ]);
+    // The map, a's deps and this.
+    assert_eq!(Shared::strong_count(&unit(&map, "folly/b")), 3);
+    let a = WeakHashObj::from(&unit(&map, "folly/a"));
+    let b = WeakHashObj::from(&unit(&map, "folly/b"));
+    drop(map);
+    assert!(a.upgrade().is_none());
+    assert!(b.upgrade().is_none());
+  }
+
+  // Until then, a cycle's members own each other through deps.
+  #[test]
+  fn dropping_a_collapsed_cycle_frees_it() {
+    let mut map = scan(&[
+      ("folly/a.h", "#include <folly/b.h>\n"),
+      ("folly/b.h", "#include <folly/a.h>\n"),
+    ]);
+    let a = WeakHashObj::from(&unit(&map, "folly/a"));
+    let b = WeakHashObj::from(&unit(&map, "folly/b"));
+    map.collapse_cycles().unwrap();
+    drop(map);
+    assert!(a.upgrade().is_none());
+    assert!(b.upgrade().is_none());
+  }
+
+  #[test]
+  fn backslash_continued_includes_are_joined() {
+    let map = scan(&[
+      (
+        "folly/Foo.h",
+        "#pragma once\n#include \\\n  <folly/Bar.h>\n# \\\ninclude <folly/Baz.h>\n",
+      ),
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Baz.h", "#pragma once\n"),
+    ]);
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/bar", "folly/baz"]);
+  }
+
+  #[test]
+  fn filter_root_only_writes_packages_under_it() {
+    let fs = mock_fs(&[]);
+    let opts = BuildOptions {
+      filter_root: Some("folly/io".to_string()),
+      ..build_options()
+    };
+    build(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/io/Foo.h", "#include <folly/Bar.h>\n"),
+      ("folly/iobuf/Baz.h", "#pragma once\n"),
+    ])
+    .generate_compilation_trie()
+    .unwrap()
+    .write_build_files(&mut opts.sink(&fs), &opts)
+    .unwrap();
+    let io = read(&fs, "/src/folly/io/BUILD");
+    assert!(io.contains("\"//folly:Bar\""), "{}", io);
+    assert!(fs.open_file(Path::new("/src/folly/BUILD")).is_err());
+    assert!(fs.open_file(Path::new("/src/folly/iobuf/BUILD")).is_err());
+  }
+
+  #[test]
+  fn protos_become_proto_and_cc_proto_libraries() {
+    let fs = mock_fs(&[]);
+    let opts = build_options();
+    build(&[
+      (
+        "folly/rpc/service.proto",
+        "syntax = \"proto3\";\n\
+         import \"folly/rpc/types.proto\";\n\
+         message Request { Id id = 1; }\n",
+      ),
+      (
+        "folly/rpc/types.proto",
+        "syntax = \"proto3\";\nmessage Id {}\n",
+      ),
+    ])
+    .generate_compilation_trie()
+    .unwrap()
+    .write_build_files(&mut opts.sink(&fs), &opts)
+    .unwrap();
+    assert_eq!(
+      read(&fs, "/src/folly/rpc/BUILD"),
+      "proto_library(
+    name = \"TypesProto\",
+    srcs = [
+        \"types.proto\",
+    ],
+    visibility = [
+        \"//visibility:private\",
+    ],
+)
+
+cc_proto_library(
+    name = \"TypesCcProto\",
+    deps = [
+        \":TypesProto\",
+    ],
+    visibility = [
+        \"//visibility:private\",
+    ],
+)
+
+proto_library(
+    name = \"ServiceProto\",
+    deps = [
+        \":TypesProto\",
+    ],
+    srcs = [
+        \"service.proto\",
+    ],
+    visibility = [
+        \"//visibility:private\",
+    ],
+)
+
+cc_proto_library(
+    name = \"ServiceCcProto\",
+    deps = [
+        \":ServiceProto\",
+    ],
+    visibility = [
+        \"//visibility:private\",
+    ],
+)
+"
+    );
+  }
+
+  #[test]
+  fn prune_unreachable_removes_phantoms_and_edges_to_them() {
+    let mut map = scan(&[
+      (
+        "folly/Foo.h",
+        "#include <folly/Bar.h>\n#include <folly/Missing.h>\n",
+      ),
+      ("folly/Bar.h", "#include <folly/io/Gone.h>\n"),
+      ("folly/Footest.cpp", "#include <folly/Missing.h>\n"),
+      ("folly/Qux.proto", "syntax = \"proto3\";\n"),
+    ]);
+    assert!(map.contains(&key("folly/missing")));
+    map.prune_unreachable();
+    assert_eq!(keys(&map), ["folly/bar", "folly/foo", "folly/qux_proto"]);
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/bar"]);
+    assert!(dep_keys(&map, "folly/bar").is_empty());
+  }
+
+  #[test]
+  fn headers_without_a_guard_are_flagged_or_fail_the_scan() {
+    let files = [
+      ("folly/Bare.h", "int f();\n"),
+      ("folly/Bare.cpp", "int f() { return 0; }\n"),
+      ("folly/Guarded.h", "#pragma once\n"),
+    ];
+    let map = scan(&files);
+    assert!(unit(&map, "folly/bare").val.borrow().missing_include_guard);
+    assert!(
+      !unit(&map, "folly/guarded")
+        .val
+        .borrow()
+        .missing_include_guard
+    );
+
+    let opts = ScanOptions {
+      error_on_missing_guard: true,
+      ..ScanOptions::default()
+    };
+    let mut map = UnitMap::new();
+    let err = map
+      .add_initial_subtree(&mock_fs(&files), Path::new("/src/folly"), &opts)
+      .unwrap_err();
+    assert!(
+      matches!(&err, FollyError::MissingIncludeGuard(path)
+        if path == Path::new("/src/folly/Bare.h")),
+      "{}",
+      err
+    );
+  }
+
+  #[test]
+  fn progress_reports_each_file_with_a_running_count() {
+    // A ProgressCallback can't capture, so it reports through a static that
+    // only this test uses.
+    static REPORTED: std::sync::Mutex<Vec<(usize, PathBuf)>> =
+      std::sync::Mutex::new(Vec::new());
+    fn record(files_processed: usize, current_file: &Path) {
+      REPORTED
+        .lock()
+        .unwrap()
+        .push((files_processed, current_file.to_path_buf()));
+    }
+
+    let fs = mock_fs(&[
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/Foo.cpp", "#include <folly/Foo.h>\n"),
+      ("folly/io/Bar.h", "#pragma once\n"),
+    ]);
+    let mut map = UnitMap::new();
+    map
+      .add_initial_subtree_with_progress(
+        &fs,
+        Path::new("/src/folly"),
+        &ScanOptions::default(),
+        Some(record),
+      )
+      .unwrap();
+    assert_eq!(keys(&map), ["folly/foo", "folly/io/bar"]);
+    let reported = REPORTED.lock().unwrap();
+    let expected: Vec<(usize, PathBuf)> = [
+      "/src/folly/Foo.cpp",
+      "/src/folly/Foo.h",
+      "/src/folly/io/Bar.h",
+    ]
+    .iter()
+    .enumerate()
+    .map(|(i, path)| (i + 1, PathBuf::from(path)))
+    .collect();
+    assert_eq!(*reported, expected);
+  }
+
+  #[test]
+  fn no_progress_callback_scans_the_same_graph() {
+    let files = [
+      ("folly/Foo.h", "#include <folly/io/Bar.h>\n"),
+      ("folly/io/Bar.h", "#pragma once\n"),
+    ];
+    let mut map = UnitMap::new();
+    map
+      .add_initial_subtree_with_progress(
+        &mock_fs(&files),
+        Path::new("/src/folly"),
+        &ScanOptions::default(),
+        None,
+      )
+      .unwrap();
+    assert_eq!(keys(&map), keys(&scan(&files)));
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/io/bar"]);
+  }
+
+  #[test]
+  fn skip_generated_keeps_the_unit_but_not_its_includes() {
+    let files = [
+      ("folly/Bar.h", "#include <folly/Types.h>\n"),
+      (
+        "folly/Types.h",
+        "// @generated by thrift\n#include <folly/Thrift.h>\n",
+      ),
+      ("folly/Thrift.h", "#pragma once\n"),
+    ];
+    let map = scan(&files);
+    assert!(unit(&map, "folly/types").val.borrow().generated);
+    assert!(!unit(&map, "folly/bar").val.borrow().generated);
+    assert_eq!(dep_keys(&map, "folly/types"), ["folly/thrift"]);
+
+    let opts = ScanOptions {
+      skip_generated: true,
+      ..ScanOptions::default()
+    };
+    let map = scan_with(&files, &opts);
+    assert!(unit(&map, "folly/types").val.borrow().generated);
+    assert!(dep_keys(&map, "folly/types").is_empty());
+    assert_eq!(dep_keys(&map, "folly/bar"), ["folly/types"]);
+    assert!(unit(&map, "folly/thrift")
+      .val
+      .borrow()
+      .reverse_deps
+      .is_empty());
+  }
+
+  #[test]
+  fn reverse_dep_counts_skip_dropped_units() {
+    let mut map = scan(&[
+      ("folly/Bar.h", "#include <folly/Foo.h>\n"),
+      ("folly/Baz.h", "#include <folly/Foo.h>\n"),
+      ("folly/Foo.h", "#pragma once\n"),
+    ]);
+    map.compute_reverse_dep_counts();
+    assert_eq!(unit(&map, "folly/foo").val.borrow().reverse_dep_count, 2);
+    assert_eq!(unit(&map, "folly/bar").val.borrow().reverse_dep_count, 0);
+
+    map.remove(&key("folly/baz"));
+    map.compute_reverse_dep_counts();
+    assert_eq!(unit(&map, "folly/foo").val.borrow().reverse_dep_count, 1);
+  }
+
+  #[test]
+  fn add_file_scans_only_that_file() {
+    let fs = mock_fs(&[
+      ("folly/Bar.h", "#include <folly/Baz.h>\n"),
+      ("folly/Baz.h", "#pragma once\n"),
+      ("folly/Foo.cpp", "#include <folly/Bar.h>\n"),
+      ("folly/Qux.h", "#pragma once\n"),
+    ]);
+    let root = Path::new("/src/folly");
+    let opts = ScanOptions::default();
+    let mut map = UnitMap::new();
+    map
+      .add_file(&fs, root, Path::new("/src/folly/Foo.cpp"), &opts)
+      .unwrap();
+    // Bar is only a name Foo includes until its own file is added.
+    assert_eq!(keys(&map), ["folly/bar", "folly/foo"]);
+    assert!(unit(&map, "folly/bar").val.borrow().is_phantom());
+    assert_eq!(unit(&map, "folly/foo").val.borrow().srcs, ["Foo.cpp"]);
+
+    map
+      .add_file(&fs, root, Path::new("/src/folly/Bar.h"), &opts)
+      .unwrap();
+    assert_eq!(keys(&map), ["folly/bar", "folly/baz", "folly/foo"]);
+    assert_eq!(dep_keys(&map, "folly/bar"), ["folly/baz"]);
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/bar"]);
+  }
+
+  #[test]
+  fn add_file_rejects_what_is_not_a_file_under_the_root() {
+    let fs = mock_fs(&[("folly/Foo.h", "#pragma once\n"), ("other/Bar.h", "")]);
+    let root = Path::new("/src/folly");
+    let opts = ScanOptions::default();
+    let mut map = UnitMap::new();
+    for path in ["/src/other/Bar.h", "/src/folly", "/src/folly/Missing.h"] {
+      assert!(
+        map.add_file(&fs, root, Path::new(path), &opts).is_err(),
+        "{}",
+        path
+      );
+    }
+    assert!(map.is_empty());
+  }
+
+  #[test]
+  fn read_manifest_skips_blank_lines() {
+    let path = std::env::temp_dir()
+      .join(format!("folly-manifest-test-{}", std::process::id()));
+    std::fs::write(&path, "folly/Foo.h\n\n  folly/io/Bar.cpp  \n\n").unwrap();
+    let files = read_manifest(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+      files,
+      [
+        PathBuf::from("folly/Foo.h"),
+        PathBuf::from("folly/io/Bar.cpp")
+      ]
+    );
+    assert!(read_manifest(&path).is_err());
+  }
+
+  #[test]
+  fn collapsed_cycles_keep_every_linkopt() {
+    let mut map = scan(&[
+      (
+        "folly/A.h",
+        "#include <folly/B.h>\n// BAZEL_LINKOPTS: -lpthread\n",
+      ),
+      (
+        "folly/B.h",
+        "#include <folly/A.h>\n// BAZEL_LINKOPTS: -ldl -lpthread\n",
+      ),
+    ]);
+    map.collapse_cycles().unwrap();
+    assert_eq!(keys(&map), ["folly/a"]);
+    assert_eq!(
+      unit(&map, "folly/a").val.borrow().link_opts,
+      ["-lpthread", "-ldl"]
+    );
+  }
+
+  #[test]
+  fn validate_warns_when_a_library_includes_a_test_helper() {
+    let map = build(&[
+      ("folly/Helper.h", "#pragma once\n"),
+      ("folly/Helpertest.cpp", "#include <folly/Helper.h>\n"),
+      ("folly/Lib.h", "#pragma once\n#include <folly/Helper.h>\n"),
+      ("folly/Lib.cpp", "#include <folly/Helpertest.cpp>\n"),
+    ]);
+    let warnings: Vec<String> = map
+      .validate()
+      .unwrap()
+      .iter()
+      .map(|warning| warning.to_string())
+      .collect();
+    assert_eq!(
+      warnings,
+      ["folly/lib is not a test, but depends on folly/helper's tests"]
+    );
+
+    // A unit with tests still has a library to depend on.
+    let clean = build(&[
+      ("folly/Helper.h", "#pragma once\n"),
+      ("folly/Helpertest.cpp", "#include <folly/Helper.h>\n"),
+      ("folly/Libtest.cpp", "#include <folly/Helper.h>\n"),
+      ("folly/Lib.h", "#pragma once\n#include <folly/Helper.h>\n"),
+    ]);
+    assert!(clean.validate().unwrap().is_empty());
+  }
+
+  #[test]
+  fn path_aliases_connect_includes_to_the_real_files() {
+    let opts = ScanOptions {
+      path_aliases: [("folly/compat".to_string(), PathBuf::from("folly/impl"))]
+        .into(),
+      ..ScanOptions::default()
+    };
+    let map = scan_with(
+      &[
+        ("folly/Foo.h", "#include <folly/compat/Bar.h>\n"),
+        ("folly/impl/Bar.h", "#pragma once\n"),
+      ],
+      &opts,
+    );
+    assert_eq!(keys(&map), ["folly/foo", "folly/impl/bar"]);
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/impl/bar"]);
+    assert!(!unit(&map, "folly/impl/bar").val.borrow().is_phantom());
+  }
+
+  // An include is conditional on the nearest #if one macro decides. The
+  // include guard is no condition, and neither is an #if that an include
+  // outside any #if also reaches.
+  #[test]
+  fn includes_under_an_if_keep_their_kind_and_condition() {
+    let map = scan(&[
+      (
+        "folly/Foo.cpp",
+        "#include <folly/Both.h>\n#ifdef FOLLY_A\n#include <folly/Impl.h>\n#endif\n",
+      ),
+      (
+        "folly/Foo.h",
+        "#ifndef FOLLY_FOO_H\n#define FOLLY_FOO_H\n\
+         #ifdef FOLLY_A\n#if FOLLY_B && FOLLY_C\n#include <folly/Nested.h>\n\
+         #endif\n#include <folly/Both.h>\n#endif\n#endif\n",
+      ),
+    ]);
+    let foo = unit(&map, "folly/foo");
+    let info = foo.val.borrow();
+    let kinds: Vec<(&str, UnitDepKind)> = info
+      .deps
+      .iter()
+      .map(|(dep, kind)| (dep.key.name.as_str(), *kind))
+      .collect();
+    assert_eq!(
+      kinds,
+      [
+        ("both", UnitDepKind::INCLUDE),
+        ("impl", UnitDepKind::SOURCE_INCLUDE),
+        ("nested", UnitDepKind::INCLUDE),
+      ]
+    );
+    let conditions: Vec<(&str, &str, bool)> = info
+      .conditions
+      .iter()
+      .map(|(key, c)| (key.name.as_str(), c.define.as_str(), c.defined))
+      .collect();
+    assert_eq!(
+      conditions,
+      [("impl", "FOLLY_A", true), ("nested", "FOLLY_A", true)]
+    );
+  }
+
+  // Scans files into a map the way a cached run left it, for
+  // rescan_changed to compare against what fs holds.
+  #[cfg(feature = "serde")]
+  fn rescan(files: &[(&str, &str)], fs: &MockFileSystem) -> UnitMap {
+    let mut map = scan(files);
+    map
+      .rescan_changed(
+        fs,
+        &Path::new(BASE).join("folly"),
+        &ScanOptions::default(),
+      )
+      .unwrap();
+    map
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn rescan_changed_leaves_an_unchanged_tree_alone() {
+    let files = [
+      ("folly/Foo.h", "#pragma once\n#include <folly/Bar.h>\n"),
+      ("folly/Bar.h", "#pragma once\n"),
+    ];
+    // Same mtimes, so the new contents are never read.
+    let fs = mock_fs(&[
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/Bar.h", "#pragma once\n"),
+    ]);
+    let map = rescan(&files, &fs);
+    assert_eq!(keys(&map), ["folly/bar", "folly/foo"]);
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/bar"]);
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn rescan_changed_only_resets_the_edited_unit() {
+    let files = [
+      ("folly/Foo.h", "#pragma once\n#include <folly/Bar.h>\n"),
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Baz.h", "#pragma once\n"),
+    ];
+    let fs = mock_fs(&[
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Baz.h", "#pragma once\n#include <folly/Bar.h>\n"),
+    ]);
+    fs.write_file(
+      Path::new("/src/folly/Foo.h"),
+      b"#pragma once\n#include <folly/Baz.h>\n",
+    )
+    .unwrap();
+    let map = rescan(&files, &fs);
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/baz"]);
+    // Baz.h kept its mtime, so its new include went unseen.
+    assert!(dep_keys(&map, "folly/baz").is_empty());
+    let bar = unit(&map, "folly/bar");
+    assert!(bar.val.borrow().reverse_deps.is_empty());
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn rescan_changed_drops_deleted_units_nothing_includes() {
+    let files = [
+      ("folly/Foo.h", "#pragma once\n#include <folly/Bar.h>\n"),
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Old.h", "#pragma once\n"),
+    ];
+    let fs = mock_fs(&files);
+    fs.remove_file(Path::new("/src/folly/Bar.h"));
+    fs.remove_file(Path::new("/src/folly/Old.h"));
+    let map = rescan(&files, &fs);
+    // Foo.h still includes Bar.h, so it stays, now with no files.
+    assert_eq!(keys(&map), ["folly/bar", "folly/foo"]);
+    let bar = unit(&map, "folly/bar");
+    assert!(bar.val.borrow().headers.is_empty());
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/bar"]);
+  }
+
+  fn seeded(files: &[(&str, &str)]) -> UnitMap {
+    let opts = ScanOptions {
+      seed_existing: true,
+      ..ScanOptions::default()
+    };
+    scan_with(files, &opts)
+  }
+
+  #[test]
+  fn seed_existing_reads_cc_rules_from_build_and_buck_files() {
+    let files = [
+      ("folly/io/IOBuf.h", "#pragma once\n"),
+      (
+        "folly/io/BUILD",
+        "cc_library(\n    name = \"IOBuf\",\n    hdrs = [\"IOBuf.h\"],\n)\n\
+         genrule(\n    name = \"Gen\",\n    outs = [\"Gen.h\"],\n)\n",
+      ),
+      ("folly/net/BUCK", "cc_library(name = \"NetOps\")\n"),
+    ];
+    let map = seeded(&files);
+    assert_eq!(keys(&map), ["folly/io/io_buf", "folly/net/net_ops"]);
+    for path in ["folly/io/io_buf", "folly/net/net_ops"] {
+      assert!(unit(&map, path).val.borrow().pre_existing, "{}", path);
+    }
+    let io_buf = unit(&map, "folly/io/io_buf");
+    assert_eq!(io_buf.val.borrow().existing_name.as_deref(), Some("IOBuf"));
+    assert_eq!(io_buf.val.borrow().headers, ["IOBuf.h"]);
+
+    // Without the flag BUILD files are ignored.
+    let map = scan(&files);
+    assert_eq!(keys(&map), ["folly/io/io_buf"]);
+    assert!(!unit(&map, "folly/io/io_buf").val.borrow().pre_existing);
+  }
+
+  #[test]
+  fn seed_existing_skips_unreadable_build_files() {
+    let map = seeded(&[
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/BUILD", "cc_library(\n    name = \"Foo\",\n"),
+    ]);
+    assert_eq!(keys(&map), ["folly/foo"]);
+    assert!(!unit(&map, "folly/foo").val.borrow().pre_existing);
+  }
+
+  #[test]
+  fn seeded_packages_get_no_build_file() {
+    let mut map = seeded(&[
+      ("folly/Foo.h", "#pragma once\n#include <folly/io/IOBuf.h>\n"),
+      ("folly/io/IOBuf.h", "#pragma once\n"),
+      ("folly/io/Cursor.h", "#pragma once\n"),
+      ("folly/io/BUILD", "cc_library(name = \"IOBuf\")\n"),
+    ]);
+    crate::builder::GraphBuilder::new()
+      .finish(&mut map)
+      .unwrap();
+    let fs = mock_fs(&[]);
+    let opts = build_options();
+    map
+      .generate_compilation_trie()
+      .unwrap()
+      .write_build_files(&mut opts.sink(&fs), &opts)
+      .unwrap();
+    let build = read(&fs, "/src/folly/BUILD");
+    // Foo still depends on IOBuf by the name it already had.
+    assert!(build.contains("\"//folly/io:IOBuf\""), "{}", build);
+    assert!(fs.open_file(Path::new("/src/folly/io/BUILD")).is_err());
   }
 }
@@ -0,0 +1,358 @@
+use std::collections::{BTreeSet, HashSet};
+
+use indexmap::IndexSet;
+
+use crate::build_file::{sorted_files, tests_use_library, BuildOptions};
+use crate::cmake::{cmake_target, write_generated};
+use crate::error::FollyError;
+use crate::file_system::FileSystemProvider;
+use crate::graph::strongly_connected_components;
+use crate::types::{UnitKey, UnitMap, UnitObj, ABSEIL_WORKSPACE};
+
+// Meson strings are single-quoted, and flags can hold quotes of either kind.
+fn quote(s: &str) -> String {
+  format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn array(items: &[String]) -> String {
+  format!("[{}]", items.join(", "))
+}
+
+// A call with one positional argument and then keyword arguments, one per
+// line. Empty keyword arguments are left out.
+fn call(function: &str, first: &str, kwargs: &[(&str, String)]) -> String {
+  let mut out = format!("{}({},\n", function, first);
+  for (name, value) in kwargs {
+    if value != "[]" {
+      out += &format!("  {}: {},\n", name, value);
+    }
+  }
+  out + ")\n"
+}
+
+// What find_package would be in CMake: the pkg-config name of a dep outside
+// the tree. Abseil's are per target, e.g. absl_flat_hash_map.
+fn external_dependency(workspace: &str, key: &UnitKey) -> String {
+  if workspace == ABSEIL_WORKSPACE {
+    format!("absl_{}", key.name)
+  } else {
+    workspace.to_string()
+  }
+}
+
+fn external_var(name: &str) -> String {
+  format!("{}_dep", name.replace(['-', '.', '+'], "_"))
+}
+
+// Every unit is a declare_dependency() named {target}_dep that dependents
+// take in dependencies:, with a library() behind it when there is anything
+// to compile. Binaries and a unit's tests, {target}Test, are executables,
+// which nothing depends on. Files are relative to the output directory,
+// like the CMake backend's. With `tests` set only the tests are rendered,
+// and otherwise everything else.
+fn render_target(
+  unit: &UnitObj,
+  opts: &BuildOptions,
+  defined: &HashSet<UnitKey>,
+  tests: bool,
+) -> Option<String> {
+  let info = unit.val.borrow();
+  if !info.protos.is_empty() {
+    return None;
+  }
+  let target = cmake_target(&unit.key);
+  let dir = &unit.key.root_dir;
+  let path = |file: &String| {
+    if dir.is_empty() {
+      quote(file)
+    } else {
+      quote(&format!("{}/{}", dir, file))
+    }
+  };
+  let files = |files: &[String]| -> Vec<String> {
+    sorted_files(files, opts).iter().map(path).collect()
+  };
+
+  // Meson has no variable until its statement has run, so only deps
+  // written earlier in the file can be named: the ones left out of this
+  // run, such as pre-existing packages, are the project's to add.
+  let dependencies =
+    |deps: &mut dyn Iterator<Item = &UnitObj>,
+     external: &IndexSet<(String, UnitKey)>| {
+      let mut deps: Vec<String> = deps
+        .filter(|dep| defined.contains(&dep.key))
+        .map(|dep| format!("{}_dep", cmake_target(&dep.key)))
+        .collect();
+      deps.sort();
+      deps.dedup();
+      deps.extend(
+        external
+          .iter()
+          .map(|(workspace, key)| {
+            external_var(&external_dependency(workspace, key))
+          })
+          .collect::<BTreeSet<_>>(),
+      );
+      deps
+    };
+  let cpp_args = (
+    "cpp_args",
+    array(
+      &info
+        .build_flags
+        .iter()
+        .map(|f| quote(f))
+        .collect::<Vec<_>>(),
+    ),
+  );
+  // Given to the dependency objects too, so that they reach whatever links
+  // this in, as Bazel's linkopts do.
+  let link_args = (
+    "link_args",
+    array(&info.link_opts.iter().map(|f| quote(f)).collect::<Vec<_>>()),
+  );
+  let include = (
+    "include_directories",
+    "include_directories('.')".to_string(),
+  );
+
+  let mut out = String::new();
+  // Files built on some platforms only are added to the list only there.
+  let mut sources = |var: String, headers: &[String], srcs: &[String]| {
+    if srcs.is_empty() {
+      array(headers)
+    } else if info.platforms.is_empty() {
+      array(&[headers, srcs].concat())
+    } else {
+      let systems: BTreeSet<String> =
+        info.platforms.iter().map(|p| quote(p.meson_id())).collect();
+      out += &format!("{} = {}\n", var, array(headers));
+      out += &format!(
+        "if host_machine.system() in {}\n  {} += {}\nendif\n",
+        array(&systems.into_iter().collect::<Vec<_>>()),
+        var,
+        array(srcs)
+      );
+      var
+    }
+  };
+  let mut rules = String::new();
+  if !tests && info.has_library() {
+    let srcs = files(&info.srcs);
+    let sources =
+      sources(format!("{}_srcs", target), &files(&info.headers), &srcs);
+    let deps = array(&dependencies(&mut info.deps.keys(), &info.external_deps));
+    if info.has_main {
+      rules += &format!(
+        "{} = {}",
+        target,
+        call(
+          "executable",
+          &quote(&target),
+          &[
+            ("sources", sources),
+            include.clone(),
+            ("dependencies", deps),
+            cpp_args.clone(),
+            link_args.clone(),
+          ]
+        )
+      );
+    } else if srcs.is_empty() {
+      rules += &format!(
+        "{}_dep = {}",
+        target,
+        call(
+          "declare_dependency",
+          &format!("sources: {}", sources),
+          &[include.clone(), ("dependencies", deps), link_args.clone()]
+        )
+      );
+    } else {
+      rules += &format!(
+        "{}_lib = {}",
+        target,
+        call(
+          "library",
+          &quote(&target),
+          &[
+            ("sources", sources),
+            include.clone(),
+            ("dependencies", deps.clone()),
+            cpp_args.clone(),
+          ]
+        )
+      );
+      rules += &format!(
+        "{}_dep = {}",
+        target,
+        call(
+          "declare_dependency",
+          &format!("link_with: {}_lib", target),
+          &[include.clone(), ("dependencies", deps), link_args.clone()]
+        )
+      );
+    }
+  }
+  if tests && !info.test_srcs.is_empty() {
+    let test = format!("{}Test", target);
+    let sources =
+      sources(format!("{}_srcs", test), &[], &files(&info.test_srcs));
+    let mut deps =
+      dependencies(&mut info.test_deps.iter(), &info.test_external_deps);
+    if tests_use_library(&info) {
+      deps.insert(0, format!("{}_dep", target));
+    }
+    rules += &format!(
+      "{} = {}",
+      test,
+      call(
+        "executable",
+        &quote(&test),
+        &[
+          ("sources", sources),
+          include,
+          ("dependencies", array(&deps)),
+          cpp_args,
+          link_args,
+        ]
+      )
+    );
+    rules += &format!("test({}, {})\n", quote(&test), test);
+  }
+  if rules.is_empty() {
+    return None;
+  }
+  Some(out + &rules)
+}
+
+pub fn render_meson(map: &UnitMap, opts: &BuildOptions) -> String {
+  // Components come deps first, which is the order Meson needs.
+  let units: Vec<UnitObj> = strongly_connected_components(map)
+    .into_iter()
+    .flatten()
+    .filter(|unit| opts.emits(&unit.key) && !unit.val.borrow().pre_existing)
+    .collect();
+
+  let external: BTreeSet<String> = units
+    .iter()
+    .flat_map(|unit| {
+      let info = unit.val.borrow();
+      info
+        .external_deps
+        .iter()
+        .chain(&info.test_external_deps)
+        .map(|(workspace, key)| external_dependency(workspace, key))
+        .collect::<Vec<_>>()
+    })
+    .collect();
+  let mut out = String::new();
+  for name in &external {
+    out += &format!("{} = dependency({})\n", external_var(name), quote(name));
+  }
+
+  // Tests come after every library, since a test can take a unit that
+  // is built later than its own.
+  let mut defined = HashSet::new();
+  for tests in [false, true] {
+    for unit in &units {
+      if let Some(target) = render_target(unit, opts, &defined, tests) {
+        if !out.is_empty() {
+          out += "\n";
+        }
+        out += &target;
+        let info = unit.val.borrow();
+        if !tests && tests_use_library(&info) {
+          defined.insert(unit.key.clone());
+        }
+      }
+    }
+  }
+  out
+}
+
+// Writes one meson.build for the whole tree to opts.output_dir, for the
+// project's own to subdir() into.
+pub fn export_meson<F: FileSystemProvider>(
+  fs: &F,
+  map: &UnitMap,
+  opts: &BuildOptions,
+) -> Result<(), FollyError> {
+  write_generated(fs, "meson.build", &render_meson(map, opts), opts)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::builder::GraphBuilder;
+  use crate::test_util::{build_options, scan_with};
+  use crate::types::ScanOptions;
+
+  #[test]
+  fn renders_libraries_dependencies_and_executables() {
+    let opts = ScanOptions {
+      known_roots: [("glog".to_string(), "libglog".to_string())]
+        .into_iter()
+        .collect(),
+      ..ScanOptions::default()
+    };
+    let mut map = scan_with(
+      &[
+        ("folly/Bartest.cpp", "#include <folly/Foo.h>\n"),
+        (
+          "folly/Foo.cpp",
+          "// BAZEL_COPTS: -mavx2\n#include <folly/Foo.h>\n",
+        ),
+        (
+          "folly/Foo.h",
+          "#include <folly/io/IOBuf.h>\n#include <glog/logging.h>\n",
+        ),
+        ("folly/io/IOBuf.h", "#pragma once\n"),
+      ],
+      &opts,
+    );
+    GraphBuilder::new().finish(&mut map).unwrap();
+    assert_eq!(
+      render_meson(&map, &build_options()),
+      "libglog_dep = dependency('libglog')
+
+folly_io_IoBuf_dep = declare_dependency(sources: ['folly/io/IOBuf.h'],
+  include_directories: include_directories('.'),
+)
+
+folly_Foo_lib = library('folly_Foo',
+  sources: ['folly/Foo.h', 'folly/Foo.cpp'],
+  include_directories: include_directories('.'),
+  dependencies: [folly_io_IoBuf_dep, libglog_dep],
+  cpp_args: ['-mavx2'],
+)
+folly_Foo_dep = declare_dependency(link_with: folly_Foo_lib,
+  include_directories: include_directories('.'),
+  dependencies: [folly_io_IoBuf_dep, libglog_dep],
+)
+
+folly_BarTest = executable('folly_BarTest',
+  sources: ['folly/Bartest.cpp'],
+  include_directories: include_directories('.'),
+  dependencies: [folly_Foo_dep],
+)
+test('folly_BarTest', folly_BarTest)
+"
+    );
+  }
+
+  #[test]
+  fn quotes_and_names_meson_values() {
+    assert_eq!(quote("-DX='a b'"), "'-DX=\\'a b\\''");
+    assert_eq!(quote("C:\\x"), "'C:\\\\x'");
+    assert_eq!(external_var("boost.any-c++"), "boost_any_c___dep");
+    assert_eq!(
+      external_dependency(ABSEIL_WORKSPACE, &UnitKey::new("str_cat", "absl")),
+      "absl_str_cat"
+    );
+    assert_eq!(
+      external_dependency("libglog", &UnitKey::default()),
+      "libglog"
+    );
+  }
+}
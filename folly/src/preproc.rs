@@ -0,0 +1,225 @@
+// Just enough of a C preprocessor lexer to pull the target out of an
+// #include line. Comments are skipped wherever whitespace is allowed; block
+// comments that carry over from an earlier line aren't tracked.
+
+pub enum IncludeTarget<'a> {
+  // #include <path>
+  Angled(&'a str),
+  // #include "path"
+  Quoted(&'a str),
+  // A computed include such as `#include FOO_H`, or a delimiter that is
+  // never closed.
+  Unrecognized,
+}
+
+struct Lexer<'a> {
+  line: &'a str,
+  pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+  fn rest(&self) -> &'a str {
+    &self.line[self.pos..]
+  }
+
+  // Skips whitespace and comments. A `//` comment runs to the end of the
+  // line, as does a `/*` that is never closed.
+  fn skip_blanks(&mut self) {
+    loop {
+      let rest = self.rest();
+      let trimmed = rest.trim_start();
+      self.pos += rest.len() - trimmed.len();
+      if trimmed.starts_with("//") {
+        self.pos = self.line.len();
+      } else if let Some(comment) = trimmed.strip_prefix("/*") {
+        self.pos = match comment.find("*/") {
+          Some(end) => self.pos + 2 + end + 2,
+          None => self.line.len(),
+        };
+      } else {
+        return;
+      }
+    }
+  }
+
+  fn eat(&mut self, c: char) -> bool {
+    if self.rest().starts_with(c) {
+      self.pos += c.len_utf8();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn identifier(&mut self) -> &'a str {
+    let rest = self.rest();
+    let len = rest
+      .find(|c: char| !(c == '_' || c.is_ascii_alphanumeric()))
+      .unwrap_or(rest.len());
+    self.pos += len;
+    &rest[..len]
+  }
+
+  // Everything up to the closing delimiter. Comment markers inside the
+  // delimiters are part of the path, as in the preprocessor proper.
+  fn delimited(&mut self, close: char) -> Option<&'a str> {
+    let rest = self.rest();
+    let end = rest.find(close)?;
+    self.pos += end + close.len_utf8();
+    Some(&rest[..end])
+  }
+}
+
+// Returns None for anything other than #include or #include_next, which are
+// the same as far as the dependency graph is concerned.
+pub fn lex_include(line: &str) -> Option<IncludeTarget<'_>> {
+  let mut lexer = Lexer { line, pos: 0 };
+  lexer.skip_blanks();
+  if !lexer.eat('#') {
+    return None;
+  }
+  lexer.skip_blanks();
+  match lexer.identifier() {
+    "include" | "include_next" => {}
+    _ => return None,
+  }
+  lexer.skip_blanks();
+
+  let target = if lexer.eat('<') {
+    lexer.delimited('>').map(IncludeTarget::Angled)
+  } else if lexer.eat('"') {
+    lexer.delimited('"').map(IncludeTarget::Quoted)
+  } else {
+    None
+  };
+  Some(target.unwrap_or(IncludeTarget::Unrecognized))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{dep_keys, scan};
+  use crate::types::{ScanOptions, UnitKey};
+  use crate::util::{strip_include, HeaderLib};
+
+  const EXTERN_C: &str = "#pragma once
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+  #include <folly/portability/Config.h>
+\t#include \"folly/CPortability.h\"
+#ifdef __cplusplus
+}
+#endif
+";
+
+  #[test]
+  fn includes_inside_extern_c_keep_their_targets() {
+    let targets: Vec<&str> = EXTERN_C
+      .lines()
+      .filter_map(lex_include)
+      .map(|target| match target {
+        IncludeTarget::Angled(path) | IncludeTarget::Quoted(path) => path,
+        IncludeTarget::Unrecognized => "?",
+      })
+      .collect();
+    assert_eq!(
+      targets,
+      ["folly/portability/Config.h", "folly/CPortability.h"]
+    );
+
+    let opts = ScanOptions::default();
+    let (key, lib) =
+      strip_include("  #include <folly/portability/Config.h>", &opts).unwrap();
+    assert_eq!(key, UnitKey::new("config", "folly/portability"));
+    assert!(lib == HeaderLib::FOLLY);
+  }
+
+  #[test]
+  fn extern_c_includes_are_deps() {
+    let map = scan(&[
+      ("folly/CWrapper.h", EXTERN_C),
+      ("folly/portability/Config.h", "#pragma once\n"),
+      ("folly/CPortability.h", "#pragma once\n"),
+    ]);
+    assert_eq!(
+      dep_keys(&map, "folly/c_wrapper"),
+      ["folly/portability/config", "folly/c_portability"]
+    );
+  }
+
+  // The target as it was written, delimiters included.
+  fn lexed(line: &str) -> Option<String> {
+    lex_include(line).map(|target| match target {
+      IncludeTarget::Angled(path) => format!("<{}>", path),
+      IncludeTarget::Quoted(path) => format!("\"{}\"", path),
+      IncludeTarget::Unrecognized => "?".to_string(),
+    })
+  }
+
+  #[test]
+  fn skips_comments_around_the_target() {
+    for (line, target) in [
+      ("#include /* comment */ <folly/Foo.h>", "<folly/Foo.h>"),
+      ("#include <folly/Foo.h> // note", "<folly/Foo.h>"),
+      ("#include \"folly/Foo.h\" /* note */", "\"folly/Foo.h\""),
+      ("#/**/include/**/<folly/Foo.h>", "<folly/Foo.h>"),
+      ("/* a */ # /* b */ include <folly/Foo.h>", "<folly/Foo.h>"),
+      ("#include <folly//Foo.h>", "<folly//Foo.h>"),
+    ] {
+      assert_eq!(lexed(line).as_deref(), Some(target), "{}", line);
+    }
+  }
+
+  #[test]
+  fn allows_any_whitespace_between_tokens() {
+    for line in [
+      "#include    <folly/Foo.h>",
+      "  #  include <folly/Foo.h>",
+      "#include\t<folly/Foo.h>",
+      "\t#\tinclude\t\t<folly/Foo.h>   ",
+    ] {
+      assert_eq!(lexed(line).as_deref(), Some("<folly/Foo.h>"), "{}", line);
+    }
+  }
+
+  #[test]
+  fn commented_out_includes_are_not_includes() {
+    for line in [
+      "// #include <folly/Foo.h>",
+      "/* #include <folly/Foo.h> */",
+      "#define FOO_H <folly/Foo.h>",
+      "#includes <folly/Foo.h>",
+      "",
+    ] {
+      assert_eq!(lexed(line), None, "{}", line);
+    }
+  }
+
+  #[test]
+  fn unterminated_or_computed_targets_are_unrecognized() {
+    for line in [
+      "#include FOO_H",
+      "#include <folly/Foo.h",
+      "#include \"folly/Foo.h",
+      "#include // no target",
+      "#include /* never closed <folly/Foo.h>",
+    ] {
+      assert_eq!(lexed(line).as_deref(), Some("?"), "{}", line);
+    }
+  }
+
+  #[test]
+  fn commented_includes_are_not_deps() {
+    let map = scan(&[
+      (
+        "folly/Foo.h",
+        "// #include <folly/Old.h>\n\
+         #include /* new */ <folly/New.h> // see below\n",
+      ),
+      ("folly/New.h", "#pragma once\n"),
+      ("folly/Old.h", "#pragma once\n"),
+    ]);
+    assert_eq!(dep_keys(&map, "folly/foo"), ["folly/new"]);
+  }
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+struct Phase {
+  name: String,
+  started: Option<Instant>,
+  elapsed: Duration,
+}
+
+// Wall-clock time spent in each phase of a run, for --profile. A phase is
+// timed from start_phase to the end_phase of the same name, and one that
+// runs more than once adds up. Phases print in the order they first
+// started.
+#[derive(Default)]
+pub struct Profiler {
+  phases: RefCell<Vec<Phase>>,
+}
+
+impl Profiler {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn start_phase(&self, name: &str) {
+    let mut phases = self.phases.borrow_mut();
+    match phases.iter_mut().find(|phase| phase.name == name) {
+      Some(phase) => phase.started = Some(Instant::now()),
+      None => phases.push(Phase {
+        name: name.to_string(),
+        started: Some(Instant::now()),
+        elapsed: Duration::ZERO,
+      }),
+    }
+  }
+
+  // A phase that was never started, or has already ended, is left alone.
+  pub fn end_phase(&self, name: &str) {
+    let mut phases = self.phases.borrow_mut();
+    if let Some(phase) = phases.iter_mut().find(|phase| phase.name == name) {
+      if let Some(started) = phase.started.take() {
+        phase.elapsed += started.elapsed();
+      }
+    }
+  }
+}
+
+impl fmt::Display for Profiler {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    const WIDTH: usize = 20;
+    let phases = self.phases.borrow();
+    let ms = |elapsed: Duration| elapsed.as_secs_f64() * 1000.0;
+    writeln!(f, "{:<WIDTH$}{:>10}", "Phase", "ms")?;
+    for phase in phases.iter() {
+      writeln!(f, "{:<WIDTH$}{:>10.1}", phase.name, ms(phase.elapsed))?;
+    }
+    let total: Duration = phases.iter().map(|phase| phase.elapsed).sum();
+    writeln!(f, "{:<WIDTH$}{:>10.1}", "Total", ms(total))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread::sleep;
+
+  // The (name, ms) rows of the table, without its header and total.
+  fn rows(profiler: &Profiler) -> Vec<(String, f64)> {
+    let table = profiler.to_string();
+    let lines: Vec<&str> = table.lines().collect();
+    assert!(lines[0].starts_with("Phase"), "{}", table);
+    assert!(lines[lines.len() - 1].starts_with("Total"), "{}", table);
+    lines[1..lines.len() - 1]
+      .iter()
+      .map(|line| {
+        let (name, ms) = line.rsplit_once(' ').unwrap();
+        (name.trim_end().to_string(), ms.parse().unwrap())
+      })
+      .collect()
+  }
+
+  fn names(profiler: &Profiler) -> Vec<String> {
+    rows(profiler).into_iter().map(|(name, _)| name).collect()
+  }
+
+  #[test]
+  fn records_each_phase_in_the_order_it_started() {
+    let profiler = Profiler::new();
+    assert!(names(&profiler).is_empty());
+    for name in ["scan", "collapse cycles", "generate trie", "write"] {
+      profiler.start_phase(name);
+      profiler.end_phase(name);
+    }
+    assert_eq!(
+      names(&profiler),
+      ["scan", "collapse cycles", "generate trie", "write"]
+    );
+  }
+
+  #[test]
+  fn a_phase_run_twice_adds_up() {
+    let profiler = Profiler::new();
+    for _ in 0..2 {
+      profiler.start_phase("scan");
+      sleep(Duration::from_millis(5));
+      profiler.end_phase("scan");
+    }
+    let rows = rows(&profiler);
+    assert_eq!(rows.len(), 1);
+    assert!(rows[0].1 >= 10.0, "{:?}", rows);
+  }
+
+  #[test]
+  fn ending_a_phase_that_is_not_running_does_nothing() {
+    let profiler = Profiler::new();
+    profiler.end_phase("scan");
+    assert!(names(&profiler).is_empty());
+
+    profiler.start_phase("scan");
+    profiler.end_phase("scan");
+    sleep(Duration::from_millis(5));
+    profiler.end_phase("scan");
+    assert!(rows(&profiler)[0].1 < 5.0);
+  }
+}
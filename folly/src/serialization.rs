@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use indexmap::IndexSet;
+use serde::de::Deserializer;
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::graph::sorted_nodes;
+use crate::intrusive_hashmap::{
+  IntrusiveRefCell, MutateExtract, Shared, WeakHashObj,
+};
+use crate::types::*;
+use crate::util::{Condition, Platform};
+
+// The Rc graph can't be written out as-is, so edges are stored as the keys
+// of the nodes they point at and re-linked against the map on the way in.
+#[derive(Serialize, Deserialize)]
+struct UnitRecord {
+  headers: Vec<String>,
+  srcs: Vec<String>,
+  #[serde(default)]
+  protos: Vec<String>,
+  #[serde(default)]
+  test_srcs: Vec<String>,
+  is_test: bool,
+  #[serde(default)]
+  has_main: bool,
+  #[serde(default)]
+  is_objc: bool,
+  #[serde(default)]
+  missing_include_guard: bool,
+  #[serde(default)]
+  generated: bool,
+  #[serde(default)]
+  platforms: Vec<Platform>,
+  #[serde(default)]
+  pre_existing: bool,
+  #[serde(default)]
+  existing_name: Option<String>,
+  deps: Vec<UnitKey>,
+  // Which of deps are SOURCE_INCLUDEs; the rest are INCLUDEs.
+  #[serde(default)]
+  source_deps: Vec<UnitKey>,
+  // And which are only included under an #if, and which.
+  #[serde(default)]
+  conditions: Vec<(UnitKey, Condition)>,
+  #[serde(default)]
+  test_deps: Vec<UnitKey>,
+  reverse_deps: Vec<UnitKey>,
+  #[serde(default)]
+  using_hints: Vec<String>,
+  #[serde(default)]
+  forward_decl_hints: Vec<String>,
+  #[serde(default)]
+  build_flags: Vec<String>,
+  #[serde(default)]
+  link_opts: Vec<String>,
+  external_deps: Vec<(String, UnitKey)>,
+  #[serde(default)]
+  test_external_deps: Vec<(String, UnitKey)>,
+  #[serde(default)]
+  test_includes: Vec<UnitKey>,
+  mtimes: HashMap<String, SystemTime>,
+}
+
+fn sorted_keys(keys: impl Iterator<Item = UnitKey>) -> Vec<UnitKey> {
+  let mut keys: Vec<UnitKey> = keys.collect();
+  keys.sort();
+  keys
+}
+
+impl From<&UnitInfo<UnitKey>> for UnitRecord {
+  fn from(info: &UnitInfo<UnitKey>) -> Self {
+    UnitRecord {
+      headers: info.headers.clone(),
+      srcs: info.srcs.clone(),
+      protos: info.protos.clone(),
+      test_srcs: info.test_srcs.clone(),
+      is_test: info.is_test,
+      has_main: info.has_main,
+      is_objc: info.is_objc,
+      missing_include_guard: info.missing_include_guard,
+      generated: info.generated,
+      platforms: info.platforms.clone(),
+      pre_existing: info.pre_existing,
+      existing_name: info.existing_name.clone(),
+      // deps keep their order; see UnitInfo. A graph is saved as scanned,
+      // when every dep is one include or the other.
+      deps: info.deps.keys().map(|dep| dep.key.clone()).collect(),
+      source_deps: sorted_keys(
+        info
+          .deps
+          .iter()
+          .filter(|(_, kind)| **kind == UnitDepKind::SOURCE_INCLUDE)
+          .map(|(dep, _)| dep.key.clone()),
+      ),
+      conditions: info
+        .conditions
+        .iter()
+        .map(|(key, condition)| (key.clone(), condition.clone()))
+        .collect(),
+      test_deps: info.test_deps.iter().map(|dep| dep.key.clone()).collect(),
+      reverse_deps: sorted_keys(
+        info
+          .reverse_deps
+          .iter()
+          .filter_map(|rdep| rdep.upgrade())
+          .map(|rdep| rdep.key.clone()),
+      ),
+      using_hints: info.using_hints.clone(),
+      forward_decl_hints: info.forward_decl_hints.clone(),
+      build_flags: info.build_flags.clone(),
+      link_opts: info.link_opts.clone(),
+      external_deps: info.external_deps.iter().cloned().collect(),
+      test_external_deps: info.test_external_deps.iter().cloned().collect(),
+      test_includes: info.test_includes.iter().cloned().collect(),
+      mtimes: info.mtimes.clone(),
+    }
+  }
+}
+
+// deps point at detached placeholder nodes until relinked by deserialize.
+// reverse_deps are left empty since a weak placeholder would dangle at
+// once; they are rebuilt from deps instead.
+impl From<UnitRecord> for UnitInfo<UnitKey> {
+  fn from(record: UnitRecord) -> Self {
+    let placeholder = |key| Shared::new(IntrusiveRefCell::from(key));
+    UnitInfo {
+      headers: record.headers,
+      srcs: record.srcs,
+      protos: record.protos,
+      test_srcs: record.test_srcs,
+      is_test: record.is_test,
+      has_main: record.has_main,
+      is_objc: record.is_objc,
+      missing_include_guard: record.missing_include_guard,
+      generated: record.generated,
+      platforms: record.platforms,
+      pre_existing: record.pre_existing,
+      existing_name: record.existing_name,
+      deps: record
+        .deps
+        .into_iter()
+        .map(|key| {
+          let kind = if record.source_deps.contains(&key) {
+            UnitDepKind::SOURCE_INCLUDE
+          } else {
+            UnitDepKind::INCLUDE
+          };
+          (placeholder(key), kind)
+        })
+        .collect(),
+      conditions: record.conditions.into_iter().collect(),
+      test_deps: record.test_deps.into_iter().map(placeholder).collect(),
+      reverse_deps: IndexSet::new(),
+      // Derived from reverse_deps, so recomputed rather than stored.
+      reverse_dep_count: 0,
+      visibility: Visibility::default(),
+      using_hints: record.using_hints,
+      forward_decl_hints: record.forward_decl_hints,
+      build_flags: record.build_flags,
+      link_opts: record.link_opts,
+      // Resolved from using_hints again after collapsing.
+      soft_deps: IndexSet::new(),
+      external_deps: record.external_deps.into_iter().collect(),
+      test_external_deps: record.test_external_deps.into_iter().collect(),
+      test_includes: record.test_includes.into_iter().collect(),
+      mtimes: record.mtimes,
+    }
+  }
+}
+
+impl Serialize for UnitInfo<UnitKey> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    UnitRecord::from(self).serialize(serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for UnitInfo<UnitKey> {
+  fn deserialize<D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    UnitRecord::deserialize(deserializer).map(UnitInfo::from)
+  }
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+  key: &'a UnitKey,
+  info: &'a UnitInfo<UnitKey>,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+  key: UnitKey,
+  info: UnitInfo<UnitKey>,
+}
+
+// serialize/deserialize follow serde's `with` convention, so a struct can
+// hold a UnitMap field tagged #[serde(with = "crate::serialization")].
+pub fn serialize<S: Serializer>(
+  map: &UnitMap,
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  let mut seq = serializer.serialize_seq(Some(map.len()))?;
+  for node in sorted_nodes(map) {
+    seq.serialize_element(&EntryRef {
+      key: &node.key,
+      info: &node.val.borrow(),
+    })?;
+  }
+  seq.end()
+}
+
+// A graph that shares no nodes with map, so that either can be changed
+// without the other seeing it.
+#[cfg(feature = "watch")]
+pub fn deep_copy(map: &UnitMap) -> Result<UnitMap, serde_json::Error> {
+  deserialize(serialize(map, serde_json::value::Serializer)?)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<UnitMap, D::Error> {
+  let entries = Vec::<Entry>::deserialize(deserializer)?;
+  let mut map = UnitMap::new();
+  let mut nodes = Vec::with_capacity(entries.len());
+  for entry in entries {
+    let node = map.extract_with_create(entry.key);
+    *node.val.borrow_mut() = entry.info;
+    nodes.push(node);
+  }
+
+  // Swap every placeholder for the node of the same key in the map.
+  for node in &nodes {
+    let deps: Vec<(UnitKey, UnitDepKind)> = node
+      .val
+      .borrow_mut()
+      .deps
+      .drain(..)
+      .map(|(dep, kind)| (dep.key.clone(), kind))
+      .collect();
+    let deps = deps
+      .into_iter()
+      .map(|(k, kind)| (map.extract_with_create(k), kind))
+      .collect();
+    let mut info = node.val.borrow_mut();
+    info.deps = deps;
+    let test_deps: Vec<UnitKey> = info
+      .test_deps
+      .drain(..)
+      .map(|dep| dep.key.clone())
+      .collect();
+    info.test_deps = test_deps
+      .into_iter()
+      .map(|key| map.extract_with_create(key))
+      .collect();
+  }
+  for node in &nodes {
+    let info = node.val.borrow();
+    for dep in info.deps.keys().chain(&info.test_deps) {
+      dep
+        .val
+        .borrow_mut()
+        .reverse_deps
+        .insert(WeakHashObj::from(node));
+    }
+  }
+  Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{dep_keys, keys, scan, unit};
+
+  fn round_trip(map: &UnitMap) -> UnitMap {
+    let json = serialize(map, serde_json::value::Serializer).unwrap();
+    deserialize(json).unwrap()
+  }
+
+  #[test]
+  fn round_trip_keeps_files_edges_and_kinds() {
+    let map = scan(&[
+      (
+        "folly/Foo.h",
+        "#pragma once\n#include <folly/Bar.h>\n#ifdef X\n#include <folly/Baz.h>\n#endif\n",
+      ),
+      (
+        "folly/Foo.cpp",
+        "// BAZEL_COPTS: -DFOO\n#include <folly/Qux.h>\nint main() {}\n",
+      ),
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Baz.h", "#pragma once\n"),
+      ("folly/Qux.h", "#pragma once\n"),
+      ("folly/Bartest.cpp", "#include <folly/Foo.h>\n"),
+    ]);
+    let copy = round_trip(&map);
+    assert_eq!(keys(&copy), keys(&map));
+    for name in ["foo", "bar"] {
+      let path = format!("folly/{}", name);
+      assert_eq!(dep_keys(&copy, &path), dep_keys(&map, &path));
+    }
+
+    let foo = unit(&copy, "folly/foo");
+    let info = foo.val.borrow();
+    assert_eq!(info.headers, ["Foo.h"]);
+    assert_eq!(info.srcs, ["Foo.cpp"]);
+    assert!(info.has_main);
+    assert_eq!(info.build_flags, ["-DFOO"]);
+    let kinds: Vec<(String, UnitDepKind)> = info
+      .deps
+      .iter()
+      .map(|(dep, kind)| (dep.key.name.clone(), *kind))
+      .collect();
+    assert_eq!(
+      kinds,
+      [
+        ("qux".to_string(), UnitDepKind::SOURCE_INCLUDE),
+        ("bar".to_string(), UnitDepKind::INCLUDE),
+        ("baz".to_string(), UnitDepKind::INCLUDE),
+      ]
+    );
+    let conditions: Vec<(&str, &Condition)> = info
+      .conditions
+      .iter()
+      .map(|(key, condition)| (key.name.as_str(), condition))
+      .collect();
+    assert_eq!(
+      conditions,
+      [(
+        "baz",
+        &Condition {
+          define: "X".to_string(),
+          defined: true,
+        }
+      )]
+    );
+    // Re-linked to the copy's own nodes, which know who includes them.
+    let bar = info.deps.get_index(1).unwrap().0;
+    assert!(Shared::ptr_eq(bar, &unit(&copy, "folly/bar")));
+    let bar_info = bar.val.borrow();
+    assert!(bar_info.is_test);
+    assert_eq!(bar_info.test_deps.len(), 1);
+    let rdeps: Vec<UnitKey> = bar_info
+      .reverse_deps
+      .iter()
+      .filter_map(WeakHashObj::upgrade)
+      .map(|rdep| rdep.key.clone())
+      .collect();
+    assert_eq!(rdeps, [UnitKey::new("foo", "folly")]);
+  }
+
+  #[test]
+  fn serializes_units_in_key_order() {
+    let map = scan(&[
+      ("folly/Z.h", "#pragma once\n"),
+      ("folly/io/A.h", "#pragma once\n"),
+      ("folly/A.h", "#pragma once\n"),
+      ("folly/io/async/B.h", "#pragma once\n"),
+      ("folly/M.h", "#pragma once\n"),
+    ]);
+    let json = serialize(&map, serde_json::value::Serializer).unwrap();
+    let serialized: Vec<String> = json
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|entry| {
+        let key = &entry["key"];
+        let field = |name: &str| key[name].as_str().unwrap().to_string();
+        format!("{}/{}", field("root_dir"), field("name"))
+      })
+      .collect();
+    assert_eq!(
+      serialized,
+      [
+        "folly/a",
+        "folly/m",
+        "folly/z",
+        "folly/io/a",
+        "folly/io/async/b"
+      ]
+    );
+  }
+}
@@ -0,0 +1,485 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use crate::error::FollyError;
+
+// The subset of Starlark that BUILD, BUCK, WORKSPACE and MODULE.bazel files
+// are written in: top-level calls and assignments whose values are strings,
+// numbers, names, lists, tuples, dicts, calls, and arithmetic on those.
+// Comments are dropped. Anything else, such as def, if or a comprehension,
+// is a SyntaxError rather than something to guess at.
+
+enum Token {
+  // Identifiers, dotted names and numbers alike.
+  Word(String),
+  // With quotes removed and escapes undone.
+  Str(String),
+  Punct(char),
+}
+
+// line is 1-based.
+#[derive(Debug)]
+pub struct SyntaxError {
+  pub line: usize,
+  pub message: String,
+}
+
+impl SyntaxError {
+  // Once the caller knows which file this came from.
+  pub fn at(self, path: &Path) -> FollyError {
+    FollyError::ParseError {
+      file: path.to_path_buf(),
+      line: self.line,
+      message: self.message,
+    }
+  }
+}
+
+impl fmt::Display for SyntaxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.message)
+  }
+}
+
+fn tokenize(contents: &str) -> Result<Vec<(Token, usize)>, SyntaxError> {
+  let mut tokens = Vec::new();
+  let mut chars = contents.chars().peekable();
+  let mut line = 1;
+  while let Some(c) = chars.next() {
+    let start = line;
+    if c == '\n' {
+      line += 1;
+    } else if c.is_whitespace() || c == '\\' {
+      // A backslash can only be continuing the line, which whitespace
+      // does anyway.
+    } else if c == '#' {
+      while chars.next_if(|c| *c != '\n').is_some() {}
+    } else if c == '"' || c == '\'' {
+      // """ and ''' strings run to the next three quotes, newlines and all.
+      let triple = chars.next_if_eq(&c).is_some();
+      if triple && chars.next_if_eq(&c).is_none() {
+        tokens.push((Token::Str(String::new()), start));
+        continue;
+      }
+      let mut s = String::new();
+      // How many of the quotes just before next were closing ones.
+      let mut quotes = 0;
+      loop {
+        let Some(mut next) = chars.next() else {
+          return Err(SyntaxError {
+            line: start,
+            message: "unterminated string".to_string(),
+          });
+        };
+        if next == '\\' {
+          next = chars.next().unwrap_or(next);
+          quotes = 0;
+        } else if next == c {
+          quotes += 1;
+          if !triple || quotes == 3 {
+            break;
+          }
+          continue;
+        } else if next == '\n' && !triple {
+          return Err(SyntaxError {
+            line: start,
+            message: "newline in string".to_string(),
+          });
+        }
+        // Quotes that turned out not to close the string are part of it.
+        for _ in 0..std::mem::take(&mut quotes) {
+          s.push(c);
+        }
+        if next == '\n' {
+          line += 1;
+        }
+        s.push(next);
+      }
+      tokens.push((Token::Str(s), start));
+    } else if c == '_' || c.is_ascii_alphanumeric() {
+      let mut word = c.to_string();
+      while let Some(c) =
+        chars.next_if(|c| *c == '_' || *c == '.' || c.is_ascii_alphanumeric())
+      {
+        word.push(c);
+      }
+      tokens.push((Token::Word(word), start));
+    } else {
+      tokens.push((Token::Punct(c), start));
+    }
+  }
+  Ok(tokens)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StarlarkValue {
+  Str(String),
+  // A name such as True or a dotted one such as native.glob, or a number,
+  // as written.
+  Ident(String),
+  List(Vec<StarlarkValue>),
+  Tuple(Vec<StarlarkValue>),
+  Dict(Vec<(StarlarkValue, StarlarkValue)>),
+  Call {
+    function: String,
+    // Positional arguments have no name.
+    args: Vec<(Option<String>, StarlarkValue)>,
+  },
+  // One of + - * %, e.g. a glob() plus a list.
+  BinaryOp(char, Box<StarlarkValue>, Box<StarlarkValue>),
+}
+
+impl StarlarkValue {
+  // Every string literal in the value, however deeply nested, in order.
+  // For an attribute such as hdrs that is what matters, whether it is a
+  // list, a glob() or a select(). Dict keys are left out, since in a
+  // select() they are conditions rather than files or labels.
+  pub fn strings(&self) -> Vec<&str> {
+    let mut out = Vec::new();
+    self.collect_strings(&mut out);
+    out
+  }
+
+  fn collect_strings<'a>(&'a self, out: &mut Vec<&'a str>) {
+    match self {
+      StarlarkValue::Str(s) => out.push(s),
+      StarlarkValue::Ident(_) => {}
+      StarlarkValue::List(items) | StarlarkValue::Tuple(items) => {
+        for item in items {
+          item.collect_strings(out);
+        }
+      }
+      StarlarkValue::Dict(entries) => {
+        for (_, value) in entries {
+          value.collect_strings(out);
+        }
+      }
+      StarlarkValue::Call { args, .. } => {
+        for (_, value) in args {
+          value.collect_strings(out);
+        }
+      }
+      StarlarkValue::BinaryOp(_, lhs, rhs) => {
+        lhs.collect_strings(out);
+        rhs.collect_strings(out);
+      }
+    }
+  }
+}
+
+// A top-level call with a string name, e.g. cc_library(name = "Foo", ...).
+// Positional arguments, as load() has, don't make it into attrs.
+#[derive(Debug)]
+pub struct StarlarkRule {
+  pub name: String,
+  pub rule_type: String,
+  pub attrs: HashMap<String, StarlarkValue>,
+}
+
+impl StarlarkRule {
+  // The strings under any of attrs, as one set.
+  pub fn strings(&self, attrs: &[&str]) -> BTreeSet<&str> {
+    attrs
+      .iter()
+      .filter_map(|attr| self.attrs.get(*attr))
+      .flat_map(StarlarkValue::strings)
+      .collect()
+  }
+
+  // For attributes that hold a single string.
+  pub fn string(&self, attr: &str) -> Option<&str> {
+    match self.attrs.get(attr)? {
+      StarlarkValue::Str(s) => Some(s),
+      _ => None,
+    }
+  }
+}
+
+struct Parser {
+  tokens: Vec<(Token, usize)>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos).map(|(token, _)| token)
+  }
+
+  fn error(&self, message: &str) -> SyntaxError {
+    let line = match self.tokens.get(self.pos).or(self.tokens.last()) {
+      Some((_, line)) => *line,
+      None => 1,
+    };
+    let found = match self.peek() {
+      Some(Token::Word(word)) => format!("`{}`", word),
+      Some(Token::Str(_)) => "a string".to_string(),
+      Some(Token::Punct(c)) => format!("`{}`", c),
+      None => "the end of the file".to_string(),
+    };
+    SyntaxError {
+      line,
+      message: format!("expected {}, found {}", message, found),
+    }
+  }
+
+  fn at_punct(&self, c: char) -> bool {
+    matches!(self.peek(), Some(Token::Punct(p)) if *p == c)
+  }
+
+  fn expect(&mut self, c: char) -> Result<(), SyntaxError> {
+    if !self.at_punct(c) {
+      return Err(self.error(&format!("`{}`", c)));
+    }
+    self.pos += 1;
+    Ok(())
+  }
+
+  // Items up to close, each separated by a `,`, with one allowed after the
+  // last.
+  fn items<T>(
+    &mut self,
+    close: char,
+    mut item: impl FnMut(&mut Self) -> Result<T, SyntaxError>,
+  ) -> Result<Vec<T>, SyntaxError> {
+    let mut items = Vec::new();
+    while !self.at_punct(close) {
+      items.push(item(self)?);
+      if !self.at_punct(close) {
+        self.expect(',')?;
+      }
+    }
+    self.pos += 1;
+    Ok(items)
+  }
+
+  fn expr(&mut self) -> Result<StarlarkValue, SyntaxError> {
+    let mut value = self.primary()?;
+    while let Some(Token::Punct(op @ ('+' | '-' | '*' | '%'))) = self.peek() {
+      let op = *op;
+      self.pos += 1;
+      value =
+        StarlarkValue::BinaryOp(op, Box::new(value), Box::new(self.primary()?));
+    }
+    Ok(value)
+  }
+
+  fn primary(&mut self) -> Result<StarlarkValue, SyntaxError> {
+    let value = match self.peek() {
+      Some(Token::Str(s)) => StarlarkValue::Str(s.clone()),
+      Some(Token::Word(word)) => StarlarkValue::Ident(word.clone()),
+      Some(Token::Punct('[')) => {
+        self.pos += 1;
+        return Ok(StarlarkValue::List(self.items(']', Self::expr)?));
+      }
+      Some(Token::Punct('(')) => {
+        self.pos += 1;
+        return Ok(StarlarkValue::Tuple(self.items(')', Self::expr)?));
+      }
+      Some(Token::Punct('{')) => {
+        self.pos += 1;
+        let entries = self.items('}', |p| {
+          let key = p.expr()?;
+          p.expect(':')?;
+          Ok((key, p.expr()?))
+        })?;
+        return Ok(StarlarkValue::Dict(entries));
+      }
+      Some(Token::Punct('-')) => {
+        self.pos += 1;
+        return match self.primary()? {
+          StarlarkValue::Ident(word) => {
+            Ok(StarlarkValue::Ident(format!("-{}", word)))
+          }
+          _ => Err(self.error("a number after `-`")),
+        };
+      }
+      _ => return Err(self.error("a value")),
+    };
+    self.pos += 1;
+    // Adjacent strings are one string, as in Python.
+    if let StarlarkValue::Str(mut s) = value {
+      while let Some(Token::Str(next)) = self.peek() {
+        s += next;
+        self.pos += 1;
+      }
+      return Ok(StarlarkValue::Str(s));
+    }
+    let StarlarkValue::Ident(function) = value else {
+      unreachable!()
+    };
+    if !self.at_punct('(') {
+      return Ok(StarlarkValue::Ident(function));
+    }
+    self.pos += 1;
+    let args = self.items(')', |p| {
+      let name = match (p.peek(), p.tokens.get(p.pos + 1)) {
+        (Some(Token::Word(name)), Some((Token::Punct('='), _))) => {
+          let name = name.clone();
+          p.pos += 2;
+          Some(name)
+        }
+        _ => None,
+      };
+      Ok((name, p.expr()?))
+    })?;
+    Ok(StarlarkValue::Call { function, args })
+  }
+
+  // A statement is an expression, or `name = expression`; only calls
+  // with a string name come back as rules.
+  fn statements(&mut self) -> Result<Vec<StarlarkRule>, SyntaxError> {
+    let mut rules = Vec::new();
+    while self.peek().is_some() {
+      if let (Some(Token::Word(_)), Some((Token::Punct('='), _))) =
+        (self.peek(), self.tokens.get(self.pos + 1))
+      {
+        self.pos += 2;
+        self.expr()?;
+        continue;
+      }
+      let StarlarkValue::Call { function, args } = self.expr()? else {
+        continue;
+      };
+      let attrs: HashMap<String, StarlarkValue> = args
+        .into_iter()
+        .filter_map(|(name, value)| Some((name?, value)))
+        .collect();
+      if let Some(StarlarkValue::Str(name)) = attrs.get("name") {
+        rules.push(StarlarkRule {
+          name: name.clone(),
+          rule_type: function,
+          attrs,
+        });
+      }
+    }
+    Ok(rules)
+  }
+}
+
+pub fn parse_build_file(
+  contents: &str,
+) -> Result<Vec<StarlarkRule>, SyntaxError> {
+  let tokens = tokenize(contents)?;
+  Parser { tokens, pos: 0 }.statements()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse_one(contents: &str) -> StarlarkRule {
+    let mut rules = parse_build_file(contents).unwrap();
+    assert_eq!(rules.len(), 1, "{:?}", rules);
+    rules.pop().unwrap()
+  }
+
+  fn str(s: &str) -> StarlarkValue {
+    StarlarkValue::Str(s.to_string())
+  }
+
+  fn error_line(contents: &str) -> (usize, String) {
+    let err = parse_build_file(contents).unwrap_err();
+    (err.line, err.message)
+  }
+
+  #[test]
+  fn strings_join_and_undo_escapes() {
+    let rule = parse_one(
+      "cc_library(\n\
+       name = \"Fo\" 'o',\n\
+       copts = [\"a\\\"b\", 'c\\\\d', \"\", '''x'y\n\"z'''],\n\
+       )\n",
+    );
+    assert_eq!(rule.name, "Foo");
+    assert_eq!(
+      rule.attrs["copts"],
+      StarlarkValue::List(vec![
+        str("a\"b"),
+        str("c\\d"),
+        str(""),
+        str("x'y\n\"z")
+      ])
+    );
+  }
+
+  #[test]
+  fn reads_assignments_and_arithmetic_on_globs_and_selects() {
+    let rules = parse_build_file(
+      "load(\"@rules_cc//cc:defs.bzl\", \"cc_library\")\n\
+       COPTS = [\"-Wall\"]\n\
+       cc_library(\n\
+       name = \"Foo\",\n\
+       srcs = glob([\"*.cpp\"], exclude = [\"Old.cpp\"]) + [\"Extra.cpp\"],\n\
+       deps = [\":Bar\"] + select({\n\
+       \":linux\": [\":Linux\"],\n\
+       \"//conditions:default\": [],\n\
+       }),\n\
+       copts = COPTS,\n\
+       )\n",
+    )
+    .unwrap();
+    // Neither load() nor the assignment is a rule.
+    assert_eq!(rules.len(), 1);
+    let rule = &rules[0];
+    assert_eq!(rule.rule_type, "cc_library");
+    assert!(matches!(
+      rule.attrs["srcs"],
+      StarlarkValue::BinaryOp('+', ..)
+    ));
+    assert_eq!(
+      rule.attrs["copts"],
+      StarlarkValue::Ident("COPTS".to_string())
+    );
+    // Exclusions are strings too, and select() conditions are not.
+    assert_eq!(
+      rule
+        .strings(&["srcs", "deps"])
+        .into_iter()
+        .collect::<Vec<_>>(),
+      ["*.cpp", ":Bar", ":Linux", "Extra.cpp", "Old.cpp"]
+    );
+  }
+
+  #[test]
+  fn only_calls_with_a_string_name_are_rules() {
+    let rules = parse_build_file(
+      "package(default_visibility = [\"//visibility:public\"])\n\
+       exports_files([\"LICENSE\"])\n\
+       cc_library(name = NAME)\n\
+       filegroup(name = \"Docs\")\n",
+    )
+    .unwrap();
+    assert_eq!(
+      rules
+        .iter()
+        .map(|rule| rule.name.as_str())
+        .collect::<Vec<_>>(),
+      ["Docs"]
+    );
+  }
+
+  #[test]
+  fn syntax_errors_name_the_line() {
+    assert_eq!(
+      error_line(
+        "cc_library(\n    name = \"Foo\",\n    srcs = []\n    deps = [],\n)\n"
+      ),
+      (4, "expected `,`, found `deps`".to_string())
+    );
+    assert_eq!(
+      error_line("x = 1\ny = \"abc\n"),
+      (2, "newline in string".to_string())
+    );
+    assert_eq!(
+      error_line("x = 1\ny = \"\"\"abc\n\ndef\n"),
+      (2, "unterminated string".to_string())
+    );
+    assert_eq!(
+      error_line("cc_library(\n    name = \"Foo\",\n"),
+      (2, "expected a value, found the end of the file".to_string())
+    );
+    let err = parse_build_file("def f():\n    pass\n").unwrap_err();
+    assert_eq!(err.line, 1);
+  }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::types::{UnitKey, UnitMap};
+
+#[derive(Default)]
+pub struct GraphStats {
+  pub nodes: usize,
+  // Sum of every unit's deps; external deps aren't counted.
+  pub edges: usize,
+  pub max_out_degree: Option<(UnitKey, usize)>,
+  pub max_in_degree: Option<(UnitKey, usize)>,
+}
+
+fn write_degree(
+  f: &mut fmt::Formatter<'_>,
+  label: &str,
+  degree: &Option<(UnitKey, usize)>,
+) -> fmt::Result {
+  match degree {
+    Some((key, n)) => writeln!(f, "{}: {} ({})", label, n, key),
+    None => writeln!(f, "{}: none", label),
+  }
+}
+
+impl fmt::Display for GraphStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Nodes: {}", self.nodes)?;
+    writeln!(f, "Edges: {}", self.edges)?;
+    write_degree(f, "Max out-degree", &self.max_out_degree)?;
+    write_degree(f, "Max in-degree", &self.max_in_degree)
+  }
+}
+
+// Ties go to the smallest key so that reruns report the same node.
+fn max_degree(
+  degrees: impl Iterator<Item = (UnitKey, usize)>,
+) -> Option<(UnitKey, usize)> {
+  degrees
+    .min_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)))
+}
+
+pub fn report_statistics(map: &UnitMap) -> GraphStats {
+  let mut out_degrees = Vec::with_capacity(map.len());
+  // Counted from deps rather than reverse_deps, which may still hold weak
+  // references to units that were merged away.
+  let mut in_degrees: HashMap<UnitKey, usize> = HashMap::new();
+  for node in map.iter() {
+    let info = node.val.borrow();
+    out_degrees.push((node.key.clone(), info.deps.len()));
+    in_degrees.entry(node.key.clone()).or_default();
+    for dep in info.deps.keys() {
+      *in_degrees.entry(dep.key.clone()).or_default() += 1;
+    }
+  }
+  GraphStats {
+    nodes: map.len(),
+    edges: out_degrees.iter().map(|(_, n)| n).sum(),
+    max_out_degree: max_degree(out_degrees.into_iter()),
+    max_in_degree: max_degree(in_degrees.into_iter()),
+  }
+}
+
+// How many units GraphMetrics lists by how many others depend on them.
+pub const TOP_DEPENDED_UPON: usize = 10;
+
+// The rest of --stats, about the shape of the graph rather than its size.
+// Like GraphStats, only deps between units are counted.
+#[derive(Default)]
+pub struct GraphMetrics {
+  pub average_deps: f64,
+  pub median_deps: f64,
+  // No deps, and no unit depending on them either.
+  pub isolated: usize,
+  // Headers and no sources.
+  pub header_only: usize,
+  pub tests: usize,
+  // Most dependents first, ties by key.
+  pub most_depended_upon: Vec<(UnitKey, usize)>,
+}
+
+impl GraphMetrics {
+  pub fn compute(map: &UnitMap) -> GraphMetrics {
+    let mut metrics = GraphMetrics::default();
+    let mut deps = Vec::with_capacity(map.len());
+    let mut in_degrees: HashMap<UnitKey, usize> = HashMap::new();
+    for node in map.iter() {
+      let info = node.val.borrow();
+      deps.push(info.deps.len());
+      in_degrees.entry(node.key.clone()).or_default();
+      for dep in info.deps.keys() {
+        *in_degrees.entry(dep.key.clone()).or_default() += 1;
+      }
+      if !info.headers.is_empty() && info.srcs.is_empty() {
+        metrics.header_only += 1;
+      }
+      if info.is_test {
+        metrics.tests += 1;
+      }
+    }
+    for node in map.iter() {
+      if node.val.borrow().deps.is_empty() && in_degrees[&node.key] == 0 {
+        metrics.isolated += 1;
+      }
+    }
+
+    if !deps.is_empty() {
+      deps.sort_unstable();
+      metrics.average_deps =
+        deps.iter().sum::<usize>() as f64 / deps.len() as f64;
+      let mid = deps.len() / 2;
+      metrics.median_deps = if deps.len() % 2 == 0 {
+        (deps[mid - 1] + deps[mid]) as f64 / 2.0
+      } else {
+        deps[mid] as f64
+      };
+    }
+
+    let mut by_in_degree: Vec<(UnitKey, usize)> =
+      in_degrees.into_iter().filter(|(_, n)| *n > 0).collect();
+    by_in_degree.sort_by(|(a_key, a), (b_key, b)| {
+      b.cmp(a).then_with(|| a_key.cmp(b_key))
+    });
+    by_in_degree.truncate(TOP_DEPENDED_UPON);
+    metrics.most_depended_upon = by_in_degree;
+    metrics
+  }
+}
+
+impl fmt::Display for GraphMetrics {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    const WIDTH: usize = 20;
+    writeln!(f, "{:<WIDTH$}{:.2}", "Average deps:", self.average_deps)?;
+    writeln!(f, "{:<WIDTH$}{:.1}", "Median deps:", self.median_deps)?;
+    writeln!(f, "{:<WIDTH$}{}", "Isolated nodes:", self.isolated)?;
+    writeln!(f, "{:<WIDTH$}{}", "Header-only nodes:", self.header_only)?;
+    writeln!(f, "{:<WIDTH$}{}", "Test nodes:", self.tests)?;
+    if self.most_depended_upon.is_empty() {
+      return writeln!(f, "{:<WIDTH$}none", "Most depended upon:");
+    }
+    writeln!(f, "Most depended upon:")?;
+    let count_width = self.most_depended_upon[0].1.to_string().len();
+    for (key, n) in &self.most_depended_upon {
+      writeln!(f, "  {:>count_width$}  {}", n, key)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{key, scan};
+
+  fn diamond() -> UnitMap {
+    scan(&[
+      ("folly/Bar.h", "#include <folly/Baz.h>\n"),
+      ("folly/Baz.h", "#pragma once\n"),
+      (
+        "folly/Foo.h",
+        "#include <folly/Bar.h>\n#include <folly/Baz.h>\n",
+      ),
+      ("folly/Qux.h", "#pragma once\n"),
+    ])
+  }
+
+  #[test]
+  fn counts_nodes_and_edges() {
+    let stats = report_statistics(&diamond());
+    assert_eq!(stats.nodes, 4);
+    assert_eq!(stats.edges, 3);
+  }
+
+  #[test]
+  fn finds_max_out_and_in_degree() {
+    let stats = report_statistics(&diamond());
+    assert_eq!(stats.max_out_degree, Some((key("folly/foo"), 2)));
+    assert_eq!(stats.max_in_degree, Some((key("folly/baz"), 2)));
+  }
+
+  #[test]
+  fn degree_ties_go_to_the_smallest_key() {
+    let map = scan(&[
+      ("folly/A.h", "#include <folly/C.h>\n"),
+      ("folly/B.h", "#include <folly/C.h>\n"),
+      ("folly/C.h", "#pragma once\n"),
+    ]);
+    let stats = report_statistics(&map);
+    assert_eq!(stats.max_out_degree, Some((key("folly/a"), 1)));
+    assert_eq!(stats.max_in_degree, Some((key("folly/c"), 2)));
+  }
+
+  #[test]
+  fn empty_graph_has_no_max_degree() {
+    let stats = report_statistics(&UnitMap::new());
+    assert_eq!(stats.nodes, 0);
+    assert_eq!(stats.edges, 0);
+    assert!(stats.max_out_degree.is_none());
+    assert_eq!(
+      stats.to_string(),
+      "Nodes: 0\nEdges: 0\nMax out-degree: none\nMax in-degree: none\n"
+    );
+  }
+
+  #[test]
+  fn displays_each_statistic() {
+    assert_eq!(
+      report_statistics(&diamond()).to_string(),
+      "Nodes: 4\nEdges: 3\nMax out-degree: 2 (folly/foo)\n\
+       Max in-degree: 2 (folly/baz)\n"
+    );
+  }
+
+  #[test]
+  fn metrics_average_and_median_deps() {
+    let metrics = GraphMetrics::compute(&diamond());
+    // Out-degrees 2, 1, 0 and 0.
+    assert_eq!(metrics.average_deps, 0.75);
+    assert_eq!(metrics.median_deps, 0.5);
+
+    let map = scan(&[
+      ("folly/A.h", "#include <folly/B.h>\n"),
+      ("folly/B.h", "#pragma once\n"),
+      ("folly/C.h", "#include <folly/A.h>\n#include <folly/B.h>\n"),
+    ]);
+    let metrics = GraphMetrics::compute(&map);
+    assert_eq!(metrics.average_deps, 1.0);
+    assert_eq!(metrics.median_deps, 1.0);
+  }
+
+  #[test]
+  fn metrics_count_isolated_header_only_and_test_units() {
+    let map = scan(&[
+      ("folly/Bar.h", "#pragma once\n"),
+      ("folly/Foo.cpp", "#include <folly/Bar.h>\n"),
+      ("folly/Foo.h", "#pragma once\n"),
+      ("folly/Lone.h", "#pragma once\n"),
+      ("folly/Qux.h", "#pragma once\n"),
+      ("folly/Quxtest.cpp", "#pragma once\n"),
+    ]);
+    let metrics = GraphMetrics::compute(&map);
+    assert_eq!(metrics.isolated, 2);
+    // Qux's test builds on its own, which leaves a header-only library.
+    assert_eq!(metrics.header_only, 3);
+    assert_eq!(metrics.tests, 1);
+  }
+
+  #[test]
+  fn metrics_rank_the_most_depended_upon() {
+    assert_eq!(
+      GraphMetrics::compute(&diamond()).most_depended_upon,
+      [(key("folly/baz"), 2), (key("folly/bar"), 1)]
+    );
+
+    let names: Vec<String> =
+      (b'a'..=b'l').map(|c| format!("H{}", c as char)).collect();
+    let hub: String = names
+      .iter()
+      .map(|name| format!("#include <folly/{}.h>\n", name))
+      .collect();
+    let mut files: Vec<(String, &str)> = names
+      .iter()
+      .map(|name| (format!("folly/{}.h", name), "#pragma once\n"))
+      .collect();
+    files.push(("folly/Hub.h".to_string(), &hub));
+    files.push((
+      "folly/Top.h".to_string(),
+      "#include <folly/Hl.h>\n#include <folly/Hub.h>\n",
+    ));
+    let files: Vec<(&str, &str)> = files
+      .iter()
+      .map(|(path, contents)| (path.as_str(), *contents))
+      .collect();
+    let metrics = GraphMetrics::compute(&scan(&files));
+    let ranked: Vec<String> = metrics
+      .most_depended_upon
+      .iter()
+      .map(|(key, n)| format!("{} {}", key, n))
+      .collect();
+    assert_eq!(ranked.len(), TOP_DEPENDED_UPON);
+    assert_eq!(ranked[0], "folly/hl 2");
+    assert_eq!(ranked[1..4], ["folly/ha 1", "folly/hb 1", "folly/hc 1"]);
+  }
+
+  #[test]
+  fn metrics_display_as_a_table() {
+    assert_eq!(
+      GraphMetrics::compute(&diamond()).to_string(),
+      "Average deps:       0.75
+Median deps:        0.5
+Isolated nodes:     1
+Header-only nodes:  4
+Test nodes:         0
+Most depended upon:
+  2  folly/baz
+  1  folly/bar
+"
+    );
+    assert!(GraphMetrics::compute(&UnitMap::new())
+      .to_string()
+      .ends_with("Most depended upon: none\n"));
+  }
+}
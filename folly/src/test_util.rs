@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+
+use crate::build_file::{BazelVersion, BuildFileName, BuildOptions};
+use crate::builder::GraphBuilder;
+
+use crate::file_system::MockFileSystem;
+use crate::types::{ScanOptions, UnitKey, UnitMap, UnitObj};
+use crate::CompileGraph;
+
+// Where scan puts the tree: files are given relative to it, so that
+// "folly/Foo.h" is what an #include of it says.
+pub const BASE: &str = "/src";
+
+// A MockFileSystem holding files, each a (path, contents) pair with the path
+// relative to BASE.
+pub fn mock_fs(files: &[(&str, &str)]) -> MockFileSystem {
+  files
+    .iter()
+    .fold(MockFileSystem::new(), |fs, (path, contents)| {
+      fs.with_file(&format!("{}/{}", BASE, path), contents)
+    })
+}
+
+// The graph a scan of BASE/folly finds in files, before cycles are
+// collapsed.
+pub fn scan_with(files: &[(&str, &str)], opts: &ScanOptions) -> UnitMap {
+  let mut map = UnitMap::new();
+  map
+    .add_initial_subtree(&mock_fs(files), &Path::new(BASE).join("folly"), opts)
+    .unwrap();
+  map
+}
+
+pub fn scan(files: &[(&str, &str)]) -> UnitMap {
+  scan_with(files, &ScanOptions::default())
+}
+
+// "folly/io/io_buf" is io_buf in folly/io.
+pub fn key(path: &str) -> UnitKey {
+  let (root_dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+  UnitKey::new(name, root_dir)
+}
+
+pub fn unit(map: &UnitMap, path: &str) -> UnitObj {
+  map.get(&key(path)).cloned().unwrap()
+}
+
+// Every key in map, sorted.
+pub fn keys(map: &UnitMap) -> Vec<String> {
+  let mut keys: Vec<UnitKey> =
+    map.iter().map(|node| node.key.clone()).collect();
+  keys.sort();
+  keys.into_iter().map(|key| key.to_string()).collect()
+}
+
+// What the command line's defaults ask for, writing under BASE.
+pub fn build_options() -> BuildOptions {
+  BuildOptions {
+    output_dir: PathBuf::from(BASE),
+    build_file_name: BuildFileName::BUILD,
+    dry_run: false,
+    verbose: false,
+    use_glob: false,
+    glob_threshold: 5,
+    filter_root: None,
+    test_wrapper: None,
+    label_prefix: "//".to_string(),
+    bazel_version: BazelVersion::V5,
+    no_recursive_headers: false,
+    rules_cc_available: false,
+    sort_srcs: true,
+  }
+}
+
+// scan, then everything GraphBuilder::build does after.
+pub fn build(files: &[(&str, &str)]) -> UnitMap {
+  let mut map = scan(files);
+  GraphBuilder::new().finish(&mut map).unwrap();
+  map
+}
+
+// Keys of what unit depends on, test_deps last, each in the order added.
+pub fn dep_keys(map: &UnitMap, path: &str) -> Vec<String> {
+  let node = unit(map, path);
+  let info = node.val.borrow();
+  info
+    .deps
+    .keys()
+    .chain(&info.test_deps)
+    .map(|dep| dep.key.to_string())
+    .collect()
+}
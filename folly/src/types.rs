@@ -1,24 +1,416 @@
-use crate::intrusive_hashmap::{HashMap, HashObj};
-use std::collections::HashSet;
+use crate::error::FollyError;
+use crate::intrusive_hashmap::{DebugSummary, HashMap, HashObj, WeakHashObj};
+use crate::util::{split_path, strip_file_name, Condition, Platform};
+use indexmap::{IndexMap, IndexSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-#[derive(Default, PartialEq, Eq, Hash)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnitKey {
   pub name: String,
   pub root_dir: String,
 }
 
+impl UnitKey {
+  pub fn new(name: impl Into<String>, root_dir: impl Into<String>) -> Self {
+    UnitKey {
+      name: name.into(),
+      root_dir: root_dir.into(),
+    }
+  }
+
+  // The key a file belongs to, given its path relative to the scan's base
+  // as in an #include: folly/io/IOBuf-inl.h is io_buf in folly/io. The
+  // directory is normalized the same way an #include's is.
+  pub fn from_path(path: &Path) -> Result<Self, FollyError> {
+    let Some(path_str) = path.to_str() else {
+      return Err(FollyError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Failure converting {} to string", path.display()),
+      )));
+    };
+    let (root_dir, file_name) = split_path(path_str);
+    if file_name.is_empty() || file_name == ".." {
+      return Err(FollyError::FileNotFound(path.to_path_buf()));
+    }
+    let (name, _) = strip_file_name(&file_name)?;
+    Ok(UnitKey::new(name, root_dir))
+  }
+}
+
+// Orders by directory first, so that sorting groups units by package.
+impl Ord for UnitKey {
+  fn cmp(&self, other: &Self) -> Ordering {
+    (&self.root_dir, &self.name).cmp(&(&other.root_dir, &other.name))
+  }
+}
+
+impl PartialOrd for UnitKey {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl fmt::Display for UnitKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.root_dir.is_empty() {
+      write!(f, "{}", self.name)
+    } else {
+      write!(f, "{}/{}", self.root_dir, self.name)
+    }
+  }
+}
+
+// Why one unit depends on another. Scanning only finds the two kinds of
+// include; the rest are inferred once the tree has been scanned. USING
+// edges are too much of a guess for deps, so BUILD files only mention
+// them in a comment.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UnitDepKind {
+  INCLUDE,
+  // An #include from srcs alone, which the unit's own users don't see.
+  SOURCE_INCLUDE,
+  // A `class Foo;` names a class that lives in the other unit.
+  FORWARD_DECL,
+  // Named as the other unit's test or benchmark, e.g. FooTest for Foo.
+  IMPLICIT,
+  // A `using` alias names a class that lives in the other unit.
+  USING,
+}
+
+impl UnitDepKind {
+  // A header's include wins over a source's, since whoever includes the
+  // header needs the dep too.
+  pub fn widen(&mut self, other: UnitDepKind) {
+    if (*self, other) == (UnitDepKind::SOURCE_INCLUDE, UnitDepKind::INCLUDE) {
+      *self = other;
+    }
+  }
+
+  // What a BUILD file says about a dep of this kind, if anything.
+  pub fn comment(&self) -> Option<&'static str> {
+    match self {
+      UnitDepKind::INCLUDE | UnitDepKind::SOURCE_INCLUDE => None,
+      UnitDepKind::FORWARD_DECL => Some("forward-decl"),
+      UnitDepKind::IMPLICIT => Some("implicit"),
+      UnitDepKind::USING => Some("using"),
+    }
+  }
+}
+
+// Who can depend on a unit, going by who does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Visibility {
+  // Something in another package depends on it. Also where every unit
+  // starts out, until infer_visibility has seen the whole graph.
+  #[default]
+  PUBLIC,
+  // Only units in its own package depend on it.
+  PACKAGE,
+  // Nothing depends on it.
+  PRIVATE,
+}
+
+impl Visibility {
+  // Bazel has nothing narrower than the package, so PACKAGE and PRIVATE
+  // come out the same.
+  pub fn label(&self) -> &'static str {
+    match self {
+      Visibility::PUBLIC => "//visibility:public",
+      Visibility::PACKAGE | Visibility::PRIVATE => "//visibility:private",
+    }
+  }
+}
+
 // TODO if we need to compare key against deps, reverse_deps,
 // then we can turn into HashSet<HashWrap...> instead.
-#[derive(Default)]
+//
+// Cloning is shallow for edges: the copy's deps and reverse_deps point at
+// the same nodes as the original's, rather than at copies of them.
+#[derive(Clone, Default)]
 pub struct UnitInfo<K: Hash> {
   pub headers: Vec<String>,
   pub srcs: Vec<String>,
-  pub deps: HashSet<HashObj<K, UnitInfo<K>>>,
-  pub reverse_deps: HashSet<HashObj<K, UnitInfo<K>>>,
+  pub protos: Vec<String>,
+  // Test files, which build into a cc_test of their own beside the unit's
+  // library, so that nothing that includes the unit depends on a test.
+  pub test_srcs: Vec<String>,
+  // Set when one of the files is a test.
+  pub is_test: bool,
+  // Set when one of srcs defines main(), making this a cc_binary.
+  pub has_main: bool,
+  // Set when one of srcs is Objective-C++, making this an objc_library.
+  pub is_objc: bool,
+  // Set when one of headers has no include guard or #pragma once.
+  pub missing_include_guard: bool,
+  // Set when one of the files says it was generated, e.g. by protoc.
+  pub generated: bool,
+  // The only platforms srcs are built on, or empty for all of them.
+  pub platforms: Vec<Platform>,
+  // Declared by a BUILD or BUCK file that was already in the tree, whose
+  // package we then leave alone.
+  pub pre_existing: bool,
+  // The name that file gave the rule, which needn't survive a round trip
+  // through camel_to_snake.
+  pub existing_name: Option<String>,
+  // IndexSets and IndexMaps keep deps in the order they were first
+  // included, so that regenerated BUILD files don't reshuffle them.
+  pub deps: IndexMap<HashObj<K, UnitInfo<K>>, UnitDepKind>,
+  // The deps that are only ever included under one #if a config_setting can
+  // stand for, with its condition. Rules take these through a select().
+  pub conditions: IndexMap<K, Condition>,
+  // Units only test files include. Only cc_test rules take them as deps,
+  // so that nothing testonly leaks into a library. They are in their deps'
+  // reverse_deps like any other edge.
+  pub test_deps: IndexSet<HashObj<K, UnitInfo<K>>>,
+  // Weak so that deps and reverse_deps don't form Rc cycles between every
+  // pair of connected nodes.
+  pub reverse_deps: IndexSet<WeakHashObj<K, UnitInfo<K>>>,
+  // How many of reverse_deps were still alive at the last
+  // compute_reverse_dep_counts. Rules list their most-used deps first.
+  pub reverse_dep_count: usize,
+  // Also derived from reverse_deps, by infer_visibility.
+  pub visibility: Visibility,
+  // Unit names from using_alias_target, resolved into soft_deps once the
+  // whole tree has been scanned.
+  pub using_hints: Vec<String>,
+  // Likewise from forward_decl_target, resolved into FORWARD_DECL deps.
+  pub forward_decl_hints: Vec<String>,
+  // Compiler flags from `// BAZEL_COPTS:` comments, in the order first seen.
+  pub build_flags: Vec<String>,
+  // Linker flags from `// BAZEL_LINKOPTS:` comments, likewise.
+  pub link_opts: Vec<String>,
+  // Units these files seem to need but don't include, and why.
+  pub soft_deps: IndexSet<(UnitDepKind, K)>,
+  // (workspace, key) pairs for includes that resolve outside the tree.
+  pub external_deps: IndexSet<(String, K)>,
+  // Likewise for includes in test files, which only the cc_test takes.
+  pub test_external_deps: IndexSet<(String, K)>,
+  // Units whose test files are included from outside a test. Only their
+  // cc_test builds those files, so this is no dep, just something for
+  // check_abi_stability to report.
+  pub test_includes: IndexSet<K>,
+  // Modification time of each file at the point it was parsed.
+  pub mtimes: std::collections::HashMap<String, SystemTime>,
+}
+
+impl<K: Hash> UnitInfo<K> {
+  // Only exists because something included it, e.g. a system header that
+  // was taken for one of ours, so a label for it would point at a target
+  // that is never written. Seeds from existing BUILD files have no files
+  // of their own either, but are real targets.
+  pub fn is_phantom(&self) -> bool {
+    !self.has_library() && self.test_srcs.is_empty() && !self.pre_existing
+  }
+
+  // Whether there are files besides tests, which make up the rule that
+  // dependents of the unit get.
+  pub fn has_library(&self) -> bool {
+    !(self.headers.is_empty() && self.srcs.is_empty() && self.protos.is_empty())
+  }
+
+  // Nothing but tests, so there is no library to depend on.
+  pub fn is_test_only(&self) -> bool {
+    !self.has_library() && !self.test_srcs.is_empty()
+  }
+}
+
+impl<K: Hash> DebugSummary for UnitInfo<K> {
+  fn debug_summary(&self) -> String {
+    format!(
+      "{} headers, {} srcs, {} deps, {} reverse deps",
+      self.headers.len(),
+      self.srcs.len(),
+      self.deps.len(),
+      self.reverse_deps.len()
+    )
+  }
+}
+
+impl fmt::Display for UnitInfo<UnitKey> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.debug_summary())
+  }
+}
+
+pub const ABSEIL_WORKSPACE: &str = "abseil-cpp";
+
+#[derive(Default)]
+pub struct ScanOptions {
+  // Include root prefix (e.g. "boost") to Bazel workspace name.
+  pub known_roots: std::collections::HashMap<String, String>,
+  // Include path, or directory of one, to the path it names instead, both
+  // as an #include would spell them. See alias_path.
+  pub path_aliases: BTreeMap<String, PathBuf>,
+  // Directory names (not paths) that are never descended into.
+  pub exclude_dirs: HashSet<String>,
+  // absl/ header path, minus extension, to the target in its package that
+  // provides it. Headers not listed are assumed to have a target of their
+  // own name, e.g. absl/container/flat_hash_map.h in :flat_hash_map.
+  pub abseil_targets: std::collections::HashMap<String, String>,
+  // Canonical file path to its copts, from compile_commands.json. When set,
+  // these are the only copts, and `// BAZEL_COPTS:` comments are ignored.
+  pub compile_flags: Option<std::collections::HashMap<PathBuf, Vec<String>>>,
+  // Directories more than this many levels below the root are skipped.
+  pub max_depth: Option<usize>,
+  // Fail the scan on a header without an include guard, rather than warn.
+  pub error_on_missing_guard: bool,
+  // Don't follow the includes of generated files.
+  pub skip_generated: bool,
+  // Whether to seed the graph from existing BUILD and BUCK files.
+  pub seed_existing: bool,
 }
 
 pub type UnitObj = HashObj<UnitKey, UnitInfo<UnitKey>>;
 pub type UnitMap = HashMap<UnitKey, UnitInfo<UnitKey>>;
-// TODO
-pub type UnitTrie = ();
+
+impl UnitInfo<UnitKey> {
+  // Adds the edge to dep, or widens the one there. The dep stays
+  // conditional only while every include of it is under the same condition,
+  // since a select() can't take it for either of two.
+  pub fn add_dep(
+    &mut self,
+    dep: UnitObj,
+    kind: UnitDepKind,
+    condition: Option<Condition>,
+  ) {
+    let key = dep.key.clone();
+    let is_new = !self.deps.contains_key(&dep);
+    self.deps.entry(dep).or_insert(kind).widen(kind);
+    match condition {
+      Some(condition) if is_new => {
+        self.conditions.insert(key, condition);
+      }
+      Some(condition) if self.conditions.get(&key) == Some(&condition) => {}
+      _ => {
+        self.conditions.shift_remove(&key);
+      }
+    }
+  }
+}
+// Prefix tree over the components of UnitKey::root_dir. Each node holds the
+// units that live directly in that directory, i.e. one BUILD file's worth.
+#[derive(Default)]
+pub struct UnitTrie {
+  pub children: std::collections::HashMap<String, UnitTrie>,
+  pub units: Vec<UnitObj>,
+}
+
+impl UnitTrie {
+  pub fn insert(&mut self, unit: UnitObj) {
+    let mut node = self;
+    for component in unit.key.root_dir.split('/').filter(|c| !c.is_empty()) {
+      node = node.children.entry(component.to_string()).or_default();
+    }
+    node.units.push(unit);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::intrusive_hashmap::Shared;
+  use crate::test_util::{scan, unit};
+
+  #[test]
+  fn debug_summarizes_nodes_on_a_cycle() {
+    let map = scan(&[
+      ("folly/A.h", "#include <folly/B.h>\n"),
+      ("folly/B.h", "#include <folly/A.h>\n"),
+    ]);
+    let a = unit(&map, "folly/a");
+    assert_eq!(
+      format!("{:?}", a),
+      "IntrusiveRefCell { key: UnitKey { name: \"a\", root_dir: \"folly\" }, \
+       val: 1 headers, 0 srcs, 1 deps, 1 reverse deps }"
+    );
+    assert!(format!("{:#?}", map.iter().collect::<Vec<_>>()).contains("\"b\""));
+  }
+
+  #[test]
+  fn debug_reports_a_value_borrowed_mutably() {
+    let map = scan(&[("folly/A.h", "#pragma once\n")]);
+    let a = unit(&map, "folly/a");
+    let info = a.val.borrow_mut();
+    assert!(format!("{:?}", a).ends_with("val: <borrowed> }"));
+    drop(info);
+    assert!(format!("{:?}", a)
+      .ends_with("val: 1 headers, 0 srcs, 0 deps, 0 reverse deps }"));
+  }
+
+  #[test]
+  fn unit_keys_sort_by_root_dir_then_name() {
+    let mut keys = [
+      UnitKey::new("a", "folly/io"),
+      UnitKey::new("z", "folly"),
+      UnitKey::new("b", "folly/io/async"),
+      UnitKey::new("a", "folly"),
+      UnitKey::new("c", ""),
+    ];
+    keys.sort();
+    let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+    assert_eq!(
+      keys,
+      ["c", "folly/a", "folly/z", "folly/io/a", "folly/io/async/b"]
+    );
+  }
+
+  #[test]
+  fn clone_shares_dep_nodes() {
+    let map = scan(&[
+      ("folly/A.h", "#pragma once\n"),
+      ("folly/B.h", "#pragma once\n"),
+      ("folly/C.h", "#pragma once\n"),
+      (
+        "folly/Foo.h",
+        "#include <folly/A.h>\n#include <folly/B.h>\n#include <folly/C.h>\n",
+      ),
+    ]);
+    let foo = unit(&map, "folly/foo");
+    let info = foo.val.borrow();
+    let copy = info.clone();
+    assert_eq!(copy.headers, info.headers);
+    assert_eq!(copy.deps.len(), 3);
+    for ((dep, kind), (copied, copied_kind)) in info.deps.iter().zip(&copy.deps)
+    {
+      assert!(Shared::ptr_eq(dep, copied), "{}", dep.key);
+      assert_eq!(kind, copied_kind);
+    }
+    let a = unit(&map, "folly/a");
+    let rdep = a.val.borrow().clone().reverse_deps[0].upgrade().unwrap();
+    assert!(Shared::ptr_eq(&rdep, &foo));
+  }
+
+  #[test]
+  fn new_takes_strs_or_strings() {
+    let key = UnitKey::new("io_buf", String::from("folly/io"));
+    assert_eq!(key.name, "io_buf");
+    assert_eq!(key.root_dir, "folly/io");
+    assert_eq!(key, UnitKey::new(String::from("io_buf"), "folly/io"));
+  }
+
+  #[test]
+  fn from_path_matches_what_a_scan_names_files() {
+    for (path, name, root_dir) in [
+      ("folly/io/IOBuf-inl.h", "io_buf", "folly/io"),
+      ("folly/io/IOBuf.cpp", "io_buf", "folly/io"),
+      ("folly/Footest.cpp", "foo", "folly"),
+      ("./folly/io/../Range.h", "range", "folly"),
+      ("folly/rpc/service.proto", "service_proto", "folly/rpc"),
+      ("Top.h", "top", ""),
+    ] {
+      let key = UnitKey::from_path(Path::new(path)).unwrap();
+      assert_eq!(key, UnitKey::new(name, root_dir), "{}", path);
+    }
+    for path in ["", "folly/.."] {
+      assert!(UnitKey::from_path(Path::new(path)).is_err(), "{}", path);
+    }
+  }
+}
@@ -1,20 +1,151 @@
-use std::io::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 
-use crate::types::UnitKey;
+use crate::error::FollyError;
+use crate::preproc::{lex_include, IncludeTarget};
+use crate::types::{ScanOptions, UnitKey};
+use crate::warnings::WARNINGS;
 
 #[derive(PartialEq)]
+#[allow(non_camel_case_types)]
 pub enum FileType {
   UNKNOWN,
   HEADER,
   SOURCE,
   TEMPLATE,
   TEST,
+  // Objective-C++ source.
+  OBJC,
+  PROTO,
+  // A BUILD, BUILD.bazel or BUCK file, which holds rules rather than code.
+  BAZEL_BUILD,
 }
 
 #[derive(PartialEq)]
 pub enum HeaderLib {
   UNKNOWN,
   FOLLY,
+  // Holds the Bazel workspace that provides the header.
+  THIRDPARTY(String),
+  // An absl/ header, which comes from the abseil-cpp module unless
+  // known_roots says otherwise.
+  ABSEIL,
+}
+
+// An OS that a file is only built for, going by its name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Platform {
+  LINUX,
+  DARWIN,
+  WINDOWS,
+  ANDROID,
+  IOS,
+}
+
+impl Platform {
+  // The config_setting that select() keys on.
+  pub fn condition(&self) -> &'static str {
+    match self {
+      Platform::LINUX => "@platforms//os:linux",
+      Platform::DARWIN => "@platforms//os:macos",
+      Platform::WINDOWS => "@platforms//os:windows",
+      Platform::ANDROID => "@platforms//os:android",
+      Platform::IOS => "@platforms//os:ios",
+    }
+  }
+
+  // What CMake's PLATFORM_ID and CMAKE_SYSTEM_NAME call it.
+  pub fn cmake_id(&self) -> &'static str {
+    match self {
+      Platform::LINUX => "Linux",
+      Platform::DARWIN => "Darwin",
+      Platform::WINDOWS => "Windows",
+      Platform::ANDROID => "Android",
+      Platform::IOS => "iOS",
+    }
+  }
+
+  // What Meson's host_machine.system() says, which doesn't tell iOS from
+  // macOS.
+  pub fn meson_id(&self) -> &'static str {
+    match self {
+      Platform::LINUX => "linux",
+      Platform::DARWIN | Platform::IOS => "darwin",
+      Platform::WINDOWS => "windows",
+      Platform::ANDROID => "android",
+    }
+  }
+}
+
+// An #if branch that a config_setting can stand for: the macro it tests,
+// and whether the branch is taken with the macro defined or without it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Condition {
+  pub define: String,
+  pub defined: bool,
+}
+
+impl Condition {
+  // From the directive_words of an #if, #ifdef, #ifndef or #elif that tests
+  // one macro, e.g. `#if defined(FOO)` or `#if !FOO`. Anything more, such
+  // as `#if FOO && BAR` or `#if 0`, is None.
+  pub fn parse(words: &[String]) -> Option<Condition> {
+    let (directive, rest) = words.split_first()?;
+    let expr = rest.join(" ");
+    let expr = expr.split("//").next()?.split("/*").next()?.trim();
+    let (defined, name) = match directive.as_str() {
+      "ifdef" | "elifdef" => (true, expr),
+      "ifndef" | "elifndef" => (false, expr),
+      "if" | "elif" => {
+        let (defined, expr) = match expr.strip_prefix('!') {
+          Some(rest) => (false, rest.trim_start()),
+          None => (true, expr),
+        };
+        let name = match expr.strip_prefix("defined") {
+          Some(rest) => {
+            let rest = rest.trim();
+            rest
+              .strip_prefix('(')
+              .and_then(|rest| rest.strip_suffix(')'))
+              .unwrap_or(rest)
+              .trim()
+          }
+          None => expr,
+        };
+        (defined, name)
+      }
+      _ => return None,
+    };
+    let is_macro = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+      && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    is_macro.then(|| Condition {
+      define: name.to_string(),
+      defined,
+    })
+  }
+
+  // The #else of this branch.
+  pub fn negate(self) -> Condition {
+    Condition {
+      defined: !self.defined,
+      ..self
+    }
+  }
+}
+
+// Looks at the last word of a snake_case unit name, so FooLinux.cpp (unit
+// foo_linux) is Linux-only but LinuxFoo.cpp is not.
+pub fn detect_platform(name: &str) -> Option<Platform> {
+  match name.rsplit('_').next()? {
+    "linux" => Some(Platform::LINUX),
+    "darwin" => Some(Platform::DARWIN),
+    "windows" => Some(Platform::WINDOWS),
+    "android" => Some(Platform::ANDROID),
+    "ios" => Some(Platform::IOS),
+    _ => None,
+  }
 }
 
 #[derive(PartialEq)]
@@ -22,18 +153,21 @@ pub enum CharType {
   DELIM,
   UPPER,
   LOWER,
+  DIGIT,
   REGULAR,
 }
 
 pub fn get_char_type(c: char) -> CharType {
   if c == '_' {
-    return CharType::DELIM;
-  } else if (c >= 'a') & (c <= 'z') {
-    return CharType::LOWER;
-  } else if (c >= 'A') & (c <= 'Z') {
-    return CharType::UPPER;
+    CharType::DELIM
+  } else if c.is_ascii_lowercase() {
+    CharType::LOWER
+  } else if c.is_ascii_uppercase() {
+    CharType::UPPER
+  } else if c.is_ascii_digit() {
+    CharType::DIGIT
   } else {
-    return CharType::REGULAR;
+    CharType::REGULAR
   }
 }
 
@@ -41,28 +175,45 @@ pub fn camel_to_snake(string: &str) -> String {
   let mut prev_char = CharType::DELIM;
   let mut word_start = 0;
   let mut snake_string: String = String::new();
-  for (i, c) in string.chars().enumerate() {
+  for (i, c) in string.char_indices() {
     let curr_char = get_char_type(c);
 
     if curr_char == CharType::DELIM {
       snake_string += &string[word_start..i].to_lowercase();
       snake_string.push(c);
-      // Technically unnecessary, but just to be safe.
-      word_start = i;
+      // Skip past the delimiter so that runs of them are copied exactly once.
+      word_start = i + 1;
     } else if prev_char == CharType::DELIM {
       word_start = i;
     } else if ((prev_char == CharType::LOWER)
+      | (prev_char == CharType::DIGIT)
       | (prev_char == CharType::REGULAR))
       & (curr_char == CharType::UPPER)
     {
       snake_string += &string[word_start..i].to_lowercase();
       snake_string.push('_');
       word_start = i;
+    } else if (prev_char == CharType::UPPER)
+      & (curr_char == CharType::DIGIT)
+      & (word_start == (i - 1))
+    {
+      // Digits stick to a preceding lowercase or all-caps word (IPv6, TCP6)
+      // but not to a lone capital (F14).
+      snake_string += &string[word_start..i].to_lowercase();
+      snake_string.push('_');
+      word_start = i;
     } else if (prev_char == CharType::UPPER)
       & (curr_char == CharType::LOWER)
       & (word_start < (i - 1))
+      & !string[i..]
+        .chars()
+        .find(|c| !c.is_ascii_lowercase())
+        .is_some_and(|c| c.is_ascii_digit())
     {
-      // To accomodate all-caps words, we admit no 1-character words.
+      // A run of capitals followed by a lowercase letter is an all-caps word
+      // and then the start of the next one: FBString is fb_string. To
+      // allow for that, we admit no 1-character words. Lowercase letters
+      // that lead into digits are a version, as in IPv6, and stay put.
       snake_string += &string[word_start..(i - 1)].to_lowercase();
       snake_string.push('_');
       word_start = i - 1;
@@ -73,19 +224,195 @@ pub fn camel_to_snake(string: &str) -> String {
   snake_string
 }
 
+// Matches `int main(` with any amount of whitespace around `main`, as long as
+// `int` isn't the tail of some longer identifier.
+pub fn defines_main(line: &str) -> bool {
+  let is_ident = |c: char| c == '_' || c.is_ascii_alphanumeric();
+  line.match_indices("int").any(|(i, _)| {
+    if line[..i].ends_with(is_ident) {
+      return false;
+    }
+    let rest = &line[(i + 3)..];
+    let after_int = rest.trim_start();
+    if after_int.len() == rest.len() {
+      return false;
+    }
+    after_int
+      .strip_prefix("main")
+      .is_some_and(|tail| tail.trim_start().starts_with('('))
+  })
+}
+
+// For an alias like `using FooImpl = detail::FooImpl<T>;`, the unit name
+// the aliased class would live in, here foo_impl. Only qualified names
+// count, and not ones from std, which aren't ours however they're named.
+pub fn using_alias_target(line: &str) -> Option<String> {
+  let code = match line.find("//") {
+    Some(i) => &line[..i],
+    None => line,
+  };
+  let rest = code.trim().strip_prefix("using")?;
+  if !rest.starts_with(char::is_whitespace) {
+    return None;
+  }
+  let (_, target) = rest.split_once('=')?;
+  let target = target.trim().strip_suffix(';')?.trim();
+  let target = target.strip_prefix("typename ").unwrap_or(target);
+  let target = target.split('<').next()?.trim_start_matches("::");
+  if target.starts_with("std::") {
+    return None;
+  }
+  let (_, class) = target.rsplit_once("::")?;
+  if class.is_empty()
+    || !class.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+  {
+    return None;
+  }
+  Some(camel_to_snake(class))
+}
+
+// The flags on a `// BAZEL_COPTS: -mavx2 -DFOO` line, split on whitespace,
+// for files that need something passed to the compiler. None for any other
+// line, including one that only mentions BAZEL_COPTS partway through.
+pub fn copts_comment(line: &str) -> Option<Vec<String>> {
+  flags_comment(line, "BAZEL_COPTS:")
+}
+
+// Likewise `// BAZEL_LINKOPTS: -lpthread -ldl`, for what the linker needs.
+pub fn linkopts_comment(line: &str) -> Option<Vec<String>> {
+  flags_comment(line, "BAZEL_LINKOPTS:")
+}
+
+fn flags_comment(line: &str, tag: &str) -> Option<Vec<String>> {
+  let comment = line.trim().strip_prefix("//")?;
+  let flags = comment.trim_start().strip_prefix(tag)?;
+  Some(flags.split_whitespace().map(str::to_string).collect())
+}
+
+// The class or struct a line forward-declares, as a unit name: `class
+// IOBuf;` gives io_buf. Only a declaration on a line of its own counts, so
+// `friend class Foo;` and `enum class Foo;` don't.
+pub fn forward_decl_target(line: &str) -> Option<String> {
+  let code = match line.find("//") {
+    Some(i) => &line[..i],
+    None => line,
+  };
+  let code = code.trim().strip_suffix(';')?;
+  let rest = code
+    .strip_prefix("class")
+    .or_else(|| code.strip_prefix("struct"))?;
+  if !rest.starts_with(char::is_whitespace) {
+    return None;
+  }
+  let class = rest.trim();
+  if class.is_empty()
+    || class.starts_with(|c: char| c.is_ascii_digit())
+    || !class.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+  {
+    return None;
+  }
+  Some(camel_to_snake(class))
+}
+
+// How far into a file is_generated looks.
+pub const GENERATED_SCAN_LINES: usize = 5;
+
+// Whether the opening lines of a file mark it as written by a tool such as
+// protoc or thrift, which the file's own includes then aren't worth
+// following.
+pub fn is_generated(lines: &[String]) -> bool {
+  lines.iter().take(GENERATED_SCAN_LINES).any(|line| {
+    let line = line.trim();
+    line.starts_with("// @generated")
+      || line.starts_with("// Generated by")
+      || line.contains("/* DO NOT EDIT */")
+  })
+}
+
+// The words of a preprocessor directive after the `#`, e.g. ["ifdef",
+// "FOO"] for `#  ifdef FOO`, or None for any other line.
+pub fn directive_words(line: &str) -> Option<Vec<String>> {
+  let rest = line.trim_start().strip_prefix('#')?;
+  Some(rest.split_whitespace().map(str::to_string).collect())
+}
+
+// How far into a header has_include_guard looks.
+pub const GUARD_SCAN_LINES: usize = 20;
+
+// Whether the opening lines of a header hold `#pragma once`, or an `#ifndef`
+// whose macro the very next directive `#define`s. Spaces around the `#` and
+// between words are allowed, as the preprocessor does.
+pub fn has_include_guard(lines: &[String]) -> bool {
+  let mut guard: Option<String> = None;
+  for line in lines.iter().take(GUARD_SCAN_LINES) {
+    let Some(words) = directive_words(line) else {
+      continue;
+    };
+    match words.as_slice() {
+      [pragma, once, ..] if pragma == "pragma" && once == "once" => {
+        return true
+      }
+      [define, name, ..] if define == "define" => {
+        if guard.as_ref() == Some(name) {
+          return true;
+        }
+        guard = None;
+      }
+      [ifndef, name, ..] if ifndef == "ifndef" => guard = Some(name.clone()),
+      _ => guard = None,
+    }
+  }
+  false
+}
+
+// Capitalizes each underscore-delimited word and drops the underscores, so
+// runs of them and leading or trailing ones vanish. This undoes
+// camel_to_snake only where no word was all caps: f_14_map comes back as
+// F14Map, but io_buf as IoBuf rather than IOBuf.
+pub fn snake_to_camel(string: &str) -> String {
+  string
+    .split('_')
+    .flat_map(|word| {
+      let mut chars = word.chars();
+      chars
+        .next()
+        .map(|first| first.to_ascii_uppercase())
+        .into_iter()
+        .chain(chars)
+    })
+    .collect()
+}
+
 // TODO if we fail in combining cc and h in one unit, try again with cc and h
 // all in their own units.
-pub fn strip_file_name(file_name: &str) -> Result<(String, FileType), Error> {
+pub fn strip_file_name(
+  file_name: &str,
+) -> Result<(String, FileType), FollyError> {
   // Brittle order.
   let suffixes = [
     ("test.cpp", FileType::TEST),
     ("test.cc", FileType::TEST),
     (".cpp", FileType::SOURCE),
     (".cc", FileType::SOURCE),
+    (".cxx", FileType::SOURCE),
+    (".c", FileType::SOURCE),
+    (".mm", FileType::OBJC),
     ("-inl.h", FileType::TEMPLATE),
+    (".hh", FileType::TEMPLATE),
+    (".hpp", FileType::HEADER),
     (".h", FileType::HEADER),
   ];
 
+  if ["BUILD", "BUILD.bazel", "BUCK"].contains(&file_name) {
+    return Ok((file_name.to_string(), FileType::BAZEL_BUILD));
+  }
+
+  // Protos get their own unit, so that foo.proto doesn't collide with the
+  // foo.h next to it.
+  if let Some(stem) = file_name.strip_suffix(".proto") {
+    return Ok((proto_unit_name(stem), FileType::PROTO));
+  }
+
   for (suffix, file_type) in suffixes {
     if file_name.ends_with(suffix) {
       return Ok((
@@ -98,57 +425,545 @@ pub fn strip_file_name(file_name: &str) -> Result<(String, FileType), Error> {
   Ok((file_name.to_string(), FileType::UNKNOWN))
 }
 
-pub fn strip_include(line: &str) -> Option<(UnitKey, HeaderLib)> {
-  if !line.starts_with("#include") {
-    return None;
+pub fn proto_unit_name(stem: &str) -> String {
+  camel_to_snake(stem) + "_proto"
+}
+
+// Splits a /-separated path into its directory and last component. `.` and
+// empty components are dropped, and `..` drops the component before it, so
+// ./folly/io/../Range.h is folly and Range.h. A `..` with nothing before it
+// to drop is kept.
+pub fn split_path(path: &str) -> (String, String) {
+  let mut parts: Vec<&str> = Vec::new();
+  for part in path.split('/') {
+    match part {
+      "" | "." => {}
+      ".." if parts.last().is_some_and(|last| *last != "..") => {
+        parts.pop();
+      }
+      _ => parts.push(part),
+    }
   }
+  let last = parts.pop().unwrap_or_default().to_string();
+  (parts.join("/"), last)
+}
 
-  let extract_unit = |start, end| {
-    let path: &str = &line[start..end]
-      .trim_end_matches("-inl.h")
-      .trim_end_matches(".h");
-    let root: &str = match path.find('/') {
-      None => path,
-      Some(i) => &path[0..i],
-    };
+// The (root_dir, unit_name) an include path, minus its extension, points
+// at: folly/io/../IOBuf is folly and io_buf, and a bare Foo is foo at the
+// top.
+pub fn normalize_include_path(path: &str) -> (String, String) {
+  let (root_dir, last) = split_path(path);
+  (root_dir, camel_to_snake(&last))
+}
 
-    let key: UnitKey = match path.rfind('/') {
-      None => UnitKey {
-        name: camel_to_snake(path),
-        root_dir: String::new(),
-      },
-      Some(i) => UnitKey {
-        name: camel_to_snake(&path[(i + 1)..]),
-        root_dir: (&path[0..i]).to_string(),
-      },
-    };
+// Works out where a unit found by its include path comes from, going by
+// the first component of its path: the unit's own name when it has no
+// directory.
+fn resolve_path(
+  root_dir: String,
+  name: String,
+  known_roots: &HashMap<String, String>,
+) -> (UnitKey, HeaderLib) {
+  let root = match root_dir.split_once('/') {
+    Some((root, _)) => root.to_string(),
+    None if root_dir.is_empty() => name.clone(),
+    None => root_dir.clone(),
+  };
+  let key = UnitKey::new(name, root_dir);
+
+  match root.as_str() {
+    "folly" => (key, HeaderLib::FOLLY),
+    _ => match known_roots.get(&root) {
+      Some(workspace) => (key, HeaderLib::THIRDPARTY(workspace.clone())),
+      None if root == "absl" => (key, HeaderLib::ABSEIL),
+      None => (key, HeaderLib::UNKNOWN),
+    },
+  }
+}
 
-    match root {
-      "folly" => Some((key, HeaderLib::FOLLY)),
-      _ => Some((key, HeaderLib::UNKNOWN)),
+// The proto counterpart of strip_include, for `import "path.proto";` along
+// with its `public` and `weak` forms.
+pub fn strip_import(
+  line: &str,
+  opts: &ScanOptions,
+) -> Option<(UnitKey, HeaderLib)> {
+  let rest = line.trim_start().strip_prefix("import")?;
+  let rest = rest.trim_start();
+  let rest = ["public", "weak"]
+    .into_iter()
+    .find_map(|modifier| rest.strip_prefix(modifier))
+    .unwrap_or(rest)
+    .trim_start();
+  let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+  let rest = &rest[1..];
+  let path = alias_path(&rest[..rest.find(quote)?], &opts.path_aliases);
+  let path = path.strip_suffix(".proto").unwrap_or(&path);
+  let (root_dir, stem) = split_path(path);
+  Some(resolve_path(
+    root_dir,
+    proto_unit_name(&stem),
+    &opts.known_roots,
+  ))
+}
+
+// The path an #include of path really names, going by the longest alias
+// that is all of path or one of its directories: with folly/compat aliased
+// to folly/impl, folly/compat/Foo.h is folly/impl/Foo.h.
+pub fn alias_path(path: &str, aliases: &BTreeMap<String, PathBuf>) -> String {
+  let alias = aliases
+    .iter()
+    .map(|(virtual_path, real)| (virtual_path.trim_end_matches('/'), real))
+    .filter(|(virtual_path, _)| match path.strip_prefix(virtual_path) {
+      Some(rest) => rest.is_empty() || rest.starts_with('/'),
+      None => false,
+    })
+    .max_by_key(|(virtual_path, _)| virtual_path.len());
+  match alias {
+    Some((virtual_path, real)) => format!(
+      "{}{}",
+      real.to_string_lossy().trim_end_matches('/'),
+      &path[virtual_path.len()..]
+    ),
+    None => path.to_string(),
+  }
+}
+
+pub fn strip_include(
+  line: &str,
+  opts: &ScanOptions,
+) -> Option<(UnitKey, HeaderLib)> {
+  let path = match lex_include(line)? {
+    IncludeTarget::Angled(path) | IncludeTarget::Quoted(path) => path,
+    IncludeTarget::Unrecognized => {
+      WARNINGS.warn(format_args!("Unexpected include: {}", line.trim()));
+      return None;
     }
   };
 
-  match line.find('<') {
-    Some(start) => match line.find('>') {
-      Some(end) => extract_unit(start + 1, end),
-      None => {
-        assert!(false, "Bad include! {}", line);
-        None
-      }
-    },
-    None => match line.find('"') {
-      Some(start) => match (&line[(start + 1)..]).find('"') {
-        Some(end) => extract_unit(start + 1, end),
-        None => {
-          assert!(false, "Bad include! {}", line);
-          None
-        }
-      },
-      None => {
-        println!("Unexpected include: {}", line);
-        None
+  let path = alias_path(path, &opts.path_aliases);
+  // protoc's C++ header for foo.proto is foo.pb.h, which belongs to the
+  // proto's unit.
+  if let Some(stem) = path.strip_suffix(".pb.h") {
+    let (root_dir, stem) = split_path(stem);
+    return Some(resolve_path(
+      root_dir,
+      proto_unit_name(&stem),
+      &opts.known_roots,
+    ));
+  }
+  let path: &str = path
+    .trim_end_matches("-inl.h")
+    .trim_end_matches(".hpp")
+    .trim_end_matches(".hh")
+    .trim_end_matches(".h");
+  let (root_dir, name) = normalize_include_path(path);
+  Some(resolve_path(root_dir, name, &opts.known_roots))
+}
+
+// The unit whose test file an #include names, e.g. folly/io/io_buf for
+// <folly/io/IOBuftest.cpp>, where strip_include would see a unit of its own.
+pub fn included_test(line: &str, opts: &ScanOptions) -> Option<UnitKey> {
+  let path = match lex_include(line)? {
+    IncludeTarget::Angled(path) | IncludeTarget::Quoted(path) => path,
+    IncludeTarget::Unrecognized => return None,
+  };
+  let (root_dir, file_name) = split_path(&alias_path(path, &opts.path_aliases));
+  match strip_file_name(&file_name) {
+    Ok((name, FileType::TEST)) => {
+      match resolve_path(root_dir, name, &opts.known_roots) {
+        (key, HeaderLib::FOLLY) => Some(key),
+        _ => None,
       }
-    },
+    }
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn camel_to_snake_digits_and_delimiters() {
+    for (camel, snake) in [
+      ("", ""),
+      ("F14Map", "f_14_map"),
+      ("SSLContext", "ssl_context"),
+      ("IPv6Address", "ipv6_address"),
+      ("SSLv3Context", "sslv3_context"),
+      ("json_pointer", "json_pointer"),
+      ("__folly_detail", "__folly_detail"),
+      ("Base64", "base64"),
+    ] {
+      assert_eq!(camel_to_snake(camel), snake, "{}", camel);
+    }
+  }
+
+  // A run of capitals is one word, up to the capital that starts the next.
+  #[test]
+  fn camel_to_snake_acronyms() {
+    for (camel, snake) in [
+      ("Foo", "foo"),
+      ("FOO", "foo"),
+      ("FBString", "fb_string"),
+      ("IOBuf", "io_buf"),
+      ("SSLContext", "ssl_context"),
+      ("HTTPServer", "http_server"),
+      ("TCP6", "tcp6"),
+      ("TCP6ServerSocket", "tcp6_server_socket"),
+      ("IPv6Address", "ipv6_address"),
+    ] {
+      assert_eq!(camel_to_snake(camel), snake, "{}", camel);
+    }
+  }
+
+  #[test]
+  fn strip_file_name_knows_every_extension() {
+    for (file_name, name, file_type) in [
+      ("Foo.hpp", "foo", FileType::HEADER),
+      ("Foo.hh", "foo", FileType::TEMPLATE),
+      ("Foo.cxx", "foo", FileType::SOURCE),
+      ("Foo.c", "foo", FileType::SOURCE),
+      ("Foo.h", "foo", FileType::HEADER),
+      ("Foo-inl.h", "foo", FileType::TEMPLATE),
+      ("Foo.cpp", "foo", FileType::SOURCE),
+      ("FooTest.cpp", "foo_test", FileType::SOURCE),
+      ("Footest.cc", "foo", FileType::TEST),
+      ("Foo.txt", "Foo.txt", FileType::UNKNOWN),
+    ] {
+      let (stripped, stripped_type) = strip_file_name(file_name).unwrap();
+      assert_eq!(stripped, name, "{}", file_name);
+      assert!(stripped_type == file_type, "{}", file_name);
+    }
+  }
+
+  fn include_key(line: &str) -> Option<UnitKey> {
+    match strip_include(line, &ScanOptions::default())? {
+      (key, HeaderLib::FOLLY) => Some(key),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn include_next_is_an_include() {
+    assert_eq!(
+      include_key("#include_next <folly/Utility.h>"),
+      Some(UnitKey::new("utility", "folly"))
+    );
+  }
+
+  #[test]
+  fn include_allows_whitespace_around_the_hash() {
+    for line in [
+      "#  include <folly/Bar.h>",
+      "\t#include <folly/Bar.h>",
+      "  #  include  \"folly/Bar.h\"",
+    ] {
+      assert_eq!(
+        include_key(line),
+        Some(UnitKey::new("bar", "folly")),
+        "{}",
+        line
+      );
+    }
+    assert_eq!(include_key("// #include <folly/Bar.h>"), None);
+  }
+
+  #[test]
+  fn defines_main_spots_every_spelling() {
+    for line in [
+      "int main(int argc, char** argv) {",
+      "int main () {",
+      "int  main\t(void)",
+      "static int main(",
+    ] {
+      assert!(defines_main(line), "{}", line);
+    }
+    for line in ["uint main(", "int mainly(", "int remain(", "// main"] {
+      assert!(!defines_main(line), "{}", line);
+    }
+  }
+
+  #[test]
+  fn mm_files_are_objective_c() {
+    for (file_name, name) in [
+      ("Foo.mm", "foo"),
+      ("FooApple.mm", "foo_apple"),
+      ("Footest.mm", "footest"),
+    ] {
+      let (stripped, file_type) = strip_file_name(file_name).unwrap();
+      assert_eq!(stripped, name, "{}", file_name);
+      assert!(file_type == FileType::OBJC, "{}", file_name);
+    }
+    let (_, file_type) = strip_file_name("Foo.m").unwrap();
+    assert!(file_type == FileType::UNKNOWN);
+  }
+
+  #[test]
+  fn strip_import_takes_every_form_of_import() {
+    let opts = ScanOptions::default();
+    for line in [
+      "import \"folly/rpc/types.proto\";",
+      "  import public \"folly/rpc/types.proto\";",
+      "import weak 'folly/rpc/types.proto';",
+    ] {
+      let (key, lib) = strip_import(line, &opts).unwrap();
+      assert_eq!(key, UnitKey::new("types_proto", "folly/rpc"), "{}", line);
+      assert!(lib == HeaderLib::FOLLY, "{}", line);
+    }
+    assert!(strip_import("syntax = \"proto3\";", &opts).is_none());
+    assert!(strip_import("import \"unterminated.proto;", &opts).is_none());
+  }
+
+  #[test]
+  fn absl_includes_are_abseil() {
+    let opts = ScanOptions::default();
+    for (line, name, root_dir) in [
+      (
+        "#include <absl/container/flat_hash_map.h>",
+        "flat_hash_map",
+        "absl/container",
+      ),
+      (
+        "#include \"absl/strings/str_cat.h\"",
+        "str_cat",
+        "absl/strings",
+      ),
+      (
+        "#include <absl/synchronization/mutex.h>",
+        "mutex",
+        "absl/synchronization",
+      ),
+    ] {
+      let (key, lib) = strip_include(line, &opts).unwrap();
+      assert_eq!(key, UnitKey::new(name, root_dir), "{}", line);
+      assert!(lib == HeaderLib::ABSEIL, "{}", line);
+    }
+    let opts = ScanOptions {
+      known_roots: [("absl".to_string(), "com_google_absl".to_string())]
+        .into_iter()
+        .collect(),
+      ..ScanOptions::default()
+    };
+    let (_, lib) =
+      strip_include("#include <absl/base/config.h>", &opts).unwrap();
+    assert!(lib == HeaderLib::THIRDPARTY("com_google_absl".to_string()));
+  }
+
+  fn guarded(contents: &str) -> bool {
+    let lines: Vec<String> = contents.lines().map(String::from).collect();
+    has_include_guard(&lines)
+  }
+
+  #[test]
+  fn include_guards_and_pragma_once_count() {
+    for contents in [
+      "#pragma once\n",
+      "/*\n * Copyright\n */\n\n#pragma once\n",
+      "  #  pragma   once\n",
+      "#ifndef FOLLY_FOO_H\n#define FOLLY_FOO_H\n#endif\n",
+      "// Copyright\n#ifndef FOLLY_FOO_H\n\n#define FOLLY_FOO_H 1\n",
+      "# ifndef FOLLY_FOO_H\n# define FOLLY_FOO_H\n",
+    ] {
+      assert!(guarded(contents), "{}", contents);
+    }
+  }
+
+  #[test]
+  fn headers_without_a_guard_are_caught() {
+    let late = "// filler\n".repeat(GUARD_SCAN_LINES) + "#pragma once\n";
+    for contents in [
+      "",
+      "int f();\n",
+      "// #pragma once\n",
+      "#ifndef FOLLY_FOO_H\n#define FOLLY_BAR_H\n",
+      "#ifndef FOLLY_FOO_H\n#include <x.h>\n#define FOLLY_FOO_H\n",
+      "#define FOLLY_FOO_H\n#ifndef FOLLY_FOO_H\n",
+      "#pragma pack(1)\n",
+      &late,
+    ] {
+      assert!(!guarded(contents), "{}", contents);
+    }
+  }
+
+  #[test]
+  fn copts_comments_split_their_flags() {
+    for (line, flags) in [
+      (
+        "// BAZEL_COPTS: -mavx2 -DFOO",
+        Some(vec!["-mavx2", "-DFOO"]),
+      ),
+      ("  //BAZEL_COPTS:-O3", Some(vec!["-O3"])),
+      ("// BAZEL_COPTS:", Some(vec![])),
+      ("int x; // BAZEL_COPTS: -mavx2", None),
+      ("// see BAZEL_COPTS: -mavx2", None),
+      ("/* BAZEL_COPTS: -mavx2 */", None),
+    ] {
+      assert_eq!(
+        copts_comment(line),
+        flags.map(|flags| flags.iter().map(|f| f.to_string()).collect()),
+        "{}",
+        line
+      );
+    }
+    assert_eq!(
+      linkopts_comment("// BAZEL_LINKOPTS: -lpthread -ldl").unwrap(),
+      ["-lpthread", "-ldl"]
+    );
+    assert!(linkopts_comment("// BAZEL_COPTS: -O3").is_none());
+  }
+
+  #[test]
+  fn normalize_include_path_resolves_dot_dot() {
+    for (path, root_dir, name) in [
+      ("folly/io/../IOBuf", "folly", "io_buf"),
+      ("folly/./io/IOBuf", "folly/io", "io_buf"),
+      ("folly/io/async/../../Range", "folly", "range"),
+      ("folly/../folly/Range", "folly", "range"),
+      ("../folly/Range", "../folly", "range"),
+    ] {
+      assert_eq!(
+        normalize_include_path(path),
+        (root_dir.to_string(), name.to_string()),
+        "{}",
+        path
+      );
+    }
+    assert_eq!(
+      include_key("#include <folly/io/../String.h>"),
+      Some(UnitKey::new("string", "folly"))
+    );
+  }
+
+  #[test]
+  fn normalize_include_path_without_a_directory() {
+    for (path, name) in [("Foo", "foo"), ("./IOBuf", "io_buf"), ("", "")] {
+      assert_eq!(
+        normalize_include_path(path),
+        (String::new(), name.to_string()),
+        "{}",
+        path
+      );
+    }
+    let (key, lib) =
+      strip_include("#include \"FooBar.h\"", &ScanOptions::default()).unwrap();
+    assert_eq!(key, UnitKey::new("foo_bar", ""));
+    assert!(lib == HeaderLib::UNKNOWN);
+  }
+
+  fn generated(contents: &str) -> bool {
+    let lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    is_generated(&lines)
+  }
+
+  #[test]
+  fn generated_markers_in_the_first_lines() {
+    for contents in [
+      "// @generated by thrift\n#pragma once\n",
+      "  // Generated by the protocol buffer compiler.\n",
+      "/* DO NOT EDIT */\n",
+      "#pragma once\n/* DO NOT EDIT */ // flatc\n",
+      "// Copyright\n//\n//\n//\n// @generated\n",
+    ] {
+      assert!(generated(contents), "{}", contents);
+    }
+    for contents in [
+      "#pragma once\n",
+      "// This file is not @generated\n",
+      "// generated by hand\n",
+      "// Copyright\n//\n//\n//\n//\n// @generated\n",
+      "",
+    ] {
+      assert!(!generated(contents), "{}", contents);
+    }
+  }
+
+  fn aliases(pairs: &[(&str, &str)]) -> BTreeMap<String, PathBuf> {
+    pairs
+      .iter()
+      .map(|(virtual_path, real)| (virtual_path.to_string(), real.into()))
+      .collect()
+  }
+
+  #[test]
+  fn alias_path_picks_the_longest_directory_match() {
+    let aliases = aliases(&[
+      ("folly/compat", "folly/impl"),
+      ("folly/compat/io/", "folly/io/"),
+      ("folly/Old.h", "folly/New.h"),
+    ]);
+    for (path, real) in [
+      ("folly/compat/Foo.h", "folly/impl/Foo.h"),
+      ("folly/compat/io/IOBuf.h", "folly/io/IOBuf.h"),
+      ("folly/compat", "folly/impl"),
+      ("folly/Old.h", "folly/New.h"),
+      // Not under folly/compat, only named like it.
+      ("folly/compatibility/Foo.h", "folly/compatibility/Foo.h"),
+      ("folly/Foo.h", "folly/Foo.h"),
+    ] {
+      assert_eq!(alias_path(path, &aliases), real, "{}", path);
+    }
+  }
+
+  #[test]
+  fn aliased_includes_name_the_real_unit() {
+    let opts = ScanOptions {
+      path_aliases: aliases(&[
+        ("folly/compat", "folly/impl"),
+        ("fmt", "folly/fmt"),
+      ]),
+      ..ScanOptions::default()
+    };
+    let (key, lib) =
+      strip_include("#include <folly/compat/io/IOBuf.h>", &opts).unwrap();
+    assert_eq!(key, UnitKey::new("io_buf", "folly/impl/io"));
+    assert!(lib == HeaderLib::FOLLY);
+
+    // An alias can bring a path from outside folly into it.
+    let (key, lib) = strip_include("#include \"fmt/Format.h\"", &opts).unwrap();
+    assert_eq!(key, UnitKey::new("format", "folly/fmt"));
+    assert!(lib == HeaderLib::FOLLY);
+  }
+
+  #[test]
+  fn included_test_names_the_unit_of_a_test_file() {
+    let opts = ScanOptions::default();
+    assert_eq!(
+      included_test("#include <folly/io/IOBuftest.cpp>", &opts),
+      Some(UnitKey::new("io_buf".to_string(), "folly/io".to_string()))
+    );
+    assert_eq!(included_test("#include <folly/io/IOBuf.h>", &opts), None);
+    assert_eq!(included_test("#include <gtest/footest.cc>", &opts), None);
+  }
+
+  #[test]
+  fn conditions_parse_from_one_macro_only() {
+    let parse = |line: &str| Condition::parse(&directive_words(line).unwrap());
+    let condition = |define: &str, defined| {
+      Some(Condition {
+        define: define.to_string(),
+        defined,
+      })
+    };
+    for (line, expected) in [
+      ("#ifdef FOO", condition("FOO", true)),
+      ("#  ifndef FOO // no foo", condition("FOO", false)),
+      ("#if FOO", condition("FOO", true)),
+      ("#if defined(FOO)", condition("FOO", true)),
+      ("#if defined FOO", condition("FOO", true)),
+      ("#if !defined( FOO )", condition("FOO", false)),
+      ("#elif ! FOO /* off */", condition("FOO", false)),
+      ("#elifdef FOO", condition("FOO", true)),
+      ("#if FOO && BAR", None),
+      ("#if 0", None),
+      ("#if", None),
+      ("#else", None),
+    ] {
+      assert_eq!(parse(line), expected, "{}", line);
+    }
+    assert_eq!(
+      condition("FOO", true).unwrap().negate(),
+      condition("FOO", false).unwrap()
+    );
   }
 }
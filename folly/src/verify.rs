@@ -0,0 +1,154 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::starlark_parser::{parse_build_file, StarlarkRule, SyntaxError};
+
+fn diff_sets(
+  problems: &mut Vec<String>,
+  rule: &str,
+  what: &str,
+  expected: BTreeSet<&str>,
+  actual: BTreeSet<&str>,
+) {
+  for missing in expected.difference(&actual) {
+    problems.push(format!("{}: missing {} {}", rule, what, missing));
+  }
+  for extra in actual.difference(&expected) {
+    problems.push(format!("{}: extra {} {}", rule, what, extra));
+  }
+}
+
+// Lists how the rules in `actual` differ from the ones we would generate.
+// Rules we don't generate are left alone, since they are presumably written
+// by hand. hdrs and srcs are compared together as one set of files, and
+// likewise deps and implementation_deps as one set of labels. Fails if
+// `actual` isn't Starlark we can read.
+pub fn diff_build_file(
+  expected: &str,
+  actual: &str,
+) -> Result<Vec<String>, SyntaxError> {
+  let actual: HashMap<String, StarlarkRule> = parse_build_file(actual)?
+    .into_iter()
+    .map(|rule| (rule.name.clone(), rule))
+    .collect();
+  let mut problems = Vec::new();
+  // We wrote expected ourselves, so it always parses.
+  for rule in parse_build_file(expected)? {
+    let Some(existing) = actual.get(&rule.name) else {
+      problems.push(format!("{}: missing {} rule", rule.name, rule.rule_type));
+      continue;
+    };
+    if existing.rule_type != rule.rule_type {
+      problems.push(format!(
+        "{}: expected {} but found {}",
+        rule.name, rule.rule_type, existing.rule_type
+      ));
+    }
+    diff_sets(
+      &mut problems,
+      &rule.name,
+      "src",
+      rule.strings(&["hdrs", "srcs"]),
+      existing.strings(&["hdrs", "srcs"]),
+    );
+    diff_sets(
+      &mut problems,
+      &rule.name,
+      "dep",
+      rule.strings(&["deps", "implementation_deps"]),
+      existing.strings(&["deps", "implementation_deps"]),
+    );
+  }
+  Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const EXPECTED: &str = r#"
+cc_library(
+    name = "Foo",
+    hdrs = ["Foo.h"],
+    srcs = ["Foo.cpp"],
+    deps = ["//folly/io:Bar"],
+    implementation_deps = [":Baz"],
+)
+"#;
+
+  #[test]
+  fn matching_rules_have_no_problems() {
+    // hdrs and srcs are one set, as are the two kinds of deps.
+    let actual = r#"
+cc_library(
+    name = "Foo",
+    srcs = ["Foo.cpp", "Foo.h"],
+    deps = [":Baz", "//folly/io:Bar"],
+)
+"#;
+    assert!(diff_build_file(EXPECTED, actual).unwrap().is_empty());
+  }
+
+  #[test]
+  fn reports_missing_and_extra_srcs_and_deps() {
+    let actual = r#"
+cc_library(
+    name = "Foo",
+    hdrs = ["Foo.h", "Old.h"],
+    deps = ["//folly/io:Bar", ":Old"],
+)
+"#;
+    assert_eq!(
+      diff_build_file(EXPECTED, actual).unwrap(),
+      [
+        "Foo: missing src Foo.cpp",
+        "Foo: extra src Old.h",
+        "Foo: missing dep :Baz",
+        "Foo: extra dep :Old",
+      ]
+    );
+  }
+
+  #[test]
+  fn reports_a_rule_of_the_wrong_type_or_none_at_all() {
+    let actual = r#"
+cc_binary(
+    name = "Foo",
+    hdrs = ["Foo.h"],
+    srcs = ["Foo.cpp"],
+    deps = ["//folly/io:Bar", ":Baz"],
+)
+"#;
+    assert_eq!(
+      diff_build_file(EXPECTED, actual).unwrap(),
+      ["Foo: expected cc_library but found cc_binary"]
+    );
+    assert_eq!(
+      diff_build_file(EXPECTED, "").unwrap(),
+      ["Foo: missing cc_library rule"]
+    );
+  }
+
+  #[test]
+  fn leaves_hand_written_rules_alone() {
+    let actual = r#"
+cc_library(
+    name = "Foo",
+    hdrs = ["Foo.h"],
+    srcs = ["Foo.cpp"],
+    deps = ["//folly/io:Bar", ":Baz"],
+)
+
+genrule(
+    name = "Generated",
+    srcs = ["Generated.in"],
+    outs = ["Generated.h"],
+)
+"#;
+    assert!(diff_build_file(EXPECTED, actual).unwrap().is_empty());
+  }
+
+  #[test]
+  fn fails_on_a_build_file_it_cannot_read() {
+    assert!(diff_build_file(EXPECTED, "cc_library(\n").is_err());
+  }
+}
@@ -0,0 +1,42 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counts the warnings a run prints, so that --max-warnings can fail it.
+// Warnings come from deep inside a scan, on whichever thread is parsing,
+// so there is one collector for the whole process rather than one to pass
+// around.
+pub struct WarningCollector {
+  count: AtomicUsize,
+}
+
+pub static WARNINGS: WarningCollector = WarningCollector::new();
+
+impl WarningCollector {
+  const fn new() -> Self {
+    WarningCollector {
+      count: AtomicUsize::new(0),
+    }
+  }
+
+  pub fn warn(&self, message: impl Display) {
+    eprintln!("Warning: {}", message);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  // Findings from --lint, which count the same but read differently.
+  pub fn lint(&self, message: impl Display) {
+    eprintln!("Lint: {}", message);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  // For problems that fail the run once they have all been printed, such
+  // as --check-header-cycles finds. Counted like warnings too.
+  pub fn error(&self, message: impl Display) {
+    eprintln!("Error: {}", message);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn count(&self) -> usize {
+    self.count.load(Ordering::Relaxed)
+  }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::build_file::BuildOptions;
+use crate::builder::GraphBuilder;
+use crate::error::FollyError;
+use crate::file_system::{FileSystemProvider, RealFileSystem};
+use crate::intrusive_hashmap::MutateExtract;
+use crate::types::{ScanOptions, UnitKey, UnitMap};
+use crate::{
+  _ParseFile, context, diff, file_unit_key, reset_unit, serialization,
+  CompileGraph, CompileTrie, FileType,
+};
+
+// Editors tend to save in several steps, e.g. write to a temporary file and
+// then rename it over the original, so events are gathered until the tree
+// has been quiet for this long.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+fn is_excluded(root: &Path, path: &Path, opts: &ScanOptions) -> bool {
+  path
+    .strip_prefix(root)
+    .unwrap_or(path)
+    .components()
+    .any(|c| match c {
+      Component::Normal(name) => name
+        .to_str()
+        .is_some_and(|name| opts.exclude_dirs.contains(name)),
+      _ => false,
+    })
+}
+
+// Files in dir that belong to the unit key, going by what is on disk now.
+fn unit_files<F: FileSystemProvider>(
+  fs: &F,
+  base: &Path,
+  dir: &Path,
+  key: &UnitKey,
+) -> Result<Vec<PathBuf>, FollyError> {
+  let mut files = Vec::new();
+  if !fs.is_dir(dir) {
+    return Ok(files);
+  }
+  for path in fs.read_dir(dir).map_err(|e| FollyError::at(dir, e))? {
+    if fs.is_dir(&path) {
+      continue;
+    }
+    match file_unit_key(base, &path) {
+      Ok((child_key, _, file_type))
+        if child_key == *key && file_type != FileType::UNKNOWN =>
+      {
+        files.push(path)
+      }
+      _ => {}
+    }
+  }
+  files.sort();
+  Ok(files)
+}
+
+// Re-parses every unit with a file among paths. A unit is only parsed as a
+// whole, since its headers and sources all feed the same node. Units left
+// with no files are removed, unless something still includes them.
+fn rescan_paths<F: FileSystemProvider>(
+  fs: &F,
+  dict: &mut UnitMap,
+  root: &Path,
+  paths: &BTreeSet<PathBuf>,
+  opts: &ScanOptions,
+) -> Result<(), FollyError> {
+  let base = root.parent().unwrap_or(root);
+  let mut units: Vec<(UnitKey, PathBuf)> = Vec::new();
+  for path in paths {
+    if is_excluded(root, path, opts) {
+      continue;
+    }
+    // Generated BUILD files land in the tree too, and seeds aren't rescanned.
+    let Ok((key, _, file_type)) = file_unit_key(base, path) else {
+      continue;
+    };
+    if file_type == FileType::UNKNOWN || file_type == FileType::BAZEL_BUILD {
+      continue;
+    }
+    let dir = path.parent().unwrap_or(base).to_path_buf();
+    if !units.iter().any(|(seen, _)| *seen == key) {
+      units.push((key, dir));
+    }
+  }
+
+  for (key, dir) in units {
+    let node = dict.extract_with_create(key.clone());
+    reset_unit(&node)?;
+    let files = unit_files(fs, base, &dir, &key)?;
+    let included = node
+      .val
+      .borrow()
+      .reverse_deps
+      .iter()
+      .any(|rdep| rdep.upgrade().is_some());
+    if files.is_empty() && !included {
+      dict.remove(&key);
+    }
+    for file in files {
+      dict.add_node(fs, base, &file, opts)?;
+    }
+  }
+  Ok(())
+}
+
+// Inspects dict as scanned, then collapses and writes out a copy of it, so
+// that dict itself stays as scanned and can take the next round of changes.
+fn write_build_files<F: FileSystemProvider>(
+  fs: &F,
+  builder: &mut GraphBuilder,
+  dict: &UnitMap,
+  opts: &BuildOptions,
+) -> Result<(), Box<dyn Error>> {
+  builder.run_inspectors(dict)?;
+  let mut collapsed = context(serialization::deep_copy(dict), || {
+    "Failed to copy dependency graph".to_string()
+  })?;
+  builder.finish(&mut collapsed)?;
+  let trie = context(collapsed.generate_compilation_trie(), || {
+    "Failed to generate trie of compilation units".to_string()
+  })?;
+  context(trie.write_build_files(&mut opts.sink(fs), opts), || {
+    "Failed to write BUILD files".to_string()
+  })
+}
+
+// Scans and writes BUILD files as a normal run would, then keeps doing so
+// for every batch of changes under root until interrupted. Each batch
+// prints how the graph changed, in the same form as --diff.
+pub fn watch(
+  builder: &mut GraphBuilder,
+  root: &Path,
+  opts: &BuildOptions,
+) -> Result<(), Box<dyn Error>> {
+  let root = context(root.canonicalize(), || {
+    format!("Could not resolve {}", root.display())
+  })?;
+  let dict = builder.scan_graph()?;
+  write_build_files(&RealFileSystem, builder, &dict, opts)?;
+
+  let (tx, rx) = mpsc::channel();
+  let mut watcher = context(notify::recommended_watcher(tx), || {
+    "Failed to start file watcher".to_string()
+  })?;
+  context(watcher.watch(&root, RecursiveMode::Recursive), || {
+    format!("Failed to watch {}", root.display())
+  })?;
+  println!("Watching {} for changes.", root.display());
+  watch_events(&RealFileSystem, builder, &root, dict, &rx, opts)
+}
+
+// The loop watch runs once dict, scanned from root, has been written out:
+// each batch of events from rx is rescanned into dict and written out
+// again. Returns once every sender for rx is gone.
+fn watch_events<F: FileSystemProvider>(
+  fs: &F,
+  builder: &mut GraphBuilder,
+  root: &Path,
+  mut dict: UnitMap,
+  rx: &Receiver<notify::Result<Event>>,
+  opts: &BuildOptions,
+) -> Result<(), Box<dyn Error>> {
+  while let Ok(first) = rx.recv() {
+    let mut events = vec![first];
+    // A sender that has gone away still leaves this batch to handle.
+    while let Ok(event) = rx.recv_timeout(SETTLE_TIME) {
+      events.push(event);
+    }
+    let mut paths = BTreeSet::new();
+    for event in events {
+      match event {
+        Ok(event) => {
+          if !matches!(event.kind, EventKind::Access(_)) {
+            paths.extend(event.paths);
+          }
+        }
+        Err(e) => eprintln!("Watch error: {}", e),
+      }
+    }
+
+    let before = context(serialization::deep_copy(&dict), || {
+      "Failed to copy dependency graph".to_string()
+    })?;
+    context(
+      rescan_paths(fs, &mut dict, root, &paths, builder.options()),
+      || format!("Failed to rescan {}", root.display()),
+    )?;
+    let changes = diff::diff_maps(&before, &dict);
+    if changes.is_empty() {
+      continue;
+    }
+    for line in &changes {
+      println!("{}", line);
+    }
+    write_build_files(fs, builder, &dict, opts)?;
+    println!("Regenerated Starlark build files.");
+  }
+  // The watcher is gone, taking the sender with it.
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+  use std::io::Read;
+
+  use notify::event::{CreateKind, ModifyKind};
+
+  use super::*;
+  use crate::test_util::{build_options, mock_fs, scan, BASE};
+
+  fn event(kind: EventKind, path: &str) -> notify::Result<Event> {
+    Ok(Event::new(kind).add_path(PathBuf::from(format!("{}/{}", BASE, path))))
+  }
+
+  #[test]
+  fn rescans_and_rewrites_on_each_batch() {
+    let fs = mock_fs(&[("folly/Foo.h", "#pragma once\n")]);
+    let dict = scan(&[("folly/Foo.h", "#pragma once\n")]);
+    let inspected = Cell::new(0);
+    let mut builder = GraphBuilder::new();
+    builder.inspect(|_| {
+      inspected.set(inspected.get() + 1);
+      Ok(())
+    });
+
+    fs.write_file(
+      Path::new("/src/folly/Foo.h"),
+      b"#pragma once\n#include <folly/Bar.h>\n",
+    )
+    .unwrap();
+    fs.write_file(Path::new("/src/folly/Bar.h"), b"#pragma once\n")
+      .unwrap();
+    let (tx, rx) = mpsc::channel();
+    tx.send(event(EventKind::Modify(ModifyKind::Any), "folly/Foo.h"))
+      .unwrap();
+    tx.send(event(EventKind::Create(CreateKind::File), "folly/Bar.h"))
+      .unwrap();
+    drop(tx);
+    let root = Path::new(BASE).join("folly");
+    watch_events(&fs, &mut builder, &root, dict, &rx, &build_options())
+      .unwrap();
+
+    assert_eq!(inspected.get(), 1);
+    let mut build = String::new();
+    fs.open_file(Path::new("/src/folly/BUILD"))
+      .unwrap()
+      .read_to_string(&mut build)
+      .unwrap();
+    assert!(build.contains("name = \"Bar\""), "{}", build);
+    assert!(build.contains("\":Bar\""), "{}", build);
+  }
+}
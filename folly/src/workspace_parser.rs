@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::FollyError;
+use crate::starlark_parser::{parse_build_file, SyntaxError};
+
+// Rules that bring in an external repository under their name attribute.
+const REPOSITORY_RULES: [&str; 2] = ["http_archive", "git_repository"];
+
+// The names that labels can use for each external repository declared in
+// a WORKSPACE or MODULE.bazel file. A bazel_dep goes by its repo_name when
+// it has one, which is how MODULE.bazel gives a module a local alias.
+pub fn repository_names(contents: &str) -> Result<Vec<String>, SyntaxError> {
+  let rules = parse_build_file(contents)?;
+  Ok(
+    rules
+      .into_iter()
+      .filter_map(|rule| {
+        if REPOSITORY_RULES.contains(&rule.rule_type.as_str()) {
+          Some(rule.name)
+        } else if rule.rule_type == "bazel_dep" {
+          let alias = rule.string("repo_name").map(str::to_string);
+          Some(alias.unwrap_or(rule.name))
+        } else {
+          None
+        }
+      })
+      .collect(),
+  )
+}
+
+pub fn load(path: &Path) -> Result<Vec<String>, FollyError> {
+  let contents =
+    fs::read_to_string(path).map_err(|e| FollyError::at(path, e))?;
+  repository_names(&contents).map_err(|e| e.at(path))
+}
+
+// Which of repositories a known root's deps should point at: the one named
+// after its workspace, or failing that the only one with the workspace or
+// the include prefix as a whole word of its name, as in
+// com_github_google_glog for glog.
+pub fn resolve_repository(
+  prefix: &str,
+  workspace: &str,
+  repositories: &[String],
+) -> Option<String> {
+  if repositories.iter().any(|repo| repo == workspace) {
+    return Some(workspace.to_string());
+  }
+  let mut matches = repositories.iter().filter(|repo| {
+    repo
+      .split(['_', '-', '.'])
+      .any(|word| word == prefix || word == workspace)
+  });
+  match (matches.next(), matches.next()) {
+    (Some(repo), None) => Some(repo.clone()),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WORKSPACE: &str = r#"
+load("@bazel_tools//tools/build_defs/repo:http.bzl", "http_archive")
+
+http_archive(
+    name = "com_google_absl",
+    urls = ["https://github.com/abseil/abseil-cpp/archive/master.zip"],
+)
+
+git_repository(
+    name = "com_github_google_glog",
+    remote = "https://github.com/google/glog.git",
+)
+
+bazel_dep(name = "boost.any", version = "1.83.0")
+bazel_dep(name = "fmt", version = "10.2.1", repo_name = "fmtlib")
+local_repository(name = "local", path = "../local")
+"#;
+
+  #[test]
+  fn repository_names_finds_each_external_repository() {
+    assert_eq!(
+      repository_names(WORKSPACE).unwrap(),
+      [
+        "com_google_absl",
+        "com_github_google_glog",
+        "boost.any",
+        "fmtlib"
+      ]
+    );
+    assert!(repository_names("http_archive(name = ").is_err());
+  }
+
+  #[test]
+  fn resolve_repository_matches_known_roots_to_repositories() {
+    let repositories = repository_names(WORKSPACE).unwrap();
+    for (prefix, workspace, repo) in [
+      ("fmt", "fmtlib", Some("fmtlib")),
+      ("glog", "glog", Some("com_github_google_glog")),
+      ("absl", "abseil", Some("com_google_absl")),
+      ("boost", "boost", Some("boost.any")),
+      ("gtest", "googletest", None),
+    ] {
+      assert_eq!(
+        resolve_repository(prefix, workspace, &repositories).as_deref(),
+        repo,
+        "{}",
+        prefix
+      );
+    }
+    let ambiguous = ["org_glog".to_string(), "com_glog".to_string()];
+    assert_eq!(resolve_repository("glog", "glog", &ambiguous), None);
+  }
+}